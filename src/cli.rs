@@ -1,50 +1,553 @@
-use crate::constants;
-use clap::{App, Arg, ArgMatches};
-use core::panic;
+use crate::{
+    config::{self, Config},
+    constants,
+    download::{
+        checksum::ChecksumKind,
+        conflicts::ConflictPolicy,
+        crawl,
+        dedup::LinkKind,
+        excludes::DirGlob,
+        fetch::{DESCRIPTION_EXTENSION, PART_EXTENSION},
+        format::{self, DirDetection, ListingFormat, ServerFormat},
+        order::{SortKey, SortOrder},
+        progress,
+        template::PathTemplate,
+        timeout,
+        types::{MatchBy, StateStore},
+        units::{
+            parse_delay_range, parse_duration, parse_range, parse_schedule, parse_since,
+            parse_size, ByteRange, DelayRange, Schedule,
+        },
+        urllist,
+    },
+};
+use anyhow::{anyhow, bail, Context};
+use chrono::{DateTime, Local, Utc};
+use clap::{App, AppSettings, Arg, ArgMatches, Shell, SubCommand};
+use lazy_static::lazy_static;
+use log::LevelFilter;
 use regex::Regex;
 use reqwest::Url;
+use std::{
+    io::IsTerminal,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// How often the state store gets persisted during a download
+#[derive(Debug, Clone, Copy)]
+pub enum SaveInterval {
+    /// After this many downloaded files
+    Files(u64),
+
+    /// After this much time (checked whenever a file is done)
+    Time(Duration),
+}
 
 #[derive(Debug)]
 pub struct CliOptions {
     pub url: Url,
-    pub destination: String,
+
+    /// All root URLs (several if the URL contains a brace pattern like `{a,b}`), `url` is the
+    /// current one
+    pub roots: Vec<Url>,
+
+    /// The destination of each root (the one of its line in the mirror list, or the destination)
+    pub root_destinations: Vec<PathBuf>,
+
+    /// The roots were read from a list (see `--mirror-list`)
+    pub mirror_list: Option<PathBuf>,
+    pub failover: bool,
+    pub destination: PathBuf,
+
+    /// Where to keep the files being downloaded (instead of next to their final path)
+    pub temp_dir: Option<PathBuf>,
+
+    /// The suffix of the files being downloaded (which keep it if they turn out incomplete)
+    pub part_suffix: String,
+
+    /// Save the state store as gzip-compressed JSON
+    pub compress_state: bool,
+
+    /// Take over the locks of the output directory & state store, even if another run holds them
+    pub force_lock: bool,
+
+    /// Put the files of this run below a directory named by its start (inside `destination`)
+    pub snapshot: bool,
+
+    /// An earlier snapshot to hardlink unchanged files from
+    pub link_dest: Option<PathBuf>,
+    pub output: Option<Url>,
+
+    /// Write the files into this tar archive instead of the destination
+    pub output_tar: Option<PathBuf>,
+    pub save_descriptions: bool,
+    pub dump_tree: Option<PathBuf>,
+
+    /// Add the metadata of the files to this SQLite database instead of downloading them
+    pub catalog: Option<PathBuf>,
+    pub manifest: bool,
+    pub subpath: Option<String>,
+    pub deterministic: bool,
+    pub path_as_name: Option<String>,
+
+    /// Shorten longer file names to this many bytes (keeping the extension, adding a hash)
+    pub max_filename_length: Option<usize>,
+    pub path_template: Option<PathTemplate>,
+
+    /// Also create the local directories of empty remote ones
+    pub include_empty_dirs: bool,
+    pub no_cross_host: bool,
+    pub allow_external: bool,
+
+    /// Skip the files marked by a file of this name (or of their name plus this suffix)
+    pub skip_marker: Option<String>,
+    pub revalidate: bool,
+
+    /// Download large files in this many ranges at once (if the server supports ranges)
+    pub segments: Option<u64>,
+
+    /// Write the files through a memory map of their pre-allocated size (if it's known)
+    pub mmap: bool,
+
+    pub stream: bool,
+
+    /// Crawl the whole tree first, even if the run could stream
+    pub no_stream: bool,
+    pub sort: Option<SortOrder>,
+
+    /// Download the files of the whole tree by date, newest first (instead of one directory at a time)
+    pub newest_first: bool,
+    pub head_sizes: bool,
+    pub decompress: bool,
+    pub urls_file: Option<PathBuf>,
+
+    /// Write the directories still to be crawled to this file (e.g. when cut off by --max-dirs)
+    pub export_frontier: Option<PathBuf>,
+
+    /// Only crawl the directories of this file (written by --export-frontier) instead of the root
+    pub import_frontier: Option<PathBuf>,
+    pub range: Option<ByteRange>,
+
+    /// Write the file at the URL to stdout (instead of crawling)
+    pub stdout: bool,
+
+    /// Only parse the listing at the URL and print what was found in it (instead of crawling)
+    pub test_url: bool,
+
+    /// Only parse this saved listing as the one at the URL and print what was found in it
+    pub from_html: Option<PathBuf>,
+
+    /// Only count the files, directories & bytes of the tree (without keeping it or downloading)
+    pub count_only: bool,
+    pub stats: bool,
+    pub ndjson: bool,
+
+    /// The Unix socket answering with the progress of the download as JSON
+    pub status_socket: Option<PathBuf>,
+    pub prune: bool,
+    pub prune_dry_run: bool,
+    pub prune_exclude: Option<Regex>,
     pub no_download: bool,
+    pub dry_run: bool,
     pub verbosity: u64,
+    pub quiet: bool,
     pub limit_count: Option<u64>,
     pub skip_count: Option<u64>,
     pub recursion_limit: Option<u64>,
+    pub max_dirs: Option<u64>,
+    pub max_nodes: Option<u64>,
+    pub preview: Option<u64>,
+
+    /// Only download the first n (wanted) files of each directory, still crawling all of them
+    pub sample_per_dir: Option<u64>,
     pub file_filter: Option<Regex>,
     pub path_filter: Option<Regex>,
     pub file_matcher: Option<Regex>,
+
+    /// The accepted MIME types of files (lowercase, e.g. `image/*`), all types if empty
+    pub accept_types: Vec<String>,
     pub path_matcher: Option<Regex>,
+
+    /// The directories which don't get crawled at all (see `--exclude-dir`)
+    pub exclude_dirs: Vec<DirGlob>,
     pub state_store_path: Option<String>,
+    pub resume: bool,
+    pub match_by: MatchBy,
+
+    /// What to do when something else is in the way of a download
+    pub on_conflict: ConflictPolicy,
+    pub save_interval: SaveInterval,
+    pub max_time: Option<Duration>,
+    pub max_requests: Option<u64>,
+    pub connect_timeout: Duration,
+    pub read_timeout: Duration,
+
+    /// Fail on listings larger than this (None when unlimited)
+    pub max_listing_size: Option<u64>,
+
+    /// Speak HTTP/2 right away (without negotiating it)
+    pub http2: bool,
+
+    /// The number of idle connections kept open per host (None for no limit, 0 for no reuse)
+    pub pool_size: Option<usize>,
+    pub pool_idle_timeout: Duration,
+
+    /// The interval of TCP keep-alive probes (None to send none)
+    pub tcp_keepalive: Option<Duration>,
+    pub dedup: Option<LinkKind>,
+    pub emit_checksums: Option<ChecksumKind>,
+
+    /// Tag downloaded files with their source URL & digest (as extended attributes)
+    pub xattrs: bool,
+
+    /// Extract downloaded archives next to them (and delete the archives afterwards)
+    pub extract: bool,
+    pub extract_delete: bool,
+    pub only_newer: bool,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+
+    /// Skip files last modified before this point in time
+    pub since: Option<DateTime<Utc>>,
+    pub confirm_over: Option<u64>,
+    pub yes: bool,
+    pub skip_errors: bool,
+
+    /// Only retry the files which failed in earlier runs (as recorded in the state store)
+    pub replay_failures: bool,
+
+    /// Abort the run once this many files failed (None for no limit)
+    pub max_failures: Option<u64>,
+
+    /// Abort the run once this many files failed in a row (None for no limit)
+    pub consecutive_failures: Option<u64>,
+    pub strict_size: bool,
+
+    /// Check the sizes (and checksums) of all downloaded files once the download is done
+    pub verify_after: bool,
+
+    /// Download the files failing the check of --verify-after again
+    pub repair: bool,
+    pub continue_on_parse_error: bool,
+
+    /// Fail on listing rows which look like entries, but can't be parsed
+    pub strict_html: bool,
+
+    /// Only take links relative to the listing as entries (no leading slash or scheme)
+    pub relative_only: bool,
+
+    /// How directories are told apart from files in listings
+    pub dir_detection: DirDetection,
+
+    /// Parse the rows of listings with this pattern instead of as a table
+    pub row_pattern: Option<Regex>,
+
+    /// Parse every listing in this format instead of detecting it
+    pub assume_format: Option<ListingFormat>,
+
+    /// Crawl directories which list the same files as another one (e.g. symlinks) as well
+    pub follow_symlinks: bool,
+    pub user_agent: String,
+    pub user_agent_file: Option<PathBuf>,
+    pub ignore_robots: bool,
+    pub crawl_delay: Option<Duration>,
+    pub delay_jitter: Option<DelayRange>,
+
+    /// Don't wait between requests during these hours of the day
+    pub schedule: Option<Schedule>,
+    pub cookies: Vec<String>,
+    pub cookies_file: Option<PathBuf>,
+    pub ca_cert: Option<PathBuf>,
+    pub insecure: bool,
+
+    /// The host name certificates are verified for, instead of the one connected to
+    pub tls_server_name: Option<String>,
+
+    /// The `host:port` of a SOCKS5 proxy for all HTTP(S) requests (e.g. Tor's)
+    pub socks5: Option<String>,
+    pub report_path: Option<PathBuf>,
+    pub write_manifest: bool,
+
+    /// Record the exact version of every download in od-get.lock in the destination
+    pub write_lockfile: bool,
+
+    /// Only download the files of od-get.lock in the destination, failing on any difference
+    pub frozen: bool,
+    pub notify_command: Option<String>,
+
+    /// A shell command to run for every downloaded file (with `{path}`, `{url}` & `{size}`)
+    pub exec: Option<String>,
+
+    /// Fail the download when the `exec` command fails (instead of only warning)
+    pub exec_fail: bool,
+    pub progress: bool,
+
+    /// The time between two lines of progress when there are no progress bars (None when quiet)
+    pub progress_interval: Option<Duration>,
+
+    /// Keys of the config file which were ignored (to be logged once logging is set up)
+    pub unknown_config_keys: Vec<String>,
 }
 
-pub fn configure_parser(default_path: &str) -> App {
+#[cfg(test)]
+impl CliOptions {
+    /// Parses the options of a command line, which have to be valid (for the tests)
+    pub fn for_test(args: &[&str]) -> Self {
+        let matches = configure_parser("./")
+            .get_matches_from_safe(std::iter::once("od-get").chain(args.iter().copied()))
+            .unwrap();
+
+        get_options(matches).unwrap()
+    }
+}
+
+impl CliOptions {
+    /// The maximum level of log messages to be shown
+    pub fn log_level(&self) -> LevelFilter {
+        if self.quiet {
+            return LevelFilter::Error;
+        }
+
+        match self.verbosity {
+            0 => LevelFilter::Info,
+            1 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        }
+    }
+
+    /// Checks if the files get written to the destination (not uploaded or archived)
+    pub fn writes_locally(&self) -> bool {
+        self.output.is_none() && self.output_tar.is_none()
+    }
+
+    /// Checks if the files get saved in another layout than the remote tree
+    pub fn custom_layout(&self) -> bool {
+        self.path_as_name.is_some() || self.path_template.is_some()
+    }
+
+    /// How the listings of the server are laid out
+    pub fn server_format(&self) -> ServerFormat {
+        ServerFormat {
+            dir_detection: self.dir_detection,
+            row_pattern: self.row_pattern.clone(),
+            assumed: self.assume_format,
+            strict_html: self.strict_html,
+            relative_only: self.relative_only,
+            max_listing_size: self.max_listing_size,
+        }
+    }
+
+    /// Checks if the whole tree has to be crawled before downloading (so the run can't stream)
+    pub fn needs_tree(&self) -> bool {
+        self.no_download
+            || self.prune
+            || self.prune_dry_run
+            || self.dump_tree.is_some()
+            || self.subpath.is_some()
+            || self.head_sizes
+            || self.urls_file.is_some()
+            || self.export_frontier.is_some()
+            || self.import_frontier.is_some()
+            || self.sort.is_some()
+            || self.sample_per_dir.is_some()
+            || self.confirm_over.is_some()
+            || self.newest_first
+            || self.deterministic
+            || self.frozen
+            || self.verify_after
+    }
+
+    /// Switches to another one of the root URLs (along with its destination and its state store
+    /// for --resume)
+    pub fn set_root(&mut self, url: Url, destination: PathBuf) -> Result<(), anyhow::Error> {
+        self.destination = destination;
+        if self.resume {
+            self.state_store_path = Some(
+                StateStore::default_path(&url, &self.destination)?
+                    .to_string_lossy()
+                    .into_owned(),
+            );
+        }
+        self.url = url;
+
+        Ok(())
+    }
+}
+
+pub fn configure_parser(default_path: &str) -> App<'_, '_> {
     let app = App::new(constants::NAME)
         .version(constants::VERSION)
         .author(constants::AUTHOR)
         .about(constants::ABOUT)
         .after_help(constants::LICENSE)
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Checks a mirror against a completed state store (without crawling)")
+                .args(&[
+                    Arg::with_name("state_store")
+                        .takes_value(true)
+                        .help("The state store of the mirror")
+                        .required(true)
+                        .short("S")
+                        .long("store-state")
+                        .value_name("path"),
+                    Arg::with_name("destination")
+                        .help("The path the files were downloaded to")
+                        .default_value(default_path)
+                        .short("d")
+                        .long("destination")
+                        .value_name("path"),
+                    Arg::with_name("max_filename_length")
+                        .takes_value(true)
+                        .help("The --max-filename-length the files were downloaded with")
+                        .long("max-filename-length")
+                        .value_name("bytes"),
+                ]),
+        )
+        .subcommand(
+            SubCommand::with_name("state-info")
+                .about("Summarizes a state store (without crawling)")
+                .arg(
+                    Arg::with_name("state_store")
+                        .help("The state store to inspect")
+                        .required(true)
+                        .value_name("path")
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("merge-state")
+                .about("Merges two state stores of the same mirror into a new one (without crawling)")
+                .args(&[
+                    Arg::with_name("first")
+                        .help("A state store to merge")
+                        .required(true)
+                        .value_name("path")
+                        .index(1),
+                    Arg::with_name("second")
+                        .help("The other state store to merge")
+                        .required(true)
+                        .value_name("path")
+                        .index(2),
+                    Arg::with_name("output")
+                        .help("Where to write the merged state store")
+                        .required(true)
+                        .value_name("path")
+                        .index(3),
+                ]),
+        )
+        .subcommand(
+            SubCommand::with_name("diff-state")
+                .about("Lists the files & directories which changed between the trees of two state stores (without crawling)")
+                .args(&[
+                    Arg::with_name("old")
+                        .help("The state store of the earlier crawl")
+                        .required(true)
+                        .value_name("path")
+                        .index(1),
+                    Arg::with_name("new")
+                        .help("The state store of the later crawl")
+                        .required(true)
+                        .value_name("path")
+                        .index(2),
+                    Arg::with_name("json")
+                        .help("Print the changes as JSON")
+                        .long("json"),
+                ]),
+        )
+        .subcommand(
+            SubCommand::with_name("completions")
+                .about("Prints a shell completion script (e.g. `source <(od-get completions bash)`)")
+                .arg(
+                    Arg::with_name("shell")
+                        .help("The shell to complete in")
+                        .required(true)
+                        .possible_values(&Shell::variants())
+                        .index(1),
+                ),
+        )
         .args(&[
             Arg::with_name("URL")
-                .help("The root URL you want to crawl & download")
-                .required(true)
+                .help("The root URL you want to crawl & download (e.g. `https://{a,b}.example.org/` for several mirrors)")
+                .required_unless_one(&["config", "mirror_list"])
                 .index(1),
+            Arg::with_name("mirror_list")
+                .takes_value(true)
+                .help("Mirror the root URLs listed in this file (one per line, each optionally followed by its destination below --destination) one after the other")
+                .long("mirror-list")
+                .value_name("path")
+                .conflicts_with("URL"),
+            Arg::with_name("config")
+                .takes_value(true)
+                .help("Read options from a TOML (or .json) file, arguments take precedence")
+                .short("c")
+                .long("config")
+                .value_name("path"),
             Arg::with_name("destination")
                 .help("The path to which to write the downloaded files to")
                 .default_value(default_path)
                 .short("d")
                 .long("destination")
+                .alias("output-dir")
                 .value_name("path"),
+            Arg::with_name("temp_dir")
+                .takes_value(true)
+                .help("Write the files being downloaded (.od-part) into this directory, e.g. on a faster disk, and move them to the destination once complete")
+                .long("temp-dir")
+                .value_name("path"),
+            Arg::with_name("force_lock")
+                .help("Take over the lock of the destination (.od-get.lock) and state store even if another run seems to hold it, e.g. after a crash")
+                .long("force-lock"),
+            Arg::with_name("part_suffix")
+                .help("The suffix of the files being downloaded, which stay in place under this name if interrupted or incomplete (e.g. for pickup by rsync) and get renamed once complete")
+                .long("part-suffix")
+                .value_name("suffix")
+                .default_value(PART_EXTENSION),
+            Arg::with_name("segments")
+                .takes_value(true)
+                .help("Download large files (from 16 MiB) in n ranges at once over several connections, if the server supports ranges")
+                .long("segments")
+                .value_name("n"),
+            Arg::with_name("mmap")
+                .help("Write large files through a memory map of their pre-allocated size (from the listing or a HEAD request), writing files of unknown size as usual")
+                .long("mmap"),
+            Arg::with_name("snapshot")
+                .help("Download into a new directory below the destination named by the time of the run (e.g. 2024-06-01T12-00-00Z), keeping earlier copies")
+                .long("snapshot"),
+            Arg::with_name("link_dest")
+                .takes_value(true)
+                .help("Hardlink files from an earlier snapshot instead of downloading them if their size and date are unchanged (like rsync)")
+                .long("link-dest")
+                .value_name("snapshot"),
             Arg::with_name("disable download")
                 .help("Crawls without downloading (you mut also use -S)")
                 .short("n")
                 .long("no-download"),
+            Arg::with_name("dry run")
+                .help("Crawls and prints a summary without downloading or persisting anything")
+                .long("dry-run")
+                .conflicts_with("state_store"),
             Arg::with_name("verbosity")
                 .short("v")
                 .multiple(true)
                 .help("The verbosity level of the application"),
+            Arg::with_name("quiet")
+                .help("Only print errors (and no progress bars)")
+                .short("q")
+                .long("quiet")
+                .conflicts_with("verbosity"),
+            Arg::with_name("no_banner")
+                .help("Don't print the name, version & license notice at the start")
+                .long("no-banner"),
+            Arg::with_name("error_format")
+                .takes_value(true)
+                .help("Print a failure as text or as a JSON object with its kind, message, URL & state store (on stderr either way, exiting with 2 for usage, 3 network, 4 parse, 5 I/O, 6 access & 1 for other errors)")
+                .long("error-format")
+                .value_name("format")
+                .possible_values(&["text", "json"])
+                .default_value("text"),
             Arg::with_name("limit")
                 .help("Limit to n finding(s) to be downloaded")
                 .short("l")
@@ -63,6 +566,27 @@ pub fn configure_parser(default_path: &str) -> App {
                 .long("recursive-depth")
                 .value_name("integer")
                 .default_value("0"),
+            Arg::with_name("max_dirs")
+                .help("Stop crawling after n directories, against loops & generated trees (0 is unlimited)")
+                .long("max-dirs")
+                .value_name("integer")
+                .default_value("0"),
+            Arg::with_name("preview")
+                .takes_value(true)
+                .help("Only crawl & download the first n files (for trying out filters or a new server)")
+                .long("preview")
+                .value_name("n"),
+            Arg::with_name("sample_per_dir")
+                .takes_value(true)
+                .help("Only download the first n files of each directory, e.g. for spot-checking a mirror (with --sort for the largest or newest ones)")
+                .long("sample-per-dir")
+                .value_name("n")
+                .conflicts_with_all(&["newest_first", "replay_failures"]),
+            Arg::with_name("max_nodes")
+                .help("Stop crawling after finding n files & directories (0 is unlimited)")
+                .long("max-nodes")
+                .value_name("integer")
+                .default_value("0"),
             Arg::with_name("file_filter")
                 .takes_value(true)
                 .help("Regex filter to exclude matching file names")
@@ -81,53 +605,1448 @@ pub fn configure_parser(default_path: &str) -> App {
                 .short("F")
                 .long("file-matcher")
                 .value_name("regex"),
+            Arg::with_name("accept_type")
+                .takes_value(true)
+                .help("Only download files of these MIME types, comma-separated (e.g. image/*,application/pdf), asking the server for each type with a HEAD request; files of unknown type are kept")
+                .long("accept-type")
+                .value_name("types"),
             Arg::with_name("path_matcher")
                 .takes_value(true)
                 .help("Regex filter to exclude non-matching paths names")
                 .short("P")
                 .long("path-matcher")
                 .value_name("regex"),
+            Arg::with_name("exclude_dir")
+                .help("Don't crawl directories matching a glob (e.g. `old`, `backup*` or `pub/**/tmp`, a glob with a `/` matches the path below the URL), can be used multiple times")
+                .long("exclude-dir")
+                .value_name("glob")
+                .multiple(true)
+                .number_of_values(1),
+            Arg::with_name("resume")
+                .help("Continue a previous download (using a state store in the cache directory)")
+                .long("resume")
+                .conflicts_with("dry run"),
             Arg::with_name("state_store")
                 .takes_value(true)
                 .help("Store progress in a file (and resume when possible)")
                 .short("S")
                 .long("store-state")
                 .value_name("path"),
+            Arg::with_name("compress_state")
+                .help("Save the state store as gzip-compressed JSON (as are state stores ending with .gz, compressed ones are read either way)")
+                .long("compress-state"),
+            Arg::with_name("match_by")
+                .takes_value(true)
+                .help("Recognize earlier downloads by their URL, their path below the URL (e.g. after the server moved), or their path, size & date [default: url]")
+                .long("match-by")
+                .value_name("identity")
+                .possible_values(&["url", "path", "content"]),
+            Arg::with_name("on_conflict")
+                .takes_value(true)
+                .help("What to do when something is in the way of a download, like a directory or a file of another size than listed: skip it, overwrite files (never directories), keep both by renaming to name.1, or error [default: overwrite]")
+                .long("on-conflict")
+                .value_name("policy")
+                .possible_values(&["skip", "overwrite", "rename", "error"]),
+            Arg::with_name("save_interval")
+                .help("Save the state store every n files or after a duration (e.g. 100, 30s, 5m)")
+                .long("save-interval")
+                .value_name("n|duration")
+                .default_value("1m"),
+            Arg::with_name("max_time")
+                .takes_value(true)
+                .help("Stop downloading (and save the state) after a duration (e.g. 30m, 1h30m)")
+                .long("max-time")
+                .value_name("duration"),
+            Arg::with_name("max_requests")
+                .help("Stop (and save the state) after n requests for listings & files combined (0 is unlimited)")
+                .long("max-requests")
+                .value_name("integer")
+                .default_value("0"),
+            Arg::with_name("connect_timeout")
+                .help("Give up connecting to a server after a duration")
+                .long("connect-timeout")
+                .value_name("duration")
+                .default_value(timeout::DEFAULT_CONNECT_TIMEOUT),
+            Arg::with_name("read_timeout")
+                .help("Give up on a request after a duration without receiving any data (downloads are tried again a few times before their file fails)")
+                .long("timeout")
+                .value_name("duration")
+                .default_value(timeout::DEFAULT_READ_TIMEOUT),
+            Arg::with_name("max_listing_size")
+                .help("Fail on listings larger than this size, e.g. from a server sending an endless page (0 for no limit)")
+                .long("max-listing-size")
+                .value_name("size")
+                .default_value(crawl::DEFAULT_MAX_LISTING_SIZE),
+            Arg::with_name("http2")
+                .help("Speak HTTP/2 right away (prior knowledge, also over plain HTTP), so requests share one connection; only for servers known to support it")
+                .long("http2"),
+            Arg::with_name("pool_size")
+                .takes_value(true)
+                .help("Keep at most n idle connections per host for reuse (0 opens a new connection for every request) [default: no limit]")
+                .long("pool-size")
+                .value_name("n"),
+            Arg::with_name("pool_idle_timeout")
+                .help("Close connections which weren't reused within a duration")
+                .long("pool-idle-timeout")
+                .value_name("duration")
+                .default_value(timeout::DEFAULT_POOL_IDLE_TIMEOUT),
+            Arg::with_name("tcp_keepalive")
+                .help("Send TCP keep-alive probes at this interval, so idle connections aren't dropped (0s to disable)")
+                .long("tcp-keepalive")
+                .value_name("duration")
+                .default_value(timeout::DEFAULT_TCP_KEEPALIVE),
+            Arg::with_name("dedup")
+                .help("Replace files identical to an earlier download with a link to it once downloaded (saving disk space, but not bandwidth)")
+                .long("dedup"),
+            Arg::with_name("dedup_link")
+                .help("The kind of link used by --dedup [default: hard]")
+                .long("dedup-link")
+                .value_name("kind")
+                .possible_values(&["hard", "symbolic"])
+                .requires("dedup"),
+            Arg::with_name("emit_checksums")
+                .takes_value(true)
+                .help("Write the digest of each downloaded file next to it (e.g. file.iso.sha256)")
+                .long("emit-checksums")
+                .value_name("algorithm")
+                .possible_values(&["sha256", "md5"]),
+            Arg::with_name("xattrs")
+                .help("Store the source URL & SHA-256 digest of each downloaded file in its extended attributes (user.od-get.*)")
+                .long("xattrs"),
+            Arg::with_name("extract")
+                .help("Extract downloaded archives (tar, tar.gz/xz/bz2 & zip) into a directory next to them")
+                .long("extract"),
+            Arg::with_name("extract_delete")
+                .help("Delete archives once extracted (so later runs download them again)")
+                .long("extract-delete")
+                .requires("extract"),
+            Arg::with_name("only_newer")
+                .help("Only download files which are newer than their local copy")
+                .long("only-newer"),
+            Arg::with_name("min_size")
+                .takes_value(true)
+                .help("Skip files smaller than this size (e.g. 500K, 100M)")
+                .long("min-size")
+                .value_name("size"),
+            Arg::with_name("max_size")
+                .takes_value(true)
+                .help("Skip files larger than this size (e.g. 500K, 4G)")
+                .long("max-size")
+                .value_name("size"),
+            Arg::with_name("since")
+                .takes_value(true)
+                .help("Only download files last modified at or after this date (e.g. 2021-03-04, or 7d for the last week), files with unknown dates are kept")
+                .long("since")
+                .value_name("date"),
+            Arg::with_name("confirm_over")
+                .takes_value(true)
+                .help("Ask before downloading more than this size in total (e.g. 100G)")
+                .long("confirm-over")
+                .value_name("size"),
+            Arg::with_name("yes")
+                .help("Don't ask before large downloads (see --confirm-over)")
+                .short("y")
+                .long("yes"),
+            Arg::with_name("user_agent")
+                .help("The User-Agent header to send (and to look for in the robots.txt)")
+                .long("user-agent")
+                .value_name("string")
+                .default_value(constants::USER_AGENT),
+            Arg::with_name("user_agent_file")
+                .takes_value(true)
+                .help("Take turns with the User-Agents in this file (one per line) for each request (the robots.txt is still matched against --user-agent)")
+                .long("user-agent-file")
+                .value_name("path"),
+            Arg::with_name("ignore_robots")
+                .help("Don't follow the robots.txt of the server")
+                .long("ignore-robots"),
+            Arg::with_name("crawl_delay")
+                .takes_value(true)
+                .help("Wait between requests (defaults to the Crawl-delay of the robots.txt)")
+                .long("crawl-delay")
+                .value_name("duration"),
+            Arg::with_name("delay_jitter")
+                .takes_value(true)
+                .help("Wait a random duration of this range between requests instead of a fixed --crawl-delay (the middle of it with --deterministic)")
+                .long("delay-jitter")
+                .value_name("min-max")
+                .conflicts_with("crawl_delay"),
+            Arg::with_name("schedule")
+                .takes_value(true)
+                .help("Go at full speed during these hours of the local time (e.g. 00:00-06:00, or 22:00-02:00,12:00-13:00), and only wait between requests (--crawl-delay, --delay-jitter or the robots.txt) outside of them")
+                .long("schedule")
+                .value_name("HH:MM-HH:MM,..."),
+            Arg::with_name("cookie")
+                .help("Send a cookie to the server (can be used multiple times)")
+                .long("cookie")
+                .value_name("name=value")
+                .multiple(true)
+                .number_of_values(1),
+            Arg::with_name("cookies_file")
+                .takes_value(true)
+                .help("Load cookies from a file in the Netscape format (as exported by browsers)")
+                .long("cookies-file")
+                .value_name("path"),
+            Arg::with_name("ca_cert")
+                .takes_value(true)
+                .help("Trust the certificates of a PEM file (e.g. an internal CA) in addition to the system roots")
+                .long("ca-cert")
+                .value_name("pem"),
+            Arg::with_name("insecure")
+                .help("Don't verify TLS certificates at all (dangerous, only for trusted networks)")
+                .long("insecure"),
+            Arg::with_name("tls_server_name")
+                .takes_value(true)
+                .help("Verify TLS certificates for this host name instead of the one connected to, still validating the chain (e.g. for a mirror behind a CDN alias)")
+                .long("tls-server-name")
+                .value_name("name")
+                .conflicts_with("insecure"),
+            Arg::with_name("socks5")
+                .takes_value(true)
+                .help("Send all HTTP(S) requests through a SOCKS5 proxy, which also resolves the host names (e.g. 127.0.0.1:9050 for Tor, needed for .onion hosts)")
+                .long("socks5")
+                .value_name("host:port"),
+            Arg::with_name("continue_on_parse_error")
+                .help("Leave out directories which can't be parsed as listings instead of aborting the crawl")
+                .long("continue-on-parse-error"),
+            Arg::with_name("strict_html")
+                .help("Fail on listing rows which look like entries, but can't be parsed (instead of leaving them out with a warning)")
+                .long("strict-html"),
+            Arg::with_name("relative_only")
+                .help("Only take links relative to the listing (e.g. sub/ or file.txt) as its entries, ignoring ones with a leading slash or a scheme")
+                .long("relative-only"),
+            Arg::with_name("dir_detection")
+                .takes_value(true)
+                .help("Tell directories apart from files by a link ending with a slash, by a missing size (like - in Apache listings), or by either [default: auto]")
+                .long("dir-detection")
+                .value_name("method")
+                .possible_values(&["auto", "slash", "size"]),
+            Arg::with_name("row_pattern")
+                .takes_value(true)
+                .help("Parse listings which aren't tables with this regex, matching one entry each (named groups href & name, optionally date, size & desc)")
+                .long("row-pattern")
+                .value_name("regex"),
+            Arg::with_name("assume_format")
+                .takes_value(true)
+                .help("Parse every listing in this format instead of detecting it, failing on pages which don't fit it")
+                .long("assume-format")
+                .value_name("format")
+                .possible_values(&["apache", "nginx", "iis", "caddy-json"])
+                .conflicts_with("row_pattern"),
+            Arg::with_name("follow_symlinks")
+                .help("Crawl directories with the same files as another one (e.g. symlinks into the tree) instead of skipping them as aliases")
+                .long("follow-symlinks"),
+            Arg::with_name("skip_errors")
+                .help("Continue with the next file if a download fails")
+                .long("skip-errors"),
+            Arg::with_name("replay_failures")
+                .help("Only download the files which failed in earlier runs again, without crawling (needs a state store)")
+                .long("replay-failures")
+                .conflicts_with_all(&["stream", "newest_first", "revalidate", "urls_file", "prune", "prune_dry_run", "manifest", "dump_tree"]),
+            Arg::with_name("max_failures")
+                .help("Abort (and save the progress) once n files failed, e.g. when the server is down (0 is unlimited)")
+                .long("max-failures")
+                .value_name("integer")
+                .default_value("0"),
+            Arg::with_name("consecutive_failures")
+                .help("Abort (and save the progress) once n files failed in a row, as any download in between resets the count (0 is unlimited)")
+                .long("consecutive-failures")
+                .value_name("integer")
+                .default_value("0"),
+            Arg::with_name("verify_after")
+                .help("Check the size (and the checksum sidecar, if any) of every downloaded file once the download is done, failing on missing or corrupted files")
+                .long("verify-after"),
+            Arg::with_name("repair")
+                .help("Download the files failing the check of --verify-after again (instead of failing)")
+                .long("repair")
+                .requires("verify_after"),
+            Arg::with_name("strict_size")
+                .help("Abort if a downloaded file doesn't have the size from the listing (instead of reporting it as failed)")
+                .long("strict-size"),
+            Arg::with_name("output")
+                .takes_value(true)
+                .help("Upload the files to an S3 bucket instead of the destination (credentials from the AWS environment)")
+                .long("output")
+                .value_name("s3://bucket/prefix"),
+            Arg::with_name("output_tar")
+                .takes_value(true)
+                .help("Write the files into one tar archive (compressed if it ends in .tar.gz or .tgz) instead of the destination, keeping their paths")
+                .long("output-tar")
+                .value_name("path")
+                .conflicts_with("output"),
+            Arg::with_name("save_descriptions")
+                .help("Save the description of each file from the listing to a .description file next to it")
+                .long("save-descriptions"),
+            Arg::with_name("dump_tree")
+                .takes_value(true)
+                .help("Write the crawled tree (including the descriptions) as JSON (- for stdout)")
+                .long("dump-tree")
+                .value_name("path"),
+            Arg::with_name("catalog")
+                .takes_value(true)
+                .help("Only add the URL, name, size & date of each file to this SQLite database (updating the rows of earlier runs) instead of downloading")
+                .long("catalog")
+                .value_name("db"),
+            Arg::with_name("manifest")
+                .help("Only print the URL, size & date of each wanted file (tab-separated) instead of downloading")
+                .long("manifest"),
+            Arg::with_name("subpath")
+                .takes_value(true)
+                .help("Only download the directory at this path (relative to the URL)")
+                .long("subpath")
+                .value_name("path"),
+            Arg::with_name("stats")
+                .help("Print the statistics of all runs using the state store (bytes, requests & time)")
+                .long("stats"),
+            Arg::with_name("ndjson")
+                .help("Write every found file & directory and every download event to stdout as JSON lines (everything else goes to stderr)")
+                .long("ndjson")
+                .conflicts_with_all(&["manifest", "range"]),
+            Arg::with_name("status_socket")
+                .takes_value(true)
+                .help("Answer every connection to this Unix socket with the progress of the download as JSON (files & bytes done, the current file & the ETA)")
+                .long("status-socket")
+                .value_name("path"),
+            Arg::with_name("failover")
+                .help("Only use the next URL of a brace pattern if the previous one fails")
+                .long("failover"),
+            Arg::with_name("range")
+                .takes_value(true)
+                .help("Only download this range of bytes of the file at the URL to stdout (e.g. 0-1023, or 1024- for the rest)")
+                .long("range")
+                .value_name("start-end"),
+            Arg::with_name("stdout")
+                .help("Only download the file at the URL, writing it to stdout (e.g. for piping it into another program)")
+                .long("stdout")
+                .conflicts_with_all(&["range", "manifest", "ndjson", "state_store", "resume", "output", "output_tar"]),
+            Arg::with_name("test_url")
+                .help("Only parse the listing at the URL and print every entry found in it (e.g. to find out why a server isn't crawled)")
+                .long("test-url")
+                .conflicts_with_all(&["range", "stdout", "manifest", "ndjson", "state_store", "resume", "output", "output_tar"]),
+            Arg::with_name("from_html")
+                .takes_value(true)
+                .help("Only parse this saved listing as if it were the one at the URL (resolving its links against it) and print every entry found in it, without any requests")
+                .long("from-html")
+                .value_name("path")
+                .conflicts_with_all(&["range", "stdout", "test_url", "manifest", "ndjson", "state_store", "resume", "output", "output_tar"]),
+            Arg::with_name("count_only")
+                .help("Only count the files, directories & bytes of the tree as fast as possible (crawling several directories at once, without keeping the tree)")
+                .long("count-only")
+                .conflicts_with_all(&["range", "stdout", "test_url", "from_html", "manifest", "ndjson", "state_store", "resume", "output", "output_tar", "stream", "dump_tree"]),
+            Arg::with_name("urls_file")
+                .takes_value(true)
+                .help("Download the file URLs listed in this file (one per line, or the output of --manifest) below the URL, instead of crawling")
+                .long("urls-file")
+                .value_name("path"),
+            Arg::with_name("export_frontier")
+                .takes_value(true)
+                .help("Write the URLs of the directories still to be crawled to this file (e.g. when cut off by --max-dirs), so another run can go on with them")
+                .long("export-frontier")
+                .value_name("path"),
+            Arg::with_name("import_frontier")
+                .takes_value(true)
+                .help("Only crawl the directories listed in this file (written by --export-frontier) instead of the whole tree")
+                .long("import-frontier")
+                .value_name("path")
+                .conflicts_with("urls_file"),
+            Arg::with_name("decompress")
+                .help("Store files sent with a Content-Encoding (gzip, deflate or brotli) decompressed instead of as sent")
+                .long("decompress"),
+            Arg::with_name("head_sizes")
+                .help("Ask the server for the exact size of each file (one HEAD request per file) instead of trusting the listing")
+                .long("head-sizes"),
+            Arg::with_name("sort")
+                .takes_value(true)
+                .help("Download the files of each directory by size, name, date or natural (numbers by value) order")
+                .long("sort")
+                .value_name("key")
+                .possible_values(&["size", "name", "date", "natural"]),
+            Arg::with_name("sort_desc")
+                .help("Sort in descending order (e.g. largest or newest first)")
+                .long("sort-desc")
+                .requires("sort"),
+            Arg::with_name("newest_first")
+                .help("Download the files of the whole tree by date, newest first, instead of one directory at a time (e.g. with --limit for just the most recent files)")
+                .long("newest-first")
+                .conflicts_with_all(&["sort", "stream"]),
+            Arg::with_name("stream")
+                .help("Download the files while crawling, without keeping the whole tree in memory (the default unless an option needs the whole tree first)")
+                .long("stream"),
+            Arg::with_name("no_stream")
+                .help("Crawl the whole tree before downloading (e.g. for the totals of the progress bars from the start)")
+                .long("no-stream")
+                .conflicts_with("stream"),
+            Arg::with_name("revalidate")
+                .help("Crawl again (finding new files, and skipping the stored ones which are gone from the server), check the size of each file with a HEAD request, and re-download the ones which changed since (using the ETag & Last-Modified of the state store)")
+                .long("revalidate"),
+            Arg::with_name("allow_external")
+                .help("Also follow links to other servers or above the URL (which get dropped otherwise)")
+                .long("allow-external"),
+            Arg::with_name("skip_marker")
+                .takes_value(true)
+                .help("Skip all files of directories containing a file of this name, and the files with a sibling named like them plus it (e.g. .nomirror marks big.iso by big.iso.nomirror)")
+                .long("skip-marker")
+                .value_name("name"),
+            Arg::with_name("no_cross_host")
+                .help("Don't follow redirects to other hosts")
+                .long("no-cross-host"),
+            Arg::with_name("path_template")
+                .takes_value(true)
+                .help("Save each file at this path below the destination, using the placeholders {host}, {dir}, {name}, {ext} & {date}")
+                .long("path-template")
+                .value_name("template"),
+            Arg::with_name("include_empty_dirs")
+                .help("Also create the local directories of remote directories without any files (which get left out otherwise)")
+                .long("include-empty-dirs"),
+            Arg::with_name("max_filename_length")
+                .takes_value(true)
+                .help("Shorten file names longer than this many bytes (e.g. 255 for ext4), keeping the extension and adding a hash of the full name; --report lists the URL of each file")
+                .long("max-filename-length")
+                .value_name("bytes"),
+            Arg::with_name("path_as_name")
+                .takes_value(true)
+                .help("Save all files into the destination, named by their path joined with the separator (e.g. sub__dir__file.txt)")
+                .long("path-as-name")
+                .value_name("separator"),
+            Arg::with_name("deterministic")
+                .help("Crawl & download in a fixed order (sorted by URL) and leave timings out of the report")
+                .long("deterministic"),
+            Arg::with_name("prune")
+                .help("Delete local files which no longer exist on the server (after crawling)")
+                .long("prune"),
+            Arg::with_name("prune_dry_run")
+                .help("List the local files --prune would delete, without deleting them")
+                .long("prune-dry-run"),
+            Arg::with_name("prune_exclude")
+                .takes_value(true)
+                .help("Never prune paths (relative to the destination) matching this regex")
+                .long("prune-exclude")
+                .value_name("regex"),
+            Arg::with_name("report")
+                .takes_value(true)
+                .help("Write a JSON report of the downloaded, skipped and failed files")
+                .long("report")
+                .value_name("path"),
+            Arg::with_name("write_manifest")
+                .help("Write the metadata of the run (URL, time, version, arguments & totals) to .od-get.json in the destination")
+                .long("write-manifest"),
+            Arg::with_name("write_lockfile")
+                .help("Record the path, URL, size, date & SHA-256 digest of every downloaded file in od-get.lock in the destination (keeping the files of earlier runs)")
+                .long("write-lockfile"),
+            Arg::with_name("frozen")
+                .help("Only download the files of od-get.lock in the destination, failing on any file which isn't in it or differs from it")
+                .long("frozen")
+                .conflicts_with("write_lockfile"),
+            Arg::with_name("notify_command")
+                .takes_value(true)
+                .help("Run a shell command when done (with the totals in OD_GET_FILES, OD_GET_BYTES, OD_GET_SKIPPED, OD_GET_FAILED, OD_GET_ELAPSED & OD_GET_STATUS)")
+                .long("notify-command")
+                .value_name("command"),
+            Arg::with_name("exec")
+                .takes_value(true)
+                .help("Run a shell command after each downloaded file, with {path}, {url} & {size} referring to the values of the file (also passed as OD_GET_PATH, OD_GET_URL & OD_GET_SIZE)")
+                .long("exec")
+                .value_name("command"),
+            Arg::with_name("exec_fail")
+                .help("Fail the download when the --exec command fails (instead of warning)")
+                .long("exec-fail")
+                .requires("exec"),
+            Arg::with_name("progress")
+                .help("Show progress bars (default when running in a terminal)")
+                .long("progress"),
+            Arg::with_name("progress_interval")
+                .help("Print a line with the progress at this interval when there are no progress bars, e.g. in CI logs (0s to disable)")
+                .long("progress-interval")
+                .value_name("duration")
+                .default_value(progress::DEFAULT_INTERVAL),
         ]);
 
     app
 }
 
 pub fn get_options(matches: ArgMatches) -> Result<CliOptions, anyhow::Error> {
+    // Read the config file (if any), which fills in the arguments not given
+    let config = match matches.value_of("config") {
+        Some(path) => Config::load(Path::new(path))?,
+        None => Config::default(),
+    };
+
+    // Prefer the command line arguments over the config file over the defaults
+    let raw_value_of = |name: &str| {
+        if matches.occurrences_of(name) > 0 {
+            return matches.value_of(name).map(str::to_owned);
+        }
+
+        config
+            .value_of(name)
+            .or_else(|| matches.value_of(name).map(str::to_owned))
+    };
+
+    // Check the variables of all values first (so none gets parsed without them substituted)
+    for name in config::value_args() {
+        if let Some(value) = raw_value_of(name).filter(|_| !VERBATIM_ARGS.contains(&name)) {
+            expand_vars(&value)
+                .with_context(|| format!("Cannot substitute the variables of {}", value))?;
+        }
+    }
+
+    // Substitute the variables (except in shell commands, which have their own)
+    let value_of = |name: &str| {
+        raw_value_of(name).map(|value| match VERBATIM_ARGS.contains(&name) {
+            true => value,
+            false => expand_vars(&value).unwrap_or(value),
+        })
+    };
+    let is_present = |name: &str| matches.is_present(name) || config.is_present(name);
+
+    // Point the errors of invalid values from the config file at their key
+    let in_config = |name: &str, error: anyhow::Error| match matches.occurrences_of(name) {
+        0 => match config.origin_of(name) {
+            Some(origin) => error.context(format!("Invalid value of {}", origin)),
+            None => error,
+        },
+        _ => error,
+    };
     let make_regex = |name: &str| {
-        matches.value_of(name).and_then(|v| match Regex::new(v) {
-            Ok(regex) => Some(regex),
-            Err(err) => panic!("{}", &format!("{:?}", err)),
+        value_of(name)
+            .map(|v| {
+                Regex::new(&v)
+                    .with_context(|| format!("Invalid regex: {}", v))
+                    .map_err(|error| in_config(name, error))
+            })
+            .transpose()
+    };
+    let count_of = |name: &str| {
+        parse_count_arg(&value_of(name).unwrap()).map_err(|error| in_config(name, error))
+    };
+
+    let state_store = value_of("state_store");
+
+    // Take the roots from the mirror list (if any), each along its own destination
+    let mirror_list = value_of("mirror_list").map(PathBuf::from);
+    let mirrors = match &mirror_list {
+        Some(path) => urllist::read_mirror_list(path)?,
+        None => expand_braces(
+            &value_of("URL")
+                .ok_or_else(|| anyhow!("No URL given (neither as argument nor in the config)"))?,
+        )
+        .iter()
+        .map(|url| Url::parse(url).with_context(|| format!("Invalid URL {}", url)))
+        .map(|url| url.map(|url| (url, None)))
+        .collect::<Result<Vec<_>, _>>()?,
+    };
+    let (roots, mirror_dirs): (Vec<_>, Vec<_>) = mirrors.into_iter().unzip();
+
+    let mut options = CliOptions {
+        url: roots[0].clone(),
+        roots,
+        root_destinations: vec![],
+        mirror_list,
+        failover: is_present("failover"),
+        destination: std::env::current_dir()?.join(value_of("destination").unwrap()),
+        compress_state: is_present("compress_state"),
+        force_lock: is_present("force_lock"),
+        part_suffix: parse_suffix_arg(&value_of("part_suffix").unwrap())?,
+        snapshot: is_present("snapshot"),
+        link_dest: value_of("link_dest")
+            .map(|path| std::env::current_dir().map(|pwd| pwd.join(path)))
+            .transpose()?,
+        temp_dir: value_of("temp_dir")
+            .map(|path| std::env::current_dir().map(|pwd| pwd.join(path)))
+            .transpose()?,
+        output: value_of("output")
+            .as_deref()
+            .map(parse_output_arg)
+            .transpose()?,
+        output_tar: value_of("output_tar")
+            .map(|path| std::env::current_dir().map(|pwd| pwd.join(path)))
+            .transpose()?,
+        save_descriptions: is_present("save_descriptions"),
+        dump_tree: value_of("dump_tree").map(PathBuf::from),
+        catalog: value_of("catalog").map(PathBuf::from),
+        manifest: is_present("manifest"),
+        subpath: value_of("subpath"),
+        deterministic: is_present("deterministic"),
+        no_cross_host: is_present("no_cross_host"),
+        allow_external: is_present("allow_external"),
+        skip_marker: value_of("skip_marker"),
+        revalidate: is_present("revalidate"),
+        mmap: is_present("mmap"),
+        segments: value_of("segments")
+            .as_deref()
+            .map(parse_segments_arg)
+            .transpose()?
+            .filter(|&segments| segments > 1),
+        stream: is_present("stream"),
+        no_stream: is_present("no_stream"),
+        sort: match value_of("sort").as_deref() {
+            None => None,
+            Some(key) => Some(SortOrder {
+                key: match key {
+                    "size" => SortKey::Size,
+                    "name" => SortKey::Name,
+                    "date" => SortKey::Date,
+                    "natural" => SortKey::Natural,
+                    _ => bail!("Invalid sort key (size, name, date or natural): {}", key),
+                },
+                descending: is_present("sort_desc"),
+            }),
+        },
+        newest_first: is_present("newest_first"),
+        head_sizes: is_present("head_sizes"),
+        decompress: is_present("decompress"),
+        urls_file: value_of("urls_file").map(PathBuf::from),
+        export_frontier: value_of("export_frontier").map(PathBuf::from),
+        import_frontier: value_of("import_frontier").map(PathBuf::from),
+        range: value_of("range")
+            .as_deref()
+            .map(parse_range_arg)
+            .transpose()?,
+        stdout: is_present("stdout"),
+        test_url: is_present("test_url"),
+        from_html: value_of("from_html").map(PathBuf::from),
+        count_only: is_present("count_only"),
+        stats: is_present("stats"),
+        ndjson: is_present("ndjson"),
+        status_socket: value_of("status_socket").map(PathBuf::from),
+        path_template: value_of("path_template")
+            .map(|template| PathTemplate::parse(&template))
+            .transpose()?,
+        include_empty_dirs: is_present("include_empty_dirs"),
+        path_as_name: value_of("path_as_name")
+            .map(|separator| {
+                if separator.is_empty() || separator.contains(['/', '\\']) {
+                    bail!("Invalid separator for --path-as-name: {:?}", separator);
+                }
+                Ok(separator)
+            })
+            .transpose()?,
+        prune: is_present("prune"),
+        prune_dry_run: is_present("prune_dry_run"),
+        prune_exclude: make_regex("prune_exclude")?,
+        no_download: is_present("disable download")
+            || is_present("dry run")
+            || is_present("manifest")
+            || is_present("catalog")
+            || is_present("test_url")
+            || is_present("from_html")
+            || is_present("count_only"),
+        dry_run: is_present("dry run"),
+        verbosity: match matches.occurrences_of("verbosity") {
+            0 => config.occurrences_of("verbosity").unwrap_or(0),
+            count => count,
+        },
+        quiet: is_present("quiet"),
+        limit_count: count_of("limit")?,
+        skip_count: count_of("skip")?,
+        recursion_limit: count_of("max_depth")?,
+        max_dirs: count_of("max_dirs")?,
+        max_nodes: count_of("max_nodes")?,
+        preview: value_of("preview")
+            .as_deref()
+            .map(parse_preview_arg)
+            .transpose()?,
+        sample_per_dir: value_of("sample_per_dir")
+            .as_deref()
+            .map(parse_sample_arg)
+            .transpose()?,
+        file_filter: make_regex("file_filter")?,
+        path_filter: make_regex("path_filter")?,
+        file_matcher: make_regex("file_matcher")?,
+        accept_types: value_of("accept_type")
+            .as_deref()
+            .map(parse_types_arg)
+            .transpose()?
+            .unwrap_or_default(),
+        path_matcher: make_regex("path_matcher")?,
+        exclude_dirs: match matches.values_of("exclude_dir") {
+            Some(globs) => globs.map(str::to_owned).collect(),
+            None => config.values_of("exclude_dir").unwrap_or_default(),
+        }
+        .iter()
+        .map(|glob| {
+            glob.parse()
+                .with_context(|| format!("Invalid --exclude-dir glob {}", glob))
+        })
+        .collect::<Result<_, anyhow::Error>>()?,
+        state_store_path: None,
+        resume: is_present("resume"),
+        match_by: match value_of("match_by").as_deref() {
+            None | Some("url") => MatchBy::Url,
+            Some("path") => MatchBy::Path,
+            Some("content") => MatchBy::Content,
+            Some(kind) => bail!("Invalid way of matching (url, path or content): {}", kind),
+        },
+        on_conflict: match value_of("on_conflict").as_deref() {
+            Some("skip") => ConflictPolicy::Skip,
+            None | Some("overwrite") => ConflictPolicy::Overwrite,
+            Some("rename") => ConflictPolicy::Rename,
+            Some("error") => ConflictPolicy::Error,
+            Some(policy) => bail!(
+                "Invalid conflict policy (skip, overwrite, rename or error): {}",
+                policy
+            ),
+        },
+        save_interval: parse_save_interval(&value_of("save_interval").unwrap())?,
+        max_time: value_of("max_time")
+            .as_deref()
+            .map(parse_duration_arg)
+            .transpose()?,
+        max_requests: count_of("max_requests")?,
+        connect_timeout: parse_duration_arg(&value_of("connect_timeout").unwrap())?,
+        read_timeout: parse_duration_arg(&value_of("read_timeout").unwrap())?,
+        max_listing_size: Some(parse_size_arg(&value_of("max_listing_size").unwrap())?)
+            .filter(|&size| size > 0),
+        http2: is_present("http2"),
+        max_filename_length: value_of("max_filename_length")
+            .as_deref()
+            .map(parse_name_length_arg)
+            .transpose()?,
+        pool_size: value_of("pool_size")
+            .map(|size| {
+                size.parse::<usize>()
+                    .map_err(|_| anyhow!("Invalid pool size (must be a number): {}", size))
+            })
+            .transpose()?,
+        pool_idle_timeout: parse_duration_arg(&value_of("pool_idle_timeout").unwrap())?,
+        tcp_keepalive: Some(parse_duration_arg(&value_of("tcp_keepalive").unwrap())?)
+            .filter(|interval| !interval.is_zero()),
+        dedup: if is_present("dedup") {
+            match value_of("dedup_link").as_deref() {
+                None | Some("hard") => Some(LinkKind::Hard),
+                Some("symbolic") => Some(LinkKind::Symbolic),
+                Some(kind) => bail!("Invalid link kind (hard or symbolic): {}", kind),
+            }
+        } else {
+            None
+        },
+        emit_checksums: match value_of("emit_checksums").as_deref() {
+            None => None,
+            Some("sha256") => Some(ChecksumKind::Sha256),
+            Some("md5") => Some(ChecksumKind::Md5),
+            Some(kind) => bail!("Invalid checksum algorithm (sha256 or md5): {}", kind),
+        },
+        xattrs: is_present("xattrs"),
+        extract: is_present("extract"),
+        extract_delete: is_present("extract_delete"),
+        only_newer: is_present("only_newer"),
+        min_size: value_of("min_size")
+            .as_deref()
+            .map(parse_size_arg)
+            .transpose()?,
+        confirm_over: value_of("confirm_over")
+            .as_deref()
+            .map(parse_size_arg)
+            .transpose()?,
+        yes: is_present("yes"),
+        max_size: value_of("max_size")
+            .as_deref()
+            .map(parse_size_arg)
+            .transpose()?,
+        since: value_of("since")
+            .as_deref()
+            .map(parse_since_arg)
+            .transpose()?,
+        skip_errors: is_present("skip_errors"),
+        replay_failures: is_present("replay_failures"),
+        max_failures: count_of("max_failures")?,
+        consecutive_failures: count_of("consecutive_failures")?,
+        strict_size: is_present("strict_size"),
+        verify_after: is_present("verify_after"),
+        repair: is_present("repair"),
+        continue_on_parse_error: is_present("continue_on_parse_error"),
+        strict_html: is_present("strict_html"),
+        relative_only: is_present("relative_only"),
+        follow_symlinks: is_present("follow_symlinks"),
+        dir_detection: match value_of("dir_detection").as_deref() {
+            None | Some("auto") => DirDetection::Auto,
+            Some("slash") => DirDetection::TrailingSlash,
+            Some("size") => DirDetection::EmptySize,
+            Some(method) => bail!(
+                "Invalid directory detection (auto, slash or size): {}",
+                method
+            ),
+        },
+        row_pattern: value_of("row_pattern")
+            .as_deref()
+            .map(parse_row_pattern_arg)
+            .transpose()?,
+        assume_format: match value_of("assume_format").as_deref() {
+            None => None,
+            Some("apache") => Some(ListingFormat::Apache),
+            Some("nginx") => Some(ListingFormat::Nginx),
+            Some("iis") => Some(ListingFormat::Iis),
+            Some("caddy-json") => Some(ListingFormat::CaddyJson),
+            Some(format) => bail!(
+                "Invalid listing format (apache, nginx, iis or caddy-json): {}",
+                format
+            ),
+        },
+        cookies: match matches.values_of("cookie") {
+            Some(cookies) => cookies.map(str::to_owned).collect(),
+            None => config.values_of("cookie").unwrap_or_default(),
+        },
+        cookies_file: value_of("cookies_file").map(PathBuf::from),
+        ca_cert: value_of("ca_cert").map(PathBuf::from),
+        insecure: is_present("insecure"),
+        tls_server_name: value_of("tls_server_name"),
+        socks5: value_of("socks5")
+            .as_deref()
+            .map(parse_socks5_arg)
+            .transpose()?,
+        user_agent: value_of("user_agent").unwrap(),
+        user_agent_file: value_of("user_agent_file").map(PathBuf::from),
+        ignore_robots: is_present("ignore_robots"),
+        crawl_delay: value_of("crawl_delay")
+            .as_deref()
+            .map(parse_duration_arg)
+            .transpose()?,
+        delay_jitter: value_of("delay_jitter")
+            .as_deref()
+            .map(parse_delay_range_arg)
+            .transpose()?,
+        schedule: value_of("schedule")
+            .as_deref()
+            .map(parse_schedule_arg)
+            .transpose()?,
+        report_path: value_of("report").map(PathBuf::from),
+        write_manifest: is_present("write_manifest"),
+        write_lockfile: is_present("write_lockfile"),
+        frozen: is_present("frozen"),
+        notify_command: value_of("notify_command"),
+        exec: value_of("exec"),
+        exec_fail: is_present("exec_fail"),
+        progress: !is_present("quiet")
+            && (is_present("progress") || std::io::stderr().is_terminal()),
+        progress_interval: Some(parse_duration_arg(&value_of("progress_interval").unwrap())?)
+            .filter(|interval| !interval.is_zero() && !is_present("quiet")),
+        unknown_config_keys: config.unknown_keys,
+    };
+
+    // Ranges are fetched with a plain HTTP(S) request
+    if options.range.is_some() && options.url.scheme() == "ftp" {
+        bail!("Cannot use --range with FTP");
+    }
+    if options.stdout && options.url.scheme() == "ftp" {
+        bail!("Cannot use --stdout with FTP");
+    }
+    if options.test_url && options.url.scheme() == "ftp" {
+        bail!("Cannot use --test-url with FTP (only HTML listings are parsed)");
+    }
+
+    // Onion services can't be resolved without Tor, and FTP doesn't go through the proxy
+    if options.socks5.is_none() && options.roots.iter().any(is_onion) {
+        bail!("Cannot reach .onion hosts without a proxy (use --socks5, e.g. --socks5 127.0.0.1:9050 for Tor)");
+    }
+    if options.socks5.is_some() && options.url.scheme() == "ftp" {
+        bail!("Cannot use --socks5 with FTP");
+    }
+
+    // Every root needs its own state store
+    if options.roots.len() > 1 && state_store.is_some() {
+        bail!("Cannot use --state-store with several URLs (use --resume instead)");
+    }
+
+    // Use the state store from the cache directory if none is given
+    options.state_store_path = match state_store {
+        Some(state_store) => Some(state_store),
+        None if options.resume => Some(
+            StateStore::default_path(&options.url, &options.destination)?
+                .to_string_lossy()
+                .into_owned(),
+        ),
+        None => None,
+    };
+
+    // A preview downloads no more than it crawls (even from a complete stored tree)
+    if let Some(preview) = options.preview {
+        options.limit_count = Some(
+            options
+                .limit_count
+                .map_or(preview, |limit| limit.min(preview)),
+        );
+    }
+
+    // These need the files on the disk
+    let elsewhere = if options.output.is_some() {
+        Some("--output")
+    } else if options.output_tar.is_some() {
+        Some("--output-tar")
+    } else {
+        None
+    };
+    if let Some(elsewhere) = elsewhere {
+        if options.prune || options.prune_dry_run {
+            bail!("Cannot use --prune with {}", elsewhere);
+        }
+        if options.dedup.is_some() {
+            bail!("Cannot use --dedup with {}", elsewhere);
+        }
+        if options.only_newer {
+            bail!("Cannot use --only-newer with {}", elsewhere);
+        }
+        if options.revalidate {
+            bail!("Cannot use --revalidate with {}", elsewhere);
+        }
+        if options.write_manifest {
+            bail!("Cannot use --write-manifest with {}", elsewhere);
+        }
+        if options.write_lockfile || options.frozen {
+            bail!(
+                "Cannot use a lockfile (--write-lockfile or --frozen) with {}",
+                elsewhere
+            );
+        }
+        if options.emit_checksums.is_some() {
+            bail!("Cannot use --emit-checksums with {}", elsewhere);
+        }
+        if options.xattrs {
+            bail!("Cannot use --xattrs with {}", elsewhere);
+        }
+        if options.extract {
+            bail!("Cannot use --extract with {}", elsewhere);
+        }
+        if options.temp_dir.is_some() {
+            bail!("Cannot use --temp-dir with {}", elsewhere);
+        }
+        if options.verify_after {
+            bail!("Cannot use --verify-after with {}", elsewhere);
+        }
+        if options.exec.is_some() {
+            bail!("Cannot use --exec with {}", elsewhere);
+        }
+    }
+
+    // The failures are only known to a state store
+    if options.replay_failures && options.state_store_path.is_none() {
+        bail!("Cannot use --replay-failures without a state store (--state-store or --resume)");
+    }
+
+    // Every snapshot is a complete copy of its own
+    if options.snapshot {
+        if options.state_store_path.is_some() {
+            bail!("Cannot use --snapshot with a state store (--state-store or --resume)");
+        }
+        if options.output.is_some() {
+            bail!("Cannot use --snapshot with --output");
+        }
+        if options.output_tar.is_some() {
+            bail!("Cannot use --snapshot with --output-tar");
+        }
+
+        let name = Utc::now().format(constants::SNAPSHOT_FORMAT).to_string();
+        options.destination = options.destination.join(name);
+    } else if options.link_dest.is_some() {
+        bail!("Cannot use --link-dest without --snapshot");
+    }
+
+    if options.path_as_name.is_some() && options.path_template.is_some() {
+        bail!("Cannot use --path-as-name with --path-template");
+    }
+
+    // The check looks for the files where the crawl puts them (as does pruning, below)
+    if options.verify_after && options.custom_layout() {
+        bail!("Cannot use --verify-after with --path-as-name or --path-template");
+    }
+
+    // The files to keep are looked for where the crawl puts them
+    if (options.prune || options.prune_dry_run) && options.custom_layout() {
+        bail!("Cannot use --prune with --path-as-name or --path-template");
+    }
+
+    // The extracted files aren't on the server (and deleted archives can't be linked)
+    if options.extract && (options.prune || options.prune_dry_run) {
+        bail!("Cannot use --extract with --prune");
+    }
+    if options.extract_delete && options.dedup.is_some() {
+        bail!("Cannot use --extract-delete with --dedup");
+    }
+
+    // These need the whole tree
+    if options.stream {
+        if options.no_download {
+            bail!(
+                "Cannot use --stream without downloading (--no-download, --dry-run or --manifest)"
+            );
+        }
+        if options.prune || options.prune_dry_run {
+            bail!("Cannot use --prune with --stream");
+        }
+        if options.dump_tree.is_some() {
+            bail!("Cannot use --dump-tree with --stream");
+        }
+        if options.subpath.is_some() {
+            bail!("Cannot use --subpath with --stream");
+        }
+        if options.head_sizes {
+            bail!("Cannot use --head-sizes with --stream");
+        }
+        if options.verify_after {
+            bail!("Cannot use --verify-after with --stream");
+        }
+        if options.urls_file.is_some() {
+            bail!("Cannot use --urls-file with --stream");
+        }
+        if options.export_frontier.is_some() || options.import_frontier.is_some() {
+            bail!("Cannot use a frontier (--export-frontier or --import-frontier) with --stream");
+        }
+        if options.sort.is_some() {
+            bail!("Cannot use --sort with --stream");
+        }
+        if options.confirm_over.is_some() {
+            bail!("Cannot use --confirm-over with --stream");
+        }
+    }
+
+    // Download while crawling unless something needs the whole tree first (the tree of a state
+    // store is kept, so an interrupted crawl can be continued)
+    if !options.no_stream && options.state_store_path.is_none() && !options.needs_tree() {
+        options.stream = true;
+    }
+
+    // The validators & statistics are kept in the state store
+    if options.revalidate && options.state_store_path.is_none() {
+        bail!("Cannot use --revalidate without --state-store (or --resume)");
+    }
+    if options.stats && options.state_store_path.is_none() {
+        bail!("Cannot use --stats without --state-store (or --resume)");
+    }
+    if options.compress_state && options.state_store_path.is_none() {
+        bail!("Cannot use --compress-state without --state-store (or --resume)");
+    }
+
+    // The destinations of the mirrors are below the destination (unless they're absolute)
+    options.root_destinations = mirror_dirs
+        .into_iter()
+        .map(|dir| match dir {
+            Some(dir) => options.destination.join(dir),
+            None => options.destination.clone(),
         })
+        .collect();
+
+    Ok(options)
+}
+
+/// The options whose values are taken as they are by `expand_vars`
+const VERBATIM_ARGS: [&str; 2] = ["notify_command", "exec"];
+
+/**
+Substitutes the variables of an option value, e.g. `${HOME}/mirror` with the environment variable
+
+`${DATE}` is today's date (like `2024-01-31`), unless there's an environment variable of that
+name. Undefined variables are an error, and `$${VAR}` stands for a literal `${VAR}`.
+*/
+fn expand_vars(text: &str) -> Result<String, anyhow::Error> {
+    lazy_static! {
+        static ref RX_VAR: Regex = Regex::new(r"\$(\$?)\{([A-Za-z_][A-Za-z0-9_]*)\}").unwrap();
+    }
+
+    let mut expanded = String::with_capacity(text.len());
+    let mut last = 0;
+    for captures in RX_VAR.captures_iter(text) {
+        let (whole, name) = (captures.get(0).unwrap(), &captures[2]);
+        expanded.push_str(&text[last..whole.start()]);
+        last = whole.end();
+
+        if !captures[1].is_empty() {
+            expanded.push_str(&whole.as_str()[1..]);
+            continue;
+        }
+        match std::env::var(name) {
+            Ok(value) => expanded.push_str(&value),
+            Err(_) if name == constants::DATE_VAR => {
+                expanded.push_str(&Local::now().format(constants::DATE_VAR_FORMAT).to_string())
+            }
+            Err(_) => bail!("The environment variable {} isn't set", name),
+        }
+    }
+    expanded.push_str(&text[last..]);
+
+    Ok(expanded)
+}
+
+/**
+Expands the brace patterns of a text, e.g. `https://{a,b}.example.org/` into
+`https://a.example.org/` and `https://b.example.org/`
+
+Several patterns expand to every combination (in order). Braces without a comma stay as they are.
+*/
+fn expand_braces(text: &str) -> Vec<String> {
+    let pattern = text
+        .find('{')
+        .and_then(|start| Some((start, start + text[start..].find('}')?)));
+    let (start, end) = match pattern {
+        Some(pattern) => pattern,
+        None => return vec![text.to_owned()],
     };
 
-    Ok(CliOptions {
-        url: Url::parse(matches.value_of("URL").unwrap())?,
-        destination: matches.value_of("destination").unwrap().to_owned(),
-        no_download: matches.is_present("disable download"),
-        verbosity: matches.occurrences_of("verbosity"),
-        limit_count: make_option(matches.value_of("limit").unwrap().parse::<u64>()),
-        skip_count: make_option(matches.value_of("skip").unwrap().parse::<u64>()),
-        recursion_limit: make_option(matches.value_of("max_depth").unwrap().parse::<u64>()),
-        file_filter: make_regex("file_filter"),
-        path_filter: make_regex("path_filter"),
-        file_matcher: make_regex("file_matcher"),
-        path_matcher: make_regex("path_matcher"),
-        state_store_path: matches.value_of("state_store").map(|path| path.to_owned()),
+    let alternatives = &text[start + 1..end];
+    let rest = expand_braces(&text[end + 1..]);
+
+    if !alternatives.contains(',') {
+        return rest
+            .into_iter()
+            .map(|rest| format!("{}{}", &text[..=end], rest))
+            .collect();
+    }
+
+    alternatives
+        .split(',')
+        .flat_map(|alternative| {
+            rest.iter()
+                .map(move |rest| format!("{}{}{}", &text[..start], alternative, rest))
+        })
+        .collect()
+}
+
+/// Parses a save interval given either as a file count or as a duration
+fn parse_save_interval(text: &str) -> Result<SaveInterval, anyhow::Error> {
+    match text.parse::<u64>() {
+        Ok(0) => bail!("The save interval must be greater than zero"),
+        Ok(files) => Ok(SaveInterval::Files(files)),
+        Err(_) => parse_duration(text)
+            .map(SaveInterval::Time)
+            .ok_or_else(|| anyhow!("Invalid save interval (e.g. 100, 30s, 5m): {}", text)),
+    }
+}
+
+/// Parses the number of files of a --preview
+fn parse_preview_arg(text: &str) -> Result<u64, anyhow::Error> {
+    match text.parse::<u64>() {
+        Ok(0) => bail!("The preview must include at least one file"),
+        Ok(files) => Ok(files),
+        Err(_) => bail!("Invalid preview (must be a number of files): {}", text),
+    }
+}
+
+/// Parses the number of connections a file is downloaded with (see --segments)
+fn parse_segments_arg(text: &str) -> Result<u64, anyhow::Error> {
+    match text.parse::<u64>() {
+        Ok(0) => bail!("A file must be downloaded in at least one segment"),
+        Ok(segments) => Ok(segments),
+        Err(_) => bail!(
+            "Invalid segments (must be a number of connections): {}",
+            text
+        ),
+    }
+}
+
+/// Parses the number of files of each directory of a --sample-per-dir
+fn parse_sample_arg(text: &str) -> Result<u64, anyhow::Error> {
+    match text.parse::<u64>() {
+        Ok(0) => bail!("The sample must include at least one file per directory"),
+        Ok(files) => Ok(files),
+        Err(_) => bail!("Invalid sample (must be a number of files): {}", text),
+    }
+}
+
+/// Parses an output given as a command line argument (only S3 buckets are supported)
+fn parse_output_arg(text: &str) -> Result<Url, anyhow::Error> {
+    match Url::parse(text) {
+        Ok(url) if url.scheme() == "s3" && url.host_str().is_some() => Ok(url),
+        _ => bail!("Invalid output (must be s3://bucket/prefix): {}", text),
+    }
+}
+
+/// Parses the address of a SOCKS5 proxy (`host:port`)
+fn parse_socks5_arg(text: &str) -> Result<String, anyhow::Error> {
+    match text.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+            Ok(text.to_owned())
+        }
+        _ => bail!("Invalid SOCKS5 proxy (e.g. 127.0.0.1:9050): {}", text),
+    }
+}
+
+/// Checks if a URL points to an onion service (which is only reachable through Tor)
+fn is_onion(url: &Url) -> bool {
+    url.host_str()
+        .is_some_and(|host| host.trim_end_matches('.').ends_with(".onion"))
+}
+
+/// Parses a byte range given as a command line argument
+fn parse_range_arg(text: &str) -> Result<ByteRange, anyhow::Error> {
+    parse_range(text).ok_or_else(|| {
+        anyhow!(
+            "Invalid range (e.g. 0-1023 or 1024-, the end must not be before the start): {}",
+            text
+        )
+    })
+}
+
+/// Parses a duration given as a command line argument
+fn parse_duration_arg(text: &str) -> Result<Duration, anyhow::Error> {
+    parse_duration(text).ok_or_else(|| anyhow!("Invalid duration (e.g. 90s, 30m, 1h30m): {}", text))
+}
+
+/// Parses a range of durations given as a command line argument
+fn parse_delay_range_arg(text: &str) -> Result<DelayRange, anyhow::Error> {
+    parse_delay_range(text).ok_or_else(|| {
+        anyhow!(
+            "Invalid delay range (e.g. 1s-3s, the end must not be before the start): {}",
+            text
+        )
+    })
+}
+
+/// Parses the windows of a --schedule
+fn parse_schedule_arg(text: &str) -> Result<Schedule, anyhow::Error> {
+    parse_schedule(text).ok_or_else(|| {
+        anyhow!(
+            "Invalid schedule (e.g. 00:00-06:00 or 22:00-02:00,12:00-13:00): {}",
+            text
+        )
+    })
+}
+
+/// Parses a size given as a command line argument
+fn parse_size_arg(text: &str) -> Result<u64, anyhow::Error> {
+    parse_size(text).ok_or_else(|| anyhow!("Invalid size (e.g. 1024, 500K, 100M, 4G): {}", text))
+}
+
+/// Parses the --max-filename-length (which has to leave room for the hash & sidecar suffixes)
+pub fn parse_name_length_arg(text: &str) -> Result<usize, anyhow::Error> {
+    match text.parse::<usize>() {
+        Ok(length) if length >= constants::MIN_FILENAME_LENGTH => Ok(length),
+        _ => bail!(
+            "Invalid maximum file name length (must be at least {}): {}",
+            constants::MIN_FILENAME_LENGTH,
+            text
+        ),
+    }
+}
+
+/// Checks a --part-suffix (which has to fit into the room --max-filename-length leaves for suffixes)
+fn parse_suffix_arg(text: &str) -> Result<String, anyhow::Error> {
+    if text.is_empty() || text.len() > DESCRIPTION_EXTENSION.len() || text.contains(['/', '\\']) {
+        bail!(
+            "Invalid part suffix (1 to {} bytes, no slashes): {}",
+            DESCRIPTION_EXTENSION.len(),
+            text
+        );
+    }
+
+    Ok(text.to_owned())
+}
+
+/// Parses a comma-separated list of MIME types (e.g. `image/*,application/pdf`)
+fn parse_types_arg(text: &str) -> Result<Vec<String>, anyhow::Error> {
+    text.split(',')
+        .map(str::trim)
+        .filter(|mime| !mime.is_empty())
+        .map(|mime| match mime.split_once('/') {
+            Some((kind, sub)) if !kind.is_empty() && !sub.is_empty() => {
+                Ok(mime.to_ascii_lowercase())
+            }
+            _ => bail!(
+                "Invalid MIME type (e.g. image/* or application/pdf): {}",
+                mime
+            ),
+        })
+        .collect()
+}
+
+/// Parses the row pattern of a listing format, which has to name the groups of a row
+fn parse_row_pattern_arg(text: &str) -> Result<Regex, anyhow::Error> {
+    let pattern = Regex::new(text).with_context(|| format!("Invalid row pattern: {}", text))?;
+
+    for group in format::REQUIRED_GROUPS {
+        if !pattern.capture_names().any(|name| name == Some(group)) {
+            bail!(
+                "The row pattern lacks the named group {} (as in (?P<{}>...))",
+                group,
+                group
+            );
+        }
+    }
+
+    Ok(pattern)
+}
+
+/// Parses a date (or a duration before now) given as a command line argument
+fn parse_since_arg(text: &str) -> Result<DateTime<Utc>, anyhow::Error> {
+    parse_since(text, Utc::now()).ok_or_else(|| {
+        anyhow!(
+            "Invalid date (e.g. 2021-03-04, 2021-03-04T12:00:00Z or 7d): {}",
+            text
+        )
     })
 }
 
-/// Converts a number (which has to be greater than zero) to an option, or None (in case of zero)
-fn make_option(number: Result<u64, std::num::ParseIntError>) -> Option<u64> {
-    match number
-        .unwrap_or_else(|err| panic!("Invalid number (must be a positive integer): {:?}", err))
-    {
-        0 => None,
-        n => Some(n),
+/// Parses a count (which has to be a positive integer) to an option, or None (in case of zero)
+fn parse_count_arg(text: &str) -> Result<Option<u64>, anyhow::Error> {
+    match text.parse::<u64>() {
+        Ok(0) => Ok(None),
+        Ok(count) => Ok(Some(count)),
+        Err(_) => bail!("Invalid number (must be a positive integer): {}", text),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses the options of a command line
+    fn options_of(args: &[&str]) -> Result<CliOptions, anyhow::Error> {
+        let matches = configure_parser("./")
+            .get_matches_from_safe(std::iter::once("od-get").chain(args.iter().copied()))?;
+
+        get_options(matches)
+    }
+
+    #[test]
+    fn parses_counts() {
+        assert_eq!(parse_count_arg("0").unwrap(), None);
+        assert_eq!(parse_count_arg("12").unwrap(), Some(12));
+        assert!(parse_count_arg("-1").is_err());
+        assert!(parse_count_arg("abc").is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_tree_limits() {
+        let options = options_of(&["--max-dirs", "3", "--max-nodes", "0", "http://a/"]).unwrap();
+        assert_eq!(options.max_dirs, Some(3));
+        assert_eq!(options.max_nodes, None);
+
+        assert!(options_of(&["--max-dirs", "abc", "http://a/"]).is_err());
+        assert!(options_of(&["--max-nodes", "1.5", "http://a/"]).is_err());
+    }
+
+    #[test]
+    fn rejects_an_invalid_request_limit() {
+        let options = options_of(&["--max-requests", "5", "http://a/"]).unwrap();
+        assert_eq!(options.max_requests, Some(5));
+
+        assert!(options_of(&["--max-requests", "abc", "http://a/"]).is_err());
+    }
+
+    #[test]
+    fn rejects_an_invalid_failure_limit() {
+        let options = options_of(&["--max-failures", "5", "http://a/"]).unwrap();
+        assert_eq!(options.max_failures, Some(5));
+
+        assert!(options_of(&["--max-failures", "abc", "http://a/"]).is_err());
+    }
+
+    #[test]
+    fn rejects_an_invalid_consecutive_failure_limit() {
+        let options = options_of(&["--consecutive-failures", "5", "http://a/"]).unwrap();
+        assert_eq!(options.consecutive_failures, Some(5));
+
+        assert!(options_of(&["--consecutive-failures", "abc", "http://a/"]).is_err());
+    }
+
+    /// Parses the options of a command line along a config file
+    fn options_with_config(config: &str, args: &[&str]) -> Result<CliOptions, anyhow::Error> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("od-get.toml");
+        std::fs::write(&path, config).unwrap();
+
+        let path = path.to_str().unwrap();
+        options_of(&[&["--config", path], args].concat())
+    }
+
+    #[test]
+    fn reads_options_from_a_config() {
+        let config = "limit_count = 3\nquiet = true\nfile_filter = \"\\\\.iso$\"\n";
+        let options = options_with_config(config, &["http://a/"]).unwrap();
+        assert_eq!(options.limit_count, Some(3));
+        assert!(options.quiet);
+        assert!(options.file_filter.unwrap().is_match("a.iso"));
+
+        // The command line takes precedence
+        let options = options_with_config(config, &["-l", "5", "http://a/"]).unwrap();
+        assert_eq!(options.limit_count, Some(5));
+    }
+
+    #[test]
+    fn names_the_config_key_of_invalid_values() {
+        for config in ["file_filter = \"(\"\n", "max_dirs = \"many\"\n"] {
+            let error = format!(
+                "{:#}",
+                options_with_config(config, &["http://a/"]).unwrap_err()
+            );
+            let key = config.split(' ').next().unwrap();
+            assert!(
+                error.contains(&format!("`{}` in the config file", key)),
+                "{}",
+                error
+            );
+        }
+
+        // Errors of the command line don't point at the config
+        let error = format!(
+            "{:#}",
+            options_with_config("", &["-f", "(", "http://a/"]).unwrap_err()
+        );
+        assert!(!error.contains("config"), "{}", error);
+    }
+
+    #[test]
+    fn logs_per_node_details_only_when_verbose() {
+        let level = |args: &[&str]| {
+            options_of(&[args, &["http://a/"]].concat())
+                .unwrap()
+                .log_level()
+        };
+
+        assert_eq!(level(&[]), LevelFilter::Info);
+        assert_eq!(level(&["-v"]), LevelFilter::Debug);
+        assert_eq!(level(&["-vv"]), LevelFilter::Trace);
+        assert_eq!(level(&["-q"]), LevelFilter::Error);
+    }
+
+    #[test]
+    fn expands_brace_patterns() {
+        assert_eq!(
+            expand_braces("https://{a,b}.example.org/"),
+            ["https://a.example.org/", "https://b.example.org/"]
+        );
+        assert_eq!(
+            expand_braces("http://{a,b}/{x,y}/"),
+            ["http://a/x/", "http://a/y/", "http://b/x/", "http://b/y/"]
+        );
+        assert_eq!(expand_braces("http://a/{x}/"), ["http://a/{x}/"]);
+        assert_eq!(expand_braces("http://a/"), ["http://a/"]);
+    }
+
+    #[test]
+    fn takes_the_first_mirror_as_the_url() {
+        let options = options_of(&["http://{a,b}.example.org/pub/"]).unwrap();
+
+        assert_eq!(options.url.as_str(), "http://a.example.org/pub/");
+        assert_eq!(options.roots.len(), 2);
+        assert!(options_of(&["-S", "state.json", "http://{a,b}.example.org/"]).is_err());
+    }
+
+    #[test]
+    fn expands_environment_variables() {
+        std::env::set_var("OD_GET_TEST_MIRROR", "/srv/mirror");
+        let options = options_of(&[
+            "-d",
+            "${OD_GET_TEST_MIRROR}/pub",
+            "-S",
+            "$${OD_GET_TEST_MIRROR}/state-${DATE}.json",
+            "http://a/",
+        ])
+        .unwrap();
+
+        assert_eq!(options.destination, PathBuf::from("/srv/mirror/pub"));
+        assert_eq!(
+            options.state_store_path.unwrap(),
+            format!(
+                "${{OD_GET_TEST_MIRROR}}/state-{}.json",
+                Local::now().format(constants::DATE_VAR_FORMAT)
+            )
+        );
+    }
+
+    #[test]
+    fn rejects_undefined_environment_variables() {
+        let error = options_of(&["-d", "${OD_GET_TEST_UNDEFINED}/pub", "http://a/"])
+            .unwrap_err()
+            .to_string();
+
+        assert!(error.contains("OD_GET_TEST_UNDEFINED"), "{}", error);
     }
 }
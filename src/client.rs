@@ -0,0 +1,202 @@
+use crate::{
+    cli::CliOptions,
+    download::{cookies, crawl, tls},
+};
+use anyhow::{Context, Result};
+use log::{info, warn};
+use reqwest::cookie::Jar;
+use std::{fs, sync::Arc};
+
+/**
+The HTTP(S) clients of a run (see `build_clients`)
+
+Both share one cookie jar, so the cookies set while crawling are sent along with the downloads.
+*/
+pub struct Clients {
+    /// Fetches listings (which always get decompressed), the robots.txt and uploads to S3
+    pub client: reqwest::Client,
+
+    /// Fetches the files (which only get decompressed with `--decompress`)
+    pub file_client: reqwest::Client,
+}
+
+/**
+Builds the clients of a run, configured alike from the options
+
+Everything about the connections is set up here: the timeouts & pooling, the User-Agent, the
+cookies, the redirect policy, the proxy, HTTP/2 and the TLS verification (with `--ca-cert`,
+`--tls-server-name` & `--insecure`).
+*/
+pub fn build_clients(options: &CliOptions) -> Result<Clients> {
+    // Keep the cookies (given ones and those set by the server) for the whole session
+    let cookie_jar = Arc::new(Jar::default());
+    for cookie in &options.cookies {
+        cookies::add_cookie(&cookie_jar, cookie, &options.url)?;
+    }
+    if let Some(cookies_file) = &options.cookies_file {
+        let count = cookies::load_cookies_file(&cookie_jar, cookies_file)?;
+        info!("Loaded {} cookies from {}", count, cookies_file.display());
+    }
+
+    // Trust an additional root certificate (e.g. of an internal CA)
+    let (ca_cert_pem, ca_cert) = match &options.ca_cert {
+        Some(path) => {
+            let pem = fs::read(path)
+                .with_context(|| format!("Cannot read the CA certificate {}", path.display()))?;
+            let ca_cert = reqwest::Certificate::from_pem(&pem)
+                .with_context(|| format!("Invalid CA certificate {}", path.display()))?;
+            (Some(pem), Some(ca_cert))
+        }
+        None => (None, None),
+    };
+
+    // Verify the certificates for another host name (if desired), which needs its own TLS setup
+    let tls_config = match &options.tls_server_name {
+        Some(server_name) => {
+            info!("Verifying TLS certificates for {}", server_name);
+            Some(tls::fixed_name_config(
+                server_name,
+                ca_cert_pem.as_deref(),
+                options.http2,
+            )?)
+        }
+        None => None,
+    };
+
+    if options.insecure {
+        warn!("NOT verifying TLS certificates (--insecure), anyone on the network may tamper with the downloads!");
+    }
+
+    // Send everything through a SOCKS5 proxy (if desired), which resolves the host names as well
+    // (`socks5h`), so .onion hosts work and no DNS requests leak
+    let proxy = match &options.socks5 {
+        Some(address) => {
+            info!("Connecting through the SOCKS5 proxy {}", address);
+            Some(
+                reqwest::Proxy::all(format!("socks5h://{}", address))
+                    .with_context(|| format!("Invalid SOCKS5 proxy {}", address))?,
+            )
+        }
+        None => None,
+    };
+
+    // Make new clients for issuing HTTP(S) requests
+    let client_builder = || {
+        let builder = reqwest::Client::builder()
+            .connect_timeout(options.connect_timeout)
+            .pool_idle_timeout(options.pool_idle_timeout)
+            .tcp_keepalive(options.tcp_keepalive)
+            .user_agent(&options.user_agent)
+            .cookie_provider(cookie_jar.clone())
+            .redirect(crawl::redirect_policy(!options.no_cross_host))
+            .danger_accept_invalid_certs(options.insecure);
+
+        let builder = match &proxy {
+            Some(proxy) => builder.proxy(proxy.clone()),
+            None => builder,
+        };
+
+        // Reuse connections (unless told otherwise), or multiplex the requests over HTTP/2
+        let builder = match options.pool_size {
+            Some(pool_size) => builder.pool_max_idle_per_host(pool_size),
+            None => builder,
+        };
+        let builder = if options.http2 {
+            builder
+                .http2_prior_knowledge()
+                .http2_keep_alive_interval(options.tcp_keepalive)
+        } else {
+            builder
+        };
+
+        let builder = match &ca_cert {
+            Some(ca_cert) => builder.add_root_certificate(ca_cert.clone()),
+            None => builder,
+        };
+
+        match &tls_config {
+            Some(tls_config) => builder.use_preconfigured_tls(tls_config.clone()),
+            None => builder,
+        }
+    };
+
+    // Listings always get decompressed, files only if desired (e.g. a `.tar.gz` is often served
+    // with `Content-Encoding: gzip`, but should be kept as it is)
+    let client = client_builder().build()?;
+    let file_client = client_builder()
+        .gzip(options.decompress)
+        .brotli(options.decompress)
+        .deflate(options.decompress)
+        .build()?;
+
+    Ok(Clients {
+        client,
+        file_client,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        sync::mpsc,
+        thread,
+    };
+
+    /// Answers one request with an empty page, returning the URL and the head of the request
+    fn capture_request() -> (String, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/pub/", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::channel();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut head = vec![];
+            let mut byte = [0];
+            while !head.ends_with(b"\r\n\r\n") && stream.read(&mut byte).unwrap() == 1 {
+                head.push(byte[0]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            sender
+                .send(String::from_utf8(head).unwrap().to_lowercase())
+                .unwrap();
+        });
+
+        (url, receiver)
+    }
+
+    #[tokio::test]
+    async fn configures_both_clients_from_the_options() {
+        for decompress in [false, true] {
+            let (url, head) = capture_request();
+            let mut args = vec!["--user-agent", "test-agent/1.0", "--cookie", "session=abc"];
+            if decompress {
+                args.push("--decompress");
+            }
+            args.push(&url);
+            let clients = build_clients(&CliOptions::for_test(&args)).unwrap();
+
+            clients.file_client.get(&url).send().await.unwrap();
+
+            let head = head.recv().unwrap();
+            assert!(head.contains("user-agent: test-agent/1.0\r\n"), "{}", head);
+            assert!(head.contains("cookie: session=abc\r\n"), "{}", head);
+            assert_eq!(
+                head.contains("accept-encoding: gzip"),
+                decompress,
+                "{}",
+                head
+            );
+        }
+
+        // Listings always get decompressed
+        let (url, head) = capture_request();
+        let clients = build_clients(&CliOptions::for_test(&[&url])).unwrap();
+        clients.client.get(&url).send().await.unwrap();
+        assert!(head.recv().unwrap().contains("accept-encoding: gzip"));
+    }
+}
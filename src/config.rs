@@ -0,0 +1,333 @@
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// The kinds of values a config key can have
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    /// A boolean for a flag (e.g. `quiet = true`)
+    Flag,
+
+    /// A number for a counted flag (e.g. `verbosity = 2`)
+    Count,
+
+    /// A string or number for an argument taking a value (e.g. `limit_count = 10`)
+    Value,
+
+    /// A string or a list of strings for an argument taking multiple values
+    Values,
+}
+
+/// The keys of the config file (named like the fields of CliOptions) and their arguments
+const KEYS: [(&str, &str, Kind); 127] = [
+    ("url", "URL", Kind::Value),
+    ("mirror_list", "mirror_list", Kind::Value),
+    ("destination", "destination", Kind::Value),
+    ("temp_dir", "temp_dir", Kind::Value),
+    ("part_suffix", "part_suffix", Kind::Value),
+    ("mmap", "mmap", Kind::Flag),
+    ("segments", "segments", Kind::Value),
+    ("output", "output", Kind::Value),
+    ("output_tar", "output_tar", Kind::Value),
+    ("save_descriptions", "save_descriptions", Kind::Flag),
+    ("dump_tree", "dump_tree", Kind::Value),
+    ("catalog", "catalog", Kind::Value),
+    ("manifest", "manifest", Kind::Flag),
+    ("subpath", "subpath", Kind::Value),
+    ("deterministic", "deterministic", Kind::Flag),
+    ("path_as_name", "path_as_name", Kind::Value),
+    ("max_filename_length", "max_filename_length", Kind::Value),
+    ("path_template", "path_template", Kind::Value),
+    ("include_empty_dirs", "include_empty_dirs", Kind::Flag),
+    ("no_cross_host", "no_cross_host", Kind::Flag),
+    ("allow_external", "allow_external", Kind::Flag),
+    ("skip_marker", "skip_marker", Kind::Value),
+    ("revalidate", "revalidate", Kind::Flag),
+    ("stream", "stream", Kind::Flag),
+    ("no_stream", "no_stream", Kind::Flag),
+    ("sort", "sort", Kind::Value),
+    ("sort_desc", "sort_desc", Kind::Flag),
+    ("newest_first", "newest_first", Kind::Flag),
+    ("head_sizes", "head_sizes", Kind::Flag),
+    ("decompress", "decompress", Kind::Flag),
+    ("urls_file", "urls_file", Kind::Value),
+    ("export_frontier", "export_frontier", Kind::Value),
+    ("import_frontier", "import_frontier", Kind::Value),
+    ("range", "range", Kind::Value),
+    ("stdout", "stdout", Kind::Flag),
+    ("test_url", "test_url", Kind::Flag),
+    ("from_html", "from_html", Kind::Value),
+    ("count_only", "count_only", Kind::Flag),
+    ("failover", "failover", Kind::Flag),
+    ("stats", "stats", Kind::Flag),
+    ("ndjson", "ndjson", Kind::Flag),
+    ("status_socket", "status_socket", Kind::Value),
+    ("prune", "prune", Kind::Flag),
+    ("prune_dry_run", "prune_dry_run", Kind::Flag),
+    ("prune_exclude", "prune_exclude", Kind::Value),
+    ("no_download", "disable download", Kind::Flag),
+    ("dry_run", "dry run", Kind::Flag),
+    ("verbosity", "verbosity", Kind::Count),
+    ("quiet", "quiet", Kind::Flag),
+    ("limit_count", "limit", Kind::Value),
+    ("skip_count", "skip", Kind::Value),
+    ("recursion_limit", "max_depth", Kind::Value),
+    ("max_dirs", "max_dirs", Kind::Value),
+    ("max_nodes", "max_nodes", Kind::Value),
+    ("preview", "preview", Kind::Value),
+    ("sample_per_dir", "sample_per_dir", Kind::Value),
+    ("file_filter", "file_filter", Kind::Value),
+    ("path_filter", "path_filter", Kind::Value),
+    ("file_matcher", "file_matcher", Kind::Value),
+    ("accept_type", "accept_type", Kind::Value),
+    ("path_matcher", "path_matcher", Kind::Value),
+    ("exclude_dirs", "exclude_dir", Kind::Values),
+    ("state_store_path", "state_store", Kind::Value),
+    ("compress_state", "compress_state", Kind::Flag),
+    ("force_lock", "force_lock", Kind::Flag),
+    ("snapshot", "snapshot", Kind::Flag),
+    ("link_dest", "link_dest", Kind::Value),
+    ("resume", "resume", Kind::Flag),
+    ("match_by", "match_by", Kind::Value),
+    ("on_conflict", "on_conflict", Kind::Value),
+    ("save_interval", "save_interval", Kind::Value),
+    ("max_time", "max_time", Kind::Value),
+    ("max_requests", "max_requests", Kind::Value),
+    ("connect_timeout", "connect_timeout", Kind::Value),
+    ("read_timeout", "read_timeout", Kind::Value),
+    ("max_listing_size", "max_listing_size", Kind::Value),
+    ("http2", "http2", Kind::Flag),
+    ("pool_size", "pool_size", Kind::Value),
+    ("pool_idle_timeout", "pool_idle_timeout", Kind::Value),
+    ("tcp_keepalive", "tcp_keepalive", Kind::Value),
+    ("dedup", "dedup", Kind::Flag),
+    ("dedup_link", "dedup_link", Kind::Value),
+    ("emit_checksums", "emit_checksums", Kind::Value),
+    ("xattrs", "xattrs", Kind::Flag),
+    ("extract", "extract", Kind::Flag),
+    ("extract_delete", "extract_delete", Kind::Flag),
+    ("only_newer", "only_newer", Kind::Flag),
+    ("min_size", "min_size", Kind::Value),
+    ("since", "since", Kind::Value),
+    ("max_size", "max_size", Kind::Value),
+    ("confirm_over", "confirm_over", Kind::Value),
+    ("yes", "yes", Kind::Flag),
+    ("skip_errors", "skip_errors", Kind::Flag),
+    ("replay_failures", "replay_failures", Kind::Flag),
+    ("max_failures", "max_failures", Kind::Value),
+    ("consecutive_failures", "consecutive_failures", Kind::Value),
+    ("strict_size", "strict_size", Kind::Flag),
+    ("verify_after", "verify_after", Kind::Flag),
+    ("repair", "repair", Kind::Flag),
+    (
+        "continue_on_parse_error",
+        "continue_on_parse_error",
+        Kind::Flag,
+    ),
+    ("follow_symlinks", "follow_symlinks", Kind::Flag),
+    ("strict_html", "strict_html", Kind::Flag),
+    ("relative_only", "relative_only", Kind::Flag),
+    ("dir_detection", "dir_detection", Kind::Value),
+    ("row_pattern", "row_pattern", Kind::Value),
+    ("assume_format", "assume_format", Kind::Value),
+    ("user_agent", "user_agent", Kind::Value),
+    ("user_agent_file", "user_agent_file", Kind::Value),
+    ("ignore_robots", "ignore_robots", Kind::Flag),
+    ("crawl_delay", "crawl_delay", Kind::Value),
+    ("delay_jitter", "delay_jitter", Kind::Value),
+    ("schedule", "schedule", Kind::Value),
+    ("cookies", "cookie", Kind::Values),
+    ("cookies_file", "cookies_file", Kind::Value),
+    ("ca_cert", "ca_cert", Kind::Value),
+    ("insecure", "insecure", Kind::Flag),
+    ("tls_server_name", "tls_server_name", Kind::Value),
+    ("socks5", "socks5", Kind::Value),
+    ("report_path", "report", Kind::Value),
+    ("write_manifest", "write_manifest", Kind::Flag),
+    ("write_lockfile", "write_lockfile", Kind::Flag),
+    ("frozen", "frozen", Kind::Flag),
+    ("notify_command", "notify_command", Kind::Value),
+    ("progress_interval", "progress_interval", Kind::Value),
+    ("exec", "exec", Kind::Value),
+    ("exec_fail", "exec_fail", Kind::Flag),
+    ("progress", "progress", Kind::Flag),
+];
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum RawValue {
+    Bool(bool),
+    Integer(u64),
+    Text(String),
+    List(Vec<String>),
+}
+
+/**
+The options read from a config file (TOML, or JSON if the file name ends with `.json`)
+
+The values are stored by the name of the argument they stand in for, so they can be used
+wherever the command line arguments are missing.
+*/
+#[derive(Debug, Default)]
+pub struct Config {
+    values: HashMap<&'static str, RawValue>,
+
+    /// The file the options were read from
+    path: PathBuf,
+
+    /// Keys which aren't known (and got ignored)
+    pub unknown_keys: Vec<String>,
+}
+
+/// Returns the names of the arguments taking a single value (which may be set in a config)
+pub fn value_args() -> impl Iterator<Item = &'static str> {
+    KEYS.iter()
+        .filter(|(_, _, kind)| *kind == Kind::Value)
+        .map(|(_, arg, _)| *arg)
+}
+
+impl Config {
+    /// Reads & parses a config file
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Cannot read the config file {}", path.display()))?;
+
+        let raw: HashMap<String, RawValue> = if path.extension().is_some_and(|ext| ext == "json") {
+            serde_json::from_str(&text).map_err(anyhow::Error::from)
+        } else {
+            toml::from_str(&text).map_err(anyhow::Error::from)
+        }
+        .with_context(|| format!("Cannot parse the config file {}", path.display()))?;
+
+        let mut config = Self {
+            path: path.to_owned(),
+            ..Self::default()
+        };
+
+        for (key, value) in raw {
+            let (_, arg, kind) = match KEYS.iter().find(|(name, _, _)| *name == key) {
+                Some(entry) => *entry,
+                None => {
+                    config.unknown_keys.push(key);
+                    continue;
+                }
+            };
+
+            // Check the type of the value
+            let expected = match (kind, &value) {
+                (Kind::Flag, RawValue::Bool(_))
+                | (Kind::Count, RawValue::Integer(_))
+                | (Kind::Value, RawValue::Integer(_))
+                | (Kind::Value, RawValue::Text(_))
+                | (Kind::Values, RawValue::Text(_))
+                | (Kind::Values, RawValue::List(_)) => None,
+                (Kind::Flag, _) => Some("a boolean"),
+                (Kind::Count, _) => Some("a number"),
+                (Kind::Value, _) => Some("a string or number"),
+                (Kind::Values, _) => Some("a list of strings"),
+            };
+            if let Some(expected) = expected {
+                let file = path.display();
+                bail!("The config key `{}` in {} must be {}", key, file, expected);
+            }
+
+            config.values.insert(arg, value);
+        }
+
+        config.unknown_keys.sort();
+
+        Ok(config)
+    }
+
+    /// Returns the value for an argument (if it's set in the config)
+    pub fn value_of(&self, arg: &str) -> Option<String> {
+        match self.values.get(arg)? {
+            RawValue::Text(text) => Some(text.clone()),
+            RawValue::Integer(number) => Some(number.to_string()),
+            RawValue::Bool(_) | RawValue::List(_) => None,
+        }
+    }
+
+    /// Names the key (and file) setting an argument (if it's set in the config), e.g. for errors
+    pub fn origin_of(&self, arg: &str) -> Option<String> {
+        self.values.get(arg)?;
+        let (key, _, _) = KEYS.iter().find(|(_, name, _)| *name == arg)?;
+
+        Some(format!(
+            "`{}` in the config file {}",
+            key,
+            self.path.display()
+        ))
+    }
+
+    /// Returns the values for an argument taking multiple values (if set in the config)
+    pub fn values_of(&self, arg: &str) -> Option<Vec<String>> {
+        match self.values.get(arg)? {
+            RawValue::Text(text) => Some(vec![text.clone()]),
+            RawValue::List(list) => Some(list.clone()),
+            _ => None,
+        }
+    }
+
+    /// Checks if a flag is set in the config
+    pub fn is_present(&self, arg: &str) -> bool {
+        matches!(self.values.get(arg), Some(RawValue::Bool(true)))
+    }
+
+    /// Returns the count of a counted flag (if it's set in the config)
+    pub fn occurrences_of(&self, arg: &str) -> Option<u64> {
+        match self.values.get(arg)? {
+            RawValue::Integer(count) => Some(*count),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes a config file into a temporary directory
+    fn write_config(name: &str, text: &str) -> (tempfile::TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(name);
+        fs::write(&path, text).unwrap();
+
+        (dir, path)
+    }
+
+    #[test]
+    fn reads_toml_and_json() {
+        let (_dir, path) = write_config("od-get.toml", "limit_count = 3\nquiet = true\n");
+        let config = Config::load(&path).unwrap();
+        assert_eq!(config.value_of("limit"), Some("3".to_owned()));
+        assert!(config.is_present("quiet"));
+
+        let (_dir, path) = write_config("od-get.json", r#"{"file_filter": "\\.iso$"}"#);
+        let config = Config::load(&path).unwrap();
+        assert_eq!(config.value_of("file_filter"), Some("\\.iso$".to_owned()));
+    }
+
+    #[test]
+    fn keeps_unknown_keys() {
+        let (_dir, path) = write_config("od-get.toml", "colour = true\nquiet = true\n");
+        let config = Config::load(&path).unwrap();
+        assert_eq!(config.unknown_keys, vec!["colour".to_owned()]);
+    }
+
+    #[test]
+    fn names_the_key_and_file_of_invalid_values() {
+        let (_dir, path) = write_config("od-get.toml", "quiet = 3\n");
+        let error = Config::load(&path).unwrap_err().to_string();
+        assert!(error.contains("`quiet`"));
+        assert!(error.contains(&path.display().to_string()));
+
+        let (_dir, path) = write_config("od-get.toml", "quiet = \n");
+        let error = format!("{:#}", Config::load(&path).unwrap_err());
+        assert!(error.contains(&path.display().to_string()));
+    }
+}
@@ -1,18 +1,49 @@
 // The name of the application
-pub const NAME: &'static str = "od-get";
+pub const NAME: &str = "od-get";
 
 /// The main author of the application
-pub const AUTHOR: &'static str = "Bernd-L <git@bernd.pw>";
+pub const AUTHOR: &str = "Bernd-L <git@bernd.pw>";
 
 /// The semantic-version string of the application
-pub const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The default User-Agent header (its product token is used to find the rules of a robots.txt)
+pub const USER_AGENT: &str = concat!("od-get/", env!("CARGO_PKG_VERSION"));
+
+/// The name of the file describing the last run in the output directory (see `--write-manifest`)
+pub const MARKER_FILE: &str = ".od-get.json";
+
+/// The name of the lock file in the output directory, held while a run writes to it
+pub const LOCK_FILE: &str = ".od-get.lock";
+
+/// The name of the file recording the exact versions of the downloads in the output directory (see
+/// `--write-lockfile` & `--frozen`)
+pub const LOCKFILE: &str = "od-get.lock";
 
 /// Describes the application (i.e. its use cases) in a short phrase
-pub const ABOUT: &'static str =
+pub const ABOUT: &str =
     "A Rust tool for recursively crawling & downloading data from open directories";
 
 /// The licence notice (AGPL 3) of the application
-pub const LICENSE: &'static str = concat![
+pub const LICENSE: &str = concat![
     "Copyright 2021 Bernd-L; All rights reserved.\n",
     "Licensed under the AGPL 3.0 <https://www.gnu.org/licenses/agpl-3.0.en.html>"
 ];
+
+/// The name of the directory of each run with `--snapshot` (the UTC time it started)
+pub const SNAPSHOT_FORMAT: &str = "%Y-%m-%dT%H-%M-%SZ";
+
+/// The variable of option values standing for today's date (unless it's set in the environment)
+pub const DATE_VAR: &str = "DATE";
+
+/// The format of `${DATE}` (the local date)
+pub const DATE_VAR_FORMAT: &str = "%Y-%m-%d";
+
+/// The path passed to `--dump-tree` to write the tree to stdout instead of a file
+pub const DUMP_TO_STDOUT: &str = "-";
+
+/// The shortest `--max-filename-length` (leaving room for the hash & the suffixes of sidecars)
+pub const MIN_FILENAME_LENGTH: usize = 32;
+
+/// How many of the flakiest files `--stats` lists
+pub const FLAKIEST_FILES: usize = 10;
@@ -0,0 +1,143 @@
+use super::types::{DirLinkMetaData, FileLinkMetaData, Node};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use log::debug;
+use reqwest::Url;
+use serde::Deserialize;
+
+/// An entry of a JSON listing (as served by Caddy's `file_server browse` when asked for JSON)
+#[derive(Debug, Deserialize)]
+struct Entry {
+    name: String,
+    url: String,
+
+    #[serde(default)]
+    size: u64,
+
+    /// An RFC 3339 date (e.g. `2021-03-04T12:34:56.789Z`)
+    #[serde(default)]
+    mod_time: String,
+
+    #[serde(default)]
+    is_dir: bool,
+
+    #[serde(default)]
+    is_symlink: bool,
+}
+
+/// Checks if a content type announces JSON (e.g. `application/json; charset=utf-8`)
+pub fn is_json(content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap_or_default().trim();
+
+    mime.eq_ignore_ascii_case("application/json")
+}
+
+/**
+Parses a JSON listing into nodes (Either PendingDir or File)
+
+The sizes and dates are exact, unlike the ones scraped from HTML listings. Symlinks are skipped
+(like the FTP backend does), as they may loop back up the tree.
+*/
+pub fn parse_listing(body: &[u8], base_url: &Url) -> Result<Vec<Node>> {
+    let entries: Vec<Entry> =
+        serde_json::from_slice(body).context("The JSON listing isn't an array of entries")?;
+
+    Ok(entries
+        .into_iter()
+        .filter_map(|entry| process_entry(entry, base_url))
+        .collect())
+}
+
+/// Turns an entry of a JSON listing into a node
+fn process_entry(entry: Entry, base_url: &Url) -> Option<Node> {
+    if entry.is_symlink {
+        debug!("(JSON) Skip symlink {}", entry.name);
+        return None;
+    }
+
+    let url = base_url.join(&entry.url).ok()?;
+    let name = entry.name.trim_end_matches('/').to_owned();
+    let last_modified = DateTime::parse_from_rfc3339(&entry.mod_time)
+        .map(|date| {
+            date.with_timezone(&Utc)
+                .format("%Y-%m-%d %H:%M")
+                .to_string()
+        })
+        .unwrap_or_default();
+
+    if entry.is_dir {
+        debug!("Got directory: {}", &name);
+
+        let mut url = url;
+        if !url.path().ends_with('/') {
+            url.set_path(&format!("{}/", url.path()));
+        }
+
+        Some(Node::PendingDir(DirLinkMetaData {
+            url: url.to_string(),
+            name,
+            last_modified,
+            description: String::new(),
+            depth: 0,
+        }))
+    } else {
+        debug!("Got file: {}", &name);
+
+        Some(Node::File(FileLinkMetaData {
+            url: url.to_string(),
+            name,
+            last_modified,
+            size: entry.size.to_string(),
+            size_bytes: Some(entry.size),
+            description: String::new(),
+            depth: 0,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_caddy_listing() {
+        let body = br#"[
+            {"name": "docs/", "size": 4096, "url": "./docs/", "mod_time": "2021-03-04T12:34:56.789Z", "mode": 2147484141, "is_dir": true, "is_symlink": false},
+            {"name": "kernel 5.10.tar.xz", "size": 117440512, "url": "./kernel%205.10.tar.xz", "mod_time": "2021-03-05T08:00:00+01:00", "mode": 420, "is_dir": false, "is_symlink": false},
+            {"name": "latest", "size": 6, "url": "./latest", "mod_time": "2021-03-05T08:00:00Z", "mode": 134218239, "is_dir": false, "is_symlink": true}
+        ]"#;
+        let base_url = Url::parse("http://example.com/pub/").unwrap();
+
+        let nodes = parse_listing(body, &base_url).unwrap();
+
+        assert_eq!(nodes.len(), 2);
+        match &nodes[0] {
+            Node::PendingDir(dir) => {
+                assert_eq!(dir.url, "http://example.com/pub/docs/");
+                assert_eq!(dir.name, "docs");
+                assert_eq!(dir.last_modified, "2021-03-04 12:34");
+            }
+            node => panic!("Not a directory: {:?}", node),
+        }
+        match &nodes[1] {
+            Node::File(file) => {
+                assert_eq!(file.url, "http://example.com/pub/kernel%205.10.tar.xz");
+                assert_eq!(file.name, "kernel 5.10.tar.xz");
+                assert_eq!(file.size_bytes, Some(117_440_512));
+                assert_eq!(file.size, "117440512");
+                // In UTC
+                assert_eq!(file.last_modified, "2021-03-05 07:00");
+            }
+            node => panic!("Not a file: {:?}", node),
+        }
+    }
+
+    #[test]
+    fn rejects_other_json() {
+        let base_url = Url::parse("http://example.com/pub/").unwrap();
+
+        assert!(parse_listing(br#"{"error": "not found"}"#, &base_url).is_err());
+        assert!(is_json("application/json; charset=utf-8"));
+        assert!(!is_json("text/html"));
+    }
+}
@@ -0,0 +1,141 @@
+use super::types::{FileLinkMetaData, Node};
+use anyhow::{Context, Result};
+use chrono::Utc;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::{fmt, path::Path};
+
+/// The table of the catalog (one row per file, by its URL)
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS files (
+    url TEXT PRIMARY KEY NOT NULL,
+    root TEXT NOT NULL,
+    name TEXT NOT NULL,
+    size TEXT NOT NULL,
+    size_bytes INTEGER,
+    last_modified TEXT NOT NULL,
+    description TEXT NOT NULL,
+    depth INTEGER NOT NULL,
+    first_seen TEXT NOT NULL,
+    last_seen TEXT NOT NULL,
+    changed_at TEXT NOT NULL
+)";
+
+/// How a catalog changed by adding the files of a tree
+#[derive(Debug, Default)]
+pub struct CatalogSummary {
+    pub added: u64,
+    pub changed: u64,
+    pub unchanged: u64,
+}
+
+impl fmt::Display for CatalogSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} new, {} changed & {} unchanged files",
+            self.added, self.changed, self.unchanged
+        )
+    }
+}
+
+/**
+Adds the files of a crawled tree to a SQLite catalog (see `--catalog`)
+
+Each file is a row of the `files` table, keyed by its URL. Files of earlier runs are updated, with
+`changed_at` only moving when the size or date of the listing differ, and `last_seen` telling
+which ones are still listed. Files which are gone stay in the catalog.
+*/
+pub fn write_catalog(root: &Node, db_path: &Path) -> Result<CatalogSummary> {
+    let mut connection = Connection::open(db_path)
+        .with_context(|| format!("Cannot open the catalog {}", db_path.display()))?;
+    connection.execute(SCHEMA, [])?;
+
+    let root_url = root.url();
+    let now = Utc::now().to_rfc3339();
+    let mut summary = CatalogSummary::default();
+
+    // A single transaction, as committing each row is slow (and a failed run changes nothing)
+    let transaction = connection.transaction()?;
+    for file in root.iter_files() {
+        let known: Option<(String, String)> = transaction
+            .query_row(
+                "SELECT size, last_modified FROM files WHERE url = ?1",
+                [&file.url],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+
+        match known {
+            None => {
+                insert_file(&transaction, file, root_url, &now)?;
+                summary.added += 1;
+            }
+            Some((size, last_modified)) => {
+                let changed = size != file.size || last_modified != file.last_modified;
+                update_file(&transaction, file, &now, changed)?;
+                if changed {
+                    summary.changed += 1;
+                } else {
+                    summary.unchanged += 1;
+                }
+            }
+        }
+    }
+    transaction
+        .commit()
+        .with_context(|| format!("Cannot write the catalog {}", db_path.display()))?;
+
+    Ok(summary)
+}
+
+fn insert_file(
+    connection: &Connection,
+    file: &FileLinkMetaData,
+    root_url: &str,
+    now: &str,
+) -> Result<()> {
+    connection.execute(
+        "INSERT INTO files (url, root, name, size, size_bytes, last_modified, description, depth,
+            first_seen, last_seen, changed_at)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?9, ?9)",
+        params![
+            file.url,
+            root_url,
+            file.name,
+            file.size,
+            file.size_bytes,
+            file.last_modified,
+            file.description,
+            file.depth,
+            now,
+        ],
+    )?;
+
+    Ok(())
+}
+
+fn update_file(
+    connection: &Connection,
+    file: &FileLinkMetaData,
+    now: &str,
+    changed: bool,
+) -> Result<()> {
+    connection.execute(
+        "UPDATE files SET name = ?2, size = ?3, size_bytes = ?4, last_modified = ?5,
+            description = ?6, depth = ?7, last_seen = ?8,
+            changed_at = CASE WHEN ?9 THEN ?8 ELSE changed_at END
+        WHERE url = ?1",
+        params![
+            file.url,
+            file.name,
+            file.size,
+            file.size_bytes,
+            file.last_modified,
+            file.description,
+            file.depth,
+            now,
+            changed,
+        ],
+    )?;
+
+    Ok(())
+}
@@ -0,0 +1,157 @@
+use md5::Md5;
+use sha2::{Digest, Sha256};
+use std::{
+    fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::AsyncWrite;
+
+/// The digest written next to each downloaded file (see `--emit-checksums`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumKind {
+    Sha256,
+    Md5,
+}
+
+impl ChecksumKind {
+    /// All kinds (in the order their sidecars are looked for)
+    pub const ALL: [ChecksumKind; 2] = [ChecksumKind::Sha256, ChecksumKind::Md5];
+
+    /// The extension of the sidecar files (like the ones of `sha256sum` & `md5sum`)
+    pub fn extension(self) -> &'static str {
+        match self {
+            ChecksumKind::Sha256 => "sha256",
+            ChecksumKind::Md5 => "md5",
+        }
+    }
+}
+
+/// A running digest of either kind
+enum Hasher {
+    Sha256(Sha256),
+    Md5(Md5),
+}
+
+impl Hasher {
+    fn new(kind: ChecksumKind) -> Self {
+        match kind {
+            ChecksumKind::Sha256 => Hasher::Sha256(Sha256::new()),
+            ChecksumKind::Md5 => Hasher::Md5(Md5::new()),
+        }
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        match self {
+            Hasher::Sha256(hasher) => hasher.update(bytes),
+            Hasher::Md5(hasher) => hasher.update(bytes),
+        }
+    }
+
+    /// Returns the digest (as lowercase hex)
+    fn finish(self) -> String {
+        let digest = match self {
+            Hasher::Sha256(hasher) => hasher.finalize().to_vec(),
+            Hasher::Md5(hasher) => hasher.finalize().to_vec(),
+        };
+
+        digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+}
+
+/**
+Passes everything written on to another writer, hashing it on the way
+
+This way the digest of a download is known without reading the file a second time.
+*/
+pub struct HashingWriter<'a, W: ?Sized> {
+    inner: &'a mut W,
+    hasher: Hasher,
+}
+
+impl<'a, W: AsyncWrite + Unpin + ?Sized> HashingWriter<'a, W> {
+    pub fn new(inner: &'a mut W, kind: ChecksumKind) -> Self {
+        Self {
+            inner,
+            hasher: Hasher::new(kind),
+        }
+    }
+
+    /// Returns the digest of everything written (as lowercase hex)
+    pub fn finish(self) -> String {
+        self.hasher.finish()
+    }
+}
+
+impl<W: AsyncWrite + Unpin + ?Sized> AsyncWrite for HashingWriter<'_, W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let poll = Pin::new(&mut *this.inner).poll_write(cx, buf);
+
+        // Only hash what actually got written
+        if let Poll::Ready(Ok(written)) = poll {
+            this.hasher.update(&buf[..written]);
+        }
+
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Returns the path of the checksum sidecar of a file (e.g. `file.iso.sha256`)
+pub fn checksum_path(file_path: &Path, kind: ChecksumKind) -> PathBuf {
+    let mut path = file_path.as_os_str().to_owned();
+    path.push(".");
+    path.push(kind.extension());
+
+    PathBuf::from(path)
+}
+
+/// Calculates the digest of a file (as lowercase hex)
+pub fn hash_file(path: &Path, kind: ChecksumKind) -> io::Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Hasher::new(kind);
+    let mut buffer = vec![0; 64 * 1024];
+
+    loop {
+        match file.read(&mut buffer)? {
+            0 => return Ok(hasher.finish()),
+            read => hasher.update(&buffer[..read]),
+        }
+    }
+}
+
+/// Reads the digest of a sidecar (if the file has one, see `sidecar_line`)
+pub fn read_sidecar(file_path: &Path, kind: ChecksumKind) -> Option<String> {
+    let line = fs::read_to_string(checksum_path(file_path, kind)).ok()?;
+
+    line.split_whitespace()
+        .next()
+        .map(|digest| digest.to_ascii_lowercase())
+}
+
+/**
+Formats a sidecar like `sha256sum` does (`<digest>  <name>`), so it can be checked with
+`sha256sum -c` (or `md5sum -c`) from within the directory
+*/
+pub fn sidecar_line(digest: &str, file_path: &Path) -> String {
+    let name = file_path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+
+    format!("{}  {}\n", digest, name)
+}
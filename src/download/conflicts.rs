@@ -0,0 +1,255 @@
+use anyhow::{anyhow, bail, Context, Result};
+use log::{info, warn};
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// What to do when something else is in the way of a download (see `--on-conflict`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Keep what's there and skip the download
+    Skip,
+
+    /// Replace files in the way (but never directories)
+    Overwrite,
+
+    /// Keep what's there and save the download under a free name (`name.1`, `name.2`, ...)
+    Rename,
+
+    /// Fail the download
+    Error,
+}
+
+/// What is in the way of a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Conflict {
+    /// A directory of the same name
+    Directory,
+
+    /// A file of another size than the one in the listing
+    OtherSize(u64),
+}
+
+/// What becomes of a file whose local path is taken
+#[derive(Debug)]
+pub enum Resolution {
+    /// Download it to its path (replacing what's there, if anything)
+    Proceed,
+
+    /// Download it to another path
+    Renamed(PathBuf),
+
+    /// Don't download it
+    Skip,
+
+    /// Fail it with this error
+    Fail(anyhow::Error),
+}
+
+/**
+Decides what becomes of a file whose local path may be taken
+
+A directory of the same name or a file of another size than the listed one (if it's known, as the
+size and its precision) count as conflicts, a file of the same size gets replaced as usual.
+Directories never get replaced: with `Overwrite`, a directory in the way fails the download.
+*/
+pub fn resolve_file(
+    path: &Path,
+    expected_size: Option<(u64, u64)>,
+    policy: ConflictPolicy,
+) -> Resolution {
+    let conflict = match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => Conflict::Directory,
+        Ok(metadata)
+            if expected_size.is_some_and(|(expected, precision)| {
+                metadata.len().abs_diff(expected) >= precision
+            }) =>
+        {
+            Conflict::OtherSize(metadata.len())
+        }
+        _ => return Resolution::Proceed,
+    };
+
+    let description = match conflict {
+        Conflict::Directory => "a directory".to_owned(),
+        Conflict::OtherSize(bytes) => format!("a file of {} bytes", bytes),
+    };
+
+    match (policy, conflict) {
+        (ConflictPolicy::Overwrite, Conflict::OtherSize(_)) => Resolution::Proceed,
+        (ConflictPolicy::Skip, _) => {
+            info!(
+                "(Conflict) Skipping {} ({} is in the way)",
+                path.display(),
+                description
+            );
+            Resolution::Skip
+        }
+        (ConflictPolicy::Rename, _) => {
+            let free = free_path(path, expected_size, |_| false);
+            info!(
+                "(Conflict) Saving {} as {} ({} is in the way)",
+                path.display(),
+                free.display(),
+                description
+            );
+            Resolution::Renamed(free)
+        }
+        (ConflictPolicy::Overwrite, Conflict::Directory) | (ConflictPolicy::Error, _) => {
+            Resolution::Fail(anyhow!(
+                "Cannot save {}, {} is in the way (see --on-conflict)",
+                path.display(),
+                description
+            ))
+        }
+    }
+}
+
+/**
+Decides what becomes of a file whose local path was already assigned to another file of the run
+
+Distinct remote names may end up at the same path (e.g. `a:b` & `a_b` once sanitized), so one
+download would silently replace the other. The first file keeps the path, the later ones follow
+the policy (`Rename` picks a name which is taken neither on the disk nor in the run). As the files
+come in the same order on every run, they end up at the same paths as well.
+*/
+pub fn resolve_collision(
+    path: &Path,
+    other_url: &str,
+    expected_size: Option<(u64, u64)>,
+    assigned: &HashMap<PathBuf, String>,
+    policy: ConflictPolicy,
+) -> Resolution {
+    match policy {
+        ConflictPolicy::Overwrite => {
+            warn!(
+                "(Conflict) Replacing the download of {} at {}",
+                other_url,
+                path.display()
+            );
+            Resolution::Proceed
+        }
+        ConflictPolicy::Skip => {
+            info!(
+                "(Conflict) Skipping {} ({} is saved there)",
+                path.display(),
+                other_url
+            );
+            Resolution::Skip
+        }
+        ConflictPolicy::Rename => {
+            let free = free_path(path, expected_size, |candidate| {
+                assigned.contains_key(candidate)
+            });
+            info!(
+                "(Conflict) Saving {} as {} ({} is saved there)",
+                path.display(),
+                free.display(),
+                other_url
+            );
+            Resolution::Renamed(free)
+        }
+        ConflictPolicy::Error => Resolution::Fail(anyhow!(
+            "Cannot save {}, {} is saved there already (see --on-conflict)",
+            path.display(),
+            other_url
+        )),
+    }
+}
+
+/**
+Makes way for a directory below the destination, whose path may be taken by files
+
+Files in the way get replaced with `Overwrite` or moved to a free name with `Rename`. Returns false
+if the files of the directory are to be skipped.
+*/
+pub fn resolve_dir(path: &Path, destination: &Path, policy: ConflictPolicy) -> Result<bool> {
+    let relative = match path.strip_prefix(destination) {
+        Ok(relative) => relative,
+        Err(_) => return Ok(true),
+    };
+
+    let mut current = destination.to_owned();
+    for component in relative.components() {
+        current.push(component);
+
+        let metadata = match fs::metadata(&current) {
+            Ok(metadata) => metadata,
+            // The rest doesn't exist yet
+            Err(_) => break,
+        };
+        if metadata.is_dir() {
+            continue;
+        }
+
+        match policy {
+            ConflictPolicy::Skip => {
+                warn!(
+                    "(Conflict) Skipping the files of {} (the file {} is in the way)",
+                    path.display(),
+                    current.display()
+                );
+                return Ok(false);
+            }
+            ConflictPolicy::Overwrite => {
+                info!(
+                    "(Conflict) Replacing the file {} by a directory",
+                    current.display()
+                );
+                fs::remove_file(&current)
+                    .with_context(|| format!("Cannot remove {}", current.display()))?;
+            }
+            ConflictPolicy::Rename => {
+                let free = free_path(&current, None, |_| false);
+                info!(
+                    "(Conflict) Moving the file {} to {} (for a directory)",
+                    current.display(),
+                    free.display()
+                );
+                fs::rename(&current, &free)
+                    .with_context(|| format!("Cannot move {}", current.display()))?;
+            }
+            ConflictPolicy::Error => bail!(
+                "Cannot create the directory {}, the file {} is in the way (see --on-conflict)",
+                path.display(),
+                current.display()
+            ),
+        }
+        break;
+    }
+
+    Ok(true)
+}
+
+/**
+Finds a free path next to a taken one, by appending a number (`name.1`, `name.2`, ...)
+
+A file of the expected size (if any) counts as free, so the next run replaces the renamed download
+of an earlier one instead of adding another copy. Paths which are `taken` otherwise are left out.
+*/
+fn free_path(
+    path: &Path,
+    expected_size: Option<(u64, u64)>,
+    taken: impl Fn(&Path) -> bool,
+) -> PathBuf {
+    (1..)
+        .map(|number| {
+            let mut name = OsString::from(path.as_os_str());
+            name.push(format!(".{}", number));
+            PathBuf::from(name)
+        })
+        .filter(|candidate| !taken(candidate))
+        .find(|candidate| match fs::symlink_metadata(candidate) {
+            Ok(metadata) => {
+                metadata.is_file()
+                    && expected_size.is_some_and(|(expected, precision)| {
+                        metadata.len().abs_diff(expected) < precision
+                    })
+            }
+            Err(_) => true,
+        })
+        .unwrap()
+}
@@ -0,0 +1,102 @@
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
+use log::{debug, warn};
+use reqwest::{cookie::Jar, Url};
+use std::{fs, path::Path};
+
+/// Lines of cookie files with this prefix are cookies (marked HttpOnly), not comments
+const HTTP_ONLY_PREFIX: &str = "#HttpOnly_";
+
+/**
+Adds a `name=value` cookie for the server of a URL
+*/
+pub fn add_cookie(jar: &Jar, cookie: &str, url: &Url) -> Result<()> {
+    match cookie.split_once('=') {
+        Some((name, _)) if !name.trim().is_empty() => {
+            jar.add_cookie_str(cookie, url);
+            Ok(())
+        }
+        _ => bail!("Invalid cookie (must be name=value): {}", cookie),
+    }
+}
+
+/**
+Loads the cookies of a cookies file in the Netscape/Mozilla format (e.g. exported by a browser)
+
+Each line has seven tab-separated fields: domain, include subdomains, path, secure, expiry,
+name & value. Invalid lines get skipped with a warning, expired cookies are ignored.
+
+Returns the number of loaded cookies.
+*/
+pub fn load_cookies_file(jar: &Jar, path: &Path) -> Result<usize> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("Cannot read the cookies file {}", path.display()))?;
+
+    let mut count = 0;
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim_end_matches('\r');
+        let line = line.strip_prefix(HTTP_ONLY_PREFIX).unwrap_or(line);
+
+        // Skip empty lines and comments
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        match parse_line(line) {
+            Ok(Some((cookie, url))) => {
+                jar.add_cookie_str(&cookie, &url);
+                count += 1;
+            }
+            Ok(None) => debug!("(Cookies) Skip expired cookie in line {}", number + 1),
+            Err(err) => warn!(
+                "Skipping line {} of the cookies file {}: {}",
+                number + 1,
+                path.display(),
+                err
+            ),
+        }
+    }
+
+    Ok(count)
+}
+
+/**
+Turns a line of a cookies file into a `Set-Cookie` string and the URL it applies to
+
+Returns None for expired cookies.
+*/
+fn parse_line(line: &str) -> Result<Option<(String, Url)>> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    let [domain, include_subdomains, path, secure, expiry, name, value] = fields[..] else {
+        bail!("Expected 7 tab-separated fields, found {}", fields.len());
+    };
+
+    let secure = secure.eq_ignore_ascii_case("TRUE");
+    let expiry: i64 = expiry
+        .parse()
+        .with_context(|| format!("Invalid expiry: {}", expiry))?;
+
+    // An expiry of 0 marks a session cookie
+    if expiry != 0 && expiry < Utc::now().timestamp() {
+        return Ok(None);
+    }
+
+    let host = domain.trim_start_matches('.');
+    let url = Url::parse(&format!(
+        "{}://{}{}",
+        if secure { "https" } else { "http" },
+        host,
+        path
+    ))
+    .with_context(|| format!("Invalid domain or path: {}{}", domain, path))?;
+
+    let mut cookie = format!("{}={}; Path={}", name, value, path);
+    if include_subdomains.eq_ignore_ascii_case("TRUE") {
+        cookie.push_str(&format!("; Domain={}", host));
+    }
+    if secure {
+        cookie.push_str("; Secure");
+    }
+
+    Ok(Some((cookie, url)))
+}
@@ -1,214 +1,2006 @@
-use std::str::FromStr;
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet, VecDeque},
+    fmt,
+    hash::{Hash, Hasher},
+    str::FromStr,
+    sync::Mutex,
+    time::Duration,
+};
 
-use super::types::{DirLinkMetaData, FileLinkMetaData, Node};
-use anyhow::{anyhow, bail, Result};
-use html_escape::decode_html_entities_to_vec;
+use super::{
+    caddy,
+    excludes::DirExcludes,
+    filenames::decode_filename,
+    format::{is_relative_href, ListingFormat, ServerFormat},
+    listing::{DirectoryListing, Fetched},
+    observer::DownloadObserver,
+    pagination::{default_paginations, Pagination},
+    selectors,
+    throttle::Throttled,
+    timeout::with_timeout,
+    types::{DirLinkMetaData, FileLinkMetaData, Node, PartialCrawl, TreeSummary, Validators},
+    units::{format_size, parse_size, ByteRange},
+    useragents::UserAgentPool,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use encoding_rs::{Encoding, UTF_8, WINDOWS_1252};
+use futures::{stream, stream::FuturesUnordered, Stream, StreamExt};
 use lazy_static::lazy_static;
-use rayon::prelude::*;
-use regex::Regex;
-use reqwest::{self, Url};
+use log::{debug, info, warn};
+use regex::{Captures, Regex};
+use reqwest::{
+    self,
+    header::{
+        HeaderMap, ACCEPT, CONTENT_LENGTH, CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH,
+        LAST_MODIFIED, RANGE, USER_AGENT,
+    },
+    redirect::Policy,
+    RequestBuilder, StatusCode, Url,
+};
+use scraper::{ElementRef, Html};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 
 // Make-shift errors
-const CANNOT_PARSE_DIRECTORY: &'static str = "Couldn't parse the directory name";
-const EMPTY_RESPONSE: &'static str = "Got a empty response";
+const CANNOT_PARSE_DIRECTORY: &str = "Couldn't parse the directory name";
+const NOT_A_LISTING: &str = "does not appear to be an open directory listing";
 
-const EMPTY_SIZE_STRING: &'static str = "  - ";
+/**
+The error of a page which couldn't be parsed as a listing
+
+Unlike other errors, it only concerns a single directory, so the crawl may go on without it (see
+`--continue-on-parse-error`).
+*/
+#[derive(Debug, Clone)]
+pub struct NotAListing {
+    /// The URL of the directory
+    pub url: String,
+    message: String,
+}
+
+impl NotAListing {
+    fn new(url: &Url, message: String) -> Self {
+        Self {
+            url: url.to_string(),
+            message,
+        }
+    }
+}
+
+impl fmt::Display for NotAListing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for NotAListing {}
+
+/// Returns the directory which isn't a listing (if that's what caused an error)
+pub fn not_a_listing(error: &anyhow::Error) -> Option<&NotAListing> {
+    error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<NotAListing>())
+}
+
+/**
+The error of a request which kept getting redirected, either in a loop (e.g. between `foo` and
+`foo/` on misconfigured servers) or more than `MAX_REDIRECTS` times
+*/
+#[derive(Debug, Clone)]
+pub struct RedirectLoop {
+    /// The URLs redirected through (the last one being the one not followed)
+    pub urls: Vec<String>,
+
+    /// Whether the last URL was visited before
+    pub looped: bool,
+}
+
+impl fmt::Display for RedirectLoop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.looped {
+            write!(f, "Redirect loop ({})", self.urls.join(" -> "))
+        } else {
+            write!(
+                f,
+                "Too many redirects (more than {}, ending at {})",
+                MAX_REDIRECTS,
+                self.urls.last().map(String::as_str).unwrap_or_default()
+            )
+        }
+    }
+}
+
+impl std::error::Error for RedirectLoop {}
+
+/// Returns the redirect loop (if that's what caused an error)
+pub fn redirect_loop(error: &anyhow::Error) -> Option<&RedirectLoop> {
+    error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<RedirectLoop>())
+}
+
+/**
+The error of a listing with rows which look like entries, but couldn't be parsed (see
+`--strict-html`)
+*/
+#[derive(Debug, Clone)]
+pub struct UnparsedRows {
+    /// The URL of the listing
+    pub url: String,
+
+    /// The text of each unparsed row
+    pub rows: Vec<String>,
+}
+
+impl fmt::Display for UnparsedRows {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} rows of {} couldn't be parsed: {}",
+            self.rows.len(),
+            self.url,
+            self.rows.join(" | ")
+        )
+    }
+}
+
+impl std::error::Error for UnparsedRows {}
+
+/**
+The error of a listing which doesn't fit the format it's assumed to have (see `--assume-format`)
+
+Without it, a wrong guess would look like an empty directory.
+*/
+#[derive(Debug, Clone)]
+pub struct FormatMismatch {
+    /// The URL of the listing
+    pub url: String,
+
+    pub format: ListingFormat,
+}
+
+impl fmt::Display for FormatMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "No entries parsed with the assumed format {} from {} (try another --assume-format or leave it out)",
+            self.format, self.url
+        )
+    }
+}
+
+impl std::error::Error for FormatMismatch {}
+
+/// The error of a request the server answered with an error status (other than to slow down)
+#[derive(Debug, Clone)]
+pub struct ErrorStatus {
+    pub url: String,
+    pub status: StatusCode,
+    message: String,
+}
+
+impl fmt::Display for ErrorStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ErrorStatus {}
+
+/// The error of a server which sends whole files when asked for a range of bytes
+#[derive(Debug, Clone)]
+pub struct RangesUnsupported {
+    pub url: String,
+}
+
+impl fmt::Display for RangesUnsupported {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The server sent all of {} instead of a range (it doesn't support partial downloads)",
+            self.url
+        )
+    }
+}
+
+impl std::error::Error for RangesUnsupported {}
+
+/// Returns the file whose server doesn't support ranges (if that's what caused an error)
+pub fn ranges_unsupported(error: &anyhow::Error) -> Option<&RangesUnsupported> {
+    error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<RangesUnsupported>())
+}
+
+/// The error of a listing which is larger than allowed (see `--max-listing-size`)
+#[derive(Debug, Clone)]
+pub struct ListingTooLarge {
+    /// The URL of the listing
+    pub url: String,
+    pub limit: u64,
+}
+
+impl fmt::Display for ListingTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The listing {} is larger than {} (raise --max-listing-size if it really is that large)",
+            self.url,
+            format_size(self.limit)
+        )
+    }
+}
+
+impl std::error::Error for ListingTooLarge {}
+
+/// Checks if an error means that a file is gone from the server (404 Not Found or 410 Gone)
+pub fn is_gone(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        cause
+            .downcast_ref::<ErrorStatus>()
+            .is_some_and(|error| matches!(error.status, StatusCode::NOT_FOUND | StatusCode::GONE))
+    })
+}
+
+/**
+A hard cap on the size of a crawl (see `--max-dirs`, `--max-nodes` & `--preview`)
+
+Once a limit is reached, no further directories get listed, so accidental loops or endless
+generated trees can't exhaust the memory. The directories which were cut off stay PendingDir nodes
+(so the crawl can be resumed from them), the files beyond `--preview` are left out.
+*/
+#[derive(Debug, Clone, Default)]
+pub struct CrawlBudget {
+    max_dirs: Option<u64>,
+    max_nodes: Option<u64>,
+    max_files: Option<u64>,
+
+    /// The number of directories listed so far
+    dirs: u64,
+
+    /// The number of files & directories found so far
+    nodes: u64,
+
+    /// The number of files found so far
+    files: u64,
+
+    /// The number of directories left out because of the limits
+    cut_off: u64,
+
+    /// The URLs of the directories left out since the last `take_left_out`
+    left_out: Vec<String>,
+
+    /// The number of files left out because of `max_files`
+    cut_off_files: u64,
+}
+
+impl CrawlBudget {
+    pub fn new(max_dirs: Option<u64>, max_nodes: Option<u64>) -> Self {
+        Self {
+            max_dirs,
+            max_nodes,
+            ..Self::default()
+        }
+    }
+
+    /// Stops the crawl once this many files were found
+    pub fn with_max_files(mut self, max_files: Option<u64>) -> Self {
+        self.max_files = max_files;
+        self
+    }
+
+    /// Checks if anything was left out of the crawl because of the limits
+    fn is_cut_off(&self) -> bool {
+        self.cut_off > 0 || self.cut_off_files > 0
+    }
+
+    /// Checks if any of the limits has been reached
+    fn is_exhausted(&self) -> bool {
+        self.max_dirs.is_some_and(|max| self.dirs >= max)
+            || self.max_nodes.is_some_and(|max| self.nodes >= max)
+            || self.max_files.is_some_and(|max| self.files >= max)
+    }
+
+    /// Returns the URLs of the directories which were left out (since the last call)
+    fn take_left_out(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.left_out)
+    }
+
+    /// Checks if another directory may be listed (and counts it if so)
+    fn take_dir(&mut self, url: &str) -> bool {
+        if self.is_exhausted() {
+            if self.cut_off == 0 {
+                warn!(
+                    "Reached the crawl limit after {} directories & {} nodes, not crawling any further",
+                    self.dirs, self.nodes
+                );
+            }
+            self.cut_off += 1;
+            self.left_out.push(url.to_owned());
+
+            return false;
+        }
+
+        self.dirs += 1;
+        true
+    }
+
+    /// Counts the nodes of a listing (dropping the files beyond `max_files`)
+    fn add_nodes(&mut self, children: &mut Vec<Node>) {
+        if let Some(max) = self.max_files {
+            let (mut files, before) = (self.files, children.len());
+            children.retain(|child| match child {
+                Node::File(_) => {
+                    files += 1;
+                    files <= max
+                }
+                _ => true,
+            });
+            self.cut_off_files += (before - children.len()) as u64;
+        }
+
+        self.files += children
+            .iter()
+            .filter(|child| matches!(child, Node::File(_)))
+            .count() as u64;
+        self.nodes += children.len() as u64;
+    }
+
+    /// Warns about the directories & files which were left out (if any)
+    fn report(&self) {
+        if self.is_cut_off() {
+            warn!(
+                "Left out {} directories & {} files because of the crawl limits (--max-dirs, --max-nodes or --preview)",
+                self.cut_off, self.cut_off_files
+            );
+        }
+    }
+}
+
+/// Asks for JSON listings (e.g. Caddy's), which are exact, but accepts HTML ones
+const LISTING_ACCEPT: &str = "application/json, text/html;q=0.9, */*;q=0.8";
+
+/// The cell positions (relative to the cell containing the link) of a listing row
+pub const POS_DATE: usize = 1;
+pub const POS_SIZE: usize = 2;
+pub const POS_DESC: usize = 3;
+
+/// The text of the link back to the parent directory
+const PARENT_DIRECTORY: &str = "Parent Directory";
+
+/// The number of redirects followed per request (like reqwest's default policy)
+const MAX_REDIRECTS: usize = 10;
+
+/// The number of listings fetched at once when only counting a tree (see `count_tree`)
+const COUNT_CONCURRENCY: usize = 16;
+
+/// The default size a listing may have (see `--max-listing-size`)
+pub const DEFAULT_MAX_LISTING_SIZE: &str = "50M";
+
+lazy_static! {
+    /// Extracts the directory name from the heading of a listing
+    static ref RX_TITLE: Regex = Regex::new("Index of (.+)").unwrap();
+
+    /// Extracts the charset declared by a `<meta>` tag
+    static ref RX_META_CHARSET: Regex =
+        Regex::new(r#"(?i)<meta[^>]+charset=["']?([\w-]+)"#).unwrap();
+
+    /// Matches the rows of an IIS listing (a date, the size or `<dir>`, and the link, each row
+    /// following a `<br>`), e.g. `<br> 1/23/2021  3:45 PM        12345 <A HREF="/a.iso">a.iso</A>`
+    static ref RX_IIS_ROW: Regex = Regex::new(
+        r#"(?i)(?:<pre>|<br>)\s*(?P<date>[^<>]*?\d{1,2}:\d{2}(?:\s*[AP]M)?)\s+(?P<size>&lt;dir&gt;|\d+)\s+<a\s[^>]*?href="(?P<href>[^"]*)"[^>]*>(?P<name>.*?)</a>"#
+    )
+    .unwrap();
+
+    /// Matches the lines of an nginx listing (the link, the date & the size or `-`)
+    static ref RX_NGINX_ROW: Regex = Regex::new(
+        r#"<a\s[^>]*?href="(?P<href>[^"]*)"[^>]*>(?P<name>.*?)</a>\s+(?P<date>\d{1,2}-\w{3}-\d{4} \d{2}:\d{2}(?::\d{2})?)\s+(?P<size>-|\d+)"#
+    )
+    .unwrap();
+}
+
+/**
+Returns the first match in a string with a given Regex pattern
+*/
+fn get_first<'a>(text: &'a str, regex: &Regex) -> Result<&'a str> {
+    Ok(regex
+        .captures(text)
+        .ok_or_else(|| anyhow!(CANNOT_PARSE_DIRECTORY))?
+        .get(1)
+        .ok_or_else(|| anyhow!(CANNOT_PARSE_DIRECTORY))?
+        .as_str())
+}
+
+/**
+Returns the whitespace-trimmed text content of an element
+*/
+fn get_text(element: &ElementRef) -> String {
+    element.text().collect::<String>().trim().to_owned()
+}
+
+/**
+Parses a given HTML-string and extracts the directory and file paths.
+
+-  Not recursive
+-  Does not make requests
+
+Returns a tuple containing the extracted name and the vector of extracted nodes. The `format`
+tells directories apart from files, and may replace the table rows with a custom pattern.
+Listings of IIS (a `<pre>` block instead of a table, without an "Index of" heading) and of nginx
+(a `<pre>` block below the heading) are recognized by their rows, unless the format is assumed.
+An assumed format which finds no entries on a page linking to some fails with `FormatMismatch`.
+*/
+pub fn cheap_extract_from_html(
+    html: &str,
+    base_url: &Url,
+    format: &ServerFormat,
+) -> Result<(String, Vec<Node>)> {
+    let document = Html::parse_document(html);
+
+    // Read the directory name from the heading
+    let heading = document
+        .select(&selectors::TITLE)
+        .map(|heading| get_text(&heading))
+        .find(|text| RX_TITLE.is_match(text));
+
+    let mismatch = |format| FormatMismatch {
+        url: base_url.to_string(),
+        format,
+    };
+
+    // IIS has neither the heading nor a table, but rows of its own (as has nginx below its heading)
+    let row_pattern = match (format.assumed, &heading, &format.row_pattern) {
+        (Some(ListingFormat::Iis), _, _) => Some(&*RX_IIS_ROW),
+        (Some(ListingFormat::Nginx), _, _) => Some(&*RX_NGINX_ROW),
+        (Some(_), _, _) => None,
+        (None, None, None) if RX_IIS_ROW.is_match(html) => {
+            debug!("(IIS) Parsing {} as an IIS listing", base_url);
+            Some(&*RX_IIS_ROW)
+        }
+        (None, Some(_), None)
+            if document.select(&selectors::ROW).next().is_none() && RX_NGINX_ROW.is_match(html) =>
+        {
+            debug!("(Nginx) Parsing {} as an nginx listing", base_url);
+            Some(&*RX_NGINX_ROW)
+        }
+        (None, _, pattern) => pattern.as_ref(),
+    };
+    let name = match (heading, row_pattern) {
+        (Some(heading), _) => get_first(&heading, &RX_TITLE)?.to_owned(),
+        // Odd servers may have no heading, but then the custom pattern has to match something
+        (None, Some(pattern)) if pattern.is_match(html) => dir_name(base_url),
+        (None, _) => match format.assumed {
+            Some(assumed) => bail!(mismatch(assumed)),
+            None => bail!(CANNOT_PARSE_DIRECTORY),
+        },
+    };
+
+    // Walk the rows of the listing table (or the matches of the custom pattern)
+    let mut unparsed = vec![];
+    let mut nodes = match row_pattern {
+        Some(pattern) => pattern
+            .captures_iter(html)
+            .filter_map(|captures| process_captures(&captures, base_url, format))
+            .collect(),
+        None => document
+            .select(&selectors::ROW)
+            .filter_map(|row| {
+                let node = cheap_process_row(row, base_url, format);
+                if node.is_none() && is_entry_like(row, base_url, format) {
+                    unparsed.push(row_text(row));
+                }
+                node
+            })
+            .collect(),
+    };
+    drop_duplicates(&mut nodes, base_url);
+
+    // A wrong format would parse nothing, which must not pass for an empty directory
+    if let Some(assumed) = format.assumed {
+        if nodes.is_empty() && links_below(&document, base_url) {
+            bail!(mismatch(assumed));
+        }
+    }
+
+    // Don't let entries go missing silently
+    if !unparsed.is_empty() {
+        for row in &unparsed {
+            debug!("(Unparsed) Row of {}: {}", base_url, row);
+        }
+        if format.strict_html {
+            bail!(UnparsedRows {
+                url: base_url.to_string(),
+                rows: unparsed,
+            });
+        }
+        warn!(
+            "(Unparsed) {} rows of {} look like entries, but couldn't be parsed",
+            unparsed.len(),
+            base_url
+        );
+    }
+
+    Ok((name, nodes))
+}
+
+/**
+Drops the nodes of a listing whose URL was listed before (keeping the first occurrence)
+
+Malformed listings sometimes list an entry twice, which would otherwise be downloaded twice.
+*/
+fn drop_duplicates(nodes: &mut Vec<Node>, base_url: &Url) {
+    let mut seen = HashSet::new();
+
+    nodes.retain(|node| {
+        let first = seen.insert(node.url().to_owned());
+        if !first {
+            warn!(
+                "(Duplicate) The listing of {} contains {} more than once",
+                base_url,
+                node.url()
+            );
+        }
+
+        first
+    });
+}
+
+/**
+Turns an ElementRef (of a HTML table-row into a node (Either PendingDir or File)
+*/
+pub fn cheap_process_row(row: ElementRef, base_url: &Url, format: &ServerFormat) -> Option<Node> {
+    let cells: Vec<ElementRef> = row.select(&selectors::CELL).collect();
+
+    // Locate the cell containing the link (the icon column is optional)
+    let link_pos = cells
+        .iter()
+        .position(|cell| cell.select(&selectors::LINK).next().is_some())?;
+    let link = cells[link_pos].select(&selectors::LINK).next()?;
+
+    // Read a cell relative to the link cell
+    let cell_text = |offset: usize| cells.get(link_pos + offset).map(get_text);
+
+    // The other values get extracted from the cells
+    let name = get_text(&link);
+    let last_modified = cell_text(POS_DATE)?;
+    let size = cell_text(POS_SIZE)?;
+    let description = cell_text(POS_DESC).unwrap_or_default();
+
+    make_node(
+        link.value().attr("href")?,
+        name,
+        last_modified,
+        size,
+        description,
+        base_url,
+        format,
+    )
+}
+
+/**
+Checks if a row which couldn't be parsed looks like an entry anyway (having a link in a cell,
+which isn't the one to the parent directory)
+*/
+fn is_entry_like(row: ElementRef, base_url: &Url, format: &ServerFormat) -> bool {
+    row.select(&selectors::CELL)
+        .find_map(|cell| cell.select(&selectors::LINK).next())
+        .is_some_and(|link| {
+            let href = link.value().attr("href").unwrap_or_default();
+            let parent = base_url
+                .join(href)
+                .is_ok_and(|href| is_parent_or_self(&href, base_url));
+            // Absolute links are left out on purpose with --relative-only
+            let ignored = format.relative_only && !is_relative_href(href);
+
+            !parent && !ignored && get_text(&link) != PARENT_DIRECTORY
+        })
+}
+
+/**
+Checks if a page links to anything below its URL (which a listing of no entries doesn't, as its
+links only lead back up or re-sort it)
+*/
+fn links_below(document: &Html, base_url: &Url) -> bool {
+    document.select(&selectors::LINK).any(|link| {
+        let href = link.value().attr("href").unwrap_or_default();
+
+        base_url.join(href).is_ok_and(|url| {
+            url.origin() == base_url.origin()
+                && url.path().starts_with(base_url.path())
+                && url.path() != base_url.path()
+        })
+    })
+}
+
+/**
+Returns the text of a table row (with its cells separated by spaces)
+*/
+fn row_text(row: ElementRef) -> String {
+    row.text()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/**
+Turns a match of the custom row pattern (see `--row-pattern`) into a node
+
+The groups are taken as the text they contain (without tags, and with entities decoded). Missing
+`date`, `size` and `desc` groups leave those fields empty.
+*/
+fn process_captures(captures: &Captures, base_url: &Url, format: &ServerFormat) -> Option<Node> {
+    let group = |name: &str| {
+        captures
+            .name(name)
+            .map(|group| fragment_text(group.as_str()))
+            .unwrap_or_default()
+    };
+
+    make_node(
+        &group("href"),
+        group("name"),
+        group("date"),
+        group("size"),
+        group("desc"),
+        base_url,
+        format,
+    )
+}
+
+/**
+Returns the whitespace-trimmed text of a piece of HTML
+*/
+fn fragment_text(html: &str) -> String {
+    Html::parse_fragment(html)
+        .root_element()
+        .text()
+        .collect::<String>()
+        .trim()
+        .to_owned()
+}
+
+/**
+Makes a node (Either PendingDir or File) of the values of a listing row
+*/
+fn make_node(
+    href: &str,
+    name: String,
+    last_modified: String,
+    size: String,
+    description: String,
+    base_url: &Url,
+    format: &ServerFormat,
+) -> Option<Node> {
+    // Only take the links relative to the listing (if desired)
+    if format.relative_only && !is_relative_href(href) {
+        debug!("(Relative) Skip absolute link {}", href);
+        return None;
+    }
+
+    // Calculate the absolute href using the base_url
+    let mut href = base_url.join(href).ok()?;
+
+    // Only take links which can be crawled or downloaded (not e.g. `mailto:` or `javascript:`)
+    if href.cannot_be_a_base() || !matches!(href.scheme(), "http" | "https" | "ftp") {
+        debug!("(Scheme) Skip link {}", &href);
+        return None;
+    }
+
+    // Don't crawl back up the tree (or into the same directory again)
+    if name == PARENT_DIRECTORY || is_parent_or_self(&href, base_url) {
+        debug!("(Parent) Skip link {}", &href);
+        return None;
+    }
+
+    // Check if the result is a directory (by examining its link & stated size)
+    if format.is_dir(&href, &size) {
+        // Listings may link to the same directory as `foo/` and `foo`
+        canonicalize_dir_url(&mut href);
+
+        // Name the directory by its path, like once it's crawled (the link text may be truncated)
+        let name = dir_name(&href);
+
+        // TODO re-introduce count
+        // debug!("Got directory ({:4}): {}", nodes.len(), &name);
+        debug!("Got directory: {}", &name);
+
+        Some(Node::PendingDir(DirLinkMetaData {
+            url: href.to_string(),
+            name,
+            last_modified,
+            description,
+            depth: 0,
+        }))
+    } else {
+        clean_url(&mut href)?;
+
+        // Name the file like its local copy (the link text may be truncated or encoded differently)
+        let name = href
+            .path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .filter(|segment| !segment.is_empty())
+            .map_or(name, decode_filename);
+
+        // TODO re-introduce count
+        // debug!("Got file ({:4}): {}", nodes.len(), &name);
+        debug!("Got file: {} ({})", &name, &href);
+
+        Some(Node::File(FileLinkMetaData {
+            url: href.to_string(),
+            name,
+            last_modified,
+            size_bytes: parse_size(&size),
+            size,
+            description,
+            depth: 0,
+        }))
+    }
+}
+
+/**
+Checks if a link points to the directory itself or to one of its ancestors
+*/
+fn is_parent_or_self(href: &Url, base_url: &Url) -> bool {
+    href.origin() == base_url.origin()
+        && href.path().ends_with('/')
+        && base_url.path().starts_with(href.path())
+}
+
+/**
+A backend for Apache-style HTML listings served via HTTP(S)
+*/
+pub struct ApacheHttpBackend {
+    client: reqwest::Client,
+    read_timeout: Duration,
+
+    /// The client for downloading files (which may keep compressed responses as they are)
+    file_client: reqwest::Client,
+
+    /// The ways of finding the next page of paginated listings
+    paginations: Vec<Box<dyn Pagination>>,
+
+    /// The User-Agents to take turns with (instead of the one of the clients)
+    user_agents: Option<UserAgentPool>,
+
+    /// How the listings are laid out
+    format: ServerFormat,
+
+    /// The URLs the redirected listings were served from (by the URL they were asked for)
+    redirects: Mutex<HashMap<String, Url>>,
+}
+
+impl ApacheHttpBackend {
+    pub fn new(client: reqwest::Client, read_timeout: Duration) -> Self {
+        Self {
+            file_client: client.clone(),
+            client,
+            read_timeout,
+            paginations: default_paginations(),
+            user_agents: None,
+            format: ServerFormat::default(),
+            redirects: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Parses the listings according to another format (see `ServerFormat`)
+    pub fn with_format(mut self, format: ServerFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Sends each request with the next User-Agent of a pool
+    pub fn with_user_agents(mut self, user_agents: UserAgentPool) -> Self {
+        self.user_agents = Some(user_agents);
+        self
+    }
+
+    /// Applies the next User-Agent of the pool to a request (if there is a pool)
+    fn user_agent(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.user_agents {
+            Some(user_agents) => request.header(USER_AGENT, user_agents.next()),
+            None => request,
+        }
+    }
+
+    /// Uses another client for downloading files (e.g. one which doesn't decompress responses)
+    pub fn with_file_client(mut self, file_client: reqwest::Client) -> Self {
+        self.file_client = file_client;
+        self
+    }
+
+    /// Replaces the ways of finding the next page of paginated listings
+    pub fn with_paginations(mut self, paginations: Vec<Box<dyn Pagination>>) -> Self {
+        self.paginations = paginations;
+        self
+    }
+
+    /**
+    Lists a single page of a listing, without following anything (see `--test-url`)
+
+    Returns the name of the directory (as parsed from the page) and its nodes.
+    */
+    pub async fn test_page(&self, url: &Url) -> Result<(String, Vec<Node>)> {
+        let (name, nodes, _) = self.list_page(url).await?;
+        Ok((name, nodes))
+    }
+
+    /// Lists a single page of a listing and returns its name, its nodes and the URL of the next page
+    async fn list_page(&self, url: &Url) -> Result<(String, Vec<Node>, Option<Url>)> {
+        // Get the HTML from the server
+        let request = self.user_agent(self.client.get(url.as_str()).header(ACCEPT, LISTING_ACCEPT));
+        let mut res = match with_timeout(self.read_timeout, request.send()).await {
+            Ok(res) => res,
+            // A directory stuck in redirects may be skipped like one which isn't a listing
+            Err(error) => match redirect_loop(&error) {
+                Some(redirects) => {
+                    let message = format!("{} cannot be listed: {}", url, redirects);
+                    return Err(NotAListing::new(url, message).into());
+                }
+                None => {
+                    return Err(error)
+                        .with_context(|| format!("Cannot fetch {} (no response)", url))
+                }
+            },
+        };
+
+        // Resolve relative links against the final URL (after redirects)
+        let final_url = res.url().clone();
+        report_redirect(url, &final_url);
+        if final_url != *url {
+            self.redirects
+                .lock()
+                .unwrap()
+                .insert(url.to_string(), final_url.clone());
+        }
+
+        // Don't mistake error pages for listings
+        check_status(url, &res)?;
+
+        // Don't try to parse files as listings
+        let content_type = res
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .map(str::to_owned);
+        let json = match self.format.assumed {
+            Some(assumed) => assumed == ListingFormat::CaddyJson,
+            None => content_type.as_deref().is_some_and(caddy::is_json),
+        };
+        if let Some(content_type) = content_type.as_deref().filter(|ct| !is_html(ct) && !json) {
+            return Err(NotAListing::new(
+                url,
+                format!("{} {} (found {})", url, NOT_A_LISTING, content_type),
+            )
+            .into());
+        }
+
+        // Stop reading at the limit (also when the server doesn't announce the length)
+        let too_large = |limit| ListingTooLarge {
+            url: url.to_string(),
+            limit,
+        };
+        if let (Some(limit), Some(length)) = (self.format.max_listing_size, res.content_length()) {
+            if length > limit {
+                return Err(too_large(limit).into());
+            }
+        }
+
+        let mut body = vec![];
+        while let Some(chunk) = with_timeout(self.read_timeout, res.chunk())
+            .await
+            .with_context(|| format!("Cannot read {} (the response broke off)", url))?
+        {
+            body.extend_from_slice(&chunk);
+            if let Some(limit) = self
+                .format
+                .max_listing_size
+                .filter(|&limit| body.len() as u64 > limit)
+            {
+                return Err(too_large(limit).into());
+            }
+        }
+
+        if body.is_empty() {
+            let message = format!("The server returned an empty response for {}", url);
+            return Err(NotAListing::new(url, message).into());
+        }
+
+        // JSON listings don't need any scraping (and aren't paginated)
+        if json {
+            let nodes = caddy::parse_listing(&body, &final_url).map_err(|error| {
+                if self.format.assumed.is_some() {
+                    debug!("(Format) Cannot parse {} as JSON: {:#}", url, error);
+                    return FormatMismatch {
+                        url: url.to_string(),
+                        format: ListingFormat::CaddyJson,
+                    }
+                    .into();
+                }
+
+                anyhow::Error::from(NotAListing::new(
+                    url,
+                    format!("Cannot parse the JSON listing {}: {:#}", url, error),
+                ))
+            })?;
+
+            return Ok((dir_name(&final_url), nodes, None));
+        }
+
+        // Sanitize the HTML (using the charset announced by the server)
+        let charset = get_charset(&res);
+        let html = sanitize_html(&body, charset.as_deref())?;
+
+        // Parse the response
+        let (name, nodes) = self.parse_html(&html, url, &final_url, content_type.as_deref())?;
+
+        Ok((name, nodes, self.next_page(&html, &final_url)))
+    }
+
+    /**
+    Parses a saved listing as if it were the one at the URL, without fetching anything (see
+    `--from-html`)
+
+    Relative links are resolved against the URL. Returns the name of the directory (as parsed from
+    the page) and its nodes.
+    */
+    pub fn parse_saved_page(&self, body: &[u8], url: &Url) -> Result<(String, Vec<Node>)> {
+        let html = sanitize_html(body, None)?;
+
+        self.parse_html(&html, url, url, Some("text/html"))
+    }
+
+    /// Parses the HTML of a listing (fetched from the URL, which ended up at the final URL)
+    fn parse_html(
+        &self,
+        html: &str,
+        url: &Url,
+        final_url: &Url,
+        content_type: Option<&str>,
+    ) -> Result<(String, Vec<Node>)> {
+        match cheap_extract_from_html(html, final_url, &self.format) {
+            Ok(result) => Ok(result),
+            Err(error) if error.is::<UnparsedRows>() || error.is::<FormatMismatch>() => Err(error),
+            Err(_) if is_login_page(html) => bail!(
+                "{} looks like a login page rather than a listing (authenticate with --cookie or --cookies-file)",
+                final_url
+            ),
+            Err(_) => Err(NotAListing::new(
+                url,
+                format!(
+                    "{} {} (found {})",
+                    url,
+                    NOT_A_LISTING,
+                    describe_page(html, content_type)
+                ),
+            )
+            .into()),
+        }
+    }
+
+    /// Finds the link to the next page of a listing (if it's paginated)
+    fn next_page(&self, html: &str, url: &Url) -> Option<Url> {
+        let document = Html::parse_document(html);
+
+        self.paginations
+            .iter()
+            .find_map(|pagination| pagination.next_page(&document, url))
+    }
+}
+
+#[async_trait]
+impl DirectoryListing for ApacheHttpBackend {
+    async fn list(&self, url: &Url) -> Result<Vec<Node>> {
+        let (_, mut nodes, mut next) = self.list_page(url).await?;
+
+        // Follow the pages of paginated listings (each one only once, so loops terminate)
+        let mut pages = HashSet::new();
+        pages.insert(url.to_string());
+
+        let mut known: HashSet<String> = nodes.iter().map(|node| node.url().to_owned()).collect();
+
+        while let Some(page) = next.take() {
+            if page.origin() != url.origin() || !pages.insert(page.to_string()) {
+                break;
+            }
+
+            debug!("(Pagination) Next page {}", page);
+            let (_, page_nodes, page_next) = self.list_page(&page).await?;
+
+            // Pages may overlap, so keep every entry only once
+            for node in page_nodes {
+                if known.insert(node.url().to_owned()) {
+                    nodes.push(node);
+                }
+            }
+            next = page_next;
+        }
+
+        Ok(nodes)
+    }
+
+    fn final_url(&self, url: &Url) -> Option<Url> {
+        self.redirects.lock().unwrap().get(url.as_str()).cloned()
+    }
+
+    async fn fetch(
+        &self,
+        file: &FileLinkMetaData,
+        validators: Option<&Validators>,
+        writer: &mut (dyn AsyncWrite + Unpin + Send),
+        observer: &dyn DownloadObserver,
+    ) -> Result<Fetched> {
+        // Request the file from the server (only if it changed, given the earlier validators)
+        let url = Url::from_str(&file.url).with_context(|| format!("Invalid URL {}", file.url))?;
+        let mut request = self.user_agent(self.file_client.get(url.as_str()));
+        if let Some(validators) = validators {
+            if let Some(etag) = &validators.etag {
+                request = request.header(IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &validators.last_modified {
+                request = request.header(IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+        let mut res = with_timeout(self.read_timeout, request.send())
+            .await
+            .with_context(|| format!("Cannot fetch {} (no response)", url))?;
+        report_redirect(&url, res.url());
+
+        if res.status() == StatusCode::NOT_MODIFIED {
+            return Ok(Fetched::NotModified);
+        }
+
+        // Don't save error pages as files
+        check_file_status(&url, &res)?;
+        let validators = get_validators(res.headers());
+
+        // Prefer the length announced by the server over the one from the listing
+        observer.on_file_start(file, res.content_length().or(file.size_bytes));
+
+        // Write the file in chunks as they arrive from the network
+        while let Some(chunk) = with_timeout(self.read_timeout, res.chunk())
+            .await
+            .with_context(|| format!("Cannot read {} (the response broke off)", url))?
+        {
+            writer
+                .write_all(&chunk)
+                .await
+                .with_context(|| format!("Cannot write the download of {}", url))?;
+            observer.on_progress(file, chunk.len() as u64);
+        }
+
+        Ok(Fetched::Modified(validators))
+    }
+
+    async fn file_size(&self, file: &FileLinkMetaData) -> Result<Option<u64>> {
+        // Not `content_length()`, as that is the length of the (empty) body of a HEAD response
+        Ok(self
+            .head(file)
+            .await?
+            .as_ref()
+            .and_then(|headers| headers.get(CONTENT_LENGTH))
+            .and_then(|length| length.to_str().ok())
+            .and_then(|length| length.parse().ok()))
+    }
+
+    async fn fetch_range(
+        &self,
+        file: &FileLinkMetaData,
+        range: ByteRange,
+        writer: &mut (dyn AsyncWrite + Unpin + Send),
+    ) -> Result<u64> {
+        let url = Url::from_str(&file.url).with_context(|| format!("Invalid URL {}", file.url))?;
+
+        fetch_range(&self.file_client, &url, range, self.read_timeout, writer).await
+    }
+
+    async fn content_type(&self, file: &FileLinkMetaData) -> Result<Option<String>> {
+        Ok(self
+            .head(file)
+            .await?
+            .as_ref()
+            .and_then(|headers| headers.get(CONTENT_TYPE))
+            .and_then(|content_type| content_type.to_str().ok())
+            .map(str::to_owned))
+    }
+}
+
+impl ApacheHttpBackend {
+    /**
+    Requests the headers of a file (None if the server doesn't support HEAD requests)
+
+    Files which don't exist (anymore) are an error (see `is_gone`).
+    */
+    async fn head(&self, file: &FileLinkMetaData) -> Result<Option<HeaderMap>> {
+        let url = Url::from_str(&file.url).with_context(|| format!("Invalid URL {}", file.url))?;
+        let request = self.user_agent(self.file_client.head(url.as_str())).send();
+        let res = with_timeout(self.read_timeout, request)
+            .await
+            .with_context(|| format!("Cannot fetch the headers of {} (no response)", file.url))?;
+
+        if matches!(res.status(), StatusCode::NOT_FOUND | StatusCode::GONE) {
+            check_file_status(&url, &res)?;
+        }
+
+        // Servers which don't support HEAD answer with an error (e.g. 405 Method Not Allowed)
+        if !res.status().is_success() {
+            debug!("(Head) No headers of {} ({})", file.url, res.status());
+            return Ok(None);
+        }
+
+        Ok(Some(res.headers().clone()))
+    }
+}
+
+/// Reads the cache validators from the headers of a response
+fn get_validators(headers: &HeaderMap) -> Validators {
+    let header = |name| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_owned)
+    };
+
+    Validators {
+        etag: header(ETAG),
+        last_modified: header(LAST_MODIFIED),
+    }
+}
+
+/**
+Returns the policy for following redirects
+
+Redirects get followed like by reqwest's default policy, but with `cross_host` disabled (see
+`--no-cross-host`), redirects leaving the original host fail instead. Loops (and too many
+redirects) fail right away with a `RedirectLoop` naming the URLs involved.
+*/
+pub fn redirect_policy(cross_host: bool) -> Policy {
+    Policy::custom(move |attempt| {
+        let leaves_host = attempt
+            .previous()
+            .first()
+            .is_some_and(|first| first.host_str() != attempt.url().host_str());
+        let looped_from = attempt
+            .previous()
+            .iter()
+            .position(|previous| previous == attempt.url());
+
+        if leaves_host && !cross_host {
+            let message = format!(
+                "Not following the redirect to another host ({})",
+                attempt.url()
+            );
+            attempt.error(message)
+        } else if looped_from.is_some() || attempt.previous().len() >= MAX_REDIRECTS {
+            let urls = attempt.previous()[looped_from.unwrap_or_default()..]
+                .iter()
+                .chain(Some(attempt.url()))
+                .map(Url::to_string)
+                .collect();
+            let looped = looped_from.is_some();
+
+            attempt.error(RedirectLoop { urls, looped })
+        } else {
+            attempt.follow()
+        }
+    })
+}
+
+/// Logs a redirect (if any) of a request, warning about ones leaving the original host
+fn report_redirect(url: &Url, final_url: &Url) {
+    if url == final_url {
+        return;
+    }
+
+    if url.host_str() != final_url.host_str() {
+        warn!(
+            "(Redirect) {} redirects to another host: {}",
+            url, final_url
+        );
+    } else {
+        debug!("(Redirect) {} redirects to {}", url, final_url);
+    }
+}
+
+/// Checks if a Content-Type denotes an HTML document
+fn is_html(content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap_or_default().trim();
+
+    mime.eq_ignore_ascii_case("text/html") || mime.eq_ignore_ascii_case("application/xhtml+xml")
+}
+
+/// Checks if an HTML page (which isn't a listing) contains a login form
+fn is_login_page(html: &str) -> bool {
+    Html::parse_document(html)
+        .select(&selectors::PASSWORD_FIELD)
+        .next()
+        .is_some()
+}
+
+/// Describes an HTML page which isn't a listing (by its Content-Type and title)
+fn describe_page(html: &str, content_type: Option<&str>) -> String {
+    let title = Html::parse_document(html)
+        .select(&selectors::PAGE_TITLE)
+        .map(|title| get_text(&title))
+        .find(|title| !title.is_empty());
+
+    let kind = content_type.unwrap_or("a page without a Content-Type");
+    match title {
+        Some(title) => format!("{} titled \"{}\"", kind, title),
+        None => format!("{} without a title", kind),
+    }
+}
+
+/**
+Checks the status of the response to a listing request
+*/
+fn check_status(url: &Url, res: &reqwest::Response) -> Result<()> {
+    match res.status() {
+        StatusCode::OK => Ok(()),
+        _ => status_error(url, res, "list"),
+    }
+}
+
+/**
+Checks the status of the response to a file request
+*/
+fn check_file_status(url: &Url, res: &reqwest::Response) -> Result<()> {
+    if res.status().is_success() {
+        Ok(())
+    } else {
+        status_error(url, res, "download")
+    }
+}
+
+/// Describes an unexpected status (always naming the URL and the status code)
+fn status_error(url: &Url, res: &reqwest::Response, action: &str) -> Result<()> {
+    // Let the request be tried again later if the server only asks to slow down
+    if let Some(throttled) = Throttled::from_response(url.as_str(), res) {
+        return Err(throttled.into());
+    }
+
+    let status = res.status();
+    let message = match status {
+        StatusCode::NOT_FOUND => format!("{} does not exist ({})", url, status),
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+            format!("Access to {} was denied ({})", url, status)
+        }
+        _ => format!(
+            "Cannot {} {}: the server responded with {}",
+            action, url, status
+        ),
+    };
+
+    Err(ErrorStatus {
+        url: url.to_string(),
+        status,
+        message,
+    }
+    .into())
+}
+
+/**
+Downloads a range of bytes of a single file (see `--range`) and returns the number of bytes
+
+Servers which ignore the Range header (and would send the whole file) are reported as an error.
+*/
+pub async fn fetch_range(
+    client: &reqwest::Client,
+    url: &Url,
+    range: ByteRange,
+    read_timeout: Duration,
+    writer: &mut (dyn AsyncWrite + Unpin + Send),
+) -> Result<u64> {
+    let request = client.get(url.as_str()).header(RANGE, range.header_value());
+    let mut res = with_timeout(read_timeout, request.send())
+        .await
+        .with_context(|| format!("Cannot fetch {} (no response)", url))?;
+
+    match res.status() {
+        StatusCode::PARTIAL_CONTENT => (),
+        StatusCode::OK => bail!(RangesUnsupported {
+            url: url.to_string()
+        }),
+        StatusCode::RANGE_NOT_SATISFIABLE => bail!(
+            "The range {} is outside of {} ({})",
+            range.header_value(),
+            url,
+            res.status()
+        ),
+        status => bail!(
+            "Cannot download {}: the server responded with {}",
+            url,
+            status
+        ),
+    }
+
+    let mut bytes = 0;
+    while let Some(chunk) = with_timeout(read_timeout, res.chunk())
+        .await
+        .with_context(|| format!("Cannot read {} (the response broke off)", url))?
+    {
+        writer.write_all(&chunk).await?;
+        bytes += chunk.len() as u64;
+    }
+
+    Ok(bytes)
+}
+
+/**
+Downloads a single file (see `--stdout`) and returns the number of bytes
+
+The body is passed on to the writer as it arrives. Only HTML responses are held back until they
+are complete, as a URL which turns out to be a listing is reported as an error (without writing
+anything).
+*/
+pub async fn fetch_file_to(
+    client: &reqwest::Client,
+    url: &Url,
+    read_timeout: Duration,
+    writer: &mut (dyn AsyncWrite + Unpin + Send),
+) -> Result<u64> {
+    let mut res = with_timeout(read_timeout, client.get(url.as_str()).send())
+        .await
+        .with_context(|| format!("Cannot fetch {} (no response)", url))?;
+    check_file_status(url, &res)?;
+
+    let html = res
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(is_html);
+    let final_url = res.url().clone();
+
+    let mut body = vec![];
+    let mut bytes = 0;
+    while let Some(chunk) = with_timeout(read_timeout, res.chunk())
+        .await
+        .with_context(|| format!("Cannot read {} (the response broke off)", url))?
+    {
+        if html {
+            body.extend_from_slice(&chunk);
+        } else {
+            writer
+                .write_all(&chunk)
+                .await
+                .with_context(|| format!("Cannot write {}", url))?;
+        }
+        bytes += chunk.len() as u64;
+    }
+
+    if html {
+        let html = String::from_utf8_lossy(&body);
+        if cheap_extract_from_html(&html, &final_url, &ServerFormat::default()).is_ok() {
+            bail!(
+                "{} is a directory listing (--stdout only downloads single files)",
+                url
+            );
+        }
+
+        writer
+            .write_all(&body)
+            .await
+            .with_context(|| format!("Cannot write {}", url))?;
+    }
 
-pub const POS_HREF: usize = 1;
-pub const POS_NAME: usize = 2;
-pub const POS_DATE: usize = 3;
-pub const POS_SIZE: usize = 4;
-pub const POS_DESC: usize = 5;
+    Ok(bytes)
+}
 
-lazy_static! {
-    /// This is an example for using doc comment attributes
-    static ref RX_MAIN: Regex = Regex::new(
-        "</td><td><a href=\"(.+?)\">(.+?)</a></td><td align=\"right\">(.+?)  </td><td align=\"right\">(.+?)</td><td>(.+?)</td></tr>"
-    ).unwrap();
+/**
+Lists the root URL and starts a crawl from it (see `crawl_frontier`)
 
-    /// This is an example for using doc comment attributes
-    static ref RX_PARENT: Regex = Regex::new(
-        "</td><td><a href=\"/(.+?)/\">Parent Directory</a>       </td><td> </td><td align=\"right\">  - </td><td> </td></tr>"
-    ).unwrap();
+The observer gets told about the files & directories of the root.
+*/
+pub async fn start_crawl(
+    url: &Url,
+    backend: &dyn DirectoryListing,
+    excludes: &DirExcludes,
+    budget: &mut CrawlBudget,
+    observer: &dyn DownloadObserver,
+) -> Result<PartialCrawl> {
+    budget.take_dir(url.as_str());
+    let mut root = get_root_dir(url, backend).await?;
+
+    if let Node::CrawledDir(_, ref mut children) = root {
+        budget.add_nodes(children);
+
+        // Drop links back to the root (and the excluded directories)
+        let mut visited = Visited::new(true);
+        visited.insert(url.to_string());
+        visited.insert(canonical_dir_url(url));
+        children.retain(|child| !is_skipped(child, &mut visited, excludes));
+        children
+            .iter()
+            .for_each(|child| observer.on_discovered(child));
+    } else {
+        bail!("Cannot expand root node")
+    }
 
-    /// This is an example for using doc comment attributes
-    static ref RX_TITLE: Regex = Regex::new("<h1>Index of (.+?)</h1>").unwrap();
+    Ok(PartialCrawl::new(root))
 }
 
 /**
-Returns the first match in a string with a given Regex pattern
+Crawls the directories of a partial crawl (until the frontier is empty or the budget is used up)
+
+The tree is only changed once a directory of the frontier is crawled completely, so a crawl which
+gets interrupted (by dropping the future) can be resumed from the partial crawl as it is. The
+directories cut off by the budget are added to the frontier. The observer gets told about every
+file & directory as soon as it's found. Unless `follow_aliases` is set, directories with the same
+files as one crawled before aren't crawled any further (see `Visited`).
 */
-fn get_first<'a>(text: &'a str, regex: &Regex) -> Result<&'a str> {
-    Ok(regex
-        .captures(text)
-        .ok_or(anyhow!(CANNOT_PARSE_DIRECTORY))?
-        .get(1)
-        .ok_or(anyhow!(CANNOT_PARSE_DIRECTORY))?
-        .as_str())
+pub async fn crawl_frontier(
+    partial: &mut PartialCrawl,
+    backend: &dyn DirectoryListing,
+    excludes: &DirExcludes,
+    continue_on_parse_error: bool,
+    follow_aliases: bool,
+    budget: &mut CrawlBudget,
+    observer: &dyn DownloadObserver,
+) -> Result<()> {
+    // Remember the directories outside the frontier, so links back to them (and aliases of them)
+    // don't get crawled again
+    let mut visited = Visited::new(follow_aliases);
+    for node in partial.root.iter() {
+        match node {
+            Node::CrawledDir(dir, children) => {
+                visited.insert(dir.url.clone());
+                visited.alias_of(&dir.url, children);
+            }
+            Node::PendingDir(dir) if !partial.frontier.contains(&dir.url) => {
+                visited.insert(dir.url.clone());
+            }
+            _ => (),
+        }
+    }
+    if let Ok(url) = Url::parse(partial.root.url()) {
+        visited.insert(canonical_dir_url(&url));
+    }
+
+    while !budget.is_exhausted() {
+        let dir = match partial.next_dir() {
+            Some(dir) => dir,
+            None => break,
+        };
+
+        // A directory of the frontier may have been crawled from elsewhere in the meantime (or
+        // be excluded since)
+        let mut nodes = vec![Node::PendingDir(dir)];
+        nodes.retain(|node| !is_skipped(node, &mut visited, excludes));
+
+        expand_node(
+            &mut nodes,
+            backend,
+            &mut visited,
+            excludes,
+            continue_on_parse_error,
+            budget,
+            observer,
+        )
+        .await?;
+
+        partial.advance(nodes.pop(), budget.take_left_out());
+        partial.truncated |= budget.cut_off_files > 0;
+    }
+    partial.truncated |= budget.cut_off_files > 0;
+    budget.report();
+
+    let unparsed = partial.unparsed_dirs().len();
+    if unparsed > 0 {
+        warn!(
+            "Couldn't parse {} directories (left out of the tree)",
+            unparsed
+        );
+    }
+
+    Ok(())
 }
 
 /**
-Parses a given HTML-string and extracts the directory and file paths.
+Expand all PengingDir nodes (recursively)
 
--  Not recursive
--  Does not make requests
+Directories whose URL is already contained in `visited` get dropped from the tree, so cycles
+(e.g. symlinked or `../` loops) terminate. Directories listing the same files as one visited
+before are kept without children (unless aliases are followed, see `Visited`). With
+`continue_on_parse_error`, directories which aren't listings stay PendingDir nodes instead of
+aborting the crawl. So do the directories beyond the `budget` (see `CrawlBudget::take_left_out`).
+Directories matching the `excludes` are dropped before they get listed.
+*/
+pub async fn expand_node(
+    nodes: &mut [Node],
+    backend: &dyn DirectoryListing,
+    visited: &mut Visited,
+    excludes: &DirExcludes,
+    continue_on_parse_error: bool,
+    budget: &mut CrawlBudget,
+    observer: &dyn DownloadObserver,
+) -> Result<()> {
+    for node in nodes.iter_mut() {
+        // Only crawl if needed
+        if let Node::PendingDir(dir) = node {
+            if !budget.take_dir(&dir.url) {
+                continue;
+            }
+
+            debug!("Now crawling: {} (depth {})", dir.name, dir.depth);
+            let url = Url::from_str(&dir.url)?;
+
+            // Get the listing from the server
+            let mut children = match backend.list(&url).await {
+                Ok(children) => children,
+                Err(error) if continue_on_parse_error && not_a_listing(&error).is_some() => {
+                    warn!("(Unparseable) Skip directory: {:#}", error);
+                    continue;
+                }
+                Err(error) => return Err(error),
+            };
+
+            // Don't crawl the same directory twice under different paths (e.g. symlinks)
+            if let Some(original) = visited.alias_of(&dir.url, &children) {
+                info!(
+                    "(Alias) Skip directory {} (same files as {})",
+                    dir.url, original
+                );
+                *node = Node::CrawledDir(crawled_meta(dir, &url), vec![]);
+                continue;
+            }
+
+            for child in &mut children {
+                child.set_depth(dir.depth + 1);
+            }
+            budget.add_nodes(&mut children);
+
+            // Drop directories which have already been crawled (or are excluded)
+            children.retain(|child| !is_skipped(child, visited, excludes));
+            children
+                .iter()
+                .for_each(|child| observer.on_discovered(child));
 
-Returns a tuple containing the extracted name and the vector of extracted nodes.
+            // Expand the sub-directories
+            Box::pin(expand_node(
+                &mut children,
+                backend,
+                visited,
+                excludes,
+                continue_on_parse_error,
+                budget,
+                observer,
+            ))
+            .await?;
+
+            // Replace the PendingDir node with a CrawledDir one
+            *node = Node::CrawledDir(crawled_meta(dir, &url), children);
+        }
+    }
+
+    Ok(())
+}
+
+/**
+Lists the root URL and returns a node
 */
-pub fn cheap_extract_from_html(html: &str, base_url: &Url) -> Result<(String, Vec<Node>)> {
-    let dir_name = get_first(html, &RX_TITLE)?;
+pub async fn get_root_dir(url: &Url, backend: &dyn DirectoryListing) -> Result<Node> {
+    info!("Crawling root URL");
 
-    // TODO maybe use the parent_href in the future
-    // let parent_href = get_first(html, &RX_PARENT)?;
+    let children = backend.list(url).await?;
 
-    // Split the string into lines
-    let nodes = html
-        .par_lines()
-        .filter_map(cheap_process_row(base_url))
-        .collect();
+    let mut root = Node::CrawledDir(root_meta(url), children);
+    root.set_depth(0);
 
-    Ok((dir_name.to_owned(), nodes))
+    Ok(root)
 }
 
 /**
-Turns an ElementRef (of a HTML table-row into a node (Either PendingDir or File)
+Crawls the tree below the root URL and yields the nodes as they are discovered
+
+Each crawled directory is yielded as a CrawledDir node (without children), followed by the File
+nodes it contains. Directories are crawled breadth-first, so callers can start working on the
+first files long before the whole tree is known. A directory which can't be listed yields an
+error, but the crawl continues with the remaining directories. Aliases of a directory crawled
+before are yielded without their files (unless `follow_aliases` is set, see `Visited`).
 */
-pub fn cheap_process_row<'a>(
-    base_url: &'a Url,
-) -> Box<dyn Fn(&str) -> Option<Node> + Send + Sync + 'a> {
-    Box::new(move |line| {
-        let captures = RX_MAIN.captures(line)?;
-
-        // Calculate the absolute href using the base_url
-        let mut href = base_url
-            .join(captures.get(POS_HREF)?.as_str())
-            .to_owned()
-            .ok()?;
-
-        // The other values get extracted using the regex
-        let name = captures.get(POS_NAME)?.as_str().to_owned();
-        let last_modified = captures.get(POS_DATE)?.as_str().to_owned();
-        let size = captures.get(POS_SIZE)?.as_str().to_owned();
-        let description = captures.get(POS_DESC)?.as_str().to_owned();
-
-        // Check if the result is a directory (by examining its stated size)
-        if captures.get(POS_SIZE)?.as_str() == EMPTY_SIZE_STRING {
-            // TODO re-introduce count
-            // println!("Got directory ({:4}): {}", nodes.len(), &name);
-            println!("Got directory: {}", &name);
-
-            Some(Node::PendingDir(DirLinkMetaData {
-                url: href.to_string(),
-                name,
-                last_modified,
-                description,
-            }))
-        } else {
-            clean_url(&mut href);
+pub fn crawl_stream<'a>(
+    url: &Url,
+    backend: &'a dyn DirectoryListing,
+    excludes: DirExcludes,
+    follow_aliases: bool,
+    budget: CrawlBudget,
+    observer: &'a dyn DownloadObserver,
+) -> impl Stream<Item = Result<Node>> + 'a {
+    struct State<'a> {
+        backend: &'a dyn DirectoryListing,
+        observer: &'a dyn DownloadObserver,
+        directories: VecDeque<DirLinkMetaData>,
+        ready: VecDeque<Result<Node>>,
+        visited: Visited,
+        excludes: DirExcludes,
+        budget: CrawlBudget,
+    }
+
+    let mut visited = Visited::new(follow_aliases);
+    visited.insert(url.to_string());
+    visited.insert(canonical_dir_url(url));
+
+    let state = State {
+        backend,
+        observer,
+        directories: VecDeque::from([root_meta(url)]),
+        ready: VecDeque::new(),
+        visited,
+        excludes,
+        budget,
+    };
+
+    stream::unfold(state, |mut state| async move {
+        loop {
+            if let Some(item) = state.ready.pop_front() {
+                return Some((item, state));
+            }
+
+            // Crawl the next directory (or end the stream if there is none)
+            let dir = match state.directories.pop_front() {
+                Some(dir) => dir,
+                None => {
+                    state.budget.report();
+                    return None;
+                }
+            };
+            if !state.budget.take_dir(&dir.url) {
+                continue;
+            }
+            debug!("Now crawling: {} (depth {})", dir.name, dir.depth);
+
+            let url = match Url::from_str(&dir.url) {
+                Ok(url) => url,
+                Err(err) => return Some((Err(err.into()), state)),
+            };
+
+            let mut children = match state.backend.list(&url).await {
+                Ok(children) => children,
+                Err(err) => return Some((Err(err), state)),
+            };
+
+            state
+                .ready
+                .push_back(Ok(Node::CrawledDir(crawled_meta(&dir, &url), vec![])));
 
-            // TODO re-introduce count
-            // println!("Got file ({:4}): {}", nodes.len(), &name);
-            println!("Got file: {}", &name);
-            println!("{}\n", &href);
+            // Don't crawl the same directory twice under different paths (e.g. symlinks)
+            if let Some(original) = state.visited.alias_of(&dir.url, &children) {
+                info!(
+                    "(Alias) Skip directory {} (same files as {})",
+                    dir.url, original
+                );
+                continue;
+            }
+            state.budget.add_nodes(&mut children);
 
-            Some(Node::File(FileLinkMetaData {
-                url: href.to_string(),
-                name,
-                last_modified,
-                size,
-                description,
-            }))
+            for mut child in children {
+                child.set_depth(dir.depth + 1);
+                if is_skipped(&child, &mut state.visited, &state.excludes) {
+                    continue;
+                }
+                state.observer.on_discovered(&child);
+
+                match child {
+                    Node::PendingDir(dir) => state.directories.push_back(dir),
+                    node => state.ready.push_back(Ok(node)),
+                }
+            }
         }
     })
-
-    // unimplemented!()
 }
 
 /**
-Expand all PengingDir nodes
+Crawls the tree below a URL, only counting its files, directories & bytes (see `--count-only`)
+
+Several listings are fetched at once, and the nodes are dropped as soon as they're counted, so
+the tree never has to fit into memory. Directories which aren't listings abort the count, unless
+they should be skipped (`continue_on_parse_error`).
 */
-pub async fn expand_node(nodes: &mut Vec<Node>, client: &reqwest::Client) -> Result<()> {
-    for node in nodes {
-        // Only crawl if needed
-        if let Node::PendingDir(dir) = node {
-            println!("Now crawling: {}", dir.name);
-            let req = client.get(&dir.url).send();
+pub async fn count_tree(
+    url: &Url,
+    backend: &dyn DirectoryListing,
+    excludes: &DirExcludes,
+    continue_on_parse_error: bool,
+    follow_aliases: bool,
+) -> Result<TreeSummary> {
+    let mut visited = Visited::new(follow_aliases);
+    visited.insert(url.to_string());
+    visited.insert(canonical_dir_url(url));
+
+    let mut summary = TreeSummary {
+        directories: 1,
+        ..TreeSummary::default()
+    };
+    let mut directories = VecDeque::from([root_meta(url)]);
+    let mut listings = FuturesUnordered::new();
 
-            // Get the HTML from the server
-            let html = match req.await {
-                Ok(res) => sanitize_html(&res.text().await.expect(EMPTY_RESPONSE))?,
-                Err(err) => bail!(err),
+    loop {
+        // Keep the next directories being listed
+        while listings.len() < COUNT_CONCURRENCY {
+            let dir = match directories.pop_front() {
+                Some(dir) => dir,
+                None => break,
             };
+            debug!("Now counting: {} (depth {})", dir.name, dir.depth);
 
-            // Perse the response
-            match cheap_extract_from_html(&html, &Url::from_str(&dir.url)?) {
-                Err(err) => bail!(err),
-                Ok(dir_data) => {
-                    // Replace the PendingDir node with a CrawledDir one
-                    *node = Node::CrawledDir(
-                        DirLinkMetaData {
-                            url: dir.url.clone(), // TODO remove copy
-                            name: dir_data.0,
-                            description: dir.description.clone(), // TODO remove copy
-                            last_modified: dir.last_modified.clone(), // TODO remove copy
-                        },
-                        dir_data.1,
-                    )
+            listings.push(async move {
+                let children = match Url::from_str(&dir.url) {
+                    Ok(url) => backend.list(&url).await,
+                    Err(error) => Err(error.into()),
+                };
+                (dir, children)
+            });
+        }
+
+        // Count the entries of whichever listing arrives first (until none are left)
+        let (dir, children) = match listings.next().await {
+            Some(listing) => listing,
+            None => break,
+        };
+        let children = match children {
+            Ok(children) => children,
+            Err(error) if continue_on_parse_error && not_a_listing(&error).is_some() => {
+                warn!("(Unparseable) Skip directory: {:#}", error);
+                continue;
+            }
+            Err(error) => return Err(error),
+        };
+
+        // Don't count the same directory twice under different paths (e.g. symlinks)
+        if let Some(original) = visited.alias_of(&dir.url, &children) {
+            info!(
+                "(Alias) Skip directory {} (same files as {})",
+                dir.url, original
+            );
+            continue;
+        }
+
+        for mut child in children {
+            child.set_depth(dir.depth + 1);
+            if is_skipped(&child, &mut visited, excludes) {
+                continue;
+            }
+
+            match child {
+                Node::File(file) => {
+                    summary.files += 1;
+                    match file.size_bytes {
+                        Some(size) => summary.total_bytes += size,
+                        None => summary.unknown_sizes += 1,
+                    }
                 }
-            };
+                Node::PendingDir(dir) | Node::CrawledDir(dir, _) => {
+                    summary.directories += 1;
+                    directories.push_back(dir);
+                }
+            }
         }
     }
 
-    Ok(())
+    Ok(summary)
 }
 
 /**
-Extracts the HTML from the root URL and returns a node
+Checks if a node is a directory which is excluded or was visited before (and marks it as visited
+otherwise)
 */
-pub async fn get_root_dir(url: &Url, client: &reqwest::Client) -> Result<Node> {
-    println!("Fetching root HTML");
+fn is_skipped(node: &Node, visited: &mut Visited, excludes: &DirExcludes) -> bool {
+    if excludes.is_excluded(node) {
+        debug!("(Excluded) Skip directory {}", node.url());
+        return true;
+    }
 
-    let res = client
-        .get(url.as_str())
-        .send()
-        .await
-        .unwrap()
-        .text()
-        .await
-        .unwrap();
+    is_visited(node, visited)
+}
 
-    // Sanitize the HTML
-    let html = sanitize_html(&res)?;
+/**
+Checks if a node is a directory which was visited before (and marks it as visited otherwise)
+*/
+fn is_visited(node: &Node, visited: &mut Visited) -> bool {
+    match node {
+        Node::PendingDir(dir) if !visited.insert(dir.url.clone()) => {
+            debug!("(Visited) Skip directory {}", dir.url);
+            true
+        }
+        _ => false,
+    }
+}
 
-    println!("Crawling root URL");
+/**
+The directories seen during a crawl
 
-    let root_data = cheap_extract_from_html(&html, url)?;
+Directories are known by their URL, and by the files they contain. Some servers expose the same
+directory under several paths (e.g. a symlink pointing back into the tree), which plain URLs
+can't tell apart. So a directory listing the same files (by name, size & date) as one seen before
+is taken as an alias of it, unless aliases are followed (see `--follow-symlinks`). Directories
+without files have nothing to be told apart by, so they are never taken as aliases.
+*/
+#[derive(Debug)]
+pub struct Visited {
+    urls: HashSet<String>,
 
-    Ok(Node::CrawledDir(
-        DirLinkMetaData {
-            url: url.to_string(),
-            name: root_data.0,
-            description: String::new(),
-            last_modified: String::new(),
-        },
-        root_data.1,
-    ))
+    /// The URL of the first directory with each set of files (None if aliases are followed)
+    listings: Option<HashMap<u64, String>>,
+}
+
+impl Visited {
+    pub fn new(follow_aliases: bool) -> Self {
+        Self {
+            urls: HashSet::new(),
+            listings: (!follow_aliases).then(HashMap::new),
+        }
+    }
+
+    /// Marks a URL as visited, returning whether it wasn't before
+    pub fn insert(&mut self, url: String) -> bool {
+        self.urls.insert(url)
+    }
+
+    /**
+    Remembers the files of a directory, returning the URL of an earlier directory with the same
+    files (if the directory is an alias of it)
+    */
+    pub fn alias_of(&mut self, url: &str, children: &[Node]) -> Option<String> {
+        let listings = self.listings.as_mut()?;
+        let fingerprint = listing_fingerprint(children)?;
+
+        match listings.get(&fingerprint) {
+            Some(original) if original != url => Some(original.clone()),
+            Some(_) => None,
+            None => {
+                listings.insert(fingerprint, url.to_owned());
+                None
+            }
+        }
+    }
+}
+
+/// Hashes the names, sizes & dates of the files of a listing (None if there are no files)
+fn listing_fingerprint(children: &[Node]) -> Option<u64> {
+    let mut files: Vec<_> = children
+        .iter()
+        .filter_map(|child| match child {
+            Node::File(file) => Some((&file.name, &file.size, &file.last_modified)),
+            _ => None,
+        })
+        .collect();
+    if files.is_empty() {
+        return None;
+    }
+    files.sort();
+
+    let mut hasher = DefaultHasher::new();
+    files.hash(&mut hasher);
+
+    Some(hasher.finish())
+}
+
+/**
+Returns the metadata of a directory which wasn't linked from a listing (e.g. the root)
+*/
+pub fn root_meta(url: &Url) -> DirLinkMetaData {
+    DirLinkMetaData {
+        url: url.to_string(),
+        name: dir_name(url),
+        description: String::new(),
+        last_modified: String::new(),
+        depth: 0,
+    }
+}
+
+/**
+Returns the metadata of a crawled directory (based on its link in the parent listing)
+*/
+fn crawled_meta(dir: &DirLinkMetaData, url: &Url) -> DirLinkMetaData {
+    DirLinkMetaData {
+        url: dir.url.clone(),
+        name: dir_name(url),
+        description: dir.description.clone(),
+        last_modified: dir.last_modified.clone(),
+        depth: dir.depth,
+    }
+}
+
+/**
+Returns the name of a directory (its decoded path, like the `Index of` heading)
+
+The segments are decoded like the ones of the local path, so both agree on names which aren't
+UTF-8 (see `decode_filename`).
+*/
+fn dir_name(url: &Url) -> String {
+    let path = url.path_segments().map_or(String::new(), |segments| {
+        segments
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| format!("/{}", decode_filename(segment)))
+            .collect()
+    });
+
+    match path.as_str() {
+        "" => "/".to_owned(),
+        _ => path,
+    }
+}
+
+/**
+Sanitize the HTML (transcode it to UTF-8)
+
+The encoding is taken from the given charset label (e.g. from the `Content-Type` header), or
+detected from the bytes if no (known) charset is given. Undecodable bytes get replaced.
+
+HTML entities are left alone: the parser decodes them per text & attribute, so decoding them
+beforehand would decode them twice (e.g. a file named `x&lt;y` would become `x<y`).
+*/
+pub fn sanitize_html(body: &[u8], charset: Option<&str>) -> Result<String> {
+    let encoding = charset
+        .and_then(|label| Encoding::for_label(label.as_bytes()))
+        .unwrap_or_else(|| detect_encoding(body));
+
+    // Transcode the body to UTF-8 (replacing malformed sequences)
+    let (text, _, had_errors) = encoding.decode(body);
+    if had_errors {
+        warn!(
+            "Listing is not valid {}, some characters were replaced",
+            encoding.name()
+        );
+    }
+
+    Ok(text.into_owned())
+}
+
+/**
+Guesses the encoding of a HTML document without a declared charset
+
+Uses UTF-8 if the bytes are valid UTF-8, the `<meta charset>` of the document if present, and
+falls back to Windows-1252 (as do browsers for legacy documents) otherwise.
+*/
+fn detect_encoding(body: &[u8]) -> &'static Encoding {
+    if std::str::from_utf8(body).is_ok() {
+        return UTF_8;
+    }
+
+    // Look for a charset declaration in the (ASCII-compatible) head of the document
+    let head = String::from_utf8_lossy(&body[..body.len().min(1024)]);
+    RX_META_CHARSET
+        .captures(&head)
+        .and_then(|captures| Encoding::for_label(captures.get(1)?.as_str().as_bytes()))
+        .unwrap_or(WINDOWS_1252)
 }
 
 /**
-Sanitize the HTML (use String::from_utf8 to check for valid UTF-8, the library is unsafe)
+Extracts the charset parameter from the `Content-Type` header of a response
 */
-pub fn sanitize_html(text: &str) -> Result<String> {
-    let mut output = Vec::new();
-    decode_html_entities_to_vec(text, &mut output);
-    Ok(String::from_utf8(output)?)
+fn get_charset(res: &reqwest::Response) -> Option<String> {
+    let content_type = res.headers().get(CONTENT_TYPE)?.to_str().ok()?;
+
+    content_type.split(';').skip(1).find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        if key.trim().eq_ignore_ascii_case("charset") {
+            Some(value.trim().trim_matches('"').to_owned())
+        } else {
+            None
+        }
+    })
+}
+
+/// Makes the URL of a directory end in exactly one slash
+fn canonicalize_dir_url(url: &mut Url) {
+    let path = format!("{}/", url.path().trim_end_matches('/'));
+    url.set_path(&path);
+}
+
+/// Returns the canonical form of a directory URL (see `canonicalize_dir_url`)
+fn canonical_dir_url(url: &Url) -> String {
+    let mut url = url.clone();
+    canonicalize_dir_url(&mut url);
+    url.to_string()
 }
 
-/// Clear a lot of trailing slashes
-fn clean_url(url: &mut Url) -> () {
+/// Clear a lot of trailing slashes (None for URLs without a path, like `mailto:` ones)
+fn clean_url(url: &mut Url) -> Option<()> {
     // TODO Improve this
     url.path_segments_mut()
-        .expect("Cannot use URL")
+        .ok()?
         .pop_if_empty()
         .pop_if_empty()
         .pop_if_empty()
@@ -226,4 +2018,624 @@ fn clean_url(url: &mut Url) -> () {
         .pop_if_empty()
         .pop_if_empty()
         .pop_if_empty();
+
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::{
+        format::DirDetection, listing::MockListing, observer::Observers, types::CrawlingState,
+        units::parse_date,
+    };
+
+    /// Parses a listing as if it were served at the URL
+    fn parse(html: &str, url: &str) -> (String, Vec<Node>) {
+        parse_bytes(html.as_bytes(), url)
+    }
+
+    /// Parses the bytes of a listing as if it were served at the URL (without a charset)
+    fn parse_bytes(body: &[u8], url: &str) -> (String, Vec<Node>) {
+        let backend = ApacheHttpBackend::new(reqwest::Client::new(), Duration::from_secs(1));
+
+        backend
+            .parse_saved_page(body, &Url::parse(url).unwrap())
+            .unwrap()
+    }
+
+    /// Returns the kinds, URLs, names & sizes of the nodes
+    fn describe(nodes: &[Node]) -> Vec<(&'static str, &str, &str, &str)> {
+        nodes
+            .iter()
+            .map(|node| match node {
+                Node::File(file) => ("file", &*file.url, &*file.name, &*file.size),
+                Node::PendingDir(dir) => ("dir", &*dir.url, &*dir.name, ""),
+                Node::CrawledDir(dir, _) => ("crawled", &*dir.url, &*dir.name, ""),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parses_a_fancy_index_with_icons() {
+        let html = r#"<!DOCTYPE HTML PUBLIC "-//W3C//DTD HTML 3.2 Final//EN">
+<html><head><title>Index of /pub/linux</title></head><body>
+<h1>Index of /pub/linux</h1>
+<table>
+   <tr><th valign="top"><img src="/icons/blank.gif" alt="[ICO]"></th><th><a href="?C=N;O=D">Name</a></th><th><a href="?C=M;O=A">Last modified</a></th><th><a href="?C=S;O=A">Size</a></th><th><a href="?C=D;O=A">Description</a></th></tr>
+   <tr><th colspan="5"><hr></th></tr>
+<tr><td valign="top"><img src="/icons/back.gif" alt="[PARENTDIR]"></td><td><a href="/pub/">Parent Directory</a></td><td>&nbsp;</td><td align="right">  - </td><td>&nbsp;</td></tr>
+<tr><td valign="top"><img src="/icons/folder.gif" alt="[DIR]"></td><td><a href="iso/">iso/</a></td><td align="right">2021-03-04 12:00  </td><td align="right">  - </td><td>&nbsp;</td></tr>
+<tr><td valign="top"><img src="/icons/compressed.gif" alt="[   ]"></td><td><a href="kernel-5.10.tar.xz">kernel-5.10.tar.xz</a></td><td align="right">2021-03-04 12:01  </td><td align="right"> 112M</td><td>&nbsp;</td></tr>
+   <tr><th colspan="5"><hr></th></tr>
+</table>
+<address>Apache/2.4.41 (Ubuntu) Server at example.com Port 80</address>
+</body></html>"#;
+
+        let (name, nodes) = parse(html, "http://example.com/pub/linux/");
+
+        assert_eq!(name, "/pub/linux");
+        assert_eq!(
+            describe(&nodes),
+            [
+                (
+                    "dir",
+                    "http://example.com/pub/linux/iso/",
+                    "/pub/linux/iso",
+                    ""
+                ),
+                (
+                    "file",
+                    "http://example.com/pub/linux/kernel-5.10.tar.xz",
+                    "kernel-5.10.tar.xz",
+                    "112M"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_uppercase_tags_and_odd_attributes() {
+        // Old servers shout, quote with single quotes (or not at all) and put attributes anywhere
+        let html = r#"<HTML><HEAD><TITLE>Index of /old</TITLE></HEAD><BODY>
+<H1>Index of /old</H1>
+<TABLE>
+<TR><TD><A HREF='readme.txt' TITLE="Read me">readme.txt</A></TD><TD ALIGN=right>04-Mar-2021 12:00  </TD><TD ALIGN=right>1.2K</TD></TR>
+<TR><TD><A class=dir href=docs/>docs/</A></TD><TD ALIGN=right>04-Mar-2021 12:00  </TD><TD ALIGN=right>-</TD></TR>
+<TR><TD><A HREF="a%20b%26c.txt">a b&amp;c.txt</A></TD><TD ALIGN=right>04-Mar-2021 12:00  </TD><TD ALIGN=right>7</TD></TR>
+</TABLE></BODY></HTML>"#;
+
+        let (name, nodes) = parse(html, "http://example.com/old/");
+
+        assert_eq!(name, "/old");
+        assert_eq!(
+            describe(&nodes),
+            [
+                (
+                    "file",
+                    "http://example.com/old/readme.txt",
+                    "readme.txt",
+                    "1.2K"
+                ),
+                ("dir", "http://example.com/old/docs/", "/old/docs", ""),
+                (
+                    "file",
+                    "http://example.com/old/a%20b%26c.txt",
+                    "a b&c.txt",
+                    "7"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_rows_spread_over_lines() {
+        let html = "<html><body><h1>Index of /x</h1><table>
+<tr>
+  <td>
+    <a href=\"a.txt\">
+      a.txt
+    </a>
+  </td>
+  <td>2021-01-01 10:00</td>
+  <td>12</td>
+</tr>
+</table></body></html>";
+
+        let (_, nodes) = parse(html, "http://example.com/x/");
+
+        assert_eq!(
+            describe(&nodes),
+            [("file", "http://example.com/x/a.txt", "a.txt", "12")]
+        );
+    }
+
+    #[test]
+    fn skips_parent_and_self_links() {
+        let html = r#"<html><body><h1>Index of /pub/a</h1><table>
+<tr><td><a href="../">Parent Directory</a></td><td>&nbsp;</td><td>-</td></tr>
+<tr><td><a href="/pub/">up</a></td><td>2021-01-01 10:00</td><td>-</td></tr>
+<tr><td><a href="./">.</a></td><td>2021-01-01 10:00</td><td>-</td></tr>
+<tr><td><a href="/pub/a/">a/</a></td><td>2021-01-01 10:00</td><td>-</td></tr>
+<tr><td><a href="/">/</a></td><td>2021-01-01 10:00</td><td>-</td></tr>
+<tr><td><a href="b/">b/</a></td><td>2021-01-01 10:00</td><td>-</td></tr>
+</table></body></html>"#;
+
+        let (_, nodes) = parse(html, "http://example.com/pub/a/");
+
+        let urls: Vec<&str> = nodes.iter().map(Node::url).collect();
+        assert_eq!(urls, ["http://example.com/pub/a/b/"]);
+    }
+
+    #[test]
+    fn decodes_windows_1252_listings() {
+        let html = "<html><body><h1>Index of /m</h1><table>
+<tr><td><a href=\"caf%E9.txt\">caf\u{e9}.txt</a></td><td>2021-01-01 10:00</td><td>3</td></tr>
+</table></body></html>";
+        let (body, _, _) = WINDOWS_1252.encode(html);
+        assert!(std::str::from_utf8(&body).is_err());
+
+        // Declared by the server
+        let html = sanitize_html(&body, Some("windows-1252")).unwrap();
+        let url = Url::parse("http://example.com/m/").unwrap();
+        let (_, nodes) = cheap_extract_from_html(&html, &url, &ServerFormat::default()).unwrap();
+        assert_eq!(describe(&nodes)[0].2, "caf\u{e9}.txt");
+
+        // Detected (without a declaration)
+        let (_, nodes) = parse_bytes(&body, "http://example.com/m/");
+        assert_eq!(describe(&nodes)[0].2, "caf\u{e9}.txt");
+    }
+
+    #[test]
+    fn decodes_the_declared_meta_charset() {
+        let html =
+            "<html><head><meta charset=\"iso-8859-7\"></head><body><h1>Index of /m</h1><table>
+<tr><td><a href=\"\u{3b1}.txt\">\u{3b1}.txt</a></td><td>2021-01-01 10:00</td><td>3</td></tr>
+</table></body></html>";
+        let (body, _, _) = encoding_rs::ISO_8859_7.encode(html);
+
+        let (_, nodes) = parse_bytes(&body, "http://example.com/m/");
+        assert_eq!(describe(&nodes)[0].2, "\u{3b1}.txt");
+    }
+
+    #[test]
+    fn skips_links_which_cannot_be_downloaded() {
+        let html = r#"<html><body><h1>Index of /pub</h1><table>
+<tr><td><a href="a.txt">a.txt</a></td><td>2021-01-01 10:00</td><td>12</td><td>&nbsp;</td></tr>
+<tr><td><a href="mailto:admin@example.com">admin</a></td><td>2021-01-01 10:00</td><td>12</td><td>&nbsp;</td></tr>
+<tr><td><a href="javascript:void(0)">js</a></td><td>2021-01-01 10:00</td><td>-</td><td>&nbsp;</td></tr>
+<tr><td><a href="data:text/plain,x">data</a></td><td>2021-01-01 10:00</td><td>1</td><td>&nbsp;</td></tr>
+</table></body></html>"#;
+
+        let (_, nodes) = parse(html, "http://example.com/pub/");
+
+        let urls: Vec<&str> = nodes.iter().map(Node::url).collect();
+        assert_eq!(urls, ["http://example.com/pub/a.txt"]);
+    }
+
+    #[test]
+    fn clean_url_refuses_urls_without_a_path() {
+        let mut url = Url::parse("mailto:admin@example.com").unwrap();
+        assert_eq!(clean_url(&mut url), None);
+
+        let mut url = Url::parse("http://example.com/pub/a.txt//").unwrap();
+        assert_eq!(clean_url(&mut url), Some(()));
+        assert_eq!(url.as_str(), "http://example.com/pub/a.txt");
+    }
+
+    #[test]
+    fn tells_directories_by_their_link_or_size() {
+        let html = r#"<html><body><h1>Index of /pub</h1><table>
+<tr><td><a href="sized/">sized/</a></td><td>2021-03-04 12:00</td><td>4.0K</td></tr>
+<tr><td><a href="em/">em/</a></td><td>2021-03-04 12:00</td><td>—</td></tr>
+<tr><td><a href="blank/">blank/</a></td><td>2021-03-04 12:00</td><td></td></tr>
+<tr><td><a href="slashless">slashless</a></td><td>2021-03-04 12:00</td><td>-</td></tr>
+<tr><td><a href="file.txt">file.txt</a></td><td>2021-03-04 12:00</td><td>12</td></tr>
+</table></body></html>"#;
+        let kinds = |dir_detection| {
+            let format = ServerFormat {
+                dir_detection,
+                ..ServerFormat::default()
+            };
+            let backend = ApacheHttpBackend::new(reqwest::Client::new(), Duration::from_secs(1))
+                .with_format(format);
+            let (_, nodes) = backend
+                .parse_saved_page(html.as_bytes(), &Url::parse("http://a/pub/").unwrap())
+                .unwrap();
+
+            describe(&nodes)
+                .into_iter()
+                .map(|(kind, _, _, _)| kind)
+                .collect::<Vec<_>>()
+        };
+
+        // The kinds of sized/, em/, blank/, slashless & file.txt
+        assert_eq!(
+            kinds(DirDetection::Auto),
+            ["dir", "dir", "dir", "dir", "file"]
+        );
+        assert_eq!(
+            kinds(DirDetection::TrailingSlash),
+            ["dir", "dir", "dir", "file", "file"]
+        );
+        assert_eq!(
+            kinds(DirDetection::EmptySize),
+            ["file", "dir", "dir", "dir", "file"]
+        );
+    }
+
+    #[test]
+    fn parses_a_listing_with_a_custom_row_pattern() {
+        let html = r#"<html><body><ul>
+<li><a href="a.txt">a.txt</a> 2021-03-04 12:00 1.2K</li>
+<li><a href="sub/">sub/</a> 2021-03-04 12:00 -</li>
+</ul></body></html>"#;
+        let format = ServerFormat {
+            row_pattern: Some(
+                Regex::new(r#"<li><a href="(?P<href>[^"]+)">(?P<name>[^<]+)</a> (?P<date>\S+ \S+) (?P<size>\S+)</li>"#)
+                    .unwrap(),
+            ),
+            ..ServerFormat::default()
+        };
+        let backend = ApacheHttpBackend::new(reqwest::Client::new(), Duration::from_secs(1))
+            .with_format(format);
+
+        let (name, nodes) = backend
+            .parse_saved_page(html.as_bytes(), &Url::parse("http://a/pub/").unwrap())
+            .unwrap();
+
+        assert_eq!(name, "/pub");
+        assert_eq!(
+            describe(&nodes),
+            [
+                ("file", "http://a/pub/a.txt", "a.txt", "1.2K"),
+                ("dir", "http://a/pub/sub/", "/pub/sub", ""),
+            ]
+        );
+    }
+
+    #[test]
+    fn fails_on_unparsed_rows_when_strict() {
+        let html = r#"<html><body><h1>Index of /pub</h1><table>
+<tr><td><a href="a.txt">a.txt</a></td><td>2021-03-04 12:00</td><td>1</td></tr>
+<tr><td><a href="broken.txt">broken.txt</a></td></tr>
+</table></body></html>"#;
+        let url = Url::parse("http://a/pub/").unwrap();
+        let parse = |strict_html| {
+            let format = ServerFormat {
+                strict_html,
+                ..ServerFormat::default()
+            };
+            ApacheHttpBackend::new(reqwest::Client::new(), Duration::from_secs(1))
+                .with_format(format)
+                .parse_saved_page(html.as_bytes(), &url)
+        };
+
+        // The row is left out, unless that should fail
+        let (_, nodes) = parse(false).unwrap();
+        assert_eq!(
+            describe(&nodes),
+            [("file", "http://a/pub/a.txt", "a.txt", "1")]
+        );
+
+        let error = parse(true).unwrap_err();
+        let unparsed = error.downcast_ref::<UnparsedRows>().unwrap();
+        assert_eq!(unparsed.url, "http://a/pub/");
+        assert_eq!(unparsed.rows.len(), 1);
+        assert!(
+            unparsed.rows[0].contains("broken.txt"),
+            "{:?}",
+            unparsed.rows
+        );
+    }
+
+    #[test]
+    fn parses_an_iis_listing() {
+        let html = r#"<html><head><title>files.example.com - /pub/</title></head><body><H1>files.example.com - /pub/</H1><hr>
+
+<pre><A HREF="/">[To Parent Directory]</A><br><br> 1/23/2021  3:45 PM        12345 <A HREF="/pub/a.iso">a.iso</A><br>Tuesday, March 2, 2021 10:05 AM        &lt;dir&gt; <A HREF="/pub/sub/">sub</A><br>01.12.2020 17:00          678 <A HREF="/pub/old.txt">old.txt</A><br></pre><hr></body></html>"#;
+
+        let (name, nodes) = parse(html, "http://example.com/pub/");
+
+        assert_eq!(name, "/pub");
+        assert_eq!(
+            describe(&nodes),
+            [
+                ("file", "http://example.com/pub/a.iso", "a.iso", "12345"),
+                ("dir", "http://example.com/pub/sub/", "/pub/sub", ""),
+                ("file", "http://example.com/pub/old.txt", "old.txt", "678"),
+            ]
+        );
+
+        // The dates of each of the formats are understood
+        let dates: Vec<String> = nodes
+            .iter()
+            .map(|node| match node {
+                Node::File(file) => &file.last_modified,
+                Node::PendingDir(dir) | Node::CrawledDir(dir, _) => &dir.last_modified,
+            })
+            .map(|date| parse_date(date).unwrap().to_rfc3339())
+            .collect();
+        assert_eq!(
+            dates,
+            [
+                "2021-01-23T15:45:00+00:00",
+                "2021-03-02T10:05:00+00:00",
+                "2020-12-01T17:00:00+00:00",
+            ]
+        );
+    }
+
+    #[test]
+    fn only_takes_relative_links_when_told_to() {
+        let html = r#"<html><body><h1>Index of /pub</h1><table>
+<tr><td><a href="a.txt">a.txt</a></td><td>2021-03-04 12:00</td><td>1</td></tr>
+<tr><td><a href="/pub/abs.txt">abs.txt</a></td><td>2021-03-04 12:00</td><td>2</td></tr>
+<tr><td><a href="sub/">sub/</a></td><td>2021-03-04 12:00</td><td>-</td></tr>
+<tr><td><a href="http://a/pub/full/">full/</a></td><td>2021-03-04 12:00</td><td>-</td></tr>
+</table></body></html>"#;
+        let urls = |relative_only| {
+            let format = ServerFormat {
+                relative_only,
+                strict_html: true,
+                ..ServerFormat::default()
+            };
+            let backend = ApacheHttpBackend::new(reqwest::Client::new(), Duration::from_secs(1))
+                .with_format(format);
+            let (_, nodes) = backend
+                .parse_saved_page(html.as_bytes(), &Url::parse("http://a/pub/").unwrap())
+                .unwrap();
+
+            describe(&nodes)
+                .into_iter()
+                .map(|(_, url, _, _)| url.to_owned())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            urls(false),
+            [
+                "http://a/pub/a.txt",
+                "http://a/pub/abs.txt",
+                "http://a/pub/sub/",
+                "http://a/pub/full/",
+            ]
+        );
+        // The absolute links are left out (without counting as unparsed rows)
+        assert_eq!(urls(true), ["http://a/pub/a.txt", "http://a/pub/sub/"]);
+    }
+
+    #[test]
+    fn parses_a_listing_in_the_assumed_format() {
+        let html = r#"<html><head><title>Index of /pub/</title></head><body><h1>Index of /pub/</h1><hr><pre><a href="../">../</a>
+<a href="sub/">sub/</a>                                               04-Mar-2021 12:00                   -
+<a href="a.txt">a.txt</a>                                              04-Mar-2021 12:00                 123
+</pre><hr></body></html>"#;
+        let url = Url::parse("http://a/pub/").unwrap();
+        let parse = |assumed| {
+            let format = ServerFormat {
+                assumed: Some(assumed),
+                ..ServerFormat::default()
+            };
+            ApacheHttpBackend::new(reqwest::Client::new(), Duration::from_secs(1))
+                .with_format(format)
+                .parse_saved_page(html.as_bytes(), &url)
+        };
+
+        for wrong in [ListingFormat::Apache, ListingFormat::Iis] {
+            let err = parse(wrong).unwrap_err();
+            let mismatch = err.downcast_ref::<FormatMismatch>().unwrap();
+            assert_eq!(mismatch.format, wrong);
+            assert!(err
+                .to_string()
+                .starts_with("No entries parsed with the assumed format"));
+        }
+
+        let (name, nodes) = parse(ListingFormat::Nginx).unwrap();
+        assert_eq!(name, "/pub/");
+        assert_eq!(
+            describe(&nodes),
+            [
+                ("dir", "http://a/pub/sub/", "/pub/sub", ""),
+                ("file", "http://a/pub/a.txt", "a.txt", "123"),
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_the_first_of_duplicate_entries() {
+        let html = r#"<html><body><h1>Index of /pub</h1><table>
+<tr><td><a href="a.txt">a.txt</a></td><td>2021-03-04 12:00</td><td>1</td></tr>
+<tr><td><a href="sub/">sub/</a></td><td>2021-03-04 12:00</td><td>-</td></tr>
+<tr><td><a href="a.txt">a.txt</a></td><td>2021-03-04 12:00</td><td>2</td></tr>
+<tr><td><a href="./sub/">sub/</a></td><td>2021-03-04 12:00</td><td>-</td></tr>
+</table></body></html>"#;
+
+        let (_, nodes) = parse(html, "http://example.com/pub/");
+
+        assert_eq!(
+            describe(&nodes),
+            [
+                ("file", "http://example.com/pub/a.txt", "a.txt", "1"),
+                ("dir", "http://example.com/pub/sub/", "/pub/sub", ""),
+            ]
+        );
+    }
+
+    #[test]
+    fn decodes_entities_once() {
+        let html = r#"<html><body><h1>Index of /pub</h1><table>
+<tr><td><a href="R%26D.txt">R&amp;D.txt</a></td><td>2021-03-04 12:00</td><td>1</td></tr>
+<tr><td><a href="x%26lt%3By.txt">x&amp;lt;y.txt</a></td><td>2021-03-04 12:00</td><td>2</td></tr>
+<tr><td><a href="get.php?a=1&amp;b=2">get.php</a></td><td>2021-03-04 12:00</td><td>3</td></tr>
+</table></body></html>"#;
+
+        let (_, nodes) = parse(html, "http://example.com/pub/");
+
+        assert_eq!(
+            describe(&nodes),
+            [
+                ("file", "http://example.com/pub/R%26D.txt", "R&D.txt", "1"),
+                (
+                    "file",
+                    "http://example.com/pub/x%26lt%3By.txt",
+                    "x&lt;y.txt",
+                    "2"
+                ),
+                (
+                    "file",
+                    "http://example.com/pub/get.php?a=1&b=2",
+                    "get.php",
+                    "3"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_the_brackets_and_the_port_of_ipv6_hosts() {
+        let html = r#"<html><body><h1>Index of /pub</h1><table>
+<tr><td><a href="a.txt">a.txt</a></td><td>2021-03-04 12:00</td><td>1</td></tr>
+<tr><td><a href="/pub/sub/">sub/</a></td><td>2021-03-04 12:00</td><td>-</td></tr>
+<tr><td><a href="http://[::1]:8080/pub/b.txt">b.txt</a></td><td>2021-03-04 12:00</td><td>2</td></tr>
+</table></body></html>"#;
+
+        let (_, nodes) = parse(html, "http://[::1]:8080/pub/");
+
+        let urls: Vec<&str> = nodes.iter().map(Node::url).collect();
+        assert_eq!(
+            urls,
+            [
+                "http://[::1]:8080/pub/a.txt",
+                "http://[::1]:8080/pub/sub/",
+                "http://[::1]:8080/pub/b.txt"
+            ]
+        );
+    }
+
+    /// A backend serving http://a/ with two files and a directory of two more (and one deeper)
+    fn mock_tree() -> MockListing {
+        let file = |url: &str| Node::File(FileLinkMetaData::for_test(url, 1));
+        let dir = |url: &str| Node::PendingDir(DirLinkMetaData::for_test(url));
+
+        MockListing::new([
+            (
+                "http://a/",
+                vec![file("http://a/1.txt"), dir("http://a/b/")],
+            ),
+            (
+                "http://a/b/",
+                vec![
+                    file("http://a/b/2.txt"),
+                    file("http://a/b/3.txt"),
+                    dir("http://a/b/c/"),
+                ],
+            ),
+            ("http://a/b/c/", vec![file("http://a/b/c/4.txt")]),
+        ])
+    }
+
+    /// Crawls the whole tree below a URL (like a run without limits does)
+    async fn batch_crawl(url: &Url, backend: &dyn DirectoryListing) -> Node {
+        let observer = Observers(vec![]);
+        let mut budget = CrawlBudget::new(None, None);
+        let excludes = DirExcludes::default();
+
+        let mut partial = start_crawl(url, backend, &excludes, &mut budget, &observer)
+            .await
+            .unwrap();
+        crawl_frontier(
+            &mut partial,
+            backend,
+            &excludes,
+            false,
+            false,
+            &mut budget,
+            &observer,
+        )
+        .await
+        .unwrap();
+
+        match partial.into_state() {
+            CrawlingState::Complete(root) => root,
+            state => panic!("Incomplete crawl: {:?}", state),
+        }
+    }
+
+    #[tokio::test]
+    async fn streams_the_tree_of_the_batch_crawl() {
+        let url = Url::parse("http://a/").unwrap();
+        let observer = Observers(vec![]);
+        let root = batch_crawl(&url, &mock_tree()).await;
+
+        let backend = mock_tree();
+        let nodes: Vec<Node> = crawl_stream(
+            &url,
+            &backend,
+            DirExcludes::default(),
+            false,
+            CrawlBudget::new(None, None),
+            &observer,
+        )
+        .map(Result::unwrap)
+        .collect()
+        .await;
+
+        let mut streamed_files: Vec<&str> = nodes
+            .iter()
+            .filter_map(|node| match node {
+                Node::File(file) => Some(file.url.as_str()),
+                _ => None,
+            })
+            .collect();
+        let mut files: Vec<&str> = root.iter_files().map(|file| file.url.as_str()).collect();
+        streamed_files.sort();
+        files.sort();
+        assert_eq!(streamed_files, files);
+
+        let mut streamed_dirs: Vec<&str> = nodes
+            .iter()
+            .filter_map(|node| match node {
+                Node::CrawledDir(dir, _) => Some(dir.url.as_str()),
+                _ => None,
+            })
+            .collect();
+        let mut dirs: Vec<&str> = root.iter_dirs().map(|dir| dir.url.as_str()).collect();
+        streamed_dirs.sort();
+        dirs.sort();
+        assert_eq!(streamed_dirs, dirs);
+
+        // Each directory is followed by its files
+        assert!(matches!(&nodes[0], Node::CrawledDir(dir, _) if dir.url == "http://a/"));
+        assert!(matches!(&nodes[1], Node::File(file) if file.url == "http://a/1.txt"));
+    }
+
+    #[tokio::test]
+    async fn records_the_depth_of_each_node() {
+        let url = Url::parse("http://a/").unwrap();
+        let root = batch_crawl(&url, &mock_tree()).await;
+
+        let dirs: Vec<(&str, u64)> = root
+            .iter_dirs()
+            .map(|dir| (dir.url.as_str(), dir.depth))
+            .collect();
+        assert_eq!(
+            dirs,
+            [("http://a/", 0), ("http://a/b/", 1), ("http://a/b/c/", 2)]
+        );
+
+        let files: Vec<(&str, u64)> = root
+            .iter_files()
+            .map(|file| (file.url.as_str(), file.depth))
+            .collect();
+        assert_eq!(
+            files,
+            [
+                ("http://a/1.txt", 1),
+                ("http://a/b/2.txt", 2),
+                ("http://a/b/3.txt", 2),
+                ("http://a/b/c/4.txt", 3),
+            ]
+        );
+    }
 }
@@ -0,0 +1,123 @@
+use anyhow::{Context, Result};
+use log::debug;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// How duplicates get linked to the first copy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkKind {
+    Hard,
+    Symbolic,
+}
+
+/// A downloaded file which later duplicates may be linked to
+#[derive(Debug)]
+struct Canonical {
+    path: PathBuf,
+    url: String,
+
+    /// Only calculated once another file of the same size shows up
+    hash: Option<Vec<u8>>,
+}
+
+/**
+Replaces downloaded files which are identical to an earlier download with links to it
+
+Files are grouped by their size, so only files whose size collides with an earlier download
+need to be hashed. As the content has to be compared, every copy is still downloaded: this saves
+disk space, but not bandwidth. (ETags can't tell copies apart up front, as they only identify
+the versions of a single URL.)
+*/
+#[derive(Debug)]
+pub struct Dedup {
+    kind: LinkKind,
+    by_size: HashMap<u64, Vec<Canonical>>,
+}
+
+impl Dedup {
+    pub fn new(kind: LinkKind) -> Self {
+        Self {
+            kind,
+            by_size: HashMap::new(),
+        }
+    }
+
+    /**
+    Checks a freshly downloaded file against the earlier downloads
+
+    If an identical file was downloaded before, the new file gets replaced by a link to it and
+    the URL of the first copy is returned. Otherwise the file is remembered as a canonical copy.
+    */
+    pub fn deduplicate(&mut self, path: &Path, url: &str) -> Result<Option<String>> {
+        let size = fs::metadata(path)?.len();
+        let candidates = self.by_size.entry(size).or_default();
+
+        // Only hash if the size collides
+        let hash = if candidates.is_empty() {
+            None
+        } else {
+            let hash = hash_file(path)?;
+
+            for candidate in candidates.iter_mut() {
+                if candidate.hash.is_none() {
+                    candidate.hash = Some(hash_file(&candidate.path)?);
+                }
+
+                if candidate.hash.as_ref() == Some(&hash) {
+                    debug!(
+                        "(Dedup) Link {} to {}",
+                        path.display(),
+                        candidate.path.display()
+                    );
+                    replace_with_link(path, &candidate.path, self.kind)?;
+
+                    return Ok(Some(candidate.url.clone()));
+                }
+            }
+
+            Some(hash)
+        };
+
+        candidates.push(Canonical {
+            path: path.to_path_buf(),
+            url: url.to_owned(),
+            hash,
+        });
+
+        Ok(None)
+    }
+}
+
+/// Calculates the SHA-256 hash of a file
+fn hash_file(path: &Path) -> Result<Vec<u8>> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+
+    Ok(hasher.finalize().to_vec())
+}
+
+/// Replaces a file with a (hard or symbolic) link to another file
+fn replace_with_link(path: &Path, target: &Path, kind: LinkKind) -> Result<()> {
+    fs::remove_file(path)?;
+
+    match kind {
+        LinkKind::Hard => fs::hard_link(target, path),
+        LinkKind::Symbolic => symlink(target, path),
+    }
+    .with_context(|| format!("Cannot link {} to {}", path.display(), target.display()))
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, path: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+#[cfg(windows)]
+fn symlink(target: &Path, path: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(target, path)
+}
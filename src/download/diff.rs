@@ -0,0 +1,268 @@
+use super::types::{FileLinkMetaData, Node};
+use serde::Serialize;
+use std::{collections::HashMap, fmt};
+
+/// A file or directory which is in both trees, but with another size or date
+#[derive(Debug, Clone, Serialize)]
+pub struct Modified {
+    pub url: String,
+
+    /// The sizes in bytes (only for files, as far as the listings knew them)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub old_size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub new_size: Option<u64>,
+
+    /// The dates as shown in the listings
+    pub old_last_modified: String,
+    pub new_last_modified: String,
+}
+
+/**
+The changes between two crawls of the same mirror (see `diff`)
+
+All nodes are named by their URL. Added & modified nodes are in the order of the new tree, removed
+ones in the order of the old tree.
+*/
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TreeDiff {
+    pub added_files: Vec<String>,
+    pub removed_files: Vec<String>,
+    pub modified_files: Vec<Modified>,
+    pub added_dirs: Vec<String>,
+    pub removed_dirs: Vec<String>,
+    pub modified_dirs: Vec<Modified>,
+}
+
+impl TreeDiff {
+    /// Checks if both trees are the same (as far as both were crawled)
+    pub fn is_empty(&self) -> bool {
+        self.added_files.is_empty()
+            && self.removed_files.is_empty()
+            && self.modified_files.is_empty()
+            && self.added_dirs.is_empty()
+            && self.removed_dirs.is_empty()
+            && self.modified_dirs.is_empty()
+    }
+}
+
+impl fmt::Display for TreeDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} files added, {} removed, {} modified; {} directories added, {} removed, {} modified",
+            self.added_files.len(),
+            self.removed_files.len(),
+            self.modified_files.len(),
+            self.added_dirs.len(),
+            self.removed_dirs.len(),
+            self.modified_dirs.len()
+        )
+    }
+}
+
+/**
+Compares two crawled trees (e.g. of two state stores, see `diff-state`)
+
+Files count as modified if their size or date changed, directories if their date changed. Nothing
+is reported below directories which weren't crawled in either tree (as their contents are unknown).
+*/
+pub fn diff(old: &Node, new: &Node) -> TreeDiff {
+    let old_nodes = by_url(old);
+    let new_nodes = by_url(new);
+
+    // The contents of directories which weren't crawled are unknown
+    let pending: Vec<&str> = old
+        .iter()
+        .chain(new.iter())
+        .filter_map(|node| match node {
+            Node::PendingDir(dir) => Some(dir.url.as_str()),
+            _ => None,
+        })
+        .collect();
+    let is_unknown = |url: &str| {
+        pending
+            .iter()
+            .any(|dir| url != *dir && url.starts_with(dir))
+    };
+
+    let mut diff = TreeDiff::default();
+
+    for node in new.iter() {
+        let url = node.url();
+        if is_unknown(url) {
+            continue;
+        }
+
+        match (old_nodes.get(url), node) {
+            (None, Node::File(_)) => diff.added_files.push(url.to_owned()),
+            (None, _) => diff.added_dirs.push(url.to_owned()),
+            (Some(Node::File(old_file)), Node::File(new_file)) => {
+                if is_modified(old_file, new_file) {
+                    diff.modified_files.push(Modified {
+                        url: url.to_owned(),
+                        old_size: old_file.size_bytes,
+                        new_size: new_file.size_bytes,
+                        old_last_modified: old_file.last_modified.clone(),
+                        new_last_modified: new_file.last_modified.clone(),
+                    });
+                }
+            }
+            // A file replaced by a directory (or the other way around)
+            (Some(Node::File(_)), _) => {
+                diff.removed_files.push(url.to_owned());
+                diff.added_dirs.push(url.to_owned());
+            }
+            (Some(_), Node::File(_)) => {
+                diff.removed_dirs.push(url.to_owned());
+                diff.added_files.push(url.to_owned());
+            }
+            (
+                Some(Node::PendingDir(old_dir) | Node::CrawledDir(old_dir, _)),
+                Node::PendingDir(new_dir) | Node::CrawledDir(new_dir, _),
+            ) => {
+                if old_dir.last_modified != new_dir.last_modified {
+                    diff.modified_dirs.push(Modified {
+                        url: url.to_owned(),
+                        old_size: None,
+                        new_size: None,
+                        old_last_modified: old_dir.last_modified.clone(),
+                        new_last_modified: new_dir.last_modified.clone(),
+                    });
+                }
+            }
+        }
+    }
+
+    for node in old.iter() {
+        let url = node.url();
+        if new_nodes.contains_key(url) || is_unknown(url) {
+            continue;
+        }
+
+        match node {
+            Node::File(_) => diff.removed_files.push(url.to_owned()),
+            _ => diff.removed_dirs.push(url.to_owned()),
+        }
+    }
+
+    diff
+}
+
+/// Indexes the nodes of a tree by their URL
+fn by_url(root: &Node) -> HashMap<&str, &Node> {
+    root.iter().map(|node| (node.url(), node)).collect()
+}
+
+/// Checks if a file has another size or date (sizes only count if both listings knew them)
+fn is_modified(old: &FileLinkMetaData, new: &FileLinkMetaData) -> bool {
+    let resized = match (old.size_bytes, new.size_bytes) {
+        (Some(old_size), Some(new_size)) => old_size != new_size,
+        _ => false,
+    };
+
+    resized || old.last_modified != new.last_modified
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::types::DirLinkMetaData;
+
+    fn file(url: &str, size: u64) -> Node {
+        Node::File(FileLinkMetaData::for_test(url, size))
+    }
+
+    fn dir(url: &str, children: Vec<Node>) -> Node {
+        Node::CrawledDir(DirLinkMetaData::for_test(url), children)
+    }
+
+    /// Makes a file with another date than the one of `file`
+    fn dated_file(url: &str, size: u64, last_modified: &str) -> Node {
+        let mut file = FileLinkMetaData::for_test(url, size);
+        file.last_modified = last_modified.to_owned();
+        Node::File(file)
+    }
+
+    /// Returns the URLs of the modified nodes
+    fn urls(modified: &[Modified]) -> Vec<&str> {
+        modified.iter().map(|node| node.url.as_str()).collect()
+    }
+
+    #[test]
+    fn finds_the_changes_between_two_trees() {
+        let old = dir(
+            "http://a/",
+            vec![
+                file("http://a/same.txt", 1),
+                file("http://a/gone.txt", 2),
+                dir(
+                    "http://a/b/",
+                    vec![
+                        file("http://a/b/resized.txt", 3),
+                        file("http://a/b/redated.txt", 5),
+                        dir("http://a/b/old/", vec![file("http://a/b/old/4.txt", 4)]),
+                    ],
+                ),
+                dir("http://a/dated/", vec![]),
+            ],
+        );
+        let mut dated = DirLinkMetaData::for_test("http://a/dated/");
+        dated.last_modified = "2022-02-02 10:00".to_owned();
+        let new = dir(
+            "http://a/",
+            vec![
+                file("http://a/same.txt", 1),
+                file("http://a/new.txt", 6),
+                dir(
+                    "http://a/b/",
+                    vec![
+                        file("http://a/b/resized.txt", 30),
+                        dated_file("http://a/b/redated.txt", 5, "2022-02-02 10:00"),
+                        dir("http://a/b/new/", vec![file("http://a/b/new/7.txt", 7)]),
+                    ],
+                ),
+                Node::CrawledDir(dated, vec![]),
+            ],
+        );
+
+        let diff = diff(&old, &new);
+
+        assert_eq!(
+            diff.added_files,
+            ["http://a/new.txt", "http://a/b/new/7.txt"]
+        );
+        assert_eq!(
+            diff.removed_files,
+            ["http://a/gone.txt", "http://a/b/old/4.txt"]
+        );
+        assert_eq!(
+            urls(&diff.modified_files),
+            ["http://a/b/resized.txt", "http://a/b/redated.txt"]
+        );
+        assert_eq!(diff.modified_files[0].old_size, Some(3));
+        assert_eq!(diff.modified_files[0].new_size, Some(30));
+        assert_eq!(diff.added_dirs, ["http://a/b/new/"]);
+        assert_eq!(diff.removed_dirs, ["http://a/b/old/"]);
+        assert_eq!(urls(&diff.modified_dirs), ["http://a/dated/"]);
+        assert_eq!(
+            diff.to_string(),
+            "2 files added, 2 removed, 2 modified; 1 directories added, 1 removed, 1 modified"
+        );
+    }
+
+    #[test]
+    fn leaves_out_directories_which_were_not_crawled() {
+        let old = dir(
+            "http://a/",
+            vec![Node::PendingDir(DirLinkMetaData::for_test("http://a/b/"))],
+        );
+        let new = dir(
+            "http://a/",
+            vec![dir("http://a/b/", vec![file("http://a/b/1.txt", 1)])],
+        );
+
+        assert!(diff(&old, &new).is_empty());
+        assert!(diff(&new, &old).is_empty());
+    }
+}
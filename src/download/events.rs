@@ -0,0 +1,128 @@
+use super::{
+    observer::DownloadObserver,
+    report::SkipReason,
+    types::{FileLinkMetaData, Node},
+};
+use serde::Serialize;
+use std::io::{self, Write};
+
+/**
+A single line of the `--ndjson` output
+
+Every event names the kind of node, its URL, its size (if known) and what happened to it.
+*/
+#[derive(Debug, Serialize)]
+struct Event<'a> {
+    #[serde(rename = "type")]
+    kind: &'a str,
+    url: &'a str,
+    size: Option<u64>,
+    status: &'a str,
+
+    /// Why a file was skipped
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<SkipReason>,
+
+    /// Why a download failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/**
+Writes the events of a crawl & download to stdout as JSON lines (see `--ndjson`)
+
+Each line is written as soon as the event happens, so other tools can process the nodes while
+the crawl runs (e.g. `od-get --ndjson <url> | jq`). A disabled instance does nothing, so it can be
+passed around unconditionally.
+*/
+#[derive(Debug, Clone, Copy)]
+pub struct NdjsonEvents {
+    enabled: bool,
+}
+
+impl NdjsonEvents {
+    pub fn new() -> Self {
+        Self { enabled: true }
+    }
+
+    /// Makes an event writer which doesn't write anything
+    pub fn disabled() -> Self {
+        Self { enabled: false }
+    }
+
+    fn emit(&self, event: Event) {
+        if !self.enabled {
+            return;
+        }
+
+        // A closed pipe (e.g. `| head`) must not abort the download
+        if let Ok(line) = serde_json::to_string(&event) {
+            let _ = writeln!(io::stdout().lock(), "{}", line);
+        }
+    }
+
+    fn emit_file(&self, file: &FileLinkMetaData, size: Option<u64>, status: &str) {
+        self.emit(Event {
+            kind: "file",
+            url: &file.url,
+            size,
+            status,
+            reason: None,
+            error: None,
+        });
+    }
+}
+
+impl Default for NdjsonEvents {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DownloadObserver for NdjsonEvents {
+    fn on_discovered(&self, node: &Node) {
+        let (kind, size) = match node {
+            Node::File(file) => ("file", file.size_bytes),
+            _ => ("dir", None),
+        };
+
+        self.emit(Event {
+            kind,
+            url: node.url(),
+            size,
+            status: "found",
+            reason: None,
+            error: None,
+        });
+    }
+
+    fn on_file_start(&self, file: &FileLinkMetaData, length: Option<u64>) {
+        self.emit_file(file, length, "downloading");
+    }
+
+    fn on_file_done(&self, file: &FileLinkMetaData, bytes: u64) {
+        self.emit_file(file, Some(bytes), "done");
+    }
+
+    fn on_file_skipped(&self, file: &FileLinkMetaData, reason: SkipReason) {
+        self.emit(Event {
+            kind: "file",
+            url: &file.url,
+            size: file.size_bytes,
+            status: "skipped",
+            reason: Some(reason),
+            error: None,
+        });
+    }
+
+    fn on_error(&self, file: &FileLinkMetaData, error: &anyhow::Error) {
+        self.emit(Event {
+            kind: "file",
+            url: &file.url,
+            size: file.size_bytes,
+            status: "failed",
+            reason: None,
+            error: Some(format!("{:#}", error)),
+        });
+    }
+}
@@ -0,0 +1,93 @@
+use super::{paths, types::Node};
+use anyhow::{anyhow, Result};
+use regex::Regex;
+use reqwest::Url;
+use std::str::FromStr;
+
+/**
+A glob for directories which shouldn't be crawled (see `--exclude-dir`)
+
+`*` and `?` match within a path segment, `**` matches across segments (including none, so a
+leading `**` matches directories at the top as well). A glob with a `/` (other than a trailing
+one) is matched against the path below the root (e.g. `pub/old`), any other glob against the name
+of the directory (e.g. `backup*`), wherever it is in the tree.
+*/
+#[derive(Debug, Clone)]
+pub struct DirGlob {
+    regex: Regex,
+    by_path: bool,
+}
+
+impl FromStr for DirGlob {
+    type Err = anyhow::Error;
+
+    fn from_str(glob: &str) -> Result<Self> {
+        let glob = glob.trim_matches('/');
+        if glob.is_empty() {
+            return Err(anyhow!("Empty directory glob"));
+        }
+
+        let mut pattern = String::from("^");
+        let mut chars = glob.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '*' if chars.peek() == Some(&'*') => {
+                    chars.next();
+
+                    // `**/` may match no directory at all (e.g. `**/tmp` matches `tmp`)
+                    if chars.peek() == Some(&'/') {
+                        chars.next();
+                        pattern.push_str("(?:.*/)?");
+                    } else {
+                        pattern.push_str(".*");
+                    }
+                }
+                '*' => pattern.push_str("[^/]*"),
+                '?' => pattern.push_str("[^/]"),
+                c => pattern.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        pattern.push('$');
+
+        Ok(Self {
+            regex: Regex::new(&pattern)?,
+            by_path: glob.contains('/'),
+        })
+    }
+}
+
+/// The directories left out of a crawl below a root URL
+#[derive(Debug, Clone, Default)]
+pub struct DirExcludes {
+    globs: Vec<DirGlob>,
+    root: Option<Url>,
+}
+
+impl DirExcludes {
+    pub fn new(globs: Vec<DirGlob>, root: &Url) -> Self {
+        Self {
+            globs,
+            root: Some(root.clone()),
+        }
+    }
+
+    /// Checks if a node is a directory which shouldn't be crawled
+    pub fn is_excluded(&self, node: &Node) -> bool {
+        let (dir, root) = match (node, &self.root) {
+            (Node::PendingDir(dir), Some(root)) if !self.globs.is_empty() => (dir, root),
+            _ => return false,
+        };
+        let url = match Url::parse(&dir.url) {
+            Ok(url) => url,
+            Err(_) => return false,
+        };
+
+        let path = paths::path_below(root, &url);
+        let path = path.trim_matches('/');
+        let name = path.rsplit('/').next().unwrap_or_default();
+
+        self.globs
+            .iter()
+            .any(|glob| glob.regex.is_match(if glob.by_path { path } else { name }))
+    }
+}
@@ -0,0 +1,151 @@
+use anyhow::{bail, Context, Result};
+use bzip2::read::BzDecoder;
+use flate2::read::GzDecoder;
+use log::warn;
+use std::{
+    fs,
+    io::{self, BufReader, Read},
+    path::{Path, PathBuf},
+};
+use xz2::read::XzDecoder;
+
+/// The kinds of archives which can be extracted (see `--extract`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveKind {
+    Tar,
+    TarGz,
+    TarXz,
+    TarBz2,
+    Zip,
+}
+
+/// The extensions of each kind (the longer ones first, so `.tar.gz` wins over a plain `.gz`)
+const EXTENSIONS: [(&str, ArchiveKind); 9] = [
+    (".tar.gz", ArchiveKind::TarGz),
+    (".tar.xz", ArchiveKind::TarXz),
+    (".tar.bz2", ArchiveKind::TarBz2),
+    (".tgz", ArchiveKind::TarGz),
+    (".txz", ArchiveKind::TarXz),
+    (".tbz2", ArchiveKind::TarBz2),
+    (".tbz", ArchiveKind::TarBz2),
+    (".tar", ArchiveKind::Tar),
+    (".zip", ArchiveKind::Zip),
+];
+
+impl ArchiveKind {
+    /**
+    Recognizes an archive by its file name (ignoring the case of the extension)
+
+    Returns its kind and the name without the extension, or None if it's no (supported) archive
+    or nothing but the extension.
+    */
+    pub fn of(name: &str) -> Option<(Self, &str)> {
+        let lowercase = name.to_lowercase();
+
+        EXTENSIONS
+            .iter()
+            .find(|(extension, _)| lowercase.ends_with(extension))
+            .map(|(extension, kind)| (*kind, &name[..name.len() - extension.len()]))
+            .filter(|(_, stem)| !stem.is_empty())
+    }
+}
+
+/**
+Extracts a downloaded archive into a sibling directory named like it (`data.tar.gz` into `data/`)
+
+Entries are contained in that directory: the ones with `..` components (or which would be written
+through a symlink pointing elsewhere) are skipped with a warning, as are symlinks in zip files.
+Absolute paths are taken as relative to the directory. Existing files get overwritten, so a newer
+archive replaces the extracted files of an older one (but files only the older one had are kept).
+
+This blocks while extracting. Returns the directory, or None if the file is no archive.
+*/
+pub fn extract(archive: &Path) -> Result<Option<PathBuf>> {
+    let (kind, stem) = match archive
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(ArchiveKind::of)
+    {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+    let target = archive.with_file_name(stem);
+
+    fs::create_dir_all(&target)
+        .with_context(|| format!("Cannot create the directory {}", target.display()))?;
+
+    let file = BufReader::new(
+        fs::File::open(archive)
+            .with_context(|| format!("Cannot open the archive {}", archive.display()))?,
+    );
+    match kind {
+        ArchiveKind::Tar => extract_tar(file, &target),
+        ArchiveKind::TarGz => extract_tar(GzDecoder::new(file), &target),
+        ArchiveKind::TarXz => extract_tar(XzDecoder::new(file), &target),
+        ArchiveKind::TarBz2 => extract_tar(BzDecoder::new(file), &target),
+        ArchiveKind::Zip => extract_zip(file.into_inner(), &target),
+    }
+    .with_context(|| format!("Cannot extract {}", archive.display()))?;
+
+    Ok(Some(target))
+}
+
+/// Extracts a (decompressed) tarball into a directory
+fn extract_tar(reader: impl Read, target: &Path) -> Result<()> {
+    let mut archive = tar::Archive::new(reader);
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+
+        // The entries check their paths themselves (refusing to leave the target)
+        if !entry.unpack_in(target)? {
+            warn!(
+                "Skipped {} (outside of {})",
+                entry.path()?.display(),
+                target.display()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Extracts a zip file into a directory
+fn extract_zip(file: fs::File, target: &Path) -> Result<()> {
+    let mut archive = zip::ZipArchive::new(file)?;
+
+    for index in 0..archive.len() {
+        let mut entry = archive.by_index(index)?;
+
+        let path = match entry.enclosed_name() {
+            Some(_) if entry.is_symlink() => {
+                warn!("Skipped the symlink {}", entry.name());
+                continue;
+            }
+            Some(path) => target.join(path),
+            None => {
+                warn!("Skipped {} (outside of {})", entry.name(), target.display());
+                continue;
+            }
+        };
+
+        if entry.is_dir() {
+            fs::create_dir_all(&path)?;
+            continue;
+        }
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+            bail!("Refusing to write through the symlink {}", path.display());
+        }
+
+        let mut output =
+            fs::File::create(&path).with_context(|| format!("Cannot create {}", path.display()))?;
+        io::copy(&mut entry, &mut output)
+            .with_context(|| format!("Cannot write {}", path.display()))?;
+    }
+
+    Ok(())
+}
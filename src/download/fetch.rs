@@ -1,52 +1,349 @@
-use crate::cli::CliOptions;
+use crate::cli::{CliOptions, SaveInterval};
 
-use super::types;
-use anyhow::{bail, Result};
+use super::{
+    checksum::{self, ChecksumKind, HashingWriter},
+    conflicts::{self, Resolution},
+    crawl::{crawl_stream, is_gone, not_a_listing, ranges_unsupported, CrawlBudget},
+    dedup::Dedup,
+    excludes::DirExcludes,
+    extract,
+    filenames::decode_filename,
+    hook,
+    ignores::LocalIgnores,
+    listing::{DirectoryListing, Fetched},
+    lockfile::{drift, Lockfile},
+    mmap::{MmapWriter, PartFile, SliceWriter},
+    observer::{DownloadObserver, Observers, RetryCounter},
+    order, paths,
+    report::{DownloadReport, SkipReason},
+    s3::S3Target,
+    tarball::{self, TarOutput},
+    template::TemplateValues,
+    timeout::{is_timeout, MAX_TIMEOUT_RETRIES},
+    types::{self, AttemptStatus, DedupLink, StateStore, Validators},
+    units::{format_size, parse_date, ByteRange},
+    verify::expected_size,
+    xattrs,
+};
+use anyhow::{bail, Context, Result};
+use filetime::FileTime;
+use futures::{future, StreamExt};
+use log::{debug, info, warn};
 use reqwest::{self, Url};
-use std::{path::Path, str::FromStr};
-use tokio::{fs, io::AsyncWriteExt};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    fmt,
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Instant,
+};
+use tokio::{
+    fs,
+    io::{self, AsyncWriteExt},
+    sync::mpsc,
+};
 use types::Node;
 
-/// Several counter variables used to keep track of limits
+/// The extension of files which are still being downloaded
+pub const PART_EXTENSION: &str = ".od-part";
+
+/// The extension of the sidecar files holding the descriptions from the listing
+pub const DESCRIPTION_EXTENSION: &str = ".description";
+
+/// The buffer size of the pipe between a download and its upload
+const PIPE_SIZE: usize = 64 * 1024;
+
+/// The number of discovered nodes which may wait for the download (see `--stream`)
+const STREAM_CAPACITY: usize = 1024;
+
+/// The smallest part of a file which gets downloaded on a connection of its own (see `--segments`)
+const MIN_SEGMENT_SIZE: u64 = 8 * 1024 * 1024;
+
+/**
+A downloaded file whose size differs from the one in the listing (e.g. a truncated transfer)
+
+The file is left as a `.od-part` file (see `--part-suffix`) and not marked as downloaded, so the
+next run tries again.
+*/
 #[derive(Debug, Clone)]
+pub struct SizeMismatch {
+    pub url: String,
+    pub expected: u64,
+    pub actual: u64,
+}
+
+impl fmt::Display for SizeMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} has {} instead of the {} from the listing",
+            self.url,
+            format_size(self.actual),
+            format_size(self.expected)
+        )
+    }
+}
+
+impl std::error::Error for SizeMismatch {}
+
+/// Returns the file of the wrong size (if that's what caused an error)
+pub fn size_mismatch(error: &anyhow::Error) -> Option<&SizeMismatch> {
+    error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<SizeMismatch>())
+}
+
+/**
+A download which ran out of disk space
+
+Its temporary file gets removed (so it doesn't take up the space needed to save the progress), and
+the run stops, as every other download would fail the same way.
+*/
+#[derive(Debug, Clone)]
+pub struct DiskFull {
+    pub url: String,
+    pub path: PathBuf,
+
+    /// The space left on the disk (if it can be found out)
+    pub available: Option<u64>,
+}
+
+impl fmt::Display for DiskFull {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Cannot save {} to {}, the disk is full",
+            self.url,
+            self.path.display()
+        )?;
+        match self.available {
+            Some(available) => write!(f, " ({} left)", format_size(available)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for DiskFull {}
+
+/// Returns the download which ran out of disk space (if that's what caused an error)
+pub fn disk_full(error: &anyhow::Error) -> Option<&DiskFull> {
+    error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<DiskFull>())
+}
+
+/// Checks if an error is caused by a full disk (ENOSPC)
+fn is_out_of_space(error: &anyhow::Error) -> bool {
+    error
+        .chain()
+        .filter_map(|cause| cause.downcast_ref::<io::Error>())
+        .any(|cause| cause.kind() == io::ErrorKind::StorageFull)
+}
+
+/// Turns an error caused by a full disk into a `DiskFull` one, leaving others as they are
+fn check_disk_full(error: anyhow::Error, url: &str, path: &Path) -> anyhow::Error {
+    if !is_out_of_space(&error) {
+        return error;
+    }
+
+    // The path may not exist (yet), but one of its directories does
+    let available = path
+        .ancestors()
+        .find_map(|dir| fs2::available_space(dir).ok());
+
+    DiskFull {
+        url: url.to_owned(),
+        path: path.to_owned(),
+        available,
+    }
+    .into()
+}
+
+/// Checks the size of a downloaded file against the listing (if the listing knows it)
+fn check_size(file: &types::FileLinkMetaData, actual: u64) -> Result<()> {
+    if let Some((expected, precision)) = expected_size(file) {
+        if actual.abs_diff(expected) >= precision {
+            return Err(SizeMismatch {
+                url: file.url.clone(),
+                expected,
+                actual,
+            }
+            .into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Several counter variables used to keep track of limits (and of what got downloaded)
+#[derive(Debug)]
 pub struct LimitCounts {
-    recursion_depth: u64,
     file_count: u64,
     skipped_files: u64,
+    unsaved_files: u64,
+
+    /// The files which failed since the last successful download (see `--consecutive-failures`)
+    failures_in_a_row: u64,
+    last_save: Instant,
+    dedup: Option<Dedup>,
+    ignores: Option<LocalIgnores>,
+    report: DownloadReport,
+
+    /// Upload the files to S3 instead of writing them to the destination
+    s3: Option<S3Target>,
+
+    /// Write the files into an archive instead of the destination
+    tar: Option<TarOutput>,
+
+    /// Record the downloads in a lockfile (or check them against it)
+    lockfile: Option<Lockfile>,
+
+    /// The local paths assigned to the files of the run (with their URLs), to find collisions
+    assigned_paths: HashMap<PathBuf, String>,
 }
 
 impl LimitCounts {
     pub fn new() -> Self {
         Self {
-            recursion_depth: 0,
             file_count: 0,
             skipped_files: 0,
+            unsaved_files: 0,
+            failures_in_a_row: 0,
+            last_save: Instant::now(),
+            dedup: None,
+            ignores: None,
+            report: DownloadReport::default(),
+            s3: None,
+            tar: None,
+            lockfile: None,
+            assigned_paths: HashMap::new(),
+        }
+    }
+
+    /// Uploads the files to an S3 bucket instead of writing them to the destination
+    pub fn with_s3(mut self, s3: S3Target) -> Self {
+        self.s3 = Some(s3);
+        self
+    }
+
+    /// Writes the files into a tar archive instead of the destination
+    pub fn with_tar(mut self, tar: TarOutput) -> Self {
+        self.tar = Some(tar);
+        self
+    }
+
+    /// Records the downloads in a lockfile (or checks them against it, if it's frozen)
+    pub fn with_lockfile(mut self, lockfile: Lockfile) -> Self {
+        self.lockfile = Some(lockfile);
+        self
+    }
+
+    /// Reports the files of an outdated stored tree which a new crawl didn't find (see
+    /// `--revalidate`) as gone
+    pub fn with_gone_files(mut self, urls: &[String]) -> Self {
+        for url in urls {
+            self.report.add_skipped(url, SkipReason::Gone);
+        }
+        self
+    }
+
+    /// Checks if the files end up in the destination (rather than in a bucket or an archive)
+    fn writes_locally(&self) -> bool {
+        self.s3.is_none() && self.tar.is_none()
+    }
+
+    /// Finishes the archive the files were written into (if any)
+    pub fn finish_tar(&mut self) -> Result<()> {
+        match &mut self.tar {
+            Some(tar) => tar.finish(),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes the lockfile the downloads were recorded in (if any)
+    pub fn save_lockfile(&self) -> Result<()> {
+        match &self.lockfile {
+            Some(lockfile) => lockfile.save(),
+            None => Ok(()),
         }
     }
+
+    /// Returns the report of the files handled so far
+    pub fn into_report(self) -> DownloadReport {
+        self.report
+    }
+}
+
+impl Default for LimitCounts {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 pub enum DownloadRecursiveStatus<'a> {
     Done,
-    Do(Vec<(&'a Node, &'a CliOptions, &'a reqwest::Client)>),
+    Do(Vec<(&'a Node, &'a CliOptions, &'a dyn DirectoryListing)>),
 }
 
 pub async fn download_files_to_dir(
     destination: &Path,
     files: &Vec<&types::FileLinkMetaData>,
-    client: &reqwest::Client,
+    backend: &dyn DirectoryListing,
     options: Option<&CliOptions>,
     mut counters: Option<&mut LimitCounts>,
-    done_list: &mut Vec<String>,
+    state: &mut StateStore,
+    observer: &dyn DownloadObserver,
 ) -> Result<()> {
     for file in files {
-        let temp = Url::from_str(&file.url)?;
+        let temp = Url::from_str(&file.url).with_context(|| format!("Invalid URL {}", file.url))?;
         let last_segment = get_last_segment(&temp);
+        let max_length = options.and_then(|options| options.max_filename_length);
+        let file_name = local_name(last_segment, max_length);
+        if max_length.is_some() && file_name != local_name(last_segment, None) {
+            info!("(Truncated) Saving {} as {}", file.url, file_name);
+        }
+        let mut file_path = match options {
+            Some(options) if options.path_template.is_some() => {
+                template_path(destination, file, &file_name, options)
+            }
+            Some(options) => match &options.path_as_name {
+                Some(separator) => path_as_name(destination, &file_name, options, separator),
+                None => destination.join(&file_name),
+            },
+            None => destination.join(&file_name),
+        };
 
-        if done_list.contains(&file.url) {
-            println!("(StateStore) Already have file {}", last_segment);
-            return Ok(());
+        // Check downloaded files for changes (if desired and possible), skip them otherwise
+        let mut validators = None;
+
+        // A conflict which fails the file (see `--on-conflict`)
+        let mut blocked = None;
+
+        let downloaded = match options {
+            Some(options) => state.is_downloaded(file, &options.url, options.match_by),
+            None => state.downloaded_urls.contains(&file.url),
+        };
+        if downloaded {
+            if options.is_some_and(|options| options.revalidate) && file_path.exists() {
+                validators = state.validators.get(&file.url).cloned();
+            }
+
+            if validators.is_none() {
+                debug!("(StateStore) Already have file {}", last_segment);
+                if let Some(counters) = &mut counters {
+                    counters
+                        .report
+                        .add_skipped(&file.url, SkipReason::AlreadyDownloaded);
+                }
+                observer.on_file_skipped(file, SkipReason::AlreadyDownloaded);
+                continue;
+            }
         }
 
+        // The size the server reported for a file of an outdated tree (see `--revalidate`)
+        let mut resized = None;
+
         // Follow options (if specified)
         if let Some(options) = options {
             if let Some(counters) = &mut counters {
@@ -58,69 +355,385 @@ pub async fn download_files_to_dir(
                 }
 
                 // Skip unwanted files
-                if let Some(regex) = &options.file_filter {
-                    if regex.is_match(last_segment) {
-                        // println!("(Filter) Skip file {} ({})", last_segment, file.name);
-                        println!("(Filter) Skip file {}", last_segment);
-                        continue;
-                    }
+                if let Some(reason) = filter_file(file, last_segment, options) {
+                    counters.report.add_skipped(&file.url, reason);
+                    observer.on_file_skipped(file, reason);
+                    continue;
                 }
 
-                // Only download wanted files
-                if let Some(regex) = &options.file_matcher {
-                    if !regex.is_match(last_segment) {
-                        // println!("(Matcher) Skip file {} ({})", last_segment, file.name);
-                        println!("(Matcher) Skip file {}", last_segment);
-                        continue;
-                    }
+                // Skip files the local copy ignores (see `.od-get-ignore`)
+                if counters.writes_locally()
+                    && counters
+                        .ignores
+                        .get_or_insert_with(|| LocalIgnores::new(&options.destination))
+                        .is_ignored(&file_path)
+                {
+                    debug!("(Ignore) Skip file {}", last_segment);
+                    counters.report.add_skipped(&file.url, SkipReason::Ignored);
+                    observer.on_file_skipped(file, SkipReason::Ignored);
+                    continue;
                 }
 
                 // Skip files if desired
                 if let Some(skip) = options.skip_count {
                     if counters.skipped_files < skip {
                         counters.skipped_files += 1;
+                        counters
+                            .report
+                            .add_skipped(&file.url, SkipReason::SkipCount);
+                        observer.on_file_skipped(file, SkipReason::SkipCount);
+                        continue;
+                    }
+                }
+
+                // Another file of the run may have been assigned the same path (e.g. once sanitized)
+                if counters.writes_locally() {
+                    let other = counters
+                        .assigned_paths
+                        .get(&file_path)
+                        .filter(|url| **url != file.url);
+                    if let Some(other) = other {
+                        match conflicts::resolve_collision(
+                            &file_path,
+                            other,
+                            expected_size(file),
+                            &counters.assigned_paths,
+                            options.on_conflict,
+                        ) {
+                            Resolution::Proceed => (),
+                            Resolution::Renamed(path) => file_path = path,
+                            Resolution::Skip => {
+                                counters.report.add_skipped(&file.url, SkipReason::Conflict);
+                                observer.on_file_skipped(file, SkipReason::Conflict);
+                                continue;
+                            }
+                            Resolution::Fail(error) => blocked = Some(error),
+                        }
+                    }
+                }
+
+                // Skip files which are already up to date locally
+                if options.only_newer && is_up_to_date(file, &file_path) {
+                    debug!("(Newer) Skip file {}", last_segment);
+                    counters.report.add_skipped(&file.url, SkipReason::UpToDate);
+                    observer.on_file_skipped(file, SkipReason::UpToDate);
+                    continue;
+                }
+
+                // Link files which are unchanged since the earlier snapshot
+                if let Some(link_dest) = &options.link_dest {
+                    if link_unchanged(file, &file_path, &options.destination, link_dest).await? {
+                        debug!("(Snapshot) Linked file {}", last_segment);
+                        counters.report.add_skipped(&file.url, SkipReason::Linked);
+                        observer.on_file_skipped(file, SkipReason::Linked);
                         continue;
                     }
                 }
 
+                // Ask for the type of the file (keeping it if the server can't tell)
+                if !options.accept_types.is_empty() {
+                    let content_type = backend.content_type(file).await.unwrap_or_else(|error| {
+                        debug!("(Type) No type of {}: {:#}", last_segment, error);
+                        None
+                    });
+                    if let Some(content_type) = content_type {
+                        if !is_accepted_type(&content_type, &options.accept_types) {
+                            debug!("(Type) Skip file {} ({})", last_segment, content_type);
+                            counters
+                                .report
+                                .add_skipped(&file.url, SkipReason::NotAccepted);
+                            observer.on_file_skipped(file, SkipReason::NotAccepted);
+                            continue;
+                        }
+                    }
+                }
+
+                // Make sure the file of a stored tree is still there (and still of its size)
+                if options.revalidate {
+                    match backend.file_size(file).await {
+                        Err(error) if is_gone(&error) => {
+                            info!("(Gone) Skip file {}", last_segment);
+                            counters.report.add_skipped(&file.url, SkipReason::Gone);
+                            observer.on_file_skipped(file, SkipReason::Gone);
+                            state.forget_file(&file.url);
+                            continue;
+                        }
+                        Ok(Some(size)) if file.size_bytes != Some(size) => {
+                            info!(
+                                "(Stale) Size of {} is {} now (not {})",
+                                last_segment,
+                                format_size(size),
+                                file.size
+                            );
+                            state.resize_file(&file.url, size);
+                            resized = Some(size);
+                        }
+                        Ok(_) => (),
+                        Err(error) => debug!("(Stale) Cannot check {}: {:#}", last_segment, error),
+                    }
+                }
+
+                // Mind what's already at the local path (see `--on-conflict`)
+                if counters.writes_locally() && validators.is_none() {
+                    match conflicts::resolve_file(
+                        &file_path,
+                        expected_size(file),
+                        options.on_conflict,
+                    ) {
+                        Resolution::Proceed => (),
+                        Resolution::Renamed(path) => file_path = path,
+                        Resolution::Skip => {
+                            counters.report.add_skipped(&file.url, SkipReason::Conflict);
+                            observer.on_file_skipped(file, SkipReason::Conflict);
+                            continue;
+                        }
+                        Resolution::Fail(error) => blocked = Some(error),
+                    }
+                }
+
+                // Only download the files of the lockfile (see `--frozen`)
+                if let Some(lockfile) = counters
+                    .lockfile
+                    .as_ref()
+                    .filter(|lockfile| lockfile.is_frozen())
+                {
+                    let name = relative_name(&file_path, &options.destination);
+                    match lockfile.check_file(&name, file) {
+                        Err(error) => blocked = Some(error),
+                        Ok(()) if lockfile.is_reproduced(&name, &file_path) => {
+                            debug!("(Lockfile) Already have {}", last_segment);
+                            counters.report.add_skipped(&file.url, SkipReason::UpToDate);
+                            observer.on_file_skipped(file, SkipReason::UpToDate);
+                            continue;
+                        }
+                        Ok(()) => (),
+                    }
+                }
+
+                // Claim the local path, now that the file is going to be written there
+                if counters.writes_locally() {
+                    counters
+                        .assigned_paths
+                        .insert(file_path.clone(), file.url.clone());
+                }
+
                 // Increment download counter
                 counters.file_count += 1;
             } else {
-                panic!("Cannot specify only one of `client` or `options` (need both or niether)");
+                panic!("Cannot specify only one of `counters` or `options` (need both or niether)");
             }
         } else {
             if counters.is_some() {
-                panic!("Cannot specify only one of `client` or `options` (need both or niether)");
+                panic!("Cannot specify only one of `counters` or `options` (need both or niether)");
+            }
+        }
+
+        // Expect the size the server reported instead of the one of the tree
+        let resized_file = resized.map(|size| types::FileLinkMetaData {
+            size_bytes: Some(size),
+            size: size.to_string(),
+            ..(*file).clone()
+        });
+        let file = resized_file.as_ref().unwrap_or(file);
+
+        // debug!("Downloading file {} ({})", last_segment, file.name);
+        debug!("Downloading file {}", last_segment);
+
+        let local = counters
+            .as_ref()
+            .is_none_or(|counters| counters.writes_locally());
+
+        // Templates may put files into directories which don't exist yet
+        if local && options.is_some_and(|options| options.path_template.is_some()) {
+            if let Some(parent) = file_path.parent() {
+                create_dir(parent).await?;
+            }
+        }
+
+        // Count the retries of the download, to record its attempts in the state store
+        let retries = RetryCounter::default();
+        let observers = Observers(vec![observer, &retries]);
+
+        let result = match (blocked, counters.as_deref_mut(), options) {
+            (Some(error), _, _) => Err(error),
+            (None, Some(LimitCounts { s3: Some(s3), .. }), Some(options)) => {
+                let key = s3.key_for(&relative_name(&file_path, &options.destination));
+                upload_file(file, s3, &key, backend, &observers)
+                    .await
+                    .map(|bytes| {
+                        Some((bytes, PathBuf::from(s3.url_of(&key)), Validators::default()))
+                    })
+            }
+            (None, Some(LimitCounts { tar: Some(tar), .. }), Some(options)) => {
+                let name = relative_name(&file_path, &options.destination);
+                archive_file(file, tar, &name, backend, &observers)
+                    .await
+                    .map(|bytes| Some((bytes, PathBuf::from(&name), Validators::default())))
+                    .map_err(|error| check_disk_full(error, &file.url, tar.path()))
+            }
+            _ => download_file(
+                file,
+                &file_path,
+                backend,
+                &observers,
+                validators.as_ref(),
+                options,
+            )
+            .await
+            .map(|fetched| {
+                fetched.map(|(bytes, validators)| (bytes, file_path.clone(), validators))
+            }),
+        };
+
+        let (bytes, saved_path, validators) = match result {
+            Ok(Some(result)) => result,
+            // Every further download would fail as well (or the mirror isn't the locked one
+            // anymore), so stop (the progress is saved on the way)
+            Err(error) if disk_full(&error).is_some() || drift(&error).is_some() => {
+                observer.on_error(file, &error);
+                return Err(error);
+            }
+            Ok(None) => {
+                debug!("(Validators) Not modified {}", last_segment);
+                if let Some(counters) = &mut counters {
+                    counters.failures_in_a_row = 0;
+                    counters.report.add_skipped(&file.url, SkipReason::UpToDate);
+                }
+                observer.on_file_skipped(file, SkipReason::UpToDate);
+                continue;
+            }
+            // Report files of the wrong size as failed (unless they should abort the download)
+            Err(error)
+                if size_mismatch(&error).is_some()
+                    && !options.is_some_and(|options| options.strict_size) =>
+            {
+                warn!("(Size) Failed file {}: {:#}", last_segment, error);
+                observer.on_error(file, &error);
+                state.mark_failed(file);
+                state.record_attempts(file, retries.attempts(), AttemptStatus::Failed);
+                if let (Some(options), Some(counters)) = (options, &mut counters) {
+                    counters.report.add_failed(&file.url, &error);
+                    counters.failures_in_a_row += 1;
+                    check_failures(options, counters)?;
+                }
+                continue;
+            }
+            // A server which keeps stalling only fails the file (once its retries are used up)
+            Err(error) if is_timeout(&error) => {
+                warn!("(Timeout) Failed file {}: {:#}", last_segment, error);
+                observer.on_error(file, &error);
+                state.mark_failed(file);
+                state.record_attempts(file, retries.attempts(), AttemptStatus::Failed);
+                if let (Some(options), Some(counters)) = (options, &mut counters) {
+                    counters.report.add_failed(&file.url, &error);
+                    counters.failures_in_a_row += 1;
+                    check_failures(options, counters)?;
+                }
+                continue;
+            }
+            Err(error)
+                if options.is_some_and(|options| options.skip_errors)
+                    && size_mismatch(&error).is_none() =>
+            {
+                warn!("(Error) Skip file {}: {:#}", last_segment, error);
+                observer.on_error(file, &error);
+                state.mark_failed(file);
+                state.record_attempts(file, retries.attempts(), AttemptStatus::Failed);
+                if let (Some(options), Some(counters)) = (options, &mut counters) {
+                    counters.report.add_failed(&file.url, &error);
+                    counters.failures_in_a_row += 1;
+                    check_failures(options, counters)?;
+                }
+                continue;
+            }
+            Err(error) => {
+                observer.on_error(file, &error);
+                state.mark_failed(file);
+                state.record_attempts(file, retries.attempts(), AttemptStatus::Failed);
+                return Err(error);
+            }
+        };
+
+        // Record the exact version of the file (or check it, see `--frozen`)
+        if let (
+            Some(options),
+            Some(LimitCounts {
+                lockfile: Some(lockfile),
+                ..
+            }),
+        ) = (options, counters.as_deref_mut())
+        {
+            let name = relative_name(&file_path, &options.destination);
+            if let Err(error) = lockfile.add_download(&name, file, &file_path, bytes) {
+                observer.on_error(file, &error);
+                return Err(error);
             }
         }
 
-        // println!("Downloading file {} ({})", last_segment, file.name);
-        println!("Downloading file {}", last_segment);
+        if let Some(counters) = &mut counters {
+            counters.failures_in_a_row = 0;
+            counters.report.add_downloaded(&file.url, saved_path, bytes);
+        }
 
-        // Request the file from the server
-        let req = client.get(file.url.as_str()).send();
+        // Link the file to an identical earlier download (if desired)
+        if let (Some(options), Some(counters)) = (options, &mut counters) {
+            if let Some(kind) = options.dedup {
+                let dedup = counters.dedup.get_or_insert_with(|| Dedup::new(kind));
 
-        let mut res = req.await?;
+                if let Some(canonical_url) = dedup.deduplicate(&file_path, &file.url)? {
+                    state.dedup_links.push(DedupLink {
+                        url: file.url.clone(),
+                        canonical_url,
+                    });
+                }
+            }
+        }
 
-        // Obtain the last segment from the server to follow redirects
-        let last_segment = get_last_segment(res.url());
+        // Keep the description from the listing next to the file (if desired)
+        if options.is_some_and(|options| options.save_descriptions)
+            && local
+            && !file.description.is_empty()
+        {
+            let path = description_path(&file_path);
+            fs::write(&path, &file.description)
+                .await
+                .with_context(|| format!("Cannot write {}", path.display()))?;
+        }
 
-        let file_path = destination.join(last_segment);
+        // Run the hook of the file (if any)
+        if let Some(options) = options.filter(|_| local) {
+            if let Some(command) = &options.exec {
+                if let Err(error) =
+                    hook::run_hook(command, &file_path, &file.url, bytes, options.exec_fail).await
+                {
+                    observer.on_error(file, &error);
+                    return Err(error);
+                }
+            }
+        }
+
+        observer.on_file_done(file, bytes);
 
-        // Use Tokio to open the target file
-        let mut file_handle = fs::OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open(&file_path)
-            .await?;
+        // Remember the validators for the next --revalidate run
+        if validators.is_empty() {
+            state.validators.remove(&file.url);
+        } else {
+            state.validators.insert(file.url.clone(), validators);
+        }
 
-        // Write the file to disk in chunks as they arrive from the network
-        while let Some(chunk) = res.chunk().await? {
-            file_handle.write_all(&chunk).await?;
+        state.record_attempts(file, retries.attempts(), AttemptStatus::Downloaded);
+
+        // Append the file URL (and its identity) to the done_list
+        match options {
+            Some(options) => state.mark_downloaded(file, &options.url),
+            None if !downloaded => state.downloaded_urls.push(file.url.clone()),
+            None => (),
         }
 
-        // Append the file URL to the done_list
-        done_list.push(file.url.clone());
+        // Persist the progress from time to time
+        if let (Some(options), Some(counters)) = (options, &mut counters) {
+            autosave(state, options, counters)?;
+        }
     }
 
     Ok(())
@@ -129,9 +742,10 @@ pub async fn download_files_to_dir(
 pub async fn download_recursive<'a>(
     node: &'a Node,
     options: &'a CliOptions,
-    client: &'a reqwest::Client,
-    counters: &'a mut LimitCounts,
-    done_list: &mut Vec<String>,
+    backend: &'a dyn DirectoryListing,
+    counters: &mut LimitCounts,
+    state: &mut StateStore,
+    observer: &dyn DownloadObserver,
 ) -> Result<DownloadRecursiveStatus<'a>> {
     // ) -> Box<dyn Future<Output = ()>> {
     // Pin<Box<dyn Future<Output = Result<()>>>>
@@ -140,73 +754,61 @@ pub async fn download_recursive<'a>(
     if let Node::CrawledDir(meta, children) = node {
         // If no download is desired, skip the download
         if options.no_download {
-            println!("Skipped download");
+            info!("Skipped download");
             return Ok(DownloadRecursiveStatus::Done);
         };
 
-        // Increment the recursion depth
-        counters.recursion_depth += 1;
-
         // The folder name from the server
-        let server_path = meta.name.split('/').last().expect("Can't split");
+        let server_path = meta.name.split('/').next_back().unwrap_or_default();
 
         // Skip unwanted folders
-        if let Some(regex) = &options.path_filter {
-            if regex.is_match(&server_path) {
-                println!("(Filter) Skip directory {}", server_path);
-                return Ok(DownloadRecursiveStatus::Done);
-            }
-        }
-
-        // Only download wanted folders
-        if let Some(regex) = &options.path_matcher {
-            if !regex.is_match(&server_path) {
-                println!("(Matcher) Skip directory {}", server_path);
-                return Ok(DownloadRecursiveStatus::Done);
-            }
+        if !is_wanted_dir(server_path, options) {
+            return Ok(DownloadRecursiveStatus::Done);
         }
 
-        let mut url = options.url.clone();
-        url.path_segments_mut()
-            .unwrap()
-            // TODO improve this somehow
-            .pop_if_empty()
-            .pop_if_empty()
-            .pop_if_empty()
-            .pop_if_empty()
-            .pop_if_empty()
-            .pop_if_empty()
-            .pop_if_empty()
-            .pop_if_empty();
-
-        let last_segment = url.path_segments().unwrap().last().unwrap();
+        // Reproduce the remote tree (including the root directory) below the destination
+        let base_url = paths::parent_url(&options.url);
+        let folder_path =
+            paths::url_to_local_path(&base_url, &Url::from_str(&meta.url)?, &options.destination);
 
-        // Create the directory (if it doesn't exist)
-        let folder_path = Path::new(&options.destination)
-            .join(last_segment)
-            .join(server_path);
+        // Create the directory (if it doesn't exist, and holds files or should be created anyway)
+        debug!("Downloading into {}", folder_path.display());
 
-        println!("{}", folder_path.to_str().unwrap());
+        let wanted = options.include_empty_dirs || node.iter_files().next().is_some();
+        if wanted
+            && counters.writes_locally()
+            && !options.custom_layout()
+            && !create_folder(&folder_path, options).await?
+        {
+            return Ok(DownloadRecursiveStatus::Done);
+        }
 
-        fs::create_dir_all(&folder_path).await?;
+        // The entries in the desired order (see --sort)
+        let children = order::sorted_children(children, options.sort);
 
         // Make a list of files
         let mut files = vec![];
 
-        for node in children {
+        for node in &children {
             if let Node::File(file) = node {
                 files.push(file);
             }
         }
 
+        // Only keep the first wanted files (see --sample-per-dir)
+        if let Some(sample) = options.sample_per_dir {
+            files = sample_files(files, sample, options, counters, observer);
+        }
+
         // Download all the files (if they pass the filters)
         download_files_to_dir(
             &folder_path,
             &files,
-            client,
+            backend,
             Some(options),
             Some(counters),
-            done_list,
+            state,
+            observer,
         )
         .await?;
 
@@ -215,18 +817,16 @@ pub async fn download_recursive<'a>(
 
         // Iterate over the sub directories
         for directory in children {
-            if let Node::CrawledDir(_, _) = directory {
-                // Stop if the recursion limit is reached
-                if let Some(rec_limit) = options.recursion_limit {
-                    if counters.recursion_depth >= rec_limit {
-                        println!("Reached recursion limit at {}", counters.recursion_depth);
-                        return Ok(DownloadRecursiveStatus::Done);
-                    }
+            if let Node::CrawledDir(sub_meta, _) = directory {
+                // Skip directories beyond the recursion limit
+                if !within_depth(sub_meta.depth, options) {
+                    debug!("(Depth) Skip directory {}", sub_meta.url);
+                    continue;
                 }
 
                 if let Some(file_limit) = options.limit_count {
                     if counters.file_count >= file_limit {
-                        println!("File limit reached at {} files", counters.file_count);
+                        info!("File limit reached at {} files", counters.file_count);
                         return Ok(DownloadRecursiveStatus::Done);
                     }
                 }
@@ -235,7 +835,7 @@ pub async fn download_recursive<'a>(
                 // (*(download_recursive(directory, options, client, counters).await))?;
 
                 // Start the next recursive iteration
-                to_do.push((directory, options, client));
+                to_do.push((directory, options, backend));
             } else if let Node::PendingDir(directory) = directory {
                 let mut url = Url::from_str(&directory.url)?;
                 url.path_segments_mut()
@@ -250,14 +850,14 @@ pub async fn download_recursive<'a>(
                     .pop_if_empty()
                     .pop_if_empty();
 
-                let last_segment = url.path_segments().unwrap().last().unwrap();
+                let last_segment = url.path_segments().unwrap().next_back().unwrap();
 
-                println!("(Skip) Directory not initialized: {}", last_segment);
+                warn!("(Skip) Directory not initialized: {}", last_segment);
             }
         }
 
         // Return the to_do list of tuples containing arguments with which this function should be called again
-        if to_do.len() == 0 {
+        if to_do.is_empty() {
             Ok(DownloadRecursiveStatus::Done)
         } else {
             Ok(DownloadRecursiveStatus::Do(to_do))
@@ -267,17 +867,1229 @@ pub async fn download_recursive<'a>(
     }
 }
 
+/**
+Downloads the files of the whole tree by their date, newest first (see `--newest-first`)
+
+Unlike `download_recursive`, which works one directory at a time, this sorts the files of all
+wanted directories together, so the most recent additions come first wherever they are.
+*/
+pub async fn download_newest_first(
+    root: &Node,
+    options: &CliOptions,
+    backend: &dyn DirectoryListing,
+    counters: &mut LimitCounts,
+    state: &mut StateStore,
+    observer: &dyn DownloadObserver,
+) -> Result<()> {
+    // If no download is desired, skip the download
+    if options.no_download {
+        info!("Skipped download");
+        return Ok(());
+    }
+
+    let mut files = vec![];
+    collect_files(root, options, &mut files)?;
+    order::sort_newest_first(&mut files, |(_, file)| file);
+
+    for (folder_path, file) in files {
+        if let Some(file_limit) = options.limit_count {
+            if counters.file_count >= file_limit {
+                info!("File limit reached at {} files", counters.file_count);
+                break;
+            }
+        }
+
+        if counters.writes_locally()
+            && !options.custom_layout()
+            && !create_folder(&folder_path, options).await?
+        {
+            continue;
+        }
+
+        download_files_to_dir(
+            &folder_path,
+            &vec![file],
+            backend,
+            Some(options),
+            Some(counters),
+            state,
+            observer,
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+/**
+Downloads the files which failed in earlier runs again, without crawling (see `--replay-failures`)
+
+The files are saved where the crawl would have put them, and leave the failed files of the state
+store once they're done. The ones which fail again stay there for the next replay.
+*/
+pub async fn download_failures(
+    options: &CliOptions,
+    backend: &dyn DirectoryListing,
+    counters: &mut LimitCounts,
+    state: &mut StateStore,
+    observer: &dyn DownloadObserver,
+) -> Result<()> {
+    let files = state.failed_files.clone();
+    info!("Retrying {} failed files", files.len());
+
+    download_listed_files(&files, options, backend, counters, state, observer).await
+}
+
+/**
+Downloads single files of the tree, without crawling (e.g. the ones to repair, see `--repair`)
+
+The files are saved where the crawl would have put them.
+*/
+pub async fn download_listed_files(
+    files: &[types::FileLinkMetaData],
+    options: &CliOptions,
+    backend: &dyn DirectoryListing,
+    counters: &mut LimitCounts,
+    state: &mut StateStore,
+    observer: &dyn DownloadObserver,
+) -> Result<()> {
+    for file in files {
+        observer.on_discovered(&Node::File(file.clone()));
+    }
+
+    let base_url = paths::parent_url(&options.url);
+    for file in files {
+        if let Some(file_limit) = options.limit_count {
+            if counters.file_count >= file_limit {
+                info!("File limit reached at {} files", counters.file_count);
+                break;
+            }
+        }
+
+        let dir_url = Url::from_str(&file.url)
+            .and_then(|url| url.join("."))
+            .with_context(|| format!("Invalid URL {}", file.url))?;
+        let folder_path = paths::url_to_local_path(&base_url, &dir_url, &options.destination);
+        if counters.writes_locally()
+            && !options.custom_layout()
+            && !create_folder(&folder_path, options).await?
+        {
+            continue;
+        }
+
+        download_files_to_dir(
+            &folder_path,
+            &vec![file],
+            backend,
+            Some(options),
+            Some(counters),
+            state,
+            observer,
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Collects the files of the wanted directories (along the local directory of each)
+fn collect_files<'a>(
+    node: &'a Node,
+    options: &CliOptions,
+    files: &mut Vec<(PathBuf, &'a types::FileLinkMetaData)>,
+) -> Result<()> {
+    if let Node::CrawledDir(meta, children) = node {
+        let server_path = meta.name.split('/').next_back().unwrap_or_default();
+        if !is_wanted_dir(server_path, options) {
+            return Ok(());
+        }
+
+        let base_url = paths::parent_url(&options.url);
+        let folder_path =
+            paths::url_to_local_path(&base_url, &Url::from_str(&meta.url)?, &options.destination);
+
+        for child in children {
+            match child {
+                Node::File(file) => files.push((folder_path.clone(), file)),
+                Node::CrawledDir(sub_meta, _) if within_depth(sub_meta.depth, options) => {
+                    collect_files(child, options, files)?
+                }
+                _ => (),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/**
+Replaces the sizes scraped from the listings by the ones the server reports (see `--head-sizes`)
+
+Only files which aren't downloaded yet are asked for, one request each. Files whose size the
+server doesn't report keep the scraped one. Returns the number of corrected sizes.
+*/
+pub async fn discover_sizes(
+    root: &mut Node,
+    backend: &dyn DirectoryListing,
+    downloaded_urls: &[String],
+) -> Result<u64> {
+    let downloaded: HashSet<&str> = downloaded_urls.iter().map(String::as_str).collect();
+
+    let mut files = vec![];
+    root.for_each_file_mut(&mut |file| {
+        if !downloaded.contains(file.url.as_str()) {
+            files.push(file.clone());
+        }
+    });
+
+    // Ask the server for the size of each file
+    let mut sizes = HashMap::new();
+    for file in &files {
+        match backend.file_size(file).await {
+            Ok(Some(size)) => {
+                sizes.insert(file.url.clone(), size);
+            }
+            Ok(None) => (),
+            Err(error) => debug!("(Head) No size of {}: {:#}", file.url, error),
+        }
+    }
+
+    let mut corrected = 0;
+    root.for_each_file_mut(&mut |file| {
+        if let Some(&size) = sizes.get(&file.url) {
+            if file.size_bytes != Some(size) {
+                debug!(
+                    "(Head) Size of {} is {} (not {})",
+                    file.url, size, file.size
+                );
+                corrected += 1;
+            }
+            file.size_bytes = Some(size);
+            file.size = size.to_string();
+        }
+    });
+
+    Ok(corrected)
+}
+
+/**
+Crawls the tree below the root URL and downloads the files as they are discovered
+
+Unlike crawling the whole tree first, the tree is never kept in memory: the crawl hands the
+nodes over through a bounded channel (so it pauses while the download falls behind), and each
+file is dropped once it's downloaded. The state store only records the downloaded files.
+*/
+pub async fn download_stream(
+    url: &Url,
+    options: &CliOptions,
+    backend: &dyn DirectoryListing,
+    counters: &mut LimitCounts,
+    state: &mut StateStore,
+    observer: &dyn DownloadObserver,
+) -> Result<()> {
+    let (sender, receiver) = mpsc::channel(STREAM_CAPACITY);
+
+    // Stops once everything is crawled or the download stopped listening
+    let crawl = async move {
+        let budget =
+            CrawlBudget::new(options.max_dirs, options.max_nodes).with_max_files(options.preview);
+        let excludes = DirExcludes::new(options.exclude_dirs.clone(), url);
+        let mut nodes = Box::pin(crawl_stream(
+            url,
+            backend,
+            excludes,
+            options.follow_symlinks,
+            budget,
+            observer,
+        ));
+        while let Some(node) = nodes.next().await {
+            if sender.send(node).await.is_err() {
+                break;
+            }
+        }
+    };
+
+    let download = async {
+        // Owned by the download, so the crawl stops when the download does
+        let mut receiver = receiver;
+        let base_url = paths::parent_url(&options.url);
+
+        // The local directory of the files being received (None if unwanted), and its URL
+        let mut folder: Option<(PathBuf, String)> = None;
+
+        // Whether the local directory exists (it's only created for its first file, unless
+        // empty directories are wanted as well)
+        let mut folder_created = false;
+
+        // The URLs of unwanted directories (so their sub-directories get skipped as well)
+        let mut skipped_dirs: Vec<String> = vec![];
+
+        while let Some(node) = receiver.recv().await {
+            let node = match node {
+                Ok(node) => node,
+                Err(error) if options.skip_errors => {
+                    warn!("(Error) Skip directory: {:#}", error);
+                    continue;
+                }
+                Err(error)
+                    if options.continue_on_parse_error && not_a_listing(&error).is_some() =>
+                {
+                    warn!("(Unparseable) Skip directory: {:#}", error);
+                    if let Some(directory) = not_a_listing(&error) {
+                        counters.report.unparsed_dirs.push(directory.url.clone());
+                    }
+                    continue;
+                }
+                Err(error) => return Err(error),
+            };
+
+            match node {
+                Node::CrawledDir(meta, _) => {
+                    // Skip unwanted folders (and everything below them)
+                    let server_path = meta.name.split('/').next_back().unwrap_or_default();
+                    if skipped_dirs
+                        .iter()
+                        .any(|url| meta.url.starts_with(url.as_str()))
+                        || !is_wanted_dir(server_path, options)
+                        || !within_depth(meta.depth, options)
+                    {
+                        skipped_dirs.push(meta.url);
+                        folder = None;
+                        continue;
+                    }
+
+                    let path = paths::url_to_local_path(
+                        &base_url,
+                        &Url::from_str(&meta.url)?,
+                        &options.destination,
+                    );
+                    // Empty directories are only created if desired, the others for their
+                    // first file
+                    folder_created = false;
+                    if options.include_empty_dirs
+                        && !ensure_folder(&path, &mut folder_created, options, counters).await?
+                    {
+                        skipped_dirs.push(meta.url);
+                        folder = None;
+                        continue;
+                    }
+                    folder = Some((path, meta.url));
+                }
+                Node::File(file) => {
+                    if let Some((folder_path, url)) = &folder {
+                        if !ensure_folder(folder_path, &mut folder_created, options, counters)
+                            .await?
+                        {
+                            skipped_dirs.push(url.clone());
+                            folder = None;
+                            continue;
+                        }
+
+                        download_files_to_dir(
+                            folder_path,
+                            &vec![&file],
+                            backend,
+                            Some(options),
+                            Some(&mut *counters),
+                            state,
+                            observer,
+                        )
+                        .await?;
+                    }
+                }
+                Node::PendingDir(_) => (),
+            }
+
+            // Stop crawling once the download limit is reached
+            if let Some(file_limit) = options.limit_count {
+                if counters.file_count >= file_limit {
+                    info!("File limit reached at {} files", counters.file_count);
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    };
+
+    let ((), res) = tokio::join!(crawl, download);
+
+    res
+}
+
+/**
+Aborts the run once too many files failed (see `--max-failures` & `--consecutive-failures`)
+
+Without it, a server which fails every request would have each of its files retried (and
+skipped) in turn.
+*/
+fn check_failures(options: &CliOptions, counters: &LimitCounts) -> Result<()> {
+    let failed = counters.report.failed.len() as u64;
+
+    if let Some(max_failures) = options.max_failures {
+        if failed >= max_failures {
+            bail!(
+                "Aborted after {} failed files (--max-failures {}), the server seems to be failing",
+                failed,
+                max_failures
+            );
+        }
+    }
+
+    match options.consecutive_failures {
+        Some(max_in_a_row) if counters.failures_in_a_row >= max_in_a_row => bail!(
+            "Aborted after {} files failed in a row (--consecutive-failures {}), the server seems to be failing",
+            counters.failures_in_a_row,
+            max_in_a_row
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// Persists the state store (if one is used) once the save interval has elapsed
+fn autosave(
+    state: &mut StateStore,
+    options: &CliOptions,
+    counters: &mut LimitCounts,
+) -> Result<()> {
+    let state_path = match &options.state_store_path {
+        Some(state_path) => state_path,
+        None => return Ok(()),
+    };
+
+    counters.unsaved_files += 1;
+
+    let due = match options.save_interval {
+        SaveInterval::Files(files) => counters.unsaved_files >= files,
+        SaveInterval::Time(interval) => counters.last_save.elapsed() >= interval,
+    };
+
+    if due {
+        state.save(state_path)?;
+        debug!("Saved progress to {}", state_path);
+
+        counters.unsaved_files = 0;
+        counters.last_save = Instant::now();
+    }
+
+    Ok(())
+}
+
+/**
+Downloads a single file (via a temporary `.od-part` file, see `save_file`)
+
+When the disk runs full, the temporary file is removed and the error becomes a `DiskFull` one.
+*/
+async fn download_file(
+    file: &types::FileLinkMetaData,
+    file_path: &Path,
+    backend: &dyn DirectoryListing,
+    observer: &dyn DownloadObserver,
+    validators: Option<&Validators>,
+    options: Option<&CliOptions>,
+) -> Result<Option<(u64, Validators)>> {
+    let temp_dir = options.and_then(|options| options.temp_dir.as_deref());
+    let part_suffix = options.map_or(PART_EXTENSION, |options| &options.part_suffix);
+    let part_path = part_path(file_path, temp_dir, part_suffix);
+
+    // Start over when the server stalls (the part file gets truncated)
+    let mut retries = 0;
+    let result = loop {
+        let result = save_file(
+            file, file_path, &part_path, backend, observer, validators, options,
+        )
+        .await;
+
+        match result {
+            Err(error) if is_timeout(&error) && retries < MAX_TIMEOUT_RETRIES => {
+                retries += 1;
+                info!(
+                    "(Timeout) {:#}, trying again ({}/{})",
+                    error, retries, MAX_TIMEOUT_RETRIES
+                );
+                observer.on_retry(file, &error);
+            }
+            result => break result,
+        }
+    };
+
+    match result {
+        Err(error) if is_out_of_space(&error) => {
+            // An incomplete download is of no use (the next run starts it over)
+            if let Err(remove_error) = fs::remove_file(&part_path).await {
+                warn!("Cannot remove {}: {}", part_path.display(), remove_error);
+            }
+
+            Err(check_disk_full(error, &file.url, file_path))
+        }
+        result => result,
+    }
+}
+
+/**
+Downloads a single file into its temporary file, and moves it into place once complete
+
+Returns its size in bytes and its validators, or None if it didn't change since the download the
+given validators stem from (the local copy is kept then). With `--emit-checksums`, the file is
+hashed while it's written and the digest is saved as a sidecar. With `--xattrs`, the source URL
+and the SHA-256 digest are stored as extended attributes of the file. With `--extract`, archives
+are extracted next to themselves afterwards (and deleted then, with `--extract-delete`).
+*/
+async fn save_file(
+    file: &types::FileLinkMetaData,
+    file_path: &Path,
+    part_path: &Path,
+    backend: &dyn DirectoryListing,
+    observer: &dyn DownloadObserver,
+    validators: Option<&Validators>,
+    options: Option<&CliOptions>,
+) -> Result<Option<(u64, Validators)>> {
+    let checksum = options.and_then(|options| options.emit_checksums);
+    let xattrs = options.is_some_and(|options| options.xattrs);
+    let part_suffix = options.map_or(PART_EXTENSION, |options| &options.part_suffix);
+
+    // Download large files in several parts at once (if desired and the server supports it)
+    let segmented = match options.and_then(|options| options.segments) {
+        Some(segments) if validators.is_none() => {
+            save_segments(file, part_path, backend, observer, segments).await?
+        }
+        _ => false,
+    };
+
+    let (validators, digest, sha256) = if segmented {
+        // The parts arrived out of order, so the file only gets hashed once complete
+        let digest = match checksum {
+            Some(kind) => Some(hash_part(part_path, kind).await?),
+            None => None,
+        };
+        let sha256 = match (xattrs, checksum, &digest) {
+            (false, _, _) => None,
+            (true, Some(ChecksumKind::Sha256), Some(digest)) => Some(digest.clone()),
+            (true, _, _) => Some(hash_part(part_path, ChecksumKind::Sha256).await?),
+        };
+
+        (Validators::default(), digest, sha256)
+    } else {
+        // Write through a memory map if desired (and the size is known from the listing or a HEAD)
+        let mmap_size = match options {
+            Some(options) if options.mmap => match expected_size(file) {
+                Some((size, precision)) => Some(size + precision),
+                None => backend.file_size(file).await.unwrap_or_else(|error| {
+                    debug!("(Mmap) No size of {}: {:#}", file.url, error);
+                    None
+                }),
+            },
+            _ => None,
+        };
+
+        // Download into a temporary file first, so an interrupted download is never mistaken
+        // for a complete one. Use Tokio to open it.
+        let mut file_handle = PartFile::create(part_path, mmap_size)
+            .await
+            .with_context(|| format!("Cannot create {}", part_path.display()))?;
+
+        // Let the backend write the file to disk (hashing it on the way, if desired). The
+        // attributes need a SHA-256 digest, so it's taken in addition to an MD5 sidecar.
+        let (fetched, digest, sha256) = match (checksum, xattrs) {
+            (Some(ChecksumKind::Md5), true) => {
+                let mut md5 = HashingWriter::new(&mut file_handle, ChecksumKind::Md5);
+                let mut sha256 = HashingWriter::new(&mut md5, ChecksumKind::Sha256);
+                let fetched = backend
+                    .fetch(file, validators, &mut sha256, observer)
+                    .await?;
+                let sha256 = sha256.finish();
+
+                (fetched, Some(md5.finish()), Some(sha256))
+            }
+            (None, true) => {
+                let mut hashing = HashingWriter::new(&mut file_handle, ChecksumKind::Sha256);
+                let fetched = backend
+                    .fetch(file, validators, &mut hashing, observer)
+                    .await?;
+
+                (fetched, None, Some(hashing.finish()))
+            }
+            (Some(kind), _) => {
+                let mut hashing = HashingWriter::new(&mut file_handle, kind);
+                let fetched = backend
+                    .fetch(file, validators, &mut hashing, observer)
+                    .await?;
+                let digest = hashing.finish();
+                let sha256 = (kind == ChecksumKind::Sha256 && xattrs).then(|| digest.clone());
+
+                (fetched, Some(digest), sha256)
+            }
+            (None, false) => {
+                let fetched = backend
+                    .fetch(file, validators, &mut file_handle, observer)
+                    .await?;
+
+                (fetched, None, None)
+            }
+        };
+
+        let validators = match fetched {
+            Fetched::Modified(validators) => validators,
+            Fetched::NotModified => {
+                drop(file_handle);
+                fs::remove_file(part_path).await?;
+                return Ok(None);
+            }
+        };
+        file_handle.finish().await?;
+
+        (validators, digest, sha256)
+    };
+
+    // Move the complete file into place (unless it turned out incomplete)
+    check_size(file, fs::metadata(part_path).await?.len())?;
+    move_into_place(part_path, file_path, part_suffix)
+        .await
+        .with_context(|| format!("Cannot move the download to {}", file_path.display()))?;
+
+    if let (Some(kind), Some(digest)) = (checksum, digest) {
+        fs::write(
+            checksum::checksum_path(file_path, kind),
+            checksum::sidecar_line(&digest, file_path),
+        )
+        .await?;
+    }
+
+    if let Some(sha256) = sha256 {
+        xattrs::tag(file_path, &file.url, &sha256);
+    }
+
+    // Take over the remote date, so the next --only-newer run (or snapshot) can compare it
+    if options.is_some_and(|options| options.only_newer || options.snapshot) {
+        if let Some(remote) = parse_date(&file.last_modified) {
+            filetime::set_file_mtime(file_path, FileTime::from_unix_time(remote.timestamp(), 0))?;
+        }
+    }
+
+    let bytes = fs::metadata(file_path).await?.len();
+
+    if let Some(options) = options.filter(|options| options.extract) {
+        let archive = file_path.to_owned();
+        let extracted = tokio::task::spawn_blocking(move || extract::extract(&archive)).await??;
+
+        if let Some(target) = extracted {
+            debug!(
+                "Extracted {} into {}",
+                file_path.display(),
+                target.display()
+            );
+            if options.extract_delete {
+                fs::remove_file(file_path).await?;
+            }
+        }
+    }
+
+    Ok(Some((bytes, validators)))
+}
+
+/**
+Downloads a file in several ranges at once, each one written into its place of the pre-allocated
+part file (see `--segments`)
+
+Only files of a known size of at least two segments get split (asking the server with a HEAD
+request, as the listing may only tell the rough size). Returns false if the file should be
+downloaded in one piece instead, e.g. as the server doesn't support ranges.
+*/
+async fn save_segments(
+    file: &types::FileLinkMetaData,
+    part_path: &Path,
+    backend: &dyn DirectoryListing,
+    observer: &dyn DownloadObserver,
+    segments: u64,
+) -> Result<bool> {
+    let size = match backend.file_size(file).await {
+        Ok(Some(size)) => size,
+        Ok(None) => return Ok(false),
+        Err(error) => {
+            debug!("(Segments) No size of {}: {:#}", file.url, error);
+            return Ok(false);
+        }
+    };
+    let segments = segments.min(size / MIN_SEGMENT_SIZE);
+    if segments < 2 {
+        return Ok(false);
+    }
+
+    let mut writer = MmapWriter::create(part_path, size)
+        .with_context(|| format!("Cannot create {}", part_path.display()))?;
+    observer.on_file_start(file, Some(size));
+    debug!("(Segments) Downloading {} in {} parts", file.url, segments);
+
+    // Split the file into (nearly) equal parts, the last one taking the rest
+    let segment_size = size.div_ceil(segments);
+    let downloads = writer
+        .whole_mut()
+        .chunks_mut(segment_size as usize)
+        .enumerate()
+        .map(|(index, slice)| async move {
+            let start = index as u64 * segment_size;
+            let length = slice.len() as u64;
+            let range = ByteRange {
+                start,
+                end: Some(start + length - 1),
+            };
+
+            let mut slice_writer =
+                SliceWriter::new(slice, |bytes| observer.on_progress(file, bytes));
+            let bytes = backend.fetch_range(file, range, &mut slice_writer).await?;
+            if bytes != length {
+                bail!(
+                    "Cannot download {}: the range {} broke off after {} bytes",
+                    file.url,
+                    range.header_value(),
+                    bytes
+                );
+            }
+
+            Ok(())
+        });
+    let results = future::join_all(downloads).await;
+
+    match results.into_iter().find_map(Result::err) {
+        Some(error) if ranges_unsupported(&error).is_some() => {
+            info!("(Segments) {}, downloading it in one piece", error);
+            Ok(false)
+        }
+        Some(error) => Err(error),
+        None => {
+            writer
+                .finish()
+                .with_context(|| format!("Cannot write {}", part_path.display()))?;
+            Ok(true)
+        }
+    }
+}
+
+/// Calculates the digest of a (complete) part file
+async fn hash_part(part_path: &Path, kind: ChecksumKind) -> Result<String> {
+    let path = part_path.to_owned();
+    let digest = tokio::task::spawn_blocking(move || checksum::hash_file(&path, kind)).await??;
+
+    Ok(digest)
+}
+
+/**
+Streams a single file into a multipart upload to S3 and returns its size in bytes
+
+The upload gets aborted if either the download or the upload fails, so no partial file ends up
+in the bucket.
+*/
+async fn upload_file(
+    file: &types::FileLinkMetaData,
+    s3: &S3Target,
+    key: &str,
+    backend: &dyn DirectoryListing,
+    observer: &dyn DownloadObserver,
+) -> Result<u64> {
+    let upload = s3.start_upload(key).await?;
+
+    // Pipe the download into the upload
+    let (mut writer, reader) = io::duplex(PIPE_SIZE);
+    let download = async move {
+        backend.fetch(file, None, &mut writer, observer).await?;
+        writer.shutdown().await?;
+
+        Ok::<_, anyhow::Error>(())
+    };
+
+    let (downloaded, uploaded) = tokio::join!(download, upload.send_parts(reader));
+
+    match downloaded.and(uploaded) {
+        Ok((etags, bytes)) => {
+            if let Err(error) = check_size(file, bytes) {
+                upload.abort().await?;
+                return Err(error);
+            }
+
+            upload.complete(&etags).await?;
+            debug!("(S3) Uploaded {}", s3.url_of(key));
+
+            Ok(bytes)
+        }
+        Err(error) => {
+            if let Err(abort_error) = upload.abort().await {
+                warn!(
+                    "Cannot abort the upload to {}: {:#}",
+                    s3.url_of(key),
+                    abort_error
+                );
+            }
+
+            Err(error)
+        }
+    }
+}
+
+/**
+Downloads a single file into the archive and returns its size in bytes
+
+A tar entry starts with the size of the file, so the download is held back until it's complete:
+in memory if the listing says it's small, otherwise in a temporary file next to the archive. A
+download of the wrong size never makes it into the archive.
+*/
+async fn archive_file(
+    file: &types::FileLinkMetaData,
+    tar: &mut TarOutput,
+    name: &str,
+    backend: &dyn DirectoryListing,
+    observer: &dyn DownloadObserver,
+) -> Result<u64> {
+    let mtime = parse_date(&file.last_modified)
+        .map_or_else(|| chrono::Utc::now().timestamp(), |date| date.timestamp())
+        .max(0) as u64;
+
+    if file
+        .size_bytes
+        .is_some_and(|size| size <= tarball::MEMORY_LIMIT)
+    {
+        let mut buffer = vec![];
+        backend.fetch(file, None, &mut buffer, observer).await?;
+        let bytes = buffer.len() as u64;
+        check_size(file, bytes)?;
+
+        tar.append(name, &buffer[..], bytes, mtime)?;
+        return Ok(bytes);
+    }
+
+    let spool_path = tar.spool_path();
+    let mut spool = fs::File::create(&spool_path)
+        .await
+        .with_context(|| format!("Cannot create {}", spool_path.display()))?;
+    let fetched = backend.fetch(file, None, &mut spool, observer).await;
+    spool.flush().await?;
+    drop(spool);
+
+    let appended = async {
+        fetched?;
+        let bytes = fs::metadata(&spool_path).await?.len();
+        check_size(file, bytes)?;
+
+        tar.append(name, std::fs::File::open(&spool_path)?, bytes, mtime)?;
+        Ok(bytes)
+    }
+    .await;
+
+    fs::remove_file(&spool_path)
+        .await
+        .with_context(|| format!("Cannot remove {}", spool_path.display()))?;
+    appended
+}
+
+/**
+Checks a file against the file filters (--file-filter, --file-matcher, --min-size & --max-size)
+
+Returns why the file is unwanted, or None if it passes all filters.
+*/
+pub fn filter_file(
+    file: &types::FileLinkMetaData,
+    last_segment: &str,
+    options: &CliOptions,
+) -> Option<SkipReason> {
+    // Skip unwanted files
+    if let Some(regex) = &options.file_filter {
+        if regex.is_match(last_segment) {
+            debug!("(Filter) Skip file {}", last_segment);
+            return Some(SkipReason::Filtered);
+        }
+    }
+
+    // Only keep wanted files
+    if let Some(regex) = &options.file_matcher {
+        if !regex.is_match(last_segment) {
+            debug!("(Matcher) Skip file {}", last_segment);
+            return Some(SkipReason::NotMatched);
+        }
+    }
+
+    // Skip files outside of the size range (files of unknown size are always kept)
+    if let Some(size) = file.size_bytes {
+        let too_small = options.min_size.is_some_and(|min| size < min);
+        let too_large = options.max_size.is_some_and(|max| size > max);
+
+        if too_small || too_large {
+            debug!("(Size) Skip file {} ({})", last_segment, file.size);
+            return Some(SkipReason::SizeLimit);
+        }
+    }
+
+    // Skip files older than the cutoff (files with an unparseable date are always kept)
+    if let (Some(since), Some(date)) = (options.since, parse_date(&file.last_modified)) {
+        if date < since {
+            debug!(
+                "(Since) Skip file {} ({})",
+                last_segment, file.last_modified
+            );
+            return Some(SkipReason::TooOld);
+        }
+    }
+
+    None
+}
+
+/**
+Keeps the first n wanted files of a directory (see `--sample-per-dir`)
+
+Unwanted files don't count towards the sample (and are kept to be skipped for their own reason).
+*/
+fn sample_files<'a>(
+    files: Vec<&'a types::FileLinkMetaData>,
+    sample: u64,
+    options: &CliOptions,
+    counters: &mut LimitCounts,
+    observer: &dyn DownloadObserver,
+) -> Vec<&'a types::FileLinkMetaData> {
+    let mut sampled = 0;
+
+    files
+        .into_iter()
+        .filter(|file| {
+            let url = Url::parse(&file.url).ok();
+            let last_segment = url.as_ref().map(get_last_segment).unwrap_or_default();
+            if filter_file(file, last_segment, options).is_some() {
+                return true;
+            }
+
+            if sampled < sample {
+                sampled += 1;
+                return true;
+            }
+
+            debug!("(Sample) Skip file {}", last_segment);
+            counters.report.add_skipped(&file.url, SkipReason::Sampled);
+            observer.on_file_skipped(file, SkipReason::Sampled);
+            false
+        })
+        .collect()
+}
+
+/// Checks if a Content-Type matches one of the accepted MIME types (see `--accept-type`)
+///
+/// The types may end in a wildcard (e.g. `image/*`). Parameters like `; charset=utf-8` are ignored.
+fn is_accepted_type(content_type: &str, accepted: &[String]) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+
+    accepted
+        .iter()
+        .any(|pattern| match pattern.strip_suffix('*') {
+            Some("*/") => true,
+            Some(prefix) => mime.starts_with(prefix),
+            None => &mime == pattern,
+        })
+}
+
+/// Checks if a directory is within the recursion limit (--recursive-depth)
+pub fn within_depth(depth: u64, options: &CliOptions) -> bool {
+    options
+        .recursion_limit
+        .is_none_or(|recursion_limit| depth < recursion_limit)
+}
+
+/**
+Checks a directory (by its name from the server) against --path-filter & --path-matcher
+*/
+pub fn is_wanted_dir(server_path: &str, options: &CliOptions) -> bool {
+    // Skip unwanted folders
+    if let Some(regex) = &options.path_filter {
+        if regex.is_match(server_path) {
+            debug!("(Filter) Skip directory {}", server_path);
+            return false;
+        }
+    }
+
+    // Only keep wanted folders
+    if let Some(regex) = &options.path_matcher {
+        if !regex.is_match(server_path) {
+            debug!("(Matcher) Skip directory {}", server_path);
+            return false;
+        }
+    }
+
+    true
+}
+
+/**
+Returns the path of a file named after its path below the root (for `--path-as-name`)
+
+The segments of the path (e.g. `sub/dir/file.txt`) get joined with the separator (e.g. to
+`sub__dir__file.txt`), so all files end up in the destination without colliding.
+*/
+fn path_as_name(
+    folder_path: &Path,
+    file_name: &str,
+    options: &CliOptions,
+    separator: &str,
+) -> PathBuf {
+    let root_path = paths::url_to_local_path(
+        &paths::parent_url(&options.url),
+        &options.url,
+        &options.destination,
+    );
+
+    let mut segments: Vec<String> = folder_path
+        .strip_prefix(&root_path)
+        .unwrap_or(Path::new(""))
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy().into_owned())
+        .collect();
+    segments.push(file_name.to_owned());
+
+    options.destination.join(fit_name(
+        segments.join(separator),
+        options.max_filename_length,
+    ))
+}
+
+/// Returns the path of a file as given by the --path-template
+fn template_path(
+    folder_path: &Path,
+    file: &types::FileLinkMetaData,
+    file_name: &str,
+    options: &CliOptions,
+) -> PathBuf {
+    let template = match &options.path_template {
+        Some(template) => template,
+        None => return folder_path.join(file_name),
+    };
+
+    // The directory as in the default layout (relative to the destination)
+    let dir = folder_path
+        .strip_prefix(&options.destination)
+        .unwrap_or(Path::new(""))
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+
+    let url = Url::from_str(&file.url).ok();
+    let values = TemplateValues {
+        host: url.as_ref().and_then(Url::host_str).unwrap_or_default(),
+        dir: &dir,
+        name: file_name,
+        last_modified: &file.last_modified,
+    };
+
+    template.render(&values, &options.destination)
+}
+
+/**
+Returns the path of the temporary file a file is downloaded to
+
+It's next to the file, or in the `temp_dir` (see `--temp-dir`). The names in there are prefixed
+with a hash of the path, so files of the same name from different directories don't collide.
+Either way the name ends in the `suffix` (see `--part-suffix`), and is the same on every run.
+*/
+pub fn part_path(file_path: &Path, temp_dir: Option<&Path>, suffix: &str) -> PathBuf {
+    let temp_dir = match temp_dir {
+        Some(temp_dir) => temp_dir,
+        None => {
+            let mut path = file_path.as_os_str().to_owned();
+            path.push(suffix);
+
+            return PathBuf::from(path);
+        }
+    };
+
+    let mut hasher = DefaultHasher::new();
+    file_path.hash(&mut hasher);
+    let name = file_path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+
+    temp_dir.join(format!("{:016x}-{}{}", hasher.finish(), name, suffix))
+}
+
+/**
+Moves a complete download from its temporary file to its final path
+
+A temporary file on another filesystem (see `--temp-dir`) can't be renamed, so it's copied next to
+the final path first. This way the file still appears at its final path at once.
+*/
+async fn move_into_place(part_path: &Path, file_path: &Path, suffix: &str) -> io::Result<()> {
+    match fs::rename(part_path, file_path).await {
+        Err(error) if error.kind() == io::ErrorKind::CrossesDevices => {
+            let local_part_path = self::part_path(file_path, None, suffix);
+            fs::copy(part_path, &local_part_path).await?;
+            fs::rename(&local_part_path, file_path).await?;
+            fs::remove_file(part_path).await
+        }
+        result => result,
+    }
+}
+
+/**
+Creates the local directory of streamed files, unless it was `created` already
+
+Returns false if the files of the folder are to be skipped.
+*/
+async fn ensure_folder(
+    path: &Path,
+    created: &mut bool,
+    options: &CliOptions,
+    counters: &LimitCounts,
+) -> Result<bool> {
+    if !*created
+        && counters.writes_locally()
+        && !options.custom_layout()
+        && !create_folder(path, options).await?
+    {
+        return Ok(false);
+    }
+    *created = true;
+
+    Ok(true)
+}
+
+/**
+Creates the local directory of a folder, making way for it (see `--on-conflict`)
+
+Returns false if the files of the folder are to be skipped.
+*/
+async fn create_folder(path: &Path, options: &CliOptions) -> Result<bool> {
+    if !conflicts::resolve_dir(path, &options.destination, options.on_conflict)? {
+        return Ok(false);
+    }
+
+    create_dir(path).await?;
+    Ok(true)
+}
+
+/// Creates a local directory (and its parents, if they don't exist yet)
+async fn create_dir(path: &Path) -> Result<()> {
+    fs::create_dir_all(path)
+        .await
+        .with_context(|| format!("Cannot create the directory {}", path.display()))
+}
+
+/// Returns the path of the description sidecar of a file
+pub fn description_path(file_path: &Path) -> PathBuf {
+    let mut path = file_path.as_os_str().to_owned();
+    path.push(DESCRIPTION_EXTENSION);
+
+    PathBuf::from(path)
+}
+
+/// Returns the path of a file below the destination, separated by `/` (for S3 keys and archives)
+fn relative_name(file_path: &Path, destination: &Path) -> String {
+    let relative = file_path.strip_prefix(destination).unwrap_or(file_path);
+
+    relative
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/**
+Checks if the local copy of a file is at least as new as the remote one
+
+If the remote date can't be parsed, the local copy counts as up to date if it has the same size.
+*/
+fn is_up_to_date(file: &types::FileLinkMetaData, file_path: &Path) -> bool {
+    let metadata = match std::fs::metadata(file_path) {
+        Ok(metadata) => metadata,
+        Err(_) => return false,
+    };
+
+    match parse_date(&file.last_modified) {
+        Some(remote) => {
+            FileTime::from_last_modification_time(&metadata).unix_seconds() >= remote.timestamp()
+        }
+        None => file.size_bytes == Some(metadata.len()),
+    }
+}
+
+/**
+Hardlinks a file from an earlier snapshot if it is unchanged (see `--link-dest`)
+
+The file counts as unchanged if the copy at the same path of the earlier snapshot has the size
+& date of the listing. Returns false if the file has to be downloaded, e.g. because the listing
+knows neither or the snapshot is on another filesystem.
+*/
+async fn link_unchanged(
+    file: &types::FileLinkMetaData,
+    file_path: &Path,
+    destination: &Path,
+    link_dest: &Path,
+) -> Result<bool> {
+    let earlier = match file_path.strip_prefix(destination) {
+        Ok(relative) => link_dest.join(relative),
+        Err(_) => return Ok(false),
+    };
+    let metadata = match fs::metadata(&earlier).await {
+        Ok(metadata) if metadata.is_file() => metadata,
+        _ => return Ok(false),
+    };
+
+    let expected_size = expected_size(file);
+    let remote_date = parse_date(&file.last_modified);
+    if expected_size.is_none() && remote_date.is_none() {
+        return Ok(false);
+    }
+    if let Some((expected, precision)) = expected_size {
+        if metadata.len().abs_diff(expected) >= precision {
+            return Ok(false);
+        }
+    }
+    if let Some(remote) = remote_date {
+        if FileTime::from_last_modification_time(&metadata).unix_seconds() != remote.timestamp() {
+            return Ok(false);
+        }
+    }
+
+    if let Some(parent) = file_path.parent() {
+        create_dir(parent).await?;
+    }
+    match fs::hard_link(&earlier, file_path).await {
+        Ok(()) => Ok(true),
+        Err(error) => {
+            debug!("(Snapshot) Cannot link {}: {}", earlier.display(), error);
+            Ok(false)
+        }
+    }
+}
+
+/**
+Turns the (encoded) last segment of a URL into a safe file name, shortened to `max_length` bytes
+(if given, see `--max-filename-length`)
+*/
+pub fn local_name(last_segment: &str, max_length: Option<usize>) -> String {
+    let name = paths::sanitize_segment(&decode_filename(last_segment))
+        .unwrap_or_else(|| "unknown_segment".to_owned());
+
+    fit_name(name, max_length)
+}
+
+/**
+Shortens a file name to `max_length` bytes (if given), leaving room for the suffixes of the files
+next to it (like `.description`), so these fit as well
+*/
+fn fit_name(name: String, max_length: Option<usize>) -> String {
+    match max_length {
+        Some(max_length) => paths::truncate_name(&name, max_length - DESCRIPTION_EXTENSION.len()),
+        None => name,
+    }
+}
+
 /// Returns a reference to the last segment of a given URL as a &str
-fn get_last_segment(url: &Url) -> &str {
+pub fn get_last_segment(url: &Url) -> &str {
     url.path_segments()
-        .and_then(|segments| segments.last())
+        .and_then(|mut segments| segments.next_back())
         .and_then(|name| if name.is_empty() { None } else { Some(name) })
-        .unwrap_or_else(|| {
-            // TODO this might need to be fixed
-            // (url.clone().path_segments_mut().unwrap().pop_if_empty());
-            // get_last_segment(url);
-            return "unknown_segment";
-        })
+        // TODO this might need to be fixed
+        // (url.clone().path_segments_mut().unwrap().pop_if_empty());
+        // get_last_segment(url);
+        .unwrap_or("unknown_segment")
 
     // TODO Maybe provide a fallback
     // See https://rust-lang-nursery.github.io/rust-cookbook/web/clients/download.html
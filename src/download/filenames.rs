@@ -0,0 +1,116 @@
+use lazy_static::lazy_static;
+use percent_encoding::percent_decode_str;
+
+lazy_static! {
+    /// The ways of encoding file names which are tried, in order (see `decode_filename`)
+    static ref DECODINGS: Vec<Box<dyn FilenameDecoding>> = default_decodings();
+}
+
+/**
+Decodes a file name encoded in one particular way
+
+Servers encode names in listings (and URLs) differently, so each way gets its own implementation
+(see `default_decodings` for the ones tried by default).
+*/
+pub trait FilenameDecoding: Send + Sync {
+    /// Returns the decoded name, or None if the name isn't encoded this way
+    fn decode(&self, raw: &str) -> Option<String>;
+}
+
+/**
+Decodes extended values as of RFC 5987 (e.g. `UTF-8''%E2%82%AC.txt`)
+
+A leading `filename*=` (as in a `Content-Disposition` header) is accepted as well. Only UTF-8 and
+ISO-8859-1 are supported, which are the charsets the RFC requires.
+*/
+#[derive(Debug, Default)]
+pub struct ExtendedValue;
+
+impl FilenameDecoding for ExtendedValue {
+    fn decode(&self, raw: &str) -> Option<String> {
+        let raw = raw.strip_prefix("filename*=").unwrap_or(raw);
+        let mut parts = raw.splitn(3, '\'');
+        let (charset, _language, value) = (parts.next()?, parts.next()?, parts.next()?);
+
+        let bytes: Vec<u8> = percent_decode_str(value).collect();
+        if charset.eq_ignore_ascii_case("UTF-8") {
+            String::from_utf8(bytes).ok()
+        } else if charset.eq_ignore_ascii_case("ISO-8859-1") {
+            Some(latin1(&bytes))
+        } else {
+            None
+        }
+    }
+}
+
+/**
+Decodes percent-encoded names (e.g. `%D0%BF%D1%80%D0%B8.txt`), and names which aren't encoded at all
+
+Decoded bytes which aren't valid UTF-8 are taken as ISO-8859-1, as older servers still use it.
+*/
+#[derive(Debug, Default)]
+pub struct PercentEncoding;
+
+impl FilenameDecoding for PercentEncoding {
+    fn decode(&self, raw: &str) -> Option<String> {
+        let bytes: Vec<u8> = percent_decode_str(raw).collect();
+
+        Some(String::from_utf8(bytes).unwrap_or_else(|error| latin1(error.as_bytes())))
+    }
+}
+
+/// Returns the ways of encoding file names which are tried by default
+pub fn default_decodings() -> Vec<Box<dyn FilenameDecoding>> {
+    vec![Box::new(ExtendedValue), Box::new(PercentEncoding)]
+}
+
+/**
+Decodes a file name from a listing or URL
+
+Both the name shown in listings and the name of the local file go through this, so they match.
+*/
+pub fn decode_filename(raw: &str) -> String {
+    DECODINGS
+        .iter()
+        .find_map(|decoding| decoding.decode(raw))
+        .unwrap_or_else(|| raw.to_owned())
+}
+
+/// Decodes ISO-8859-1 (whose bytes are the first 256 code points)
+fn latin1(bytes: &[u8]) -> String {
+    bytes.iter().map(|&byte| char::from(byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_percent_encoded_names() {
+        assert_eq!(
+            decode_filename("%D0%BF%D1%80%D0%B8%D0%B2%D0%B5%D1%82.txt"),
+            "привет.txt"
+        );
+        assert_eq!(decode_filename("caf%E9.txt"), "café.txt");
+    }
+
+    #[test]
+    fn decodes_extended_values() {
+        assert_eq!(decode_filename("filename*=UTF-8''%E2%82%AC.txt"), "€.txt");
+        assert_eq!(decode_filename("UTF-8'ru'%D0%B4%D0%B0.txt"), "да.txt");
+        assert_eq!(decode_filename("ISO-8859-1''caf%E9.txt"), "café.txt");
+    }
+
+    #[test]
+    fn keeps_plain_names() {
+        assert_eq!(decode_filename("readme.txt"), "readme.txt");
+        assert_eq!(decode_filename("привет.txt"), "привет.txt");
+    }
+
+    #[test]
+    fn decodes_both_forms_of_a_name_alike() {
+        let encoded = "%D0%B4%D0%B0%20%D0%BD%D0%B5%D1%82.txt";
+
+        assert_eq!(decode_filename(encoded), decode_filename("да нет.txt"));
+    }
+}
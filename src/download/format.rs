@@ -0,0 +1,109 @@
+use regex::Regex;
+use reqwest::Url;
+use std::fmt;
+
+/// How the rows of a listing are told to be directories (see `--dir-detection`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DirDetection {
+    /// Either by a link ending with a slash or by a missing size
+    #[default]
+    Auto,
+
+    /// Only by a link ending with a slash (for servers which show sizes of directories)
+    TrailingSlash,
+
+    /// Only by a missing size (for servers which link directories without a trailing slash)
+    EmptySize,
+}
+
+/// The layout of the listings of a server, which is detected unless assumed (see `--assume-format`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListingFormat {
+    /// A table with a row per entry (like Apache's `mod_autoindex`)
+    Apache,
+
+    /// A `<pre>` block with a link, the date & the size per line (like nginx's `autoindex`)
+    Nginx,
+
+    /// A `<pre>` block with the date, the size & a link per line, without a heading
+    Iis,
+
+    /// A JSON array of entries (like Caddy's `file_server browse`)
+    CaddyJson,
+}
+
+impl fmt::Display for ListingFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Apache => write!(f, "apache"),
+            Self::Nginx => write!(f, "nginx"),
+            Self::Iis => write!(f, "iis"),
+            Self::CaddyJson => write!(f, "caddy-json"),
+        }
+    }
+}
+
+/// The groups a custom row pattern must capture (`date`, `size` & `desc` are optional)
+pub const REQUIRED_GROUPS: [&str; 2] = ["href", "name"];
+
+/**
+Describes how the listings of a server are laid out
+
+Apache shows a `-` in the size column of directories, but other servers show sizes (e.g.
+`4.0K`), other dashes, or nothing at all. The defaults fit most servers.
+
+Listings which aren't tables at all can be parsed with a custom pattern instead, which gets
+matched against the whole page (see `--row-pattern`). Each match is a row, with the values in the
+named groups `href`, `name`, `date`, `size` & `desc`.
+*/
+#[derive(Debug, Clone, Default)]
+pub struct ServerFormat {
+    pub dir_detection: DirDetection,
+    pub row_pattern: Option<Regex>,
+
+    /// Fail on rows which look like entries, but can't be parsed (instead of leaving them out)
+    pub strict_html: bool,
+
+    /// Only take links relative to the listing as entries (see `--relative-only`)
+    pub relative_only: bool,
+
+    /// Parse every listing in this format instead of detecting it (see `--assume-format`)
+    pub assumed: Option<ListingFormat>,
+
+    /// Fail on listings larger than this, so an endless page can't use up the memory (see
+    /// `ListingTooLarge`)
+    pub max_listing_size: Option<u64>,
+}
+
+impl ServerFormat {
+    /// Checks if a row of a listing (with the given link & size cell) is a directory
+    pub fn is_dir(&self, href: &Url, size: &str) -> bool {
+        let slash = href.path().ends_with('/');
+
+        match self.dir_detection {
+            DirDetection::Auto => slash || is_empty_size(size),
+            DirDetection::TrailingSlash => slash,
+            DirDetection::EmptySize => is_empty_size(size),
+        }
+    }
+}
+
+/**
+Checks if a link is relative to the listing it's in (e.g. `sub/` or `file.txt`)
+
+Links with a leading slash (e.g. `/other/`) or a scheme (e.g. `https://cdn.example.com/file`) point
+elsewhere, even if they happen to end up below the listing.
+*/
+pub fn is_relative_href(href: &str) -> bool {
+    let href = href.trim();
+
+    !href.starts_with('/') && !href.starts_with('\\') && Url::parse(href).is_err()
+}
+
+/// Checks if a size cell is empty, only a dash (e.g. `-`, `--` or `—`) or the `<dir>` of IIS
+fn is_empty_size(size: &str) -> bool {
+    size.trim().eq_ignore_ascii_case("<dir>")
+        || size
+            .chars()
+            .all(|c| c.is_whitespace() || matches!(c, '-' | '\u{2013}' | '\u{2014}'))
+}
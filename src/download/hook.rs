@@ -0,0 +1,64 @@
+use anyhow::{anyhow, Context, Result};
+use log::warn;
+use std::{path::Path, process::Command};
+
+/// A command run by the shell of the system (`sh -c`, or `cmd /C` on Windows)
+pub fn shell(command: &str) -> Command {
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    shell.arg(command);
+
+    shell
+}
+
+/// Refers to an environment variable, taken literally by the shell (whatever its value)
+fn variable(name: &str) -> String {
+    if cfg!(windows) {
+        format!("\"%{}%\"", name)
+    } else {
+        format!("\"${}\"", name)
+    }
+}
+
+/**
+Runs the hook of a downloaded file (see `--exec`)
+
+The values of the file are passed as environment variables (`OD_GET_PATH`, `OD_GET_URL` &
+`OD_GET_SIZE`), which `{path}`, `{url}` & `{size}` in the command refer to. So names chosen by the
+server never become part of the command. A hook failing is only logged, unless `fail` is set.
+*/
+pub async fn run_hook(command: &str, path: &Path, url: &str, size: u64, fail: bool) -> Result<()> {
+    let command = command
+        .replace("{path}", &variable("OD_GET_PATH"))
+        .replace("{url}", &variable("OD_GET_URL"))
+        .replace("{size}", &variable("OD_GET_SIZE"));
+    let mut shell = shell(&command);
+    shell
+        .env("OD_GET_PATH", path)
+        .env("OD_GET_URL", url)
+        .env("OD_GET_SIZE", size.to_string());
+
+    let status = tokio::process::Command::from(shell)
+        .status()
+        .await
+        .with_context(|| format!("Cannot run the hook of {}", path.display()));
+
+    let error = match status {
+        Ok(status) if status.success() => return Ok(()),
+        Ok(status) => anyhow!("The hook of {} failed ({})", path.display(), status),
+        Err(error) => error,
+    };
+    if fail {
+        return Err(error);
+    }
+    warn!("{:#}", error);
+
+    Ok(())
+}
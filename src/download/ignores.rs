@@ -0,0 +1,92 @@
+use ignore::{
+    gitignore::{Gitignore, GitignoreBuilder},
+    Match,
+};
+use log::{debug, warn};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+/// The name of the files listing what not to download below their directory
+pub const IGNORE_FILE: &str = ".od-get-ignore";
+
+/**
+The ignore files found in the local copy of a mirror
+
+An ignore file holds gitignore-style patterns (e.g. `*.tmp`, `/old/` or `!keep.tmp`), which apply
+relative to the directory containing it. Like with git, the patterns of deeper files take
+precedence over the ones above them. Only files within the destination are looked at, and each
+directory is only read once per run.
+*/
+#[derive(Debug)]
+pub struct LocalIgnores {
+    destination: PathBuf,
+    loaded: HashMap<PathBuf, Option<Gitignore>>,
+}
+
+impl LocalIgnores {
+    pub fn new(destination: &Path) -> Self {
+        Self {
+            destination: destination.to_owned(),
+            loaded: HashMap::new(),
+        }
+    }
+
+    /// Checks if the local path of a file is ignored (by itself or by one of its directories)
+    pub fn is_ignored(&mut self, path: &Path) -> bool {
+        if !path.starts_with(&self.destination) {
+            return false;
+        }
+
+        for dir in path.ancestors().skip(1) {
+            if let Some(ignores) = self.load(dir) {
+                match ignores.matched_path_or_any_parents(path, false) {
+                    Match::Ignore(glob) => {
+                        debug!(
+                            "(Ignore) {} matches {} of {}",
+                            path.display(),
+                            glob.original(),
+                            dir.join(IGNORE_FILE).display()
+                        );
+                        return true;
+                    }
+                    Match::Whitelist(_) => return false,
+                    Match::None => (),
+                }
+            }
+
+            if dir == self.destination {
+                break;
+            }
+        }
+
+        false
+    }
+
+    /// Reads the ignore file of a directory (once), if there is one
+    fn load(&mut self, dir: &Path) -> Option<&Gitignore> {
+        self.loaded
+            .entry(dir.to_owned())
+            .or_insert_with(|| {
+                let path = dir.join(IGNORE_FILE);
+                if !path.is_file() {
+                    return None;
+                }
+
+                let mut builder = GitignoreBuilder::new(dir);
+                if let Some(error) = builder.add(&path) {
+                    warn!("Cannot read all of {}: {}", path.display(), error);
+                }
+
+                match builder.build() {
+                    Ok(ignores) => Some(ignores),
+                    Err(error) => {
+                        warn!("Cannot use {}: {}", path.display(), error);
+                        None
+                    }
+                }
+            })
+            .as_ref()
+    }
+}
@@ -0,0 +1,557 @@
+use super::{
+    crawl::{ApacheHttpBackend, RangesUnsupported},
+    format::ServerFormat,
+    ftp::FtpBackend,
+    observer::DownloadObserver,
+    types::{self, FileLinkMetaData, Node, Validators},
+    units::ByteRange,
+    useragents::UserAgentPool,
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use log::debug;
+use reqwest::Url;
+use std::{sync::Mutex, time::Duration};
+use tokio::io::AsyncWrite;
+
+/**
+A kind of server which serves a tree of directories & files
+
+The crawling & downloading logic only talks to servers through this trait, so supporting another
+listing format or protocol only requires another implementation.
+*/
+#[async_trait]
+pub trait DirectoryListing: Send + Sync {
+    /**
+    Lists the entries of a single directory (not recursive)
+
+    Sub-directories are returned as PendingDir nodes, files as File nodes.
+    */
+    async fn list(&self, url: &Url) -> Result<Vec<Node>>;
+
+    /**
+    Writes the content of a file to the writer (as it arrives from the network)
+
+    Given the validators of an earlier download, the file is only transferred if it changed
+    since (as far as the server supports conditional requests). The start and progress of the
+    transfer get reported to `observer`.
+    */
+    async fn fetch(
+        &self,
+        file: &FileLinkMetaData,
+        validators: Option<&Validators>,
+        writer: &mut (dyn AsyncWrite + Unpin + Send),
+        observer: &dyn DownloadObserver,
+    ) -> Result<Fetched>;
+
+    /**
+    Asks the server for the exact size of a file (e.g. via a HEAD request)
+
+    Returns None if the server (or the backend) can't tell.
+    */
+    async fn file_size(&self, _file: &FileLinkMetaData) -> Result<Option<u64>> {
+        Ok(None)
+    }
+
+    /**
+    Writes a range of bytes of a file to the writer (returning their number)
+
+    Fails with `RangesUnsupported` if the server (or the backend) can't send parts of files.
+    */
+    async fn fetch_range(
+        &self,
+        file: &FileLinkMetaData,
+        _range: ByteRange,
+        _writer: &mut (dyn AsyncWrite + Unpin + Send),
+    ) -> Result<u64> {
+        Err(RangesUnsupported {
+            url: file.url.clone(),
+        }
+        .into())
+    }
+
+    /**
+    Asks the server for the MIME type of a file (e.g. via a HEAD request)
+
+    Returns None if the server (or the backend) can't tell.
+    */
+    async fn content_type(&self, _file: &FileLinkMetaData) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    /**
+    Returns the URL a listed directory was finally served from, if it got redirected
+
+    Returns None if the directory wasn't redirected (or the backend can't tell).
+    */
+    fn final_url(&self, _url: &Url) -> Option<Url> {
+        None
+    }
+}
+
+/// The outcome of fetching a file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fetched {
+    /// The file was written (along the validators for the next conditional request)
+    Modified(Validators),
+
+    /// The file didn't change since the download the given validators stem from
+    NotModified,
+}
+
+/**
+Picks the backend matching the scheme of the root URL
+
+`ftp://` URLs are handled by the FTP backend, everything else is treated as an Apache-style
+HTML listing served via HTTP(S). Listings are requested with `client`, files with `file_client` (both taking turns with the given
+User-Agents, if any), and parsed according to the `format`.
+The read timeout applies to each read of a response, the connect timeout is already part of the
+(HTTP) clients.
+*/
+pub fn backend_for(
+    url: &Url,
+    client: reqwest::Client,
+    file_client: reqwest::Client,
+    connect_timeout: Duration,
+    read_timeout: Duration,
+    user_agents: Option<UserAgentPool>,
+    format: ServerFormat,
+) -> Box<dyn DirectoryListing> {
+    match url.scheme() {
+        "ftp" => Box::new(FtpBackend::new(connect_timeout, read_timeout)),
+        _ => {
+            let backend = ApacheHttpBackend::new(client, read_timeout)
+                .with_file_client(file_client)
+                .with_format(format);
+            match user_agents {
+                Some(user_agents) => Box::new(backend.with_user_agents(user_agents)),
+                None => Box::new(backend),
+            }
+        }
+    }
+}
+
+/**
+A backend which lists the entries of each directory in a fixed order (sorted by URL)
+
+Servers may list entries in any order (and change it between requests), so wrapping a backend in
+this makes the crawl (and thus the download) happen in the same order on every run.
+*/
+pub struct SortedListing {
+    inner: Box<dyn DirectoryListing>,
+}
+
+impl SortedListing {
+    pub fn new(inner: Box<dyn DirectoryListing>) -> Self {
+        Self { inner }
+    }
+}
+
+#[async_trait]
+impl DirectoryListing for SortedListing {
+    async fn list(&self, url: &Url) -> Result<Vec<Node>> {
+        let mut nodes = self.inner.list(url).await?;
+        types::sort_nodes(&mut nodes);
+
+        Ok(nodes)
+    }
+
+    async fn fetch(
+        &self,
+        file: &FileLinkMetaData,
+        validators: Option<&Validators>,
+        writer: &mut (dyn AsyncWrite + Unpin + Send),
+        observer: &dyn DownloadObserver,
+    ) -> Result<Fetched> {
+        self.inner.fetch(file, validators, writer, observer).await
+    }
+
+    async fn file_size(&self, file: &FileLinkMetaData) -> Result<Option<u64>> {
+        self.inner.file_size(file).await
+    }
+
+    async fn fetch_range(
+        &self,
+        file: &FileLinkMetaData,
+        range: ByteRange,
+        writer: &mut (dyn AsyncWrite + Unpin + Send),
+    ) -> Result<u64> {
+        self.inner.fetch_range(file, range, writer).await
+    }
+
+    async fn content_type(&self, file: &FileLinkMetaData) -> Result<Option<String>> {
+        self.inner.content_type(file).await
+    }
+}
+
+/**
+A backend which drops the files marked as not to be mirrored (see `--skip-marker`)
+
+A file named like the marker (e.g. `.nomirror`) marks all files of its directory, a file named
+like a sibling plus the marker (e.g. `big.iso.nomirror`) only marks that sibling. The markers are
+dropped as well, while sub-directories are still listed.
+*/
+pub struct MarkedListing {
+    inner: Box<dyn DirectoryListing>,
+    marker: String,
+}
+
+impl MarkedListing {
+    pub fn new(inner: Box<dyn DirectoryListing>, marker: &str) -> Self {
+        Self {
+            inner,
+            marker: marker.to_owned(),
+        }
+    }
+}
+
+#[async_trait]
+impl DirectoryListing for MarkedListing {
+    async fn list(&self, url: &Url) -> Result<Vec<Node>> {
+        let mut nodes = self.inner.list(url).await?;
+
+        let names: Vec<&str> = nodes
+            .iter()
+            .filter_map(|node| match node {
+                Node::File(file) => Some(file.name.as_str()),
+                _ => None,
+            })
+            .collect();
+        let whole_dir = names.contains(&self.marker.as_str());
+        let marked: Vec<String> = names
+            .iter()
+            .filter_map(|name| name.strip_suffix(self.marker.as_str()))
+            .filter(|name| !name.is_empty())
+            .map(str::to_owned)
+            .collect();
+
+        // Drop the marked files & the markers themselves
+        nodes.retain(|node| {
+            let file = match node {
+                Node::File(file) => file,
+                _ => return true,
+            };
+            let skip =
+                whole_dir || file.name.ends_with(&self.marker) || marked.contains(&file.name);
+            if skip {
+                debug!("(Marker) Skip {}", file.url);
+            }
+            !skip
+        });
+
+        Ok(nodes)
+    }
+
+    async fn fetch(
+        &self,
+        file: &FileLinkMetaData,
+        validators: Option<&Validators>,
+        writer: &mut (dyn AsyncWrite + Unpin + Send),
+        observer: &dyn DownloadObserver,
+    ) -> Result<Fetched> {
+        self.inner.fetch(file, validators, writer, observer).await
+    }
+
+    async fn file_size(&self, file: &FileLinkMetaData) -> Result<Option<u64>> {
+        self.inner.file_size(file).await
+    }
+
+    async fn fetch_range(
+        &self,
+        file: &FileLinkMetaData,
+        range: ByteRange,
+        writer: &mut (dyn AsyncWrite + Unpin + Send),
+    ) -> Result<u64> {
+        self.inner.fetch_range(file, range, writer).await
+    }
+
+    async fn content_type(&self, file: &FileLinkMetaData) -> Result<Option<String>> {
+        self.inner.content_type(file).await
+    }
+}
+
+/**
+A backend which only lists the entries below the root URL
+
+Listings may link to other servers or to directories above the root (by accident or on purpose),
+so wrapping a backend in this drops all entries which don't share the origin (scheme, host &
+port) of the root URL or aren't below its path.
+*/
+pub struct BoundedListing {
+    inner: Box<dyn DirectoryListing>,
+    root: Url,
+
+    /// Where the root URL got redirected to (once it was listed), which bounds the crawl instead
+    moved_root: Mutex<Option<Url>>,
+}
+
+impl BoundedListing {
+    pub fn new(inner: Box<dyn DirectoryListing>, root: &Url) -> Self {
+        Self {
+            inner,
+            root: as_dir(root),
+            moved_root: Mutex::new(None),
+        }
+    }
+
+    /// Checks if a URL is below the root URL (or where it got redirected to)
+    fn is_inside(&self, url: &str) -> bool {
+        let moved_root = self.moved_root.lock().unwrap();
+        let root = moved_root.as_ref().unwrap_or(&self.root);
+
+        Url::parse(url)
+            .is_ok_and(|url| url.origin() == root.origin() && url.path().starts_with(root.path()))
+    }
+}
+
+#[async_trait]
+impl DirectoryListing for BoundedListing {
+    async fn list(&self, url: &Url) -> Result<Vec<Node>> {
+        let mut nodes = self.inner.list(url).await?;
+
+        // A redirected root moves the bounds along (e.g. from `/old/` to `/new/`)
+        if as_dir(url) == self.root {
+            if let Some(final_url) = self.inner.final_url(url) {
+                debug!("(External) The root moved to {}", final_url);
+                *self.moved_root.lock().unwrap() = Some(as_dir(&final_url));
+            }
+        }
+
+        // Drop the entries outside of the root
+        nodes.retain(|node| {
+            let inside = self.is_inside(node.url());
+            if !inside {
+                debug!("(External) Skip {}", node.url());
+            }
+            inside
+        });
+
+        Ok(nodes)
+    }
+
+    async fn fetch(
+        &self,
+        file: &FileLinkMetaData,
+        validators: Option<&Validators>,
+        writer: &mut (dyn AsyncWrite + Unpin + Send),
+        observer: &dyn DownloadObserver,
+    ) -> Result<Fetched> {
+        self.inner.fetch(file, validators, writer, observer).await
+    }
+
+    async fn file_size(&self, file: &FileLinkMetaData) -> Result<Option<u64>> {
+        self.inner.file_size(file).await
+    }
+
+    async fn fetch_range(
+        &self,
+        file: &FileLinkMetaData,
+        range: ByteRange,
+        writer: &mut (dyn AsyncWrite + Unpin + Send),
+    ) -> Result<u64> {
+        self.inner.fetch_range(file, range, writer).await
+    }
+
+    async fn content_type(&self, file: &FileLinkMetaData) -> Result<Option<String>> {
+        self.inner.content_type(file).await
+    }
+}
+
+/// Treats a URL as the one of a directory, even if it lacks the trailing slash
+fn as_dir(url: &Url) -> Url {
+    let mut url = url.clone();
+    if !url.path().ends_with('/') {
+        url.set_path(&format!("{}/", url.path()));
+    }
+
+    url
+}
+
+/**
+A backend serving a canned tree (for the tests)
+
+The children of the directories are listed by URL, and files have their URL as their content.
+The URLs of all requests get recorded.
+*/
+#[cfg(test)]
+#[derive(Default)]
+pub struct MockListing {
+    pub dirs: std::collections::HashMap<String, Vec<Node>>,
+
+    /// The directories which got redirected (by URL), with the URLs they were served from
+    pub redirects: std::collections::HashMap<String, Url>,
+    requests: Mutex<Vec<String>>,
+}
+
+#[cfg(test)]
+impl MockListing {
+    pub fn new<'a>(dirs: impl IntoIterator<Item = (&'a str, Vec<Node>)>) -> Self {
+        Self {
+            dirs: dirs
+                .into_iter()
+                .map(|(url, children)| (url.to_owned(), children))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Returns the URLs requested so far (in order)
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl DirectoryListing for MockListing {
+    async fn list(&self, url: &Url) -> Result<Vec<Node>> {
+        self.requests.lock().unwrap().push(url.to_string());
+
+        self.dirs
+            .get(url.as_str())
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Not a listing: {}", url))
+    }
+
+    async fn fetch(
+        &self,
+        file: &FileLinkMetaData,
+        _validators: Option<&Validators>,
+        writer: &mut (dyn AsyncWrite + Unpin + Send),
+        observer: &dyn DownloadObserver,
+    ) -> Result<Fetched> {
+        use tokio::io::AsyncWriteExt;
+
+        self.requests.lock().unwrap().push(file.url.clone());
+        observer.on_file_start(file, Some(file.url.len() as u64));
+        writer.write_all(file.url.as_bytes()).await?;
+        observer.on_progress(file, file.url.len() as u64);
+
+        Ok(Fetched::Modified(Validators::default()))
+    }
+
+    fn final_url(&self, url: &Url) -> Option<Url> {
+        self.redirects.get(url.as_str()).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::{
+        crawl::{self, CrawlBudget},
+        excludes::DirExcludes,
+        observer::Observers,
+        types::{CrawlingState, DirLinkMetaData},
+    };
+
+    #[tokio::test]
+    async fn crawls_the_tree_of_a_backend() {
+        let file = |url: &str| Node::File(FileLinkMetaData::for_test(url, 1));
+        let dir = |url: &str| Node::PendingDir(DirLinkMetaData::for_test(url));
+        let backend = MockListing::new([
+            (
+                "http://a/",
+                vec![file("http://a/1.txt"), dir("http://a/b/")],
+            ),
+            (
+                "http://a/b/",
+                vec![file("http://a/b/2.txt"), dir("http://a/b/c/")],
+            ),
+            ("http://a/b/c/", vec![file("http://a/b/c/3.txt")]),
+        ]);
+        let url = Url::parse("http://a/").unwrap();
+        let observer = Observers(vec![]);
+        let mut budget = CrawlBudget::new(None, None);
+
+        let mut partial = crawl::start_crawl(
+            &url,
+            &backend,
+            &DirExcludes::default(),
+            &mut budget,
+            &observer,
+        )
+        .await
+        .unwrap();
+        crawl::crawl_frontier(
+            &mut partial,
+            &backend,
+            &DirExcludes::default(),
+            false,
+            false,
+            &mut budget,
+            &observer,
+        )
+        .await
+        .unwrap();
+
+        let root = match partial.into_state() {
+            CrawlingState::Complete(root) => root,
+            state => panic!("Incomplete crawl: {:?}", state),
+        };
+        let files: Vec<&str> = root.iter_files().map(|file| file.url.as_str()).collect();
+        assert_eq!(
+            files,
+            ["http://a/1.txt", "http://a/b/2.txt", "http://a/b/c/3.txt"]
+        );
+        assert_eq!(
+            backend.requests(),
+            ["http://a/", "http://a/b/", "http://a/b/c/"]
+        );
+    }
+
+    fn bounded_tree(root: &str, children: Vec<Node>) -> BoundedListing {
+        let backend = MockListing::new([(root, children)]);
+        BoundedListing::new(Box::new(backend), &Url::parse(root).unwrap())
+    }
+
+    fn urls_of(nodes: &[Node]) -> Vec<&str> {
+        nodes.iter().map(Node::url).collect()
+    }
+
+    #[tokio::test]
+    async fn drops_the_entries_outside_of_the_root() {
+        let file = |url: &str| Node::File(FileLinkMetaData::for_test(url, 1));
+        let dir = |url: &str| Node::PendingDir(DirLinkMetaData::for_test(url));
+        let listing = bounded_tree(
+            "http://a/pub/",
+            vec![
+                file("http://b/pub/x.txt"),
+                file("https://a/pub/y.txt"),
+                dir("http://a/"),
+                file("http://a/other/z.txt"),
+                file("http://a/pub/kept.txt"),
+                dir("http://a/pub/sub/"),
+            ],
+        );
+
+        let nodes = listing
+            .list(&Url::parse("http://a/pub/").unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            urls_of(&nodes),
+            ["http://a/pub/kept.txt", "http://a/pub/sub/"]
+        );
+    }
+
+    #[tokio::test]
+    async fn moves_the_bounds_with_a_redirected_root() {
+        let file = |url: &str| Node::File(FileLinkMetaData::for_test(url, 1));
+        let mut backend = MockListing::new([(
+            "http://a/old",
+            vec![file("http://a/new/x.txt"), file("http://a/old/y.txt")],
+        )]);
+        backend.redirects.insert(
+            "http://a/old".to_owned(),
+            Url::parse("http://a/new/").unwrap(),
+        );
+        let root = Url::parse("http://a/old").unwrap();
+        let listing = BoundedListing::new(Box::new(backend), &root);
+
+        let nodes = listing.list(&root).await.unwrap();
+
+        assert_eq!(urls_of(&nodes), ["http://a/new/x.txt"]);
+    }
+}
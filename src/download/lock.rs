@@ -0,0 +1,85 @@
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
+use log::warn;
+use std::{
+    fs,
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+/**
+A lock on a mirror (or state store), so two runs don't write the same files at once
+
+The lock is a file holding the process ID and the start of the run which holds it. It's removed
+once the lock is dropped, i.e. when the run ends (also with an error or Ctrl-C). A run which got
+killed leaves a stale lock behind, which can be taken over with `force` (see `--force-lock`).
+*/
+#[derive(Debug)]
+pub struct RunLock {
+    path: PathBuf,
+}
+
+impl RunLock {
+    /// Acquires the lock at the given path (failing if another run holds it, unless forced)
+    pub fn acquire(path: &Path, force: bool) -> Result<Self> {
+        if let Some(parent) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Cannot create the directory {}", parent.display()))?;
+        }
+
+        let mut file = match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+        {
+            Ok(file) => file,
+            Err(error) if error.kind() == ErrorKind::AlreadyExists => {
+                let holder = describe_holder(path);
+                if !force {
+                    bail!(
+                        "Another run holds the lock {} ({}), use --force-lock if it's stale",
+                        path.display(),
+                        holder
+                    );
+                }
+
+                warn!("Taking over the lock {} ({})", path.display(), holder);
+                fs::File::create(path)
+                    .with_context(|| format!("Cannot take over the lock {}", path.display()))?
+            }
+            Err(error) => {
+                return Err(error)
+                    .with_context(|| format!("Cannot create the lock {}", path.display()))
+            }
+        };
+
+        writeln!(file, "{} {}", std::process::id(), Utc::now().to_rfc3339())
+            .with_context(|| format!("Cannot write the lock {}", path.display()))?;
+
+        Ok(Self {
+            path: path.to_owned(),
+        })
+    }
+}
+
+impl Drop for RunLock {
+    fn drop(&mut self) {
+        if let Err(error) = fs::remove_file(&self.path) {
+            warn!("Cannot remove the lock {}: {}", self.path.display(), error);
+        }
+    }
+}
+
+/// Describes the run holding a lock (as far as the lock file tells)
+fn describe_holder(path: &Path) -> String {
+    let text = fs::read_to_string(path).unwrap_or_default();
+    let mut parts = text.split_whitespace();
+
+    match (parts.next(), parts.next()) {
+        (Some(pid), Some(since)) => format!("process {} since {}", pid, since),
+        _ => "unknown process".to_owned(),
+    }
+}
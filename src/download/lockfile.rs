@@ -0,0 +1,303 @@
+use super::{
+    checksum::{self, ChecksumKind},
+    types::{FileLinkMetaData, Node},
+    verify::expected_size,
+};
+use anyhow::{bail, Context, Result};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+/// The version of the lockfile format
+const LOCKFILE_VERSION: u64 = 1;
+
+/// The exact version of a downloaded file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LockedFile {
+    pub url: String,
+
+    /// The size in bytes (as downloaded)
+    pub size: u64,
+
+    /// The date from the listing
+    pub last_modified: String,
+
+    /// The SHA-256 digest (as lowercase hex)
+    pub sha256: String,
+}
+
+/// The contents of a lockfile (the files by their path below the destination)
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Contents {
+    version: u64,
+    files: BTreeMap<String, LockedFile>,
+}
+
+/**
+A file on the server which differs from the lockfile (see `--frozen`)
+
+The run stops at the first one, as the mirror wouldn't be the one of the lockfile anymore.
+*/
+#[derive(Debug, Clone)]
+pub struct Drift {
+    /// The path of the file below the destination
+    pub path: String,
+    pub url: String,
+    reason: String,
+}
+
+impl fmt::Display for Drift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}) differs from the lockfile: {}",
+            self.path, self.url, self.reason
+        )
+    }
+}
+
+impl std::error::Error for Drift {}
+
+/// Returns the file which differs from the lockfile (if that's what caused an error)
+pub fn drift(error: &anyhow::Error) -> Option<&Drift> {
+    error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<Drift>())
+}
+
+/**
+The exact versions of the downloaded files, kept in the destination (see `--write-lockfile`)
+
+Each file is recorded by its path below the destination, with its URL, size, date & SHA-256
+digest. Files downloaded by earlier runs stay in it, so a resumed run completes the lockfile.
+With `--frozen`, a run checks the server and every download against it instead.
+*/
+#[derive(Debug)]
+pub struct Lockfile {
+    path: PathBuf,
+    files: BTreeMap<String, LockedFile>,
+
+    /// Check the downloads against the lockfile instead of recording them
+    frozen: bool,
+}
+
+impl Lockfile {
+    /// Opens the lockfile to record the downloads in (continuing an existing one)
+    pub fn open(path: &Path) -> Result<Self> {
+        let files = match fs::read_to_string(path) {
+            Ok(json) => parse(&json, path)?.files,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(error) => {
+                return Err(error)
+                    .with_context(|| format!("Cannot read the lockfile {}", path.display()))
+            }
+        };
+
+        Ok(Self {
+            path: path.to_owned(),
+            files,
+            frozen: false,
+        })
+    }
+
+    /// Opens the lockfile to check the downloads against (which has to exist)
+    pub fn open_frozen(path: &Path) -> Result<Self> {
+        let json = fs::read_to_string(path).with_context(|| {
+            format!(
+                "Cannot read the lockfile {} (write one with --write-lockfile)",
+                path.display()
+            )
+        })?;
+
+        Ok(Self {
+            path: path.to_owned(),
+            files: parse(&json, path)?.files,
+            frozen: true,
+        })
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.frozen
+    }
+
+    /**
+    Checks the files of a crawled tree against the lockfile
+
+    Every file of the lockfile has to be listed with the same size & date (as far as the listing
+    tells), so a drift shows before anything gets downloaded.
+    */
+    pub fn check_tree(&self, root: &Node) -> Result<()> {
+        let listed: HashMap<&str, &FileLinkMetaData> = root
+            .iter_files()
+            .map(|file| (file.url.as_str(), file))
+            .collect();
+
+        for (path, locked) in &self.files {
+            match listed.get(locked.url.as_str()) {
+                Some(file) => self.check_listed(path, locked, file)?,
+                None => return Err(drift_of(path, &locked.url, "it's no longer listed")),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks a file which is about to be downloaded (to the path below the destination)
+    pub fn check_file(&self, path: &str, file: &FileLinkMetaData) -> Result<()> {
+        match self.files.get(path) {
+            Some(locked) if locked.url != file.url => Err(drift_of(
+                path,
+                &file.url,
+                &format!("it's locked to {}", locked.url),
+            )),
+            Some(locked) => self.check_listed(path, locked, file),
+            None => Err(drift_of(path, &file.url, "it's not in the lockfile")),
+        }
+    }
+
+    /// Checks if the local copy of a file is the locked version already (so it needn't be downloaded)
+    pub fn is_reproduced(&self, path: &str, file_path: &Path) -> bool {
+        let locked = match self.files.get(path) {
+            Some(locked) => locked,
+            None => return false,
+        };
+
+        fs::metadata(file_path).is_ok_and(|metadata| metadata.len() == locked.size)
+            && hash_file(file_path).is_ok_and(|sha256| sha256 == locked.sha256)
+    }
+
+    /**
+    Checks the digest of a downloaded file (or records it, unless frozen)
+
+    A frozen download of another digest gets removed again.
+    */
+    pub fn add_download(
+        &mut self,
+        path: &str,
+        file: &FileLinkMetaData,
+        file_path: &Path,
+        bytes: u64,
+    ) -> Result<()> {
+        let sha256 =
+            hash_file(file_path).with_context(|| format!("Cannot hash {}", file_path.display()))?;
+
+        if !self.frozen {
+            self.files.insert(
+                path.to_owned(),
+                LockedFile {
+                    url: file.url.clone(),
+                    size: bytes,
+                    last_modified: file.last_modified.clone(),
+                    sha256,
+                },
+            );
+            return Ok(());
+        }
+
+        let locked = match self.files.get(path) {
+            Some(locked) => locked,
+            None => return Err(drift_of(path, &file.url, "it's not in the lockfile")),
+        };
+        if locked.sha256 != sha256 || locked.size != bytes {
+            fs::remove_file(file_path)
+                .with_context(|| format!("Cannot remove {}", file_path.display()))?;
+
+            return Err(drift_of(
+                path,
+                &file.url,
+                &format!(
+                    "its content changed ({} bytes with SHA-256 {} instead of {} bytes with {})",
+                    bytes, sha256, locked.size, locked.sha256
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Writes the lockfile (unless frozen, which leaves it as it is)
+    pub fn save(&self) -> Result<()> {
+        if self.frozen {
+            return Ok(());
+        }
+
+        let contents = Contents {
+            version: LOCKFILE_VERSION,
+            files: self.files.clone(),
+        };
+        fs::write(&self.path, serde_json::to_string_pretty(&contents)?)
+            .with_context(|| format!("Cannot write the lockfile {}", self.path.display()))?;
+
+        info!(
+            "Wrote the lockfile {} ({} files)",
+            self.path.display(),
+            self.files.len()
+        );
+        Ok(())
+    }
+
+    /// Checks the size & date of a listed file against its entry
+    fn check_listed(&self, path: &str, locked: &LockedFile, file: &FileLinkMetaData) -> Result<()> {
+        if let Some((expected, precision)) = expected_size(file) {
+            if locked.size.abs_diff(expected) >= precision {
+                return Err(drift_of(
+                    path,
+                    &file.url,
+                    &format!(
+                        "it's listed with {} instead of {} bytes",
+                        file.size, locked.size
+                    ),
+                ));
+            }
+        }
+
+        if file.last_modified.trim() != locked.last_modified.trim() {
+            return Err(drift_of(
+                path,
+                &file.url,
+                &format!(
+                    "it's listed as modified {} instead of {}",
+                    file.last_modified.trim(),
+                    locked.last_modified.trim()
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Parses the contents of a lockfile
+fn parse(json: &str, path: &Path) -> Result<Contents> {
+    let contents: Contents = serde_json::from_str(json)
+        .with_context(|| format!("Cannot parse the lockfile {}", path.display()))?;
+
+    if contents.version > LOCKFILE_VERSION {
+        bail!(
+            "The lockfile {} is of a newer version ({}) than this one of od-get can read ({})",
+            path.display(),
+            contents.version,
+            LOCKFILE_VERSION
+        );
+    }
+
+    Ok(contents)
+}
+
+fn drift_of(path: &str, url: &str, reason: &str) -> anyhow::Error {
+    Drift {
+        path: path.to_owned(),
+        url: url.to_owned(),
+        reason: reason.to_owned(),
+    }
+    .into()
+}
+
+/// Calculates the SHA-256 digest of a file (as lowercase hex)
+fn hash_file(path: &Path) -> io::Result<String> {
+    checksum::hash_file(path, ChecksumKind::Sha256)
+}
@@ -0,0 +1,72 @@
+use crate::cli::CliOptions;
+
+use super::{
+    fetch::{filter_file, get_last_segment, is_wanted_dir, within_depth},
+    order::sorted_children,
+    types::{FileLinkMetaData, Node},
+};
+use anyhow::Result;
+use reqwest::Url;
+use std::{io::Write, str::FromStr};
+
+/**
+Writes a flat manifest of the files in a crawled tree (which pass the filters)
+
+Every file gets a line `url<TAB>size<TAB>last_modified`, in the order the files would be
+downloaded. The size is in bytes (or as stated by the listing, if it cannot be parsed).
+
+Returns the number of listed files.
+*/
+pub fn write_manifest(root: &Node, options: &CliOptions, writer: &mut dyn Write) -> Result<u64> {
+    let mut count = 0;
+    write_node(root, options, writer, &mut count)?;
+
+    Ok(count)
+}
+
+/// Writes the files of a directory and its children
+fn write_node(
+    node: &Node,
+    options: &CliOptions,
+    writer: &mut dyn Write,
+    count: &mut u64,
+) -> Result<()> {
+    let (meta, children) = match node {
+        Node::CrawledDir(meta, children) => (meta, children),
+        _ => return Ok(()),
+    };
+
+    // Skip unwanted folders (like the download does)
+    let server_path = meta.name.split('/').next_back().unwrap_or_default();
+    if !is_wanted_dir(server_path, options) || !within_depth(meta.depth, options) {
+        return Ok(());
+    }
+
+    let children = sorted_children(children, options.sort);
+
+    for child in &children {
+        if let Node::File(file) = child {
+            let url = Url::from_str(&file.url)?;
+            if filter_file(file, get_last_segment(&url), options).is_none() {
+                writeln!(writer, "{}", manifest_line(file))?;
+                *count += 1;
+            }
+        }
+    }
+
+    for child in children {
+        write_node(child, options, writer, count)?;
+    }
+
+    Ok(())
+}
+
+/// Formats the manifest line of a file
+fn manifest_line(file: &FileLinkMetaData) -> String {
+    let size = match file.size_bytes {
+        Some(size) => size.to_string(),
+        None => file.size.clone(),
+    };
+
+    format!("{}\t{}\t{}", file.url, size, file.last_modified)
+}
@@ -0,0 +1,319 @@
+use super::types::{CrawlingState, Node, PartialCrawl, StateStore};
+use chrono::{DateTime, FixedOffset};
+use log::warn;
+use std::fmt;
+
+/// A file which has different sizes in the merged state stores
+#[derive(Debug, Clone)]
+pub struct MergeConflict {
+    /// The URL of the file (or its path below the root, for downloaded files)
+    pub file: String,
+
+    /// The size in the newer state store (which is kept)
+    pub newer: Option<u64>,
+
+    /// The size in the older state store
+    pub older: Option<u64>,
+}
+
+impl fmt::Display for MergeConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} has {} in the newer state store, but {} in the older one",
+            self.file,
+            describe_size(self.newer),
+            describe_size(self.older)
+        )
+    }
+}
+
+/// The outcome of merging two state stores
+#[derive(Debug)]
+pub struct Merged {
+    pub state_store: StateStore,
+    pub conflicts: Vec<MergeConflict>,
+}
+
+/**
+Merges two state stores of the same mirror (e.g. crawled from two machines, see `merge-state`)
+
+The downloaded URLs & files, the validators, the failed files and the statistics are united. The crawled trees are
+merged, with the one of the newer state store (by modification time) taking precedence. Files
+which have different sizes in both are reported as conflicts (keeping the newer size).
+*/
+pub fn merge(a: StateStore, b: StateStore) -> Merged {
+    let (mut newer, older) = if modified_at(&a) >= modified_at(&b) {
+        (a, b)
+    } else {
+        (b, a)
+    };
+    let mut conflicts = vec![];
+
+    let newer_state = std::mem::replace(&mut newer.crawling_state, CrawlingState::None);
+    newer.crawling_state = merge_crawls(newer_state, older.crawling_state, &mut conflicts);
+
+    for url in older.downloaded_urls {
+        if !newer.downloaded_urls.contains(&url) {
+            newer.downloaded_urls.push(url);
+        }
+    }
+
+    for identity in older.downloaded_files {
+        match newer
+            .downloaded_files
+            .iter()
+            .find(|downloaded| downloaded.path == identity.path)
+        {
+            Some(downloaded) if downloaded.size_bytes != identity.size_bytes => {
+                conflicts.push(MergeConflict {
+                    file: identity.path,
+                    newer: downloaded.size_bytes,
+                    older: identity.size_bytes,
+                })
+            }
+            Some(_) => {}
+            None => newer.downloaded_files.push(identity),
+        }
+    }
+
+    for link in older.dedup_links {
+        if !newer.dedup_links.iter().any(|known| known.url == link.url) {
+            newer.dedup_links.push(link);
+        }
+    }
+
+    for (url, validators) in older.validators {
+        newer.validators.entry(url).or_insert(validators);
+    }
+
+    // Files which failed in either are retried, unless the other one has downloaded them
+    for file in older.failed_files {
+        if !newer
+            .failed_files
+            .iter()
+            .any(|failed| failed.url == file.url)
+        {
+            newer.failed_files.push(file);
+        }
+    }
+    let downloaded = &newer.downloaded_urls;
+    newer
+        .failed_files
+        .retain(|failed| !downloaded.contains(&failed.url));
+
+    newer.stats.add(&older.stats);
+    if older.created_at < newer.created_at {
+        newer.created_at = older.created_at;
+    }
+
+    // The queue of either download may miss directories of the merged tree, so the next download
+    // walks all of it
+    newer.download_queue.clear();
+
+    Merged {
+        state_store: newer,
+        conflicts,
+    }
+}
+
+/// Returns when a state store was last modified (unparseable times count as the oldest)
+fn modified_at(state_store: &StateStore) -> Option<DateTime<FixedOffset>> {
+    DateTime::parse_from_rfc3339(&state_store.last_modified).ok()
+}
+
+/// Merges the crawls of two state stores (which is only possible if they are of the same URL)
+fn merge_crawls(
+    newer: CrawlingState,
+    older: CrawlingState,
+    conflicts: &mut Vec<MergeConflict>,
+) -> CrawlingState {
+    match (into_partial(newer), into_partial(older)) {
+        (Some(mut newer), Some(older)) if newer.root.url() == older.root.url() => {
+            merge_nodes(&mut newer.root, older.root, conflicts);
+
+            // The directories crawled by either aren't pending anymore
+            for url in older.frontier {
+                if !newer.frontier.contains(&url) {
+                    newer.frontier.push(url);
+                }
+            }
+            let root = &newer.root;
+            newer
+                .frontier
+                .retain(|url| root.find_pending_dir(url).is_some());
+            newer.truncated |= older.truncated;
+
+            newer.into_state()
+        }
+        (Some(newer), Some(older)) => {
+            warn!(
+                "The state stores are of different URLs ({} & {}), keeping the tree of the newer one",
+                newer.root.url(),
+                older.root.url()
+            );
+            newer.into_state()
+        }
+        (Some(crawl), None) | (None, Some(crawl)) => crawl.into_state(),
+        (None, None) => CrawlingState::None,
+    }
+}
+
+/// Treats a complete crawl as a partial one with nothing left to crawl
+fn into_partial(state: CrawlingState) -> Option<PartialCrawl> {
+    match state {
+        CrawlingState::Complete(root) => Some(PartialCrawl {
+            root,
+            frontier: vec![],
+            truncated: false,
+        }),
+        CrawlingState::Partial(partial) => Some(partial),
+        CrawlingState::None => None,
+    }
+}
+
+/// Adds the nodes of the older tree to the newer one (matching them by URL)
+fn merge_nodes(newer: &mut Node, older: Node, conflicts: &mut Vec<MergeConflict>) {
+    match (newer, older) {
+        (Node::CrawledDir(_, children), Node::CrawledDir(_, older_children)) => {
+            for older_child in older_children {
+                match children
+                    .iter_mut()
+                    .find(|child| child.url() == older_child.url())
+                {
+                    Some(child) => merge_nodes(child, older_child, conflicts),
+                    None => children.push(older_child),
+                }
+            }
+        }
+
+        // Only the older one crawled the directory
+        (newer @ Node::PendingDir(_), older @ Node::CrawledDir(..)) => *newer = older,
+
+        (Node::File(newer), Node::File(older)) if newer.size_bytes != older.size_bytes => conflicts
+            .push(MergeConflict {
+                file: older.url,
+                newer: newer.size_bytes,
+                older: older.size_bytes,
+            }),
+        _ => {}
+    }
+}
+
+fn describe_size(size: Option<u64>) -> String {
+    size.map(|bytes| format!("{} bytes", bytes))
+        .unwrap_or_else(|| "an unknown size".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::types::{DirLinkMetaData, FileLinkMetaData};
+
+    fn file(url: &str, size: u64) -> Node {
+        Node::File(FileLinkMetaData::for_test(url, size))
+    }
+
+    fn crawled(url: &str, children: Vec<Node>) -> Node {
+        Node::CrawledDir(DirLinkMetaData::for_test(url), children)
+    }
+
+    fn pending(url: &str) -> Node {
+        Node::PendingDir(DirLinkMetaData::for_test(url))
+    }
+
+    /// Makes a state store of a partial crawl, last modified at a time
+    fn state_store(
+        last_modified: &str,
+        root: Node,
+        frontier: &[&str],
+        downloaded_urls: &[&str],
+    ) -> StateStore {
+        let mut state_store = StateStore::new();
+        state_store.last_modified = last_modified.to_owned();
+        state_store.crawling_state = PartialCrawl {
+            root,
+            frontier: frontier.iter().map(|url| url.to_string()).collect(),
+            truncated: false,
+        }
+        .into_state();
+        state_store.downloaded_urls = downloaded_urls.iter().map(|url| url.to_string()).collect();
+        state_store
+    }
+
+    fn urls_of_files(state_store: &StateStore) -> Vec<(String, Option<u64>)> {
+        let mut files: Vec<_> = state_store
+            .crawling_state
+            .root()
+            .unwrap()
+            .iter_files()
+            .map(|file| (file.url.clone(), file.size_bytes))
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn merges_overlapping_state_stores() {
+        let older = state_store(
+            "2021-01-01T10:00:00+00:00",
+            crawled(
+                "http://a/",
+                vec![
+                    file("http://a/1.txt", 1),
+                    crawled("http://a/b/", vec![file("http://a/b/2.txt", 2)]),
+                    pending("http://a/c/"),
+                ],
+            ),
+            &["http://a/c/"],
+            &["http://a/1.txt", "http://a/b/2.txt"],
+        );
+        let newer = state_store(
+            "2021-01-02T10:00:00+00:00",
+            crawled(
+                "http://a/",
+                vec![
+                    file("http://a/1.txt", 10),
+                    pending("http://a/b/"),
+                    crawled("http://a/c/", vec![file("http://a/c/3.txt", 3)]),
+                ],
+            ),
+            &["http://a/b/"],
+            &["http://a/1.txt", "http://a/c/3.txt"],
+        );
+
+        // The order of the arguments doesn't matter
+        for (a, b) in [(older.clone(), newer.clone()), (newer, older)] {
+            let Merged {
+                state_store,
+                conflicts,
+            } = merge(a, b);
+
+            assert_eq!(
+                state_store.downloaded_urls,
+                ["http://a/1.txt", "http://a/c/3.txt", "http://a/b/2.txt"]
+            );
+            assert_eq!(
+                urls_of_files(&state_store),
+                [
+                    ("http://a/1.txt".to_owned(), Some(10)),
+                    ("http://a/b/2.txt".to_owned(), Some(2)),
+                    ("http://a/c/3.txt".to_owned(), Some(3)),
+                ]
+            );
+
+            // Each directory was crawled by one of them, so nothing is left to crawl
+            match state_store.crawling_state {
+                CrawlingState::Complete(_) => {}
+                state => panic!("Incomplete crawl: {:?}", state),
+            }
+
+            assert_eq!(conflicts.len(), 1);
+            assert_eq!(conflicts[0].file, "http://a/1.txt");
+            assert_eq!(
+                (conflicts[0].newer, conflicts[0].older),
+                (Some(10), Some(1))
+            );
+        }
+    }
+}
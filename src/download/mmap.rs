@@ -0,0 +1,271 @@
+use fs2::FileExt;
+use memmap2::MmapMut;
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::{fs, io::AsyncWrite, io::AsyncWriteExt};
+
+/**
+Writes a file through a memory map (see `--mmap`)
+
+The file is pre-allocated to the expected size and mapped as a whole, so writing is copying into
+memory (and chunks could be written out of order). Files turning out larger get the map grown,
+and `finish` cuts the file to what was actually written.
+*/
+pub struct MmapWriter {
+    file: File,
+    path: PathBuf,
+    map: MmapMut,
+    written: usize,
+}
+
+impl MmapWriter {
+    /// Creates (or truncates) a file of the given size (which must not be 0) and maps it
+    pub fn create(path: &Path, size: u64) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        allocate(&file, path, size)?;
+
+        // Safety: the file was just created by us, and nothing else is supposed to touch it
+        let map = unsafe { MmapMut::map_mut(&file)? };
+
+        Ok(Self {
+            file,
+            path: path.to_owned(),
+            map,
+            written: 0,
+        })
+    }
+
+    /// Makes room for more bytes (at least doubling the file, so growing stays rare)
+    fn reserve(&mut self, additional: usize) -> io::Result<()> {
+        let needed = self.written + additional;
+        if needed <= self.map.len() {
+            return Ok(());
+        }
+
+        self.map.flush()?;
+        let size = needed.max(self.map.len() * 2);
+        allocate(&self.file, &self.path, size as u64)?;
+
+        // Safety: see `create`
+        self.map = unsafe { MmapMut::map_mut(&self.file)? };
+
+        Ok(())
+    }
+
+    /**
+    Hands out the whole file to be written at once (e.g. in parts, from several connections)
+
+    It all counts as written, so the file keeps its size.
+    */
+    pub fn whole_mut(&mut self) -> &mut [u8] {
+        self.written = self.map.len();
+
+        &mut self.map
+    }
+
+    /// Writes everything to disk and cuts the file to the written bytes (returning their number)
+    pub fn finish(self) -> io::Result<u64> {
+        self.map.flush()?;
+        drop(self.map);
+        self.file.set_len(self.written as u64)?;
+
+        Ok(self.written as u64)
+    }
+}
+
+/**
+Grows a file to a size, reserving its blocks on the disk
+
+Writing through a map of a sparse file would kill the process (with SIGBUS) once the disk is full,
+so a lack of space has to show here, as an error of the kind `StorageFull`.
+*/
+fn allocate(file: &File, path: &Path, size: u64) -> io::Result<()> {
+    let error = match file.allocate(size) {
+        Ok(()) => return Ok(()),
+        Err(error) => error,
+    };
+
+    // The error of posix_fallocate isn't always passed on as it is, so check the space as well
+    let available = path.parent().and_then(|dir| fs2::available_space(dir).ok());
+    match available {
+        Some(available) if available < size => Err(io::Error::new(
+            io::ErrorKind::StorageFull,
+            format!("No space left for {} bytes ({} available)", size, available),
+        )),
+        _ => Err(error),
+    }
+}
+
+impl AsyncWrite for MmapWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if let Err(error) = this.reserve(buf.len()) {
+            return Poll::Ready(Err(error));
+        }
+
+        this.map[this.written..this.written + buf.len()].copy_from_slice(buf);
+        this.written += buf.len();
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(self.map.flush_async())
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+/**
+The temporary file a download is written to, either directly or through a memory map
+*/
+pub enum PartFile {
+    Plain(fs::File),
+    Mapped(MmapWriter),
+}
+
+impl PartFile {
+    /**
+    Creates (or truncates) the file, mapping it if a size to pre-allocate is given
+
+    Files of unknown (or no) size are written directly, as there is nothing to map.
+    */
+    pub async fn create(path: &Path, mmap_size: Option<u64>) -> io::Result<Self> {
+        match mmap_size.filter(|&size| size > 0) {
+            Some(size) => Ok(Self::Mapped(MmapWriter::create(path, size)?)),
+            None => fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(path)
+                .await
+                .map(Self::Plain),
+        }
+    }
+
+    /// Writes everything to disk (and cuts a mapped file to the written bytes)
+    pub async fn finish(self) -> io::Result<()> {
+        match self {
+            Self::Plain(mut file) => file.flush().await,
+            Self::Mapped(writer) => writer.finish().map(drop),
+        }
+    }
+}
+
+impl AsyncWrite for PartFile {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Plain(file) => Pin::new(file).poll_write(cx, buf),
+            Self::Mapped(writer) => Pin::new(writer).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(file) => Pin::new(file).poll_flush(cx),
+            Self::Mapped(writer) => Pin::new(writer).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(file) => Pin::new(file).poll_shutdown(cx),
+            Self::Mapped(writer) => Pin::new(writer).poll_shutdown(cx),
+        }
+    }
+}
+
+/**
+Writes into a part of a memory map (e.g. one segment of a file, see `--segments`)
+
+Reports the number of bytes of each write to `on_write`. Writing past the end of the part fails.
+*/
+pub struct SliceWriter<'a, F> {
+    slice: &'a mut [u8],
+    written: usize,
+    on_write: F,
+}
+
+impl<'a, F: FnMut(u64) + Unpin> SliceWriter<'a, F> {
+    pub fn new(slice: &'a mut [u8], on_write: F) -> Self {
+        Self {
+            slice,
+            written: 0,
+            on_write,
+        }
+    }
+}
+
+impl<F: FnMut(u64) + Unpin> AsyncWrite for SliceWriter<'_, F> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let end = this.written + buf.len();
+        if end > this.slice.len() {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                "more bytes than the segment holds",
+            )));
+        }
+
+        this.slice[this.written..end].copy_from_slice(buf);
+        this.written = end;
+        (this.on_write)(buf.len() as u64);
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reserves_the_blocks_of_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.bin");
+        let size = 1 << 20;
+
+        let mut writer = MmapWriter::create(&path, size).unwrap();
+        assert!(writer.file.allocated_size().unwrap() >= size);
+
+        // Growing the map reserves the new blocks as well
+        let data = vec![1; 3 << 20];
+        writer.write_all(&data).await.unwrap();
+        assert!(writer.file.allocated_size().unwrap() >= data.len() as u64);
+
+        assert_eq!(writer.finish().unwrap(), data.len() as u64);
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+    }
+}
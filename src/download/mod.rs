@@ -1,5 +1,47 @@
+pub mod caddy;
+pub mod catalog;
+pub mod checksum;
+pub mod conflicts;
+pub mod cookies;
 pub mod crawl;
+pub mod dedup;
+pub mod diff;
+pub mod events;
+pub mod excludes;
+pub mod extract;
 pub mod fetch;
+pub mod filenames;
+pub mod format;
+pub mod ftp;
+pub mod hook;
+pub mod ignores;
+pub mod listing;
+pub mod lock;
+pub mod lockfile;
+pub mod manifest;
+pub mod merge;
+pub mod mmap;
+pub mod observer;
+pub mod order;
+pub mod pagination;
+pub mod paths;
+pub mod progress;
+pub mod prune;
+pub mod report;
+pub mod robots;
+pub mod s3;
+pub mod status;
+pub mod tarball;
+pub mod template;
+pub mod throttle;
+pub mod throughput;
+pub mod timeout;
+pub mod tls;
 pub mod types;
+pub mod units;
+pub mod urllist;
+pub mod useragents;
+pub mod verify;
+pub mod xattrs;
 
 mod selectors;
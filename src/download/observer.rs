@@ -0,0 +1,257 @@
+use super::{
+    report::SkipReason,
+    types::{FileLinkMetaData, Node},
+};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/**
+Gets notified about the lifecycle of each file of a download
+
+All methods do nothing by default, so implementations only need to handle the events they are
+interested in. Observers may get called from concurrent downloads, so they have to be Send + Sync
+(and use interior mutability to keep state).
+
+For each file, either `on_file_skipped` gets called, or `on_file_start` followed by any number of
+`on_progress` calls and finally either `on_file_done` or `on_error`. A download which gets tried
+again reports `on_retry` (and starts over). Before that, the crawl
+reports every file & directory it finds with `on_discovered`.
+*/
+pub trait DownloadObserver: Send + Sync {
+    /// A file or directory was found in a listing
+    fn on_discovered(&self, _node: &Node) {}
+
+    /// A file starts downloading (with the length announced by the server, if any)
+    fn on_file_start(&self, _file: &FileLinkMetaData, _length: Option<u64>) {}
+
+    /// A number of bytes of a file arrived
+    fn on_progress(&self, _file: &FileLinkMetaData, _bytes: u64) {}
+
+    /// A file is completely downloaded (of the given size in bytes)
+    fn on_file_done(&self, _file: &FileLinkMetaData, _bytes: u64) {}
+
+    /// A file doesn't get downloaded
+    fn on_file_skipped(&self, _file: &FileLinkMetaData, _reason: SkipReason) {}
+
+    /// The download of a file failed
+    fn on_error(&self, _file: &FileLinkMetaData, _error: &anyhow::Error) {}
+
+    /// The download of a file failed, but gets tried again
+    fn on_retry(&self, _file: &FileLinkMetaData, _error: &anyhow::Error) {}
+}
+
+/**
+Passes the events on to several observers (in order)
+*/
+pub struct Observers<'a>(pub Vec<&'a dyn DownloadObserver>);
+
+impl DownloadObserver for Observers<'_> {
+    fn on_discovered(&self, node: &Node) {
+        self.0
+            .iter()
+            .for_each(|observer| observer.on_discovered(node));
+    }
+
+    fn on_file_start(&self, file: &FileLinkMetaData, length: Option<u64>) {
+        self.0
+            .iter()
+            .for_each(|observer| observer.on_file_start(file, length));
+    }
+
+    fn on_progress(&self, file: &FileLinkMetaData, bytes: u64) {
+        self.0
+            .iter()
+            .for_each(|observer| observer.on_progress(file, bytes));
+    }
+
+    fn on_file_done(&self, file: &FileLinkMetaData, bytes: u64) {
+        self.0
+            .iter()
+            .for_each(|observer| observer.on_file_done(file, bytes));
+    }
+
+    fn on_file_skipped(&self, file: &FileLinkMetaData, reason: SkipReason) {
+        self.0
+            .iter()
+            .for_each(|observer| observer.on_file_skipped(file, reason));
+    }
+
+    fn on_error(&self, file: &FileLinkMetaData, error: &anyhow::Error) {
+        self.0
+            .iter()
+            .for_each(|observer| observer.on_error(file, error));
+    }
+
+    fn on_retry(&self, file: &FileLinkMetaData, error: &anyhow::Error) {
+        self.0
+            .iter()
+            .for_each(|observer| observer.on_retry(file, error));
+    }
+}
+
+/**
+Counts the retries of a download (to record its attempts in the state store)
+*/
+#[derive(Default)]
+pub struct RetryCounter(AtomicU32);
+
+impl RetryCounter {
+    /// The requests the download took (the first one & its retries)
+    pub fn attempts(&self) -> u32 {
+        self.0.load(Ordering::Relaxed) + 1
+    }
+}
+
+impl DownloadObserver for RetryCounter {
+    fn on_retry(&self, _file: &FileLinkMetaData, _error: &anyhow::Error) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cli::CliOptions,
+        download::{
+            fetch::{self, DownloadRecursiveStatus, LimitCounts},
+            listing::MockListing,
+            types::{DirLinkMetaData, StateStore},
+        },
+    };
+    use std::sync::Mutex;
+
+    /// Records the events (with the names of the files)
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<String>>);
+
+    impl Recorder {
+        fn record(&self, event: &str, file: &FileLinkMetaData) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("{} {}", event, file.name));
+        }
+    }
+
+    impl DownloadObserver for Recorder {
+        fn on_file_start(&self, file: &FileLinkMetaData, _length: Option<u64>) {
+            self.record("start", file);
+        }
+
+        fn on_progress(&self, file: &FileLinkMetaData, _bytes: u64) {
+            self.record("progress", file);
+        }
+
+        fn on_file_done(&self, file: &FileLinkMetaData, _bytes: u64) {
+            self.record("done", file);
+        }
+
+        fn on_file_skipped(&self, file: &FileLinkMetaData, reason: SkipReason) {
+            self.record(&format!("skipped ({:?})", reason), file);
+        }
+
+        fn on_error(&self, file: &FileLinkMetaData, _error: &anyhow::Error) {
+            self.record("error", file);
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_the_lifecycle_of_each_file() {
+        let destination = tempfile::tempdir().unwrap();
+        let options = CliOptions::for_test(&[
+            "-f",
+            r"\.log$",
+            "-d",
+            destination.path().to_str().unwrap(),
+            "http://a/pub/",
+        ]);
+        // The mock backend serves the URLs as the contents
+        let file = |url: &str| Node::File(FileLinkMetaData::for_test(url, url.len() as u64));
+        let root = Node::CrawledDir(
+            DirLinkMetaData {
+                name: "/pub".to_owned(),
+                ..DirLinkMetaData::for_test("http://a/pub/")
+            },
+            vec![
+                file("http://a/pub/1.txt"),
+                file("http://a/pub/2.log"),
+                file("http://a/pub/3.txt"),
+            ],
+        );
+        let backend = MockListing::default();
+        let recorder = Recorder::default();
+
+        let status = fetch::download_recursive(
+            &root,
+            &options,
+            &backend,
+            &mut LimitCounts::new(),
+            &mut StateStore::new(),
+            &recorder,
+        )
+        .await
+        .unwrap();
+
+        assert!(matches!(status, DownloadRecursiveStatus::Done));
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            [
+                "start 1.txt",
+                "progress 1.txt",
+                "done 1.txt",
+                "skipped (Filtered) 2.log",
+                "start 3.txt",
+                "progress 3.txt",
+                "done 3.txt",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn downloads_in_the_order_of_the_sizes() {
+        let destination = tempfile::tempdir().unwrap();
+        let options = CliOptions::for_test(&[
+            "--sort",
+            "size",
+            "-d",
+            destination.path().to_str().unwrap(),
+            "http://a/pub/",
+        ]);
+        // The longer the URL, the larger the file
+        let file = |url: &str| Node::File(FileLinkMetaData::for_test(url, url.len() as u64));
+        let root = Node::CrawledDir(
+            DirLinkMetaData {
+                name: "/pub".to_owned(),
+                ..DirLinkMetaData::for_test("http://a/pub/")
+            },
+            vec![
+                file("http://a/pub/large.bin"),
+                file("http://a/pub/s.bin"),
+                file("http://a/pub/mid.bin"),
+            ],
+        );
+        let backend = MockListing::default();
+        let recorder = Recorder::default();
+
+        fetch::download_recursive(
+            &root,
+            &options,
+            &backend,
+            &mut LimitCounts::new(),
+            &mut StateStore::new(),
+            &recorder,
+        )
+        .await
+        .unwrap();
+
+        let started: Vec<String> = recorder
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|event| event.starts_with("start"))
+            .cloned()
+            .collect();
+        assert_eq!(started, ["start s.bin", "start mid.bin", "start large.bin"]);
+    }
+}
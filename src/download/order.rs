@@ -0,0 +1,157 @@
+use super::{
+    types::{FileLinkMetaData, Node},
+    units::parse_date,
+};
+use std::cmp::{Ordering, Reverse};
+
+/// What the files of a directory get downloaded by (see `--sort`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    Size,
+    Name,
+    Date,
+
+    /// Like `Name`, but numbers compare by their value (e.g. `part2` before `part10`)
+    Natural,
+}
+
+/// The order of the files (and subdirectories) of a directory
+#[derive(Debug, Clone, Copy)]
+pub struct SortOrder {
+    pub key: SortKey,
+    pub descending: bool,
+}
+
+/**
+Returns the children of a directory in the desired order (or in the order of the listing)
+
+Directories have no size, so they're ordered by name when sorting by size. Entries whose size
+or date is unknown always come last.
+*/
+pub fn sorted_children(children: &[Node], order: Option<SortOrder>) -> Vec<&Node> {
+    let mut sorted: Vec<&Node> = children.iter().collect();
+
+    if let Some(order) = order {
+        // A stable sort keeps the listing order among equal entries
+        sorted.sort_by(|a, b| compare(a, b, order));
+    }
+
+    sorted
+}
+
+/**
+Sorts files (e.g. of the whole tree) by their date, newest first (see `--newest-first`)
+
+Files whose date is unknown come last, files of the same date keep their order.
+*/
+pub fn sort_newest_first<T>(files: &mut [T], file_of: impl Fn(&T) -> &FileLinkMetaData) {
+    files.sort_by_cached_key(|item| {
+        Reverse(parse_date(&file_of(item).last_modified).map(|date| date.timestamp()))
+    });
+}
+
+/// Compares two nodes of the same directory
+fn compare(a: &Node, b: &Node, order: SortOrder) -> Ordering {
+    let (name_a, name_b) = (name(a), name(b));
+
+    let by_key = match order.key {
+        SortKey::Size => compare_known(size(a), size(b), order.descending),
+        SortKey::Date => compare_known(date(a), date(b), order.descending),
+        SortKey::Name => Ordering::Equal,
+        SortKey::Natural => directed(natural_cmp(name_a, name_b), order.descending),
+    };
+
+    by_key.then_with(|| directed(name_a.cmp(name_b), order.descending))
+}
+
+/// Compares optional values, with unknown ones last (regardless of the direction)
+fn compare_known<T: Ord>(a: Option<T>, b: Option<T>, descending: bool) -> Ordering {
+    match (a, b) {
+        (Some(a), Some(b)) => directed(a.cmp(&b), descending),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    }
+}
+
+fn directed(ordering: Ordering, descending: bool) -> Ordering {
+    if descending {
+        ordering.reverse()
+    } else {
+        ordering
+    }
+}
+
+fn name(node: &Node) -> &str {
+    match node {
+        Node::File(file) => &file.name,
+        Node::PendingDir(dir) | Node::CrawledDir(dir, _) => &dir.name,
+    }
+}
+
+fn size(node: &Node) -> Option<u64> {
+    match node {
+        Node::File(file) => file.size_bytes,
+        _ => None,
+    }
+}
+
+fn date(node: &Node) -> Option<i64> {
+    let last_modified = match node {
+        Node::File(file) => &file.last_modified,
+        Node::PendingDir(dir) | Node::CrawledDir(dir, _) => &dir.last_modified,
+    };
+
+    parse_date(last_modified).map(|date| date.timestamp())
+}
+
+/// Compares names like a human would, with runs of digits compared by their value
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a, b);
+
+    loop {
+        match (a.chars().next(), b.chars().next()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let (number_a, rest_a) = split_number(a);
+                let (number_b, rest_b) = split_number(b);
+
+                // Compare the values without leading zeros (by length first, so any length works)
+                let (value_a, value_b) = (
+                    number_a.trim_start_matches('0'),
+                    number_b.trim_start_matches('0'),
+                );
+                let ordering = value_a
+                    .len()
+                    .cmp(&value_b.len())
+                    .then_with(|| value_a.cmp(value_b));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+
+                a = rest_a;
+                b = rest_b;
+            }
+            (Some(x), Some(y)) => {
+                let ordering = x.to_lowercase().cmp(y.to_lowercase());
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+
+                a = &a[x.len_utf8()..];
+                b = &b[y.len_utf8()..];
+            }
+        }
+    }
+}
+
+/// Splits the leading digits off a string
+fn split_number(text: &str) -> (&str, &str) {
+    let end = text
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(text.len());
+
+    text.split_at(end)
+}
@@ -0,0 +1,61 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+use reqwest::Url;
+use scraper::{Html, Selector};
+
+lazy_static! {
+    /// Matches links & link elements declaring the next page (`rel="next"`)
+    static ref REL_NEXT: Selector = Selector::parse(r#"a[rel~="next"][href], link[rel~="next"][href]"#).unwrap();
+
+    /// Matches every link carrying an href
+    static ref LINK: Selector = Selector::parse("a[href]").unwrap();
+
+    /// Matches the usual captions of links to the next page (e.g. `Next`, `Next page »`, `>>`)
+    static ref RX_NEXT_TEXT: Regex = Regex::new(r"(?i)^\s*(next(\s+page)?\s*[>»›]*|[>»›]{1,2})\s*$").unwrap();
+}
+
+/**
+Finds the link to the next page of a paginated listing
+
+Servers paginate listings in different ways, so each way gets its own implementation (see
+`default_paginations` for the ones used by default).
+*/
+pub trait Pagination: Send + Sync {
+    /// Returns the URL of the page following the given page of a listing (if any)
+    fn next_page(&self, document: &Html, url: &Url) -> Option<Url>;
+}
+
+/**
+Follows links marked with `rel="next"` (as `<a>` or `<link>` element)
+*/
+#[derive(Debug, Default)]
+pub struct RelNext;
+
+impl Pagination for RelNext {
+    fn next_page(&self, document: &Html, url: &Url) -> Option<Url> {
+        let href = document.select(&REL_NEXT).next()?.value().attr("href")?;
+
+        url.join(href).ok()
+    }
+}
+
+/**
+Follows links captioned like a link to the next page (e.g. `Next` or `»`)
+*/
+#[derive(Debug, Default)]
+pub struct NextLinkText;
+
+impl Pagination for NextLinkText {
+    fn next_page(&self, document: &Html, url: &Url) -> Option<Url> {
+        let link = document
+            .select(&LINK)
+            .find(|link| RX_NEXT_TEXT.is_match(&link.text().collect::<String>()))?;
+
+        url.join(link.value().attr("href")?).ok()
+    }
+}
+
+/// Returns the ways of pagination which are detected by default
+pub fn default_paginations() -> Vec<Box<dyn Pagination>> {
+    vec![Box::new(RelNext), Box::new(NextLinkText)]
+}
@@ -0,0 +1,243 @@
+use super::filenames::decode_filename;
+use reqwest::Url;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+
+/// Characters which aren't allowed in file names on at least one common platform
+const RESERVED_CHARS: [char; 9] = ['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+
+/// The longest extension kept when shortening a file name (longer ones are rather part of the name)
+const MAX_EXTENSION: usize = 16;
+
+/// File names which are reserved on Windows (regardless of their extension)
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/**
+Shortens a file name to at most `max_bytes` bytes (UTF-8), keeping its extension
+
+A hash of the whole name gets appended to the shortened stem (e.g. `a-very-lo~1a2b3c4d.txt`), so
+different long names don't collide, and the same name is always shortened the same way.
+*/
+pub fn truncate_name(name: &str, max_bytes: usize) -> String {
+    if name.len() <= max_bytes {
+        return name.to_owned();
+    }
+
+    let hash: String = Sha256::digest(name.as_bytes())[..4]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+
+    // Drop the extension if there is no room for it
+    let extension = match name.rfind('.') {
+        Some(dot) if dot > 0 && name.len() - dot <= MAX_EXTENSION => &name[dot..],
+        _ => "",
+    };
+    let extension = if extension.len() + hash.len() + 1 < max_bytes {
+        extension
+    } else {
+        ""
+    };
+
+    let stem = &name[..name.len() - extension.len()];
+    let mut end = max_bytes.saturating_sub(extension.len() + hash.len() + 1);
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    format!("{}~{}{}", &stem[..end], hash, extension)
+}
+
+/**
+Maps a remote URL to a path below the output directory
+
+The path of `url` relative to `base_url` gets decoded (see `decode_filename`) and each segment gets sanitized,
+so the remote tree is reproduced below `output_dir`. URLs outside of `base_url` keep their full
+path. Segments like `..` are dropped, so the result can never escape `output_dir`.
+*/
+pub fn url_to_local_path(base_url: &Url, url: &Url, output_dir: &Path) -> PathBuf {
+    let mut path = output_dir.to_path_buf();
+
+    for segment in relative_segments(base_url, url) {
+        if let Some(segment) = sanitize_segment(&decode_filename(segment)) {
+            path.push(segment);
+        }
+    }
+
+    path
+}
+
+/**
+Returns the decoded path of a URL below the path of the base URL, separated by `/`
+
+Unlike `url_to_local_path`, the server is ignored, so the same file on a mirror (or on a moved
+server) has the same path.
+*/
+pub fn path_below(base_url: &Url, url: &Url) -> String {
+    let segments: Vec<&str> = url.path_segments().map_or(vec![], |s| s.collect());
+    let base: Vec<&str> = base_url.path_segments().map_or(vec![], |s| {
+        s.filter(|segment| !segment.is_empty()).collect()
+    });
+
+    let below = if segments.len() >= base.len() && segments[..base.len()] == base[..] {
+        &segments[base.len()..]
+    } else {
+        &segments[..]
+    };
+
+    below
+        .iter()
+        .map(|segment| decode_filename(segment))
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Returns the path segments of a URL which follow the path of the base URL
+fn relative_segments<'a>(base_url: &Url, url: &'a Url) -> Vec<&'a str> {
+    let segments: Vec<&str> = url.path_segments().map_or(vec![], |s| s.collect());
+
+    // Only strip the base if the URL is actually below it
+    if url.origin() == base_url.origin() {
+        let base: Vec<&str> = base_url.path_segments().map_or(vec![], |s| {
+            s.filter(|segment| !segment.is_empty()).collect()
+        });
+
+        if segments.len() >= base.len() && segments[..base.len()] == base[..] {
+            return segments[base.len()..].to_vec();
+        }
+    }
+
+    segments
+}
+
+/**
+Makes a (decoded) path segment safe to use as a file name on all common platforms
+
+Returns None for segments which must not be used at all (empty, `.` and `..`).
+*/
+pub fn sanitize_segment(segment: &str) -> Option<String> {
+    // Replace reserved and control characters
+    let sanitized: String = segment
+        .chars()
+        .map(|c| {
+            if RESERVED_CHARS.contains(&c) || c.is_control() {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+
+    // Windows drops trailing dots and spaces
+    let sanitized = sanitized.trim_end_matches(['.', ' ']);
+
+    if sanitized.is_empty() {
+        return None;
+    }
+
+    // Avoid device names like `CON` or `nul.txt`
+    let stem = sanitized.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES
+        .iter()
+        .any(|name| name.eq_ignore_ascii_case(stem))
+    {
+        return Some(format!("_{}", sanitized));
+    }
+
+    Some(sanitized.to_owned())
+}
+
+/**
+Returns the URL of the directory containing the given (directory) URL
+*/
+pub fn parent_url(url: &Url) -> Url {
+    url.join("..").unwrap_or_else(|_| url.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Maps a URL below `http://a/pub/` to a local path below `/out`
+    fn local_path(url: &str) -> PathBuf {
+        let base_url = Url::parse("http://a/pub/").unwrap();
+
+        url_to_local_path(&base_url, &Url::parse(url).unwrap(), Path::new("/out"))
+    }
+
+    #[test]
+    fn decodes_percent_encoded_names() {
+        assert_eq!(
+            local_path("http://a/pub/My%20Files/caf%C3%A9.txt"),
+            Path::new("/out/My Files/café.txt")
+        );
+    }
+
+    #[test]
+    fn replaces_reserved_characters() {
+        assert_eq!(
+            local_path("http://a/pub/a%3Ab%3F%2A%7C%22c%5C.txt"),
+            Path::new("/out/a_b____c_.txt")
+        );
+        assert_eq!(
+            local_path("http://a/pub/CON.txt"),
+            Path::new("/out/_CON.txt")
+        );
+        assert_eq!(local_path("http://a/pub/dots..."), Path::new("/out/dots"));
+        assert_eq!(sanitize_segment("tab\there"), Some("tab_here".to_owned()));
+    }
+
+    #[test]
+    fn contains_traversals() {
+        // Encoded, so they survive the URL parser
+        assert_eq!(
+            local_path("http://a/pub/%2E%2E/%2E%2E/etc/passwd"),
+            Path::new("/out/etc/passwd")
+        );
+        assert_eq!(
+            local_path("http://a/pub/..%2F..%2Fetc%2Fpasswd"),
+            Path::new("/out/.._.._etc_passwd")
+        );
+        assert_eq!(sanitize_segment(".."), None);
+        assert_eq!(sanitize_segment("."), None);
+    }
+
+    #[test]
+    fn truncates_long_names() {
+        let long = format!("{}.tar.gz", "x".repeat(300));
+        let truncated = truncate_name(&long, 64);
+
+        assert!(truncated.len() <= 64, "{}", truncated);
+        assert!(truncated.starts_with("xxxx"));
+        assert!(truncated.ends_with(".gz"));
+        assert_eq!(truncated.matches('~').count(), 1);
+        assert_eq!(truncate_name(&long, 64), truncated);
+
+        // Names differing beyond the limit still get different names
+        let other = format!("{}.tar.gz", "x".repeat(301));
+        assert_ne!(truncate_name(&other, 64), truncated);
+
+        assert_eq!(truncate_name("short.txt", 64), "short.txt");
+    }
+
+    #[test]
+    fn truncates_on_character_boundaries() {
+        let long = format!("{}.txt", "ä".repeat(150));
+        let truncated = truncate_name(&long, 63);
+
+        assert!(truncated.len() <= 63, "{}", truncated);
+        assert!(truncated.ends_with(".txt"));
+        assert!(truncated.starts_with("ää"));
+    }
+
+    #[test]
+    fn keeps_the_full_path_of_urls_elsewhere() {
+        assert_eq!(
+            local_path("http://b/pub/x/y.txt"),
+            Path::new("/out/pub/x/y.txt")
+        );
+    }
+}
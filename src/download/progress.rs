@@ -0,0 +1,403 @@
+use super::{
+    observer::DownloadObserver,
+    report::SkipReason,
+    throughput::Throughput,
+    types::{FileLinkMetaData, Node},
+};
+use indicatif::{
+    HumanBytes, HumanDuration, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle,
+};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+/// The default time between two progress lines (see `--progress-interval`)
+pub const DEFAULT_INTERVAL: &str = "30s";
+
+const OVERALL_TEMPLATE: &str = "{prefix} [{bar:40}] {pos}/{len} files, {msg}";
+const FILE_TEMPLATE: &str = "{msg:30!} [{bar:40}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})";
+const FILE_TEMPLATE_UNKNOWN: &str = "{msg:30!} {spinner} {bytes} ({bytes_per_sec})";
+
+/**
+Displays the progress of a download using terminal progress bars
+
+- An overall bar counts the processed files (out of all crawled files) and the downloaded bytes
+  (when streaming, the totals grow as the crawl discovers more files), and estimates the time
+  left by the throughput of the last few seconds
+- A bar per (concurrently) downloading file shows its bytes, total and speed
+
+It gets driven by the events of the download (as a DownloadObserver). A disabled instance does
+nothing, so it can be passed around unconditionally.
+*/
+#[derive(Debug, Clone)]
+pub struct Progress {
+    bars: Option<Arc<Bars>>,
+}
+
+#[derive(Debug)]
+struct Bars {
+    multi: MultiProgress,
+    overall: ProgressBar,
+    downloaded_bytes: AtomicU64,
+    total_bytes: AtomicU64,
+
+    /// The size of the skipped files (which are done without being downloaded)
+    skipped_bytes: AtomicU64,
+    throughput: Mutex<Throughput>,
+
+    /// Whether the files get counted as they are discovered (see `Progress::growing`)
+    growing: bool,
+
+    /// The bars of the files being downloaded (by URL)
+    files: Mutex<HashMap<String, ProgressBar>>,
+}
+
+impl Progress {
+    /// Makes a progress display which doesn't show anything
+    pub fn disabled() -> Self {
+        Self { bars: None }
+    }
+
+    /// Makes a progress display for all the files in the given (crawled) tree
+    pub fn new(root: &Node) -> Self {
+        let summary = root.summarize();
+
+        Self::with_totals(summary.files, summary.total_bytes, false)
+    }
+
+    /// Makes a progress display for a tree which is still being crawled (see `--stream`)
+    pub fn growing() -> Self {
+        Self::with_totals(0, 0, true)
+    }
+
+    fn with_totals(files: u64, total_bytes: u64, growing: bool) -> Self {
+        let multi = MultiProgress::with_draw_target(ProgressDrawTarget::stderr());
+        let overall = multi.add(ProgressBar::new(files));
+        overall.set_style(
+            ProgressStyle::with_template(OVERALL_TEMPLATE)
+                .unwrap()
+                .progress_chars("=> "),
+        );
+        overall.set_prefix("Total");
+
+        let bars = Bars {
+            multi,
+            overall,
+            downloaded_bytes: AtomicU64::new(0),
+            total_bytes: AtomicU64::new(total_bytes),
+            skipped_bytes: AtomicU64::new(0),
+            throughput: Mutex::new(Throughput::new()),
+            growing,
+            files: Mutex::new(HashMap::new()),
+        };
+        bars.add_bytes(0);
+
+        Self {
+            bars: Some(Arc::new(bars)),
+        }
+    }
+
+    /// Removes the bars from the terminal
+    pub fn finish(&self) {
+        if let Some(bars) = &self.bars {
+            bars.overall.finish_and_clear();
+        }
+    }
+}
+
+impl Bars {
+    /// Adds to the downloaded bytes and updates the overall bar
+    fn add_bytes(&self, bytes: u64) {
+        let downloaded = self.downloaded_bytes.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        let total = self.total_bytes.load(Ordering::Relaxed);
+        let remaining =
+            total.saturating_sub(downloaded + self.skipped_bytes.load(Ordering::Relaxed));
+
+        let mut throughput = self.throughput.lock().unwrap();
+        if bytes > 0 {
+            throughput.add(bytes);
+        }
+
+        match throughput.eta(remaining) {
+            Some(eta) => self.overall.set_message(format!(
+                "{}/{}, {} left",
+                HumanBytes(downloaded),
+                HumanBytes(total),
+                HumanDuration(eta)
+            )),
+            None => self.overall.set_message(format!(
+                "{}/{}",
+                HumanBytes(downloaded),
+                HumanBytes(total)
+            )),
+        }
+    }
+
+    /// Removes the bar of a file (if any) and counts the file as processed
+    fn finish_file(&self, url: &str) {
+        if let Some(bar) = self.files.lock().unwrap().remove(url) {
+            bar.finish_and_clear();
+        }
+
+        self.overall.inc(1);
+    }
+}
+
+impl DownloadObserver for Progress {
+    fn on_discovered(&self, node: &Node) {
+        if let (Some(bars), Node::File(file)) = (&self.bars, node) {
+            if bars.growing {
+                bars.overall.inc_length(1);
+                bars.total_bytes
+                    .fetch_add(file.size_bytes.unwrap_or_default(), Ordering::Relaxed);
+                bars.add_bytes(0);
+            }
+        }
+    }
+
+    fn on_file_start(&self, file: &FileLinkMetaData, length: Option<u64>) {
+        if let Some(bars) = &self.bars {
+            let (bar, template) = match length {
+                Some(length) => (ProgressBar::new(length), FILE_TEMPLATE),
+                None => (ProgressBar::new_spinner(), FILE_TEMPLATE_UNKNOWN),
+            };
+            bar.set_style(
+                ProgressStyle::with_template(template)
+                    .unwrap()
+                    .progress_chars("=> "),
+            );
+            bar.set_message(file.name.clone());
+
+            let bar = bars.multi.add(bar);
+            bars.files.lock().unwrap().insert(file.url.clone(), bar);
+        }
+    }
+
+    fn on_progress(&self, file: &FileLinkMetaData, bytes: u64) {
+        if let Some(bars) = &self.bars {
+            if let Some(bar) = bars.files.lock().unwrap().get(&file.url) {
+                bar.inc(bytes);
+            }
+            bars.add_bytes(bytes);
+        }
+    }
+
+    fn on_file_done(&self, file: &FileLinkMetaData, _bytes: u64) {
+        if let Some(bars) = &self.bars {
+            bars.finish_file(&file.url);
+        }
+    }
+
+    fn on_file_skipped(&self, file: &FileLinkMetaData, _reason: SkipReason) {
+        if let Some(bars) = &self.bars {
+            bars.skipped_bytes
+                .fetch_add(file.size_bytes.unwrap_or_default(), Ordering::Relaxed);
+            bars.finish_file(&file.url);
+        }
+    }
+
+    fn on_error(&self, file: &FileLinkMetaData, _error: &anyhow::Error) {
+        if let Some(bars) = &self.bars {
+            bars.finish_file(&file.url);
+        }
+    }
+}
+
+/**
+Prints the progress of a download as a line every few seconds (see `--progress-interval`)
+
+Made for logs (e.g. of CI jobs), which only get appended to, so there are no bars to redraw:
+`[120/500 files, 3.2 GiB/12.0 GiB, 4.1 MiB/s, 40m left]`. The counts follow the events of the
+download like the progress bars, while the lines come from a task of their own (so a stalled
+download keeps printing). The task stops once the instance is dropped.
+*/
+pub struct ProgressLines {
+    counts: Arc<Mutex<Counts>>,
+    printer: tokio::task::JoinHandle<()>,
+}
+
+/// What the lines are made of
+#[derive(Debug)]
+struct Counts {
+    /// The files which are done (downloaded, skipped or failed)
+    files_done: u64,
+    files_total: u64,
+    bytes_done: u64,
+
+    /// The size of the skipped files (which are done without being downloaded)
+    bytes_skipped: u64,
+    bytes_total: u64,
+    throughput: Throughput,
+
+    /// Whether the files get counted as they are discovered (see `--stream`)
+    growing: bool,
+}
+
+impl Counts {
+    fn line(&self) -> String {
+        let remaining = self
+            .bytes_total
+            .saturating_sub(self.bytes_done + self.bytes_skipped);
+        let eta = match self.throughput.eta(remaining) {
+            Some(eta) => format!(", {} left", HumanDuration(eta)),
+            None => String::new(),
+        };
+
+        format!(
+            "[{}/{} files, {}/{}, {}/s{}]",
+            self.files_done,
+            self.files_total,
+            HumanBytes(self.bytes_done),
+            HumanBytes(self.bytes_total),
+            HumanBytes(self.throughput.rate() as u64),
+            eta
+        )
+    }
+}
+
+impl ProgressLines {
+    /// Prints the progress of a download of a crawled tree
+    pub fn new(root: &Node, interval: Duration) -> Self {
+        let summary = root.summarize();
+
+        Self::with_totals(summary.files, summary.total_bytes, false, interval)
+    }
+
+    /// Prints the progress of a download of a tree which is still being crawled
+    pub fn growing(interval: Duration) -> Self {
+        Self::with_totals(0, 0, true, interval)
+    }
+
+    fn with_totals(files: u64, bytes: u64, growing: bool, interval: Duration) -> Self {
+        let counts = Arc::new(Mutex::new(Counts {
+            files_done: 0,
+            files_total: files,
+            bytes_done: 0,
+            bytes_skipped: 0,
+            bytes_total: bytes,
+            throughput: Throughput::new(),
+            growing,
+        }));
+
+        let printed = counts.clone();
+        let printer = tokio::spawn(async move {
+            let start = tokio::time::Instant::now() + interval;
+            let mut ticks = tokio::time::interval_at(start, interval);
+            loop {
+                ticks.tick().await;
+                match printed.lock() {
+                    Ok(counts) => eprintln!("{}", counts.line()),
+                    Err(_) => return,
+                }
+            }
+        });
+
+        Self { counts, printer }
+    }
+
+    fn update(&self, function: impl FnOnce(&mut Counts)) {
+        if let Ok(mut counts) = self.counts.lock() {
+            function(&mut counts);
+        }
+    }
+}
+
+impl Drop for ProgressLines {
+    fn drop(&mut self) {
+        self.printer.abort();
+    }
+}
+
+impl DownloadObserver for ProgressLines {
+    fn on_discovered(&self, node: &Node) {
+        if let Node::File(file) = node {
+            self.update(|counts| {
+                if counts.growing {
+                    counts.files_total += 1;
+                    counts.bytes_total += file.size_bytes.unwrap_or_default();
+                }
+            });
+        }
+    }
+
+    fn on_progress(&self, _file: &FileLinkMetaData, bytes: u64) {
+        self.update(|counts| {
+            counts.bytes_done += bytes;
+            counts.throughput.add(bytes);
+        });
+    }
+
+    fn on_file_done(&self, _file: &FileLinkMetaData, _bytes: u64) {
+        self.update(|counts| counts.files_done += 1);
+    }
+
+    fn on_file_skipped(&self, file: &FileLinkMetaData, _reason: SkipReason) {
+        self.update(|counts| {
+            counts.files_done += 1;
+            counts.bytes_skipped += file.size_bytes.unwrap_or_default();
+        });
+    }
+
+    fn on_error(&self, _file: &FileLinkMetaData, _error: &anyhow::Error) {
+        self.update(|counts| counts.files_done += 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::types::DirLinkMetaData;
+
+    #[test]
+    fn counts_reach_the_totals() {
+        let files: Vec<_> = (0..8)
+            .map(|i| FileLinkMetaData::for_test(&format!("http://a/{}.bin", i), 1000))
+            .collect();
+        let root = Node::CrawledDir(
+            DirLinkMetaData::for_test("http://a/"),
+            files.iter().cloned().map(Node::File).collect(),
+        );
+        let progress = Progress::new(&root);
+
+        // Download the files concurrently, in chunks
+        std::thread::scope(|scope| {
+            for file in &files {
+                let progress = progress.clone();
+                scope.spawn(move || {
+                    progress.on_file_start(file, Some(1000));
+                    for _ in 0..4 {
+                        progress.on_progress(file, 250);
+                    }
+                    progress.on_file_done(file, 1000);
+                });
+            }
+        });
+
+        let bars = progress.bars.as_ref().unwrap();
+        assert_eq!(bars.overall.position(), 8);
+        assert_eq!(bars.overall.length(), Some(8));
+        assert_eq!(bars.downloaded_bytes.load(Ordering::Relaxed), 8000);
+        assert!(bars.files.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn growing_totals_follow_the_crawl() {
+        let progress = Progress::growing();
+        let file = FileLinkMetaData::for_test("http://a/a.bin", 10);
+        progress.on_discovered(&Node::File(file.clone()));
+        progress.on_discovered(&Node::PendingDir(DirLinkMetaData::for_test("http://a/b/")));
+
+        let bars = progress.bars.as_ref().unwrap();
+        assert_eq!(bars.overall.length(), Some(1));
+        assert_eq!(bars.total_bytes.load(Ordering::Relaxed), 10);
+
+        progress.on_file_skipped(&file, SkipReason::Filtered);
+        assert_eq!(bars.overall.position(), 1);
+        assert_eq!(bars.skipped_bytes.load(Ordering::Relaxed), 10);
+    }
+}
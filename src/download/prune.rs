@@ -0,0 +1,206 @@
+use super::{
+    checksum::{checksum_path, ChecksumKind},
+    fetch::{description_path, get_last_segment, local_name},
+    ignores::IGNORE_FILE,
+    paths,
+    types::Node,
+};
+use anyhow::{Context, Result};
+use log::{debug, info};
+use regex::Regex;
+use reqwest::Url;
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// The local paths which correspond to remote nodes
+#[derive(Debug, Default)]
+struct Expected {
+    /// Files & directories which are still on the server
+    paths: HashSet<PathBuf>,
+
+    /// Directories which weren't crawled (so nothing below them may be pruned)
+    unknown: HashSet<PathBuf>,
+}
+
+/**
+Deletes local files & directories which no longer exist on the server
+
+Only the local copy of the root directory (below the destination) is walked, so nothing else in
+the destination is ever touched. Paths in `protected` (e.g. the state store) and paths matching
+`exclude` (relative to the destination, separated by `/`) are kept, as is everything below
+directories which weren't crawled.
+
+Files are expected under the names they are downloaded as, so `max_length` has to be the
+`--max-filename-length` of the download.
+
+Returns the pruned paths (which are only listed, but not deleted, on a dry run).
+*/
+pub fn prune(
+    root: &Node,
+    base_url: &Url,
+    destination: &Path,
+    protected: &[PathBuf],
+    exclude: Option<&Regex>,
+    max_length: Option<usize>,
+    dry_run: bool,
+) -> Result<Vec<PathBuf>> {
+    let mut expected = Expected::default();
+    collect_expected(root, base_url, destination, max_length, &mut expected)?;
+
+    let root_path = match root {
+        Node::CrawledDir(meta, _) => {
+            paths::url_to_local_path(base_url, &Url::from_str(&meta.url)?, destination)
+        }
+        _ => return Ok(vec![]),
+    };
+
+    let mut pruned = vec![];
+    if root_path.is_dir() {
+        prune_dir(
+            &root_path,
+            destination,
+            &expected,
+            protected,
+            exclude,
+            dry_run,
+            &mut pruned,
+        )?;
+    }
+
+    Ok(pruned)
+}
+
+/// Collects the local paths of a node and its children
+fn collect_expected(
+    node: &Node,
+    base_url: &Url,
+    destination: &Path,
+    max_length: Option<usize>,
+    expected: &mut Expected,
+) -> Result<()> {
+    match node {
+        Node::CrawledDir(meta, children) => {
+            let dir_path =
+                paths::url_to_local_path(base_url, &Url::from_str(&meta.url)?, destination);
+
+            for child in children {
+                match child {
+                    Node::File(file) => {
+                        let url = Url::from_str(&file.url)?;
+                        let file_path =
+                            dir_path.join(local_name(get_last_segment(&url), max_length));
+
+                        // Keep the description sidecar (from --save-descriptions) as well
+                        if !file.description.is_empty() {
+                            expected.paths.insert(description_path(&file_path));
+                        }
+
+                        // And the checksum sidecars (from --emit-checksums)
+                        for kind in [ChecksumKind::Sha256, ChecksumKind::Md5] {
+                            expected.paths.insert(checksum_path(&file_path, kind));
+                        }
+                        expected.paths.insert(file_path);
+                    }
+                    _ => collect_expected(child, base_url, destination, max_length, expected)?,
+                }
+            }
+
+            expected.paths.insert(dir_path);
+        }
+        Node::PendingDir(meta) => {
+            let dir_path =
+                paths::url_to_local_path(base_url, &Url::from_str(&meta.url)?, destination);
+
+            expected.paths.insert(dir_path.clone());
+            expected.unknown.insert(dir_path);
+        }
+        Node::File(_) => (),
+    }
+
+    Ok(())
+}
+
+/// Prunes the entries of a directory (recursively)
+fn prune_dir(
+    dir: &Path,
+    destination: &Path,
+    expected: &Expected,
+    protected: &[PathBuf],
+    exclude: Option<&Regex>,
+    dry_run: bool,
+    pruned: &mut Vec<PathBuf>,
+) -> Result<()> {
+    let entries = fs::read_dir(dir)
+        .with_context(|| format!("Cannot read the directory {}", dir.display()))?;
+
+    for entry in entries {
+        let path = entry?.path();
+
+        // Keep protected & excluded paths (and the ignore files put into the mirror)
+        if protected.contains(&path)
+            || is_excluded(&path, destination, exclude)
+            || path.file_name().is_some_and(|name| name == IGNORE_FILE)
+        {
+            debug!("(Prune) Keep {}", path.display());
+            continue;
+        }
+
+        // Don't follow symbolic links (e.g. from --dedup)
+        let is_dir = fs::symlink_metadata(&path)?.is_dir();
+
+        if expected.paths.contains(&path) {
+            // Descend into directories which were crawled
+            if is_dir && !expected.unknown.contains(&path) {
+                prune_dir(
+                    &path,
+                    destination,
+                    expected,
+                    protected,
+                    exclude,
+                    dry_run,
+                    pruned,
+                )?;
+            }
+            continue;
+        }
+
+        if dry_run {
+            info!("Would prune {}", path.display());
+        } else {
+            if is_dir {
+                fs::remove_dir_all(&path)
+            } else {
+                fs::remove_file(&path)
+            }
+            .with_context(|| format!("Cannot prune {}", path.display()))?;
+
+            info!("Pruned {}", path.display());
+        }
+
+        pruned.push(path);
+    }
+
+    Ok(())
+}
+
+/// Checks if a path (relative to the destination) matches the exclude pattern
+fn is_excluded(path: &Path, destination: &Path, exclude: Option<&Regex>) -> bool {
+    let regex = match exclude {
+        Some(regex) => regex,
+        None => return false,
+    };
+
+    let relative = path
+        .strip_prefix(destination)
+        .unwrap_or(path)
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+
+    regex.is_match(&relative)
+}
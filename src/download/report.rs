@@ -0,0 +1,258 @@
+use super::units::format_size;
+use reqwest::Url;
+use serde::Serialize;
+use std::{collections::BTreeMap, fmt, path::PathBuf};
+
+/// Why a file wasn't downloaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    /// The file is in the done_list of the state store
+    AlreadyDownloaded,
+
+    /// The file name matches the --file-filter
+    Filtered,
+
+    /// The file name doesn't match the --file-matcher
+    NotMatched,
+
+    /// The size of the file is out of range (--min-size and --max-size)
+    SizeLimit,
+
+    /// The file is among the first n files (--skip)
+    SkipCount,
+
+    /// The local copy is at least as new (--only-newer)
+    UpToDate,
+
+    /// The file was last modified before --since
+    TooOld,
+
+    /// The file is unchanged and was hardlinked from the --link-dest snapshot
+    Linked,
+
+    /// The Content-Type of the file isn't among the --accept-type
+    NotAccepted,
+
+    /// The local path of the file matches an .od-get-ignore file
+    Ignored,
+
+    /// Something else is in the way of the file (--on-conflict skip)
+    Conflict,
+
+    /// The file is beyond the first n files of its directory (--sample-per-dir)
+    Sampled,
+
+    /// The file of the stored tree is gone from the server (--revalidate)
+    Gone,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DownloadedFile {
+    pub url: String,
+    pub path: PathBuf,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SkippedFile {
+    pub url: String,
+    pub reason: SkipReason,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FailedFile {
+    pub url: String,
+    pub error: String,
+}
+
+/**
+A machine-readable summary of a download
+
+Unlike the done_list of the state store, it covers only one run, but also the files which were
+skipped or failed (and why).
+*/
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DownloadReport {
+    pub downloaded: Vec<DownloadedFile>,
+    pub skipped: Vec<SkippedFile>,
+    pub failed: Vec<FailedFile>,
+
+    /// The URLs of the directories which couldn't be parsed (see --continue-on-parse-error)
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unparsed_dirs: Vec<String>,
+
+    pub total_bytes: u64,
+    pub elapsed_secs: f64,
+}
+
+impl DownloadReport {
+    pub fn add_downloaded(&mut self, url: &str, path: PathBuf, bytes: u64) {
+        self.total_bytes += bytes;
+        self.downloaded.push(DownloadedFile {
+            url: url.to_owned(),
+            path,
+            bytes,
+        });
+    }
+
+    pub fn add_skipped(&mut self, url: &str, reason: SkipReason) {
+        self.skipped.push(SkippedFile {
+            url: url.to_owned(),
+            reason,
+        });
+    }
+
+    pub fn add_failed(&mut self, url: &str, error: &anyhow::Error) {
+        self.failed.push(FailedFile {
+            url: url.to_owned(),
+            error: format!("{:#}", error),
+        });
+    }
+
+    /**
+    Returns the totals as (name, value) pairs, e.g. `("files", "123")`
+
+    They make up the final summary line and the environment of the `--notify-command`.
+    */
+    pub fn totals(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("files", self.downloaded.len().to_string()),
+            ("bytes", self.total_bytes.to_string()),
+            ("skipped", self.skipped.len().to_string()),
+            ("failed", self.failed.len().to_string()),
+            ("elapsed", format!("{:.1}s", self.elapsed_secs)),
+        ]
+    }
+
+    /**
+    Returns a single machine-parseable line summarizing the download
+
+    E.g. `DONE files=123 bytes=456789 skipped=4 failed=0 elapsed=12.3s` (with `FAILED` instead
+    of `DONE` if the download was aborted by an error).
+    */
+    pub fn summary_line(&self, aborted: bool) -> String {
+        let status = if aborted { "FAILED" } else { "DONE" };
+
+        self.totals()
+            .into_iter()
+            .fold(status.to_owned(), |line, (name, value)| {
+                format!("{} {}={}", line, name, value)
+            })
+    }
+}
+
+/**
+The metadata of a run, kept next to the downloaded files for later reference (see
+`--write-manifest`)
+
+Unlike the state store, it's only informational and never read back by od-get.
+*/
+#[derive(Debug, Clone, Serialize)]
+pub struct RunMarker {
+    pub source: String,
+
+    /// When the run ended (RFC 3339)
+    pub finished_at: String,
+    pub version: String,
+
+    /// The command line arguments of the run (without secrets, see `redact_arguments`)
+    pub arguments: Vec<String>,
+    pub aborted: bool,
+
+    /// The totals of the report (see `DownloadReport::totals`)
+    pub totals: BTreeMap<&'static str, String>,
+}
+
+impl RunMarker {
+    pub fn new(source: &str, version: &str, report: &DownloadReport, aborted: bool) -> Self {
+        Self {
+            source: redact_url(source),
+            finished_at: chrono::Utc::now().to_rfc3339(),
+            version: version.to_owned(),
+            arguments: redact_arguments(std::env::args().skip(1)),
+            aborted,
+            totals: report.totals().into_iter().collect(),
+        }
+    }
+}
+
+/// What secrets are replaced with in the marker
+const REDACTED: &str = "<redacted>";
+
+/// The options whose values may be secrets (cookies, or commands with tokens of web hooks)
+const SECRET_OPTIONS: [&str; 3] = ["--cookie", "--notify-command", "--exec"];
+
+/**
+Hides the secrets of command line arguments, as the marker is kept (and often published) along
+with the files
+
+The values of the options in `SECRET_OPTIONS` are replaced, and so are the credentials of URLs
+and of the `--socks5` proxy.
+*/
+fn redact_arguments(arguments: impl IntoIterator<Item = String>) -> Vec<String> {
+    let mut previous: Option<String> = None;
+
+    arguments
+        .into_iter()
+        .map(|argument| {
+            let option = previous.replace(argument.clone()).unwrap_or_default();
+            if let Some((name, value)) = argument.split_once('=') {
+                if name.starts_with("--") {
+                    return format!("{}={}", name, redact_value(name, value));
+                }
+            }
+
+            redact_value(&option, &argument)
+        })
+        .collect()
+}
+
+/// Hides the secrets of the value of an option (or of a plain argument)
+fn redact_value(option: &str, value: &str) -> String {
+    if SECRET_OPTIONS.contains(&option) {
+        return REDACTED.to_owned();
+    }
+
+    match value.rsplit_once('@') {
+        Some((_, address)) if option == "--socks5" => format!("{}@{}", REDACTED, address),
+        _ => redact_url(value),
+    }
+}
+
+/// Leaves the user name & password out of a URL (other values are returned as they are)
+fn redact_url(value: &str) -> String {
+    match Url::parse(value) {
+        Ok(mut url) if !url.username().is_empty() || url.password().is_some() => {
+            // Only fails for URLs without a host, which have no credentials
+            let _ = url.set_username("");
+            let _ = url.set_password(None);
+            url.to_string()
+        }
+        _ => value.to_owned(),
+    }
+}
+
+impl fmt::Display for DownloadReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Downloaded {} files ({}), skipped {}, failed {} in {:.1}s",
+            self.downloaded.len(),
+            format_size(self.total_bytes),
+            self.skipped.len(),
+            self.failed.len(),
+            self.elapsed_secs
+        )?;
+
+        if !self.unparsed_dirs.is_empty() {
+            write!(
+                f,
+                " ({} directories couldn't be parsed)",
+                self.unparsed_dirs.len()
+            )?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,549 @@
+use super::{
+    listing::{DirectoryListing, Fetched},
+    observer::DownloadObserver,
+    throttle::{throttled, Backoff, MAX_THROTTLED_RETRIES},
+    timeout::with_timeout,
+    types::{FileLinkMetaData, Node, Validators},
+    units::{parse_duration, ByteRange, DelayRange, Schedule},
+};
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use chrono::{Local, NaiveTime};
+use log::{debug, info, warn};
+use regex::Regex;
+use reqwest::{StatusCode, Url};
+use std::{
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{io::AsyncWrite, sync::watch};
+
+/// A rule of a robots.txt group
+#[derive(Debug)]
+struct Rule {
+    allow: bool,
+    pattern: Regex,
+
+    /// The length of the original pattern (the longest matching rule wins)
+    length: usize,
+}
+
+/**
+The rules of a robots.txt which apply to a user agent
+*/
+#[derive(Debug, Default)]
+pub struct Robots {
+    rules: Vec<Rule>,
+    pub crawl_delay: Option<Duration>,
+
+    /// The URL of the robots.txt (its rules only apply to the same server)
+    url: Option<Url>,
+}
+
+impl Robots {
+    /**
+    Parses a robots.txt, keeping the groups which apply to the given user agent
+
+    Groups naming the product token of the user agent (e.g. `od-get` for `od-get/0.3.1`) take
+    precedence over the `*` group.
+    */
+    pub fn parse(text: &str, user_agent: &str) -> Self {
+        let token = user_agent
+            .split('/')
+            .next()
+            .unwrap_or_default()
+            .to_lowercase();
+
+        let mut specific = Self::default();
+        let mut fallback = Self::default();
+
+        // Which robots the current group applies to (consecutive user-agent lines share a group)
+        let mut agents: Vec<String> = vec![];
+        let mut in_rules = false;
+
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            let (key, value) = match line.split_once(':') {
+                Some((key, value)) => (key.trim().to_lowercase(), value.trim()),
+                None => continue,
+            };
+
+            if key == "user-agent" {
+                if in_rules {
+                    agents.clear();
+                    in_rules = false;
+                }
+                agents.push(value.to_lowercase());
+                continue;
+            }
+            in_rules = true;
+
+            let target = if agents
+                .iter()
+                .any(|agent| !token.is_empty() && token == *agent)
+            {
+                &mut specific
+            } else if agents.iter().any(|agent| agent == "*") {
+                &mut fallback
+            } else {
+                continue;
+            };
+
+            match key.as_str() {
+                "allow" | "disallow" if !value.is_empty() => match pattern_to_regex(value) {
+                    Some(pattern) => target.rules.push(Rule {
+                        allow: key == "allow",
+                        pattern,
+                        length: value.len(),
+                    }),
+                    None => debug!("(Robots) Ignoring the rule {}", line),
+                },
+                "crawl-delay" => {
+                    target.crawl_delay = f64::from_str(value)
+                        .ok()
+                        .filter(|seconds| seconds.is_finite() && *seconds >= 0.0)
+                        .map(Duration::from_secs_f64)
+                        .or_else(|| parse_duration(value));
+                }
+                _ => (),
+            }
+        }
+
+        if specific.rules.is_empty() && specific.crawl_delay.is_none() {
+            fallback
+        } else {
+            specific
+        }
+    }
+
+    /**
+    Checks if a URL may be crawled (URLs on other servers always may)
+    */
+    pub fn is_url_allowed(&self, url: &Url) -> bool {
+        match &self.url {
+            Some(robots_url) if robots_url.origin() == url.origin() => {
+                let path = match url.query() {
+                    Some(query) => format!("{}?{}", url.path(), query),
+                    None => url.path().to_owned(),
+                };
+                self.is_allowed(&path)
+            }
+            _ => true,
+        }
+    }
+
+    /**
+    Checks if a path (including the query) may be crawled
+
+    The longest matching rule decides, `Allow` wins a tie. Paths without a matching rule are
+    allowed.
+    */
+    pub fn is_allowed(&self, path: &str) -> bool {
+        self.rules
+            .iter()
+            .filter(|rule| rule.pattern.is_match(path))
+            .max_by_key(|rule| (rule.length, rule.allow))
+            .is_none_or(|rule| rule.allow)
+    }
+
+    /**
+    Fetches & parses the robots.txt of the server of a URL
+
+    A missing robots.txt (or one which can't be fetched in time) allows everything.
+    */
+    pub async fn fetch(
+        client: &reqwest::Client,
+        url: &Url,
+        user_agent: &str,
+        read_timeout: Duration,
+    ) -> Self {
+        let robots_url = match url.join("/robots.txt") {
+            Ok(robots_url) => robots_url,
+            Err(_) => return Self::default(),
+        };
+
+        let res = match with_timeout(read_timeout, client.get(robots_url.as_str()).send()).await {
+            Ok(res) => res,
+            Err(err) => {
+                warn!("Cannot fetch {}, ignoring it: {}", robots_url, err);
+                return Self::default();
+            }
+        };
+
+        if res.status() != StatusCode::OK {
+            debug!("(Robots) No robots.txt ({})", res.status());
+            return Self::default();
+        }
+
+        match with_timeout(read_timeout, res.text()).await {
+            Ok(text) => {
+                info!("Following the rules of {}", robots_url);
+                Self {
+                    url: Some(robots_url),
+                    ..Self::parse(&text, user_agent)
+                }
+            }
+            Err(err) => {
+                warn!("Cannot read {}, ignoring it: {}", robots_url, err);
+                Self::default()
+            }
+        }
+    }
+}
+
+/**
+Turns a robots.txt path pattern (with `*` wildcards and an optional `$` anchor) into a Regex
+*/
+fn pattern_to_regex(pattern: &str) -> Option<Regex> {
+    let (pattern, anchored) = match pattern.strip_suffix('$') {
+        Some(pattern) => (pattern, true),
+        None => (pattern, false),
+    };
+
+    let body = pattern
+        .split('*')
+        .map(regex::escape)
+        .collect::<Vec<_>>()
+        .join(".*");
+
+    Regex::new(&format!("^{}{}", body, if anchored { "$" } else { "" })).ok()
+}
+
+/**
+A backend which follows the robots.txt and waits between requests
+
+Wraps another backend: Entries disallowed by the robots.txt are dropped from listings (so they
+never make it into the tree), and requests are spaced by at least the crawl delay.
+*/
+pub struct PoliteBackend {
+    inner: Box<dyn DirectoryListing>,
+    robots: Robots,
+    crawl_delay: Option<Duration>,
+
+    /// Wait for a random duration of this range instead of the crawl delay (see `--delay-jitter`)
+    jitter: Option<DelayRange>,
+
+    /// The hours without any delay (see `--schedule`)
+    schedule: Option<Schedule>,
+
+    /// Whether the previous request was within the schedule (to log the changes)
+    scheduled: Mutex<Option<bool>>,
+
+    /// Tells the time of day the schedule is checked against (the local time, but not in tests)
+    clock: fn() -> NaiveTime,
+
+    /// The earliest point in time for the next request
+    next_request: Mutex<Instant>,
+
+    /// The extra delay while the server asks to slow down
+    backoff: Backoff,
+
+    /// The number of requests made through this backend
+    requests: AtomicU64,
+
+    /// Make no more than this many requests (see `--max-requests`)
+    max_requests: Option<u64>,
+
+    /// Set once a request had to be held back because of the limit
+    out_of_requests: watch::Sender<bool>,
+}
+
+impl PoliteBackend {
+    /// Wraps a backend (an explicit crawl delay takes precedence over the one of the robots.txt)
+    pub fn new(
+        inner: Box<dyn DirectoryListing>,
+        robots: Robots,
+        crawl_delay: Option<Duration>,
+    ) -> Self {
+        let crawl_delay = crawl_delay.or(robots.crawl_delay);
+
+        Self {
+            inner,
+            robots,
+            crawl_delay,
+            jitter: None,
+            schedule: None,
+            scheduled: Mutex::new(None),
+            clock: || Local::now().time(),
+            next_request: Mutex::new(Instant::now()),
+            backoff: Backoff::default(),
+            requests: AtomicU64::new(0),
+            max_requests: None,
+            out_of_requests: watch::Sender::new(false),
+        }
+    }
+
+    /// Waits for a random duration of the range between requests (instead of the crawl delay)
+    pub fn with_jitter(mut self, jitter: DelayRange) -> Self {
+        self.jitter = Some(jitter);
+        self
+    }
+
+    /// Goes at full speed (without the delay) during the hours of the schedule
+    pub fn with_schedule(mut self, schedule: Schedule) -> Self {
+        self.schedule = Some(schedule);
+        self
+    }
+
+    /// Checks the schedule against another clock than the local time
+    #[cfg(test)]
+    fn with_clock(mut self, clock: fn() -> NaiveTime) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /**
+    Makes no more than a number of requests
+
+    The requests after that never complete, so the run has to stop once `out_of_requests` does.
+    */
+    pub fn with_max_requests(mut self, max_requests: u64) -> Self {
+        self.max_requests = Some(max_requests);
+        self
+    }
+
+    /// Returns the number of requests made so far (listings, downloads & HEAD requests)
+    pub fn requests(&self) -> u64 {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    /// Checks if a request was held back because of the limit
+    pub fn is_out_of_requests(&self) -> bool {
+        *self.out_of_requests.borrow()
+    }
+
+    /// Completes once a request is held back because of the limit, or never if there is none
+    pub async fn out_of_requests(&self) {
+        let mut reached = self.out_of_requests.subscribe();
+        if reached.wait_for(|reached| *reached).await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+
+    /// Checks if a URL may be crawled
+    fn is_allowed(&self, url: &str) -> bool {
+        Url::from_str(url).map_or(true, |url| self.robots.is_url_allowed(&url))
+    }
+
+    /**
+    Waits until the crawl delay since the previous request has passed (and counts the request)
+
+    While the server asks to slow down, the delay grows by the backoff (see `retry_throttled`).
+    Once the limit of requests is used up, this waits forever instead.
+    */
+    async fn wait_turn(&self) {
+        let made = self.requests.fetch_add(1, Ordering::Relaxed);
+        if self
+            .max_requests
+            .is_some_and(|max_requests| made >= max_requests)
+        {
+            self.requests.fetch_sub(1, Ordering::Relaxed);
+            debug!("(Requests) Holding back a request after {} requests", made);
+            self.out_of_requests.send_replace(true);
+            std::future::pending::<()>().await;
+        }
+
+        let delay = match (self.jitter, self.crawl_delay) {
+            _ if self.is_full_speed() => Duration::ZERO,
+            (Some(jitter), _) => jitter.sample(),
+            (None, Some(delay)) => delay,
+            (None, None) => Duration::ZERO,
+        } + self.backoff.delay();
+
+        let wait = {
+            let mut next_request = self.next_request.lock().unwrap();
+            let now = Instant::now();
+            let at = (*next_request).max(now);
+            *next_request = at + delay;
+
+            at - now
+        };
+
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Checks if the schedule currently lifts the delay (the local time of day is within it)
+    fn is_full_speed(&self) -> bool {
+        let schedule = match &self.schedule {
+            Some(schedule) => schedule,
+            None => return false,
+        };
+        let full_speed = schedule.contains((self.clock)());
+
+        let mut scheduled = self.scheduled.lock().unwrap();
+        if *scheduled != Some(full_speed) {
+            if full_speed {
+                info!("(Schedule) Going at full speed (within the schedule)");
+            } else {
+                info!("(Schedule) Waiting between requests (outside of the schedule)");
+            }
+            *scheduled = Some(full_speed);
+        }
+
+        full_speed
+    }
+
+    /**
+    Checks if a failed request should be tried again, because the server only asked to slow down
+    (e.g. with `429 Too Many Requests`)
+
+    The next request (of any kind) is then held back for as long as the Retry-After header asks
+    (or the backoff, if there is none), and the delay between requests grows.
+    */
+    fn retry_throttled(&self, error: &anyhow::Error, attempts: &mut u32) -> bool {
+        let throttled = match throttled(error) {
+            Some(throttled) if *attempts < MAX_THROTTLED_RETRIES => throttled,
+            _ => return false,
+        };
+        *attempts += 1;
+
+        let wait = self.backoff.throttled(throttled);
+        info!(
+            "(Throttled) {}, trying again in {:?} ({}/{})",
+            throttled, wait, attempts, MAX_THROTTLED_RETRIES
+        );
+
+        let mut next_request = self.next_request.lock().unwrap();
+        *next_request = (*next_request).max(Instant::now() + wait);
+
+        true
+    }
+}
+
+#[async_trait]
+impl DirectoryListing for PoliteBackend {
+    async fn list(&self, url: &Url) -> Result<Vec<Node>> {
+        if !self.is_allowed(url.as_str()) {
+            bail!(
+                "{} is disallowed by the robots.txt (use --ignore-robots to crawl it anyway)",
+                url
+            );
+        }
+
+        let mut attempts = 0;
+        let mut nodes = loop {
+            self.wait_turn().await;
+            match self.inner.list(url).await {
+                Err(error) if self.retry_throttled(&error, &mut attempts) => continue,
+                Err(error) => return Err(error),
+                Ok(nodes) => break nodes,
+            }
+        };
+        self.backoff.ease_off();
+
+        // Drop the entries the robots.txt disallows
+        nodes.retain(|node| {
+            let url = node.url();
+
+            let allowed = self.is_allowed(url);
+            if !allowed {
+                debug!("(Robots) Skip {}", url);
+            }
+
+            allowed
+        });
+
+        Ok(nodes)
+    }
+
+    async fn fetch(
+        &self,
+        file: &FileLinkMetaData,
+        validators: Option<&Validators>,
+        writer: &mut (dyn AsyncWrite + Unpin + Send),
+        observer: &dyn DownloadObserver,
+    ) -> Result<Fetched> {
+        let mut attempts = 0;
+        loop {
+            self.wait_turn().await;
+            match self.inner.fetch(file, validators, writer, observer).await {
+                Err(error) if self.retry_throttled(&error, &mut attempts) => {
+                    observer.on_retry(file, &error);
+                    continue;
+                }
+                Err(error) => return Err(error),
+                Ok(fetched) => {
+                    self.backoff.ease_off();
+                    return Ok(fetched);
+                }
+            }
+        }
+    }
+
+    async fn file_size(&self, file: &FileLinkMetaData) -> Result<Option<u64>> {
+        if !self.is_allowed(&file.url) {
+            return Ok(None);
+        }
+
+        self.wait_turn().await;
+        self.inner.file_size(file).await
+    }
+
+    async fn fetch_range(
+        &self,
+        file: &FileLinkMetaData,
+        range: ByteRange,
+        writer: &mut (dyn AsyncWrite + Unpin + Send),
+    ) -> Result<u64> {
+        self.wait_turn().await;
+        self.inner.fetch_range(file, range, writer).await
+    }
+
+    async fn content_type(&self, file: &FileLinkMetaData) -> Result<Option<String>> {
+        if !self.is_allowed(&file.url) {
+            return Ok(None);
+        }
+
+        self.wait_turn().await;
+        self.inner.content_type(file).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::{listing::MockListing, units::parse_schedule};
+    use std::sync::atomic::AtomicU32;
+
+    /// The simulated time of day (in seconds since midnight)
+    static NOW: AtomicU32 = AtomicU32::new(0);
+
+    fn simulated_time() -> NaiveTime {
+        NaiveTime::from_num_seconds_from_midnight_opt(NOW.load(Ordering::SeqCst), 0).unwrap()
+    }
+
+    /// Measures how long a number of requests wait for their turn
+    async fn time_requests(backend: &PoliteBackend, count: usize) -> Duration {
+        let started = Instant::now();
+        for _ in 0..count {
+            backend.wait_turn().await;
+        }
+
+        started.elapsed()
+    }
+
+    #[tokio::test]
+    async fn lifts_the_delay_within_the_schedule() {
+        let backend = PoliteBackend::new(
+            Box::new(MockListing::default()),
+            Robots::default(),
+            Some(Duration::from_millis(200)),
+        )
+        .with_schedule(parse_schedule("00:00-06:00").unwrap())
+        .with_clock(simulated_time);
+
+        // Just before the end of the window, one request follows the other
+        NOW.store(6 * 3600 - 1, Ordering::SeqCst);
+        assert!(time_requests(&backend, 5).await < Duration::from_millis(100));
+
+        // From then on, each one waits for the delay after the previous one
+        NOW.store(6 * 3600, Ordering::SeqCst);
+        assert!(time_requests(&backend, 3).await >= Duration::from_millis(400));
+    }
+}
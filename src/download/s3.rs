@@ -0,0 +1,456 @@
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use log::debug;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use regex::Regex;
+use reqwest::{Method, Url};
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, env, fs, path::PathBuf};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// The size of the parts of multipart uploads (S3 requires at least 5 MiB, except for the last)
+pub const PART_SIZE: usize = 8 * 1024 * 1024;
+
+/// The region used if none is configured
+const DEFAULT_REGION: &str = "us-east-1";
+
+/// The characters which AWS requires to be encoded (all but the unreserved ones)
+const AWS_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+lazy_static! {
+    static ref RX_UPLOAD_ID: Regex = Regex::new(r"<UploadId>([^<]+)</UploadId>").unwrap();
+}
+
+/// The credentials for signing requests
+#[derive(Debug, Clone)]
+struct Credentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+}
+
+/**
+A bucket (and a key prefix within it) which downloads get uploaded to instead of the disk
+
+Credentials and the region come from the standard AWS environment variables, or from the shared
+`~/.aws/credentials` and `~/.aws/config` files (of the `AWS_PROFILE`, or `default`).
+`AWS_ENDPOINT_URL` points it at an S3-compatible server (using path-style requests).
+*/
+#[derive(Debug, Clone)]
+pub struct S3Target {
+    client: reqwest::Client,
+    bucket: String,
+    prefix: String,
+    region: String,
+    endpoint: Option<Url>,
+    credentials: Credentials,
+}
+
+impl S3Target {
+    /// Makes a target from an `s3://bucket/prefix` URL
+    pub fn from_url(url: &Url, client: reqwest::Client) -> Result<Self> {
+        let bucket = match url.host_str() {
+            Some(bucket) if url.scheme() == "s3" => bucket.to_owned(),
+            _ => bail!("Invalid S3 URL (must be s3://bucket/prefix): {}", url),
+        };
+
+        let profile = env::var("AWS_PROFILE").unwrap_or_else(|_| "default".to_owned());
+
+        let endpoint =
+            match env::var("AWS_ENDPOINT_URL_S3").or_else(|_| env::var("AWS_ENDPOINT_URL")) {
+                Ok(endpoint) => Some(
+                    Url::parse(&endpoint)
+                        .with_context(|| format!("Invalid AWS_ENDPOINT_URL: {}", endpoint))?,
+                ),
+                Err(_) => None,
+            };
+
+        Ok(Self {
+            client,
+            bucket,
+            prefix: url.path().trim_matches('/').to_owned(),
+            region: load_region(&profile),
+            endpoint,
+            credentials: load_credentials(&profile)?,
+        })
+    }
+
+    /// Returns the key for a path (relative to the prefix, separated by `/`)
+    pub fn key_for(&self, path: &str) -> String {
+        if self.prefix.is_empty() {
+            path.to_owned()
+        } else {
+            format!("{}/{}", self.prefix, path)
+        }
+    }
+
+    /// Returns the `s3://` URL of a key
+    pub fn url_of(&self, key: &str) -> String {
+        format!("s3://{}/{}", self.bucket, key)
+    }
+
+    /// Starts a multipart upload to a key
+    pub async fn start_upload(&self, key: &str) -> Result<MultipartUpload<'_>> {
+        let res = self
+            .request(Method::POST, key, &[("uploads", "")], vec![])
+            .await?;
+        let text = res.text().await?;
+
+        let upload_id = RX_UPLOAD_ID
+            .captures(&text)
+            .map(|captures| captures[1].to_owned())
+            .ok_or_else(|| anyhow!("No UploadId in the response for {}", self.url_of(key)))?;
+
+        debug!("(S3) Started upload {} of {}", upload_id, key);
+
+        Ok(MultipartUpload {
+            target: self,
+            key: key.to_owned(),
+            upload_id,
+        })
+    }
+
+    /// Sends a signed request (AWS Signature Version 4) and checks its status
+    async fn request(
+        &self,
+        method: Method,
+        key: &str,
+        query: &[(&str, &str)],
+        body: Vec<u8>,
+    ) -> Result<reqwest::Response> {
+        let encoded_key = key
+            .split('/')
+            .map(|segment| utf8_percent_encode(segment, AWS_ENCODE_SET).to_string())
+            .collect::<Vec<_>>()
+            .join("/");
+
+        // Virtual-hosted requests for AWS, path-style ones for custom endpoints
+        let (base, path) = match &self.endpoint {
+            Some(endpoint) => (
+                endpoint.clone(),
+                format!("/{}/{}", self.bucket, encoded_key),
+            ),
+            None => (
+                Url::parse(&format!(
+                    "https://{}.s3.{}.amazonaws.com",
+                    self.bucket, self.region
+                ))?,
+                format!("/{}", encoded_key),
+            ),
+        };
+
+        let host = match (base.host_str(), base.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_owned(),
+            (None, _) => bail!("The S3 endpoint {} has no host", base),
+        };
+
+        let mut query: Vec<(String, String)> = query
+            .iter()
+            .map(|(key, value)| {
+                (
+                    utf8_percent_encode(key, AWS_ENCODE_SET).to_string(),
+                    utf8_percent_encode(value, AWS_ENCODE_SET).to_string(),
+                )
+            })
+            .collect();
+        query.sort();
+        let query = query
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let payload_hash = hex(&Sha256::digest(&body));
+
+        // The headers to sign (sorted by name)
+        let mut headers = vec![
+            ("host", host),
+            ("x-amz-content-sha256", payload_hash.clone()),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        if let Some(token) = &self.credentials.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+
+        let canonical_headers: String = headers
+            .iter()
+            .map(|(name, value)| format!("{}:{}\n", name, value))
+            .collect();
+        let signed_headers = headers
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+
+        let canonical_request = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            method, path, query, canonical_headers, signed_headers, payload_hash
+        );
+
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+
+        let signing_key = [self.region.as_str(), "s3", "aws4_request"].iter().fold(
+            hmac(
+                format!("AWS4{}", self.credentials.secret_access_key).as_bytes(),
+                date.as_bytes(),
+            ),
+            |key, part| hmac(&key, part.as_bytes()),
+        );
+        let signature = hex(&hmac(&signing_key, string_to_sign.as_bytes()));
+
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.credentials.access_key_id, scope, signed_headers, signature
+        );
+
+        let mut url = format!("{}://{}{}", base.scheme(), headers[0].1, path);
+        if !query.is_empty() {
+            url.push('?');
+            url.push_str(&query);
+        }
+
+        let mut req = self
+            .client
+            .request(method, url.as_str())
+            .header("Authorization", authorization)
+            .body(body);
+        for (name, value) in &headers[1..] {
+            req = req.header(*name, value);
+        }
+
+        let res = req
+            .send()
+            .await
+            .with_context(|| format!("Cannot reach S3 at {} (no response)", url))?;
+
+        if !res.status().is_success() {
+            let status = res.status();
+            let text = res.text().await.unwrap_or_default();
+            bail!("S3 request to {} failed with {}: {}", url, status, text);
+        }
+
+        Ok(res)
+    }
+}
+
+/// A multipart upload which was started (and has to be completed or aborted)
+pub struct MultipartUpload<'a> {
+    target: &'a S3Target,
+    key: String,
+    upload_id: String,
+}
+
+impl MultipartUpload<'_> {
+    /**
+    Uploads everything a reader yields in parts of PART_SIZE
+
+    Returns the ETags of the parts and the total number of bytes.
+    */
+    pub async fn send_parts(
+        &self,
+        mut reader: impl AsyncRead + Unpin,
+    ) -> Result<(Vec<String>, u64)> {
+        let mut etags = vec![];
+        let mut bytes = 0;
+
+        loop {
+            let mut part = Vec::with_capacity(PART_SIZE);
+            (&mut reader)
+                .take(PART_SIZE as u64)
+                .read_to_end(&mut part)
+                .await?;
+
+            // An empty file still needs one (empty) part
+            if part.is_empty() && !etags.is_empty() {
+                break;
+            }
+
+            let is_last = part.len() < PART_SIZE;
+            bytes += part.len() as u64;
+
+            let part_number = (etags.len() + 1).to_string();
+            let res = self
+                .target
+                .request(
+                    Method::PUT,
+                    &self.key,
+                    &[("partNumber", &part_number), ("uploadId", &self.upload_id)],
+                    part,
+                )
+                .await?;
+
+            let etag = res
+                .headers()
+                .get("ETag")
+                .and_then(|etag| etag.to_str().ok())
+                .ok_or_else(|| anyhow!("No ETag for part {} of {}", part_number, self.key))?;
+            etags.push(etag.to_owned());
+
+            if is_last {
+                break;
+            }
+        }
+
+        Ok((etags, bytes))
+    }
+
+    /// Completes the upload from the uploaded parts
+    pub async fn complete(&self, etags: &[String]) -> Result<()> {
+        let parts: String = etags
+            .iter()
+            .enumerate()
+            .map(|(index, etag)| {
+                format!(
+                    "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                    index + 1,
+                    etag
+                )
+            })
+            .collect();
+        let body = format!(
+            "<CompleteMultipartUpload>{}</CompleteMultipartUpload>",
+            parts
+        );
+
+        self.target
+            .request(
+                Method::POST,
+                &self.key,
+                &[("uploadId", &self.upload_id)],
+                body.into_bytes(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Aborts the upload (so the uploaded parts don't linger in the bucket)
+    pub async fn abort(&self) -> Result<()> {
+        self.target
+            .request(
+                Method::DELETE,
+                &self.key,
+                &[("uploadId", &self.upload_id)],
+                vec![],
+            )
+            .await?;
+
+        Ok(())
+    }
+}
+
+/// Reads the credentials from the environment or the shared credentials file
+fn load_credentials(profile: &str) -> Result<Credentials> {
+    if let (Ok(access_key_id), Ok(secret_access_key)) = (
+        env::var("AWS_ACCESS_KEY_ID"),
+        env::var("AWS_SECRET_ACCESS_KEY"),
+    ) {
+        return Ok(Credentials {
+            access_key_id,
+            secret_access_key,
+            session_token: env::var("AWS_SESSION_TOKEN").ok(),
+        });
+    }
+
+    let path = env::var("AWS_SHARED_CREDENTIALS_FILE")
+        .map(PathBuf::from)
+        .ok()
+        .or_else(|| aws_dir().map(|dir| dir.join("credentials")));
+
+    let mut section = path
+        .and_then(|path| read_section(&path, profile))
+        .unwrap_or_default();
+
+    match (
+        section.remove("aws_access_key_id"),
+        section.remove("aws_secret_access_key"),
+    ) {
+        (Some(access_key_id), Some(secret_access_key)) => Ok(Credentials {
+            access_key_id,
+            secret_access_key,
+            session_token: section.remove("aws_session_token"),
+        }),
+        _ => bail!(
+            "No AWS credentials found (set AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY, or use ~/.aws/credentials)"
+        ),
+    }
+}
+
+/// Reads the region from the environment or the shared config file
+fn load_region(profile: &str) -> String {
+    if let Ok(region) = env::var("AWS_REGION").or_else(|_| env::var("AWS_DEFAULT_REGION")) {
+        return region;
+    }
+
+    let path = env::var("AWS_CONFIG_FILE")
+        .map(PathBuf::from)
+        .ok()
+        .or_else(|| aws_dir().map(|dir| dir.join("config")));
+
+    // The config file names its sections `profile <name>` (except for the default one)
+    let section = if profile == "default" {
+        profile.to_owned()
+    } else {
+        format!("profile {}", profile)
+    };
+
+    path.and_then(|path| read_section(&path, &section))
+        .and_then(|mut section| section.remove("region"))
+        .unwrap_or_else(|| DEFAULT_REGION.to_owned())
+}
+
+/// Returns the `.aws` directory in the home directory
+fn aws_dir() -> Option<PathBuf> {
+    env::var_os("HOME")
+        .or_else(|| env::var_os("USERPROFILE"))
+        .map(|home| PathBuf::from(home).join(".aws"))
+}
+
+/// Reads the keys of a section of an INI file (like the AWS config & credentials files)
+fn read_section(path: &PathBuf, name: &str) -> Option<HashMap<String, String>> {
+    let text = fs::read_to_string(path).ok()?;
+
+    let mut values = HashMap::new();
+    let mut in_section = false;
+
+    for line in text.lines().map(str::trim) {
+        if line.starts_with('[') && line.ends_with(']') {
+            in_section = line[1..line.len() - 1].trim() == name;
+        } else if in_section {
+            if let Some((key, value)) = line.split_once('=') {
+                values.insert(key.trim().to_lowercase(), value.trim().to_owned());
+            }
+        }
+    }
+
+    Some(values)
+}
+
+/// Calculates the HMAC-SHA256 of a message
+fn hmac(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(message);
+
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Encodes bytes as lowercase hex
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
@@ -1,4 +1,22 @@
-// use scraper::Selector;
+use lazy_static::lazy_static;
+use scraper::Selector;
 
-// TODO fix this to avoid re-parsing the same selectors multiple times
-// pub const DIRECTORY: &'static Selector = &Selector::parse("body > h1").unwrap();
+lazy_static! {
+    /// Matches the heading of a listing (e.g. `<h1>Index of /pub</h1>`)
+    pub static ref TITLE: Selector = Selector::parse("h1").unwrap();
+
+    /// Matches the title of a page (to describe pages which aren't listings)
+    pub static ref PAGE_TITLE: Selector = Selector::parse("title").unwrap();
+
+    /// Matches every row of the listing table
+    pub static ref ROW: Selector = Selector::parse("tr").unwrap();
+
+    /// Matches the data cells of a table row
+    pub static ref CELL: Selector = Selector::parse("td").unwrap();
+
+    /// Matches a link carrying an href
+    pub static ref LINK: Selector = Selector::parse("a[href]").unwrap();
+
+    /// Matches the password fields of forms (which give away login pages)
+    pub static ref PASSWORD_FIELD: Selector = Selector::parse(r#"form input[type="password" i]"#).unwrap();
+}
@@ -0,0 +1,264 @@
+use super::{
+    observer::DownloadObserver,
+    report::SkipReason,
+    throughput::Throughput,
+    types::{FileLinkMetaData, Node},
+};
+use anyhow::Result;
+use serde::Serialize;
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+/// The file being downloaded right now
+#[derive(Debug, Clone, Serialize)]
+struct CurrentFile {
+    url: String,
+
+    /// The bytes which arrived so far
+    bytes: u64,
+
+    /// The length announced by the server (if any)
+    length: Option<u64>,
+}
+
+/// The progress of a download, as returned by the status socket
+#[derive(Debug, Clone, Serialize)]
+struct Snapshot {
+    files_done: u64,
+    files_skipped: u64,
+    files_failed: u64,
+
+    /// The number of files of the tree (growing along the crawl when streaming)
+    files_total: u64,
+
+    /// The bytes downloaded so far (including the ones of the current file)
+    bytes_done: u64,
+
+    /// The size of the tree (as far as the listings tell)
+    bytes_total: u64,
+    current: Option<CurrentFile>,
+    elapsed_secs: f64,
+
+    /// The throughput of the last few seconds
+    bytes_per_sec: f64,
+
+    /// The estimated time until the download is done (while anything arrives)
+    eta_secs: Option<f64>,
+}
+
+/// What the observer keeps track of
+#[derive(Debug)]
+struct Status {
+    started_at: Instant,
+    files_done: u64,
+    files_skipped: u64,
+    files_failed: u64,
+    files_total: u64,
+    bytes_done: u64,
+
+    /// The size of the files which were skipped (which don't need to be downloaded anymore)
+    bytes_skipped: u64,
+    bytes_total: u64,
+    current: Option<CurrentFile>,
+    throughput: Throughput,
+
+    /// Grow the totals with the files found by the crawl
+    growing: bool,
+}
+
+impl Status {
+    fn snapshot(&self) -> Snapshot {
+        let remaining = self
+            .bytes_total
+            .saturating_sub(self.bytes_done + self.bytes_skipped);
+
+        Snapshot {
+            files_done: self.files_done,
+            files_skipped: self.files_skipped,
+            files_failed: self.files_failed,
+            files_total: self.files_total,
+            bytes_done: self.bytes_done,
+            bytes_total: self.bytes_total,
+            current: self.current.clone(),
+            elapsed_secs: self.started_at.elapsed().as_secs_f64(),
+            bytes_per_sec: self.throughput.rate(),
+            eta_secs: self.throughput.eta(remaining).map(|eta| eta.as_secs_f64()),
+        }
+    }
+}
+
+/**
+Answers every connection to a Unix socket with the progress of the download (see `--status-socket`)
+
+The progress is written as a single line of JSON, after which the connection is closed (e.g.
+`socat - UNIX-CONNECT:od-get.sock`). The socket is served by a task of its own, so a slow client
+never holds up the download. The socket file is removed once the status socket is dropped.
+*/
+pub struct StatusSocket {
+    path: PathBuf,
+    status: Arc<Mutex<Status>>,
+    server: tokio::task::JoinHandle<()>,
+}
+
+impl StatusSocket {
+    /// Serves the progress of a download of a crawled tree
+    pub fn bind(path: &Path, root: &Node) -> Result<Self> {
+        let summary = root.summarize();
+
+        Self::with_totals(path, summary.files, summary.total_bytes, false)
+    }
+
+    /// Serves the progress of a download of a tree which is still being crawled (see `--stream`)
+    pub fn bind_growing(path: &Path) -> Result<Self> {
+        Self::with_totals(path, 0, 0, true)
+    }
+
+    fn with_totals(path: &Path, files: u64, bytes: u64, growing: bool) -> Result<Self> {
+        let status = Arc::new(Mutex::new(Status {
+            started_at: Instant::now(),
+            files_done: 0,
+            files_skipped: 0,
+            files_failed: 0,
+            files_total: files,
+            bytes_done: 0,
+            bytes_skipped: 0,
+            bytes_total: bytes,
+            current: None,
+            throughput: Throughput::new(),
+            growing,
+        }));
+        let server = serve(path, status.clone())?;
+
+        Ok(Self {
+            path: path.to_owned(),
+            status,
+            server,
+        })
+    }
+
+    fn update(&self, function: impl FnOnce(&mut Status)) {
+        if let Ok(mut status) = self.status.lock() {
+            function(&mut status);
+        }
+    }
+}
+
+impl Drop for StatusSocket {
+    fn drop(&mut self) {
+        self.server.abort();
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+impl DownloadObserver for StatusSocket {
+    fn on_discovered(&self, node: &Node) {
+        if let Node::File(file) = node {
+            self.update(|status| {
+                if status.growing {
+                    status.files_total += 1;
+                    status.bytes_total += file.size_bytes.unwrap_or_default();
+                }
+            });
+        }
+    }
+
+    fn on_file_start(&self, file: &FileLinkMetaData, length: Option<u64>) {
+        self.update(|status| {
+            status.current = Some(CurrentFile {
+                url: file.url.clone(),
+                bytes: 0,
+                length,
+            });
+        });
+    }
+
+    fn on_progress(&self, _file: &FileLinkMetaData, bytes: u64) {
+        self.update(|status| {
+            status.bytes_done += bytes;
+            status.throughput.add(bytes);
+            if let Some(current) = &mut status.current {
+                current.bytes += bytes;
+            }
+        });
+    }
+
+    fn on_file_done(&self, _file: &FileLinkMetaData, _bytes: u64) {
+        self.update(|status| {
+            status.files_done += 1;
+            status.current = None;
+        });
+    }
+
+    fn on_file_skipped(&self, file: &FileLinkMetaData, _reason: SkipReason) {
+        self.update(|status| {
+            status.files_skipped += 1;
+            status.bytes_skipped += file.size_bytes.unwrap_or_default();
+        });
+    }
+
+    fn on_error(&self, _file: &FileLinkMetaData, _error: &anyhow::Error) {
+        self.update(|status| {
+            status.files_failed += 1;
+            status.current = None;
+        });
+    }
+}
+
+/// Listens on the socket, answering each connection with a snapshot of the status
+#[cfg(unix)]
+fn serve(path: &Path, status: Arc<Mutex<Status>>) -> Result<tokio::task::JoinHandle<()>> {
+    use anyhow::{bail, Context};
+    use log::{debug, info};
+    use std::os::unix::fs::FileTypeExt;
+    use tokio::{io::AsyncWriteExt, net::UnixListener};
+
+    // A socket left behind by an earlier run is in the way (but nothing else may be replaced)
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            bail!(
+                "Cannot create the status socket {}, a file is in the way",
+                path.display()
+            );
+        }
+        std::fs::remove_file(path)
+            .with_context(|| format!("Cannot remove the old socket {}", path.display()))?;
+    }
+
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("Cannot create the status socket {}", path.display()))?;
+    info!("Serving the status at {}", path.display());
+
+    Ok(tokio::spawn(async move {
+        loop {
+            let mut stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(error) => {
+                    debug!("(Status) Cannot accept a connection: {}", error);
+                    continue;
+                }
+            };
+
+            let snapshot = match status.lock() {
+                Ok(status) => status.snapshot(),
+                Err(_) => return,
+            };
+
+            // Each client gets a task of its own, so a slow one doesn't keep the others waiting
+            tokio::spawn(async move {
+                if let Ok(mut line) = serde_json::to_vec(&snapshot) {
+                    line.push(b'\n');
+                    let _ = stream.write_all(&line).await;
+                    let _ = stream.shutdown().await;
+                }
+            });
+        }
+    }))
+}
+
+#[cfg(not(unix))]
+fn serve(_path: &Path, _status: Arc<Mutex<Status>>) -> Result<tokio::task::JoinHandle<()>> {
+    anyhow::bail!("The status socket is only supported on Unix")
+}
@@ -0,0 +1,201 @@
+use anyhow::{bail, Context, Result};
+use flate2::{write::GzEncoder, Compression};
+use log::debug;
+use std::{
+    fs,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+/// The largest files which are held in memory until they're complete (larger ones, or the ones of
+/// unknown size, are spooled into a temporary file next to the archive)
+pub const MEMORY_LIMIT: u64 = 8 * 1024 * 1024;
+
+/// The extension of the temporary file holding the file being downloaded into the archive
+const SPOOL_EXTENSION: &str = ".od-spool";
+
+/// The file an archive is written to (compressed or not)
+enum Sink {
+    Plain(fs::File),
+    Gzip(GzEncoder<fs::File>),
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(file) => file.write(buf),
+            Self::Gzip(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(file) => file.flush(),
+            Self::Gzip(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/**
+A tar archive the downloaded files are written into instead of the destination (see `--output-tar`)
+
+A name ending in `.tar.gz` or `.tgz` gets the archive compressed. The entries are named by the
+path below the destination, which the files would have been saved to. An existing plain archive
+is continued (after its last complete entry), so a resumed run adds the missing files to it; a
+compressed one can't be continued and gets refused.
+
+The archive is only valid once it's finished, so this must happen at the end of every run (also
+when it fails).
+*/
+pub struct TarOutput {
+    path: PathBuf,
+    builder: Option<tar::Builder<Sink>>,
+}
+
+impl std::fmt::Debug for TarOutput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TarOutput")
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+impl TarOutput {
+    /// Creates the archive, or opens an existing (plain) one to add more files to it
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(parent) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Cannot create the directory {}", parent.display()))?;
+        }
+
+        let exists = path.is_file();
+        let sink = if is_compressed(path) {
+            if exists {
+                bail!(
+                    "Cannot add files to the compressed archive {} (only plain .tar archives can be continued)",
+                    path.display()
+                );
+            }
+
+            let file = fs::File::create(path)
+                .with_context(|| format!("Cannot create the archive {}", path.display()))?;
+            Sink::Gzip(GzEncoder::new(file, Compression::default()))
+        } else {
+            let mut file = fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)
+                .with_context(|| format!("Cannot open the archive {}", path.display()))?;
+
+            // Overwrite the end-of-archive blocks (and anything incomplete) with the new entries
+            let end = if exists {
+                end_of_entries(&file)
+                    .with_context(|| format!("Cannot read the archive {}", path.display()))?
+            } else {
+                0
+            };
+            file.set_len(end)?;
+            file.seek(SeekFrom::Start(end))?;
+            if end > 0 {
+                debug!("(Tar) Continuing {} after {} bytes", path.display(), end);
+            }
+
+            Sink::Plain(file)
+        };
+
+        Ok(Self {
+            path: path.to_owned(),
+            builder: Some(tar::Builder::new(sink)),
+        })
+    }
+
+    /// Returns the path of the archive
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the path of the temporary file a download is spooled into
+    pub fn spool_path(&self) -> PathBuf {
+        let mut path = self.path.as_os_str().to_owned();
+        path.push(SPOOL_EXTENSION);
+
+        PathBuf::from(path)
+    }
+
+    /// Adds a file of the given size (blocking while it's written)
+    pub fn append(&mut self, name: &str, data: impl Read, size: u64, mtime: u64) -> Result<()> {
+        let builder = match &mut self.builder {
+            Some(builder) => builder,
+            None => bail!("The archive {} is already finished", self.path.display()),
+        };
+
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_size(size);
+        header.set_mode(0o644);
+        header.set_mtime(mtime);
+
+        builder
+            .append_data(&mut header, name, data)
+            .with_context(|| format!("Cannot add {} to {}", name, self.path.display()))
+    }
+
+    /// Writes the end of the archive (does nothing if it's already finished)
+    pub fn finish(&mut self) -> Result<()> {
+        let builder = match self.builder.take() {
+            Some(builder) => builder,
+            None => return Ok(()),
+        };
+
+        let sink = builder
+            .into_inner()
+            .with_context(|| format!("Cannot finish the archive {}", self.path.display()))?;
+        match sink {
+            Sink::Plain(mut file) => file.flush()?,
+            Sink::Gzip(encoder) => {
+                encoder.finish().with_context(|| {
+                    format!("Cannot finish the archive {}", self.path.display())
+                })?;
+            }
+        }
+
+        debug!("(Tar) Finished {}", self.path.display());
+        Ok(())
+    }
+}
+
+/// Checks if an archive is to be compressed (judging by its name)
+fn is_compressed(path: &Path) -> bool {
+    let name = path.to_string_lossy().to_lowercase();
+
+    name.ends_with(".tar.gz") || name.ends_with(".tgz")
+}
+
+/// Finds the end of the last complete entry of an existing archive
+fn end_of_entries(file: &fs::File) -> Result<u64> {
+    let length = file.metadata()?.len();
+    let mut archive = tar::Archive::new(file);
+    let mut end = 0;
+
+    for entry in archive.entries()? {
+        let entry = match entry {
+            Ok(entry) => entry,
+            // An interrupted run may have left half an entry behind
+            Err(_) => break,
+        };
+
+        let size = entry.header().entry_size()?;
+        let entry_end = entry.raw_file_position() + size.div_ceil(512) * 512;
+        if entry_end > length {
+            break;
+        }
+        end = entry_end;
+    }
+
+    Ok(end)
+}
@@ -0,0 +1,177 @@
+use super::{paths::sanitize_segment, units::parse_date};
+use anyhow::{bail, Result};
+use std::path::{Path, PathBuf};
+
+/// The placeholders which may be used in a path template
+const PLACEHOLDERS: [&str; 5] = ["host", "dir", "name", "ext", "date"];
+
+/// The value of `{date}` for files without a (parseable) date
+const UNKNOWN_DATE: &str = "unknown-date";
+
+/**
+A template for the local path of each file (see `--path-template`)
+
+E.g. `{host}/{date}/{name}` stores the files by server and date of modification. The rendered
+path is relative to the destination, with each of its segments sanitized.
+
+- `{host}` The host of the server
+- `{dir}` The path of the directory (as in the default layout, e.g. `pub/docs`)
+- `{name}` The name of the file (e.g. `readme.md`)
+- `{ext}` The extension of the file (e.g. `md`, or nothing)
+- `{date}` The date of modification from the listing (e.g. `2021-01-31`)
+*/
+#[derive(Debug, Clone)]
+pub struct PathTemplate {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    Placeholder(String),
+}
+
+/// The values of the placeholders for a single file
+#[derive(Debug, Clone)]
+pub struct TemplateValues<'a> {
+    pub host: &'a str,
+    pub dir: &'a str,
+    pub name: &'a str,
+    pub last_modified: &'a str,
+}
+
+impl PathTemplate {
+    /// Parses a template, failing on unknown or unterminated placeholders
+    pub fn parse(template: &str) -> Result<Self> {
+        let mut parts = vec![];
+        let mut rest = template;
+
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(Part::Text(rest[..start].to_owned()));
+            }
+
+            let end = match rest[start..].find('}') {
+                Some(end) => start + end,
+                None => bail!("Unterminated placeholder in the path template {}", template),
+            };
+
+            let name = &rest[start + 1..end];
+            if !PLACEHOLDERS.contains(&name) {
+                bail!(
+                    "Unknown placeholder {{{}}} in the path template (known are {})",
+                    name,
+                    PLACEHOLDERS.map(|name| format!("{{{}}}", name)).join(", ")
+                );
+            }
+            parts.push(Part::Placeholder(name.to_owned()));
+
+            rest = &rest[end + 1..];
+        }
+
+        if !rest.is_empty() {
+            parts.push(Part::Text(rest.to_owned()));
+        }
+
+        Ok(Self { parts })
+    }
+
+    /// Renders the path of a file below the destination
+    pub fn render(&self, values: &TemplateValues, destination: &Path) -> PathBuf {
+        let rendered: String = self
+            .parts
+            .iter()
+            .map(|part| match part {
+                Part::Text(text) => text.clone(),
+                Part::Placeholder(name) => values.get(name),
+            })
+            .collect();
+
+        // Sanitize each segment (empty ones, e.g. from an empty {dir}, get dropped)
+        let mut path = destination.to_path_buf();
+        for segment in rendered.split('/').filter_map(sanitize_segment) {
+            path.push(segment);
+        }
+
+        path
+    }
+}
+
+impl TemplateValues<'_> {
+    /// Returns the value of a placeholder
+    fn get(&self, placeholder: &str) -> String {
+        match placeholder {
+            "host" => self.host.to_owned(),
+            "dir" => self.dir.to_owned(),
+            "name" => self.name.to_owned(),
+            "ext" => match self.name.rsplit_once('.') {
+                Some((stem, ext)) if !stem.is_empty() => ext.to_owned(),
+                _ => String::new(),
+            },
+            "date" => parse_date(self.last_modified).map_or_else(
+                || UNKNOWN_DATE.to_owned(),
+                |date| date.format("%Y-%m-%d").to_string(),
+            ),
+            _ => String::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(template: &str, name: &str, last_modified: &str) -> PathBuf {
+        let values = TemplateValues {
+            host: "example.com",
+            dir: "pub/docs",
+            name,
+            last_modified,
+        };
+
+        PathTemplate::parse(template)
+            .unwrap()
+            .render(&values, Path::new("dest"))
+    }
+
+    #[test]
+    fn renders_the_placeholders() {
+        let path = |parts: &[&str]| parts.iter().collect::<PathBuf>();
+
+        assert_eq!(
+            render("{host}/{date}/{name}", "readme.md", "2021-01-31 10:00"),
+            path(&["dest", "example.com", "2021-01-31", "readme.md"])
+        );
+        assert_eq!(
+            render("{dir}/{ext}/{name}", "readme.md", ""),
+            path(&["dest", "pub", "docs", "md", "readme.md"])
+        );
+        assert_eq!(
+            render("by-date/{date}-{name}", "a.txt", "yesterday"),
+            path(&["dest", "by-date", "unknown-date-a.txt"])
+        );
+    }
+
+    #[test]
+    fn drops_empty_segments() {
+        let path = |parts: &[&str]| parts.iter().collect::<PathBuf>();
+
+        // Neither a hidden file nor a file without a dot has an extension
+        assert_eq!(
+            render("{ext}/{name}", ".profile", ""),
+            path(&["dest", ".profile"])
+        );
+        assert_eq!(
+            render("{ext}/{name}", "Makefile", ""),
+            path(&["dest", "Makefile"])
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_placeholders() {
+        let error = PathTemplate::parse("{host}/{size}").unwrap_err();
+        assert!(error.to_string().contains("{size}"), "{}", error);
+
+        assert!(PathTemplate::parse("{host").is_err());
+    }
+}
@@ -0,0 +1,120 @@
+use chrono::{DateTime, Utc};
+use reqwest::{header::RETRY_AFTER, Response, StatusCode};
+use std::{fmt, sync::Mutex, time::Duration};
+
+/// The extra delay after the first throttled response (doubled with each further one)
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The longest extra delay (which also caps the waits asked for by Retry-After)
+pub const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// How often a throttled request is tried again before its error is passed on
+pub const MAX_THROTTLED_RETRIES: u32 = 5;
+
+/**
+The error of a request the server refused because of too many requests (429, or 503 with a
+Retry-After header)
+
+Unlike other errors, it's worth trying again (after the wait the server asked for, if any).
+*/
+#[derive(Debug, Clone)]
+pub struct Throttled {
+    pub url: String,
+    pub status: StatusCode,
+
+    /// How long the server asked to wait (see the Retry-After header)
+    pub retry_after: Option<Duration>,
+}
+
+impl Throttled {
+    /// Checks if a response asks to slow down
+    pub fn from_response(url: &str, res: &Response) -> Option<Self> {
+        let retry_after = res
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_retry_after);
+
+        match res.status() {
+            StatusCode::TOO_MANY_REQUESTS => {}
+            StatusCode::SERVICE_UNAVAILABLE if retry_after.is_some() => {}
+            _ => return None,
+        }
+
+        Some(Self {
+            url: url.to_owned(),
+            status: res.status(),
+            retry_after,
+        })
+    }
+}
+
+impl fmt::Display for Throttled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The server asked to slow down for {} ({})",
+            self.url, self.status
+        )
+    }
+}
+
+impl std::error::Error for Throttled {}
+
+/// Returns the throttled request (if that's what caused an error)
+pub fn throttled(error: &anyhow::Error) -> Option<&Throttled> {
+    error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<Throttled>())
+}
+
+/// Parses a Retry-After header, which is either a number of seconds or an HTTP date
+pub fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let at = DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        (at.with_timezone(&Utc) - Utc::now())
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+/**
+An extra delay between requests, which grows while the server keeps throttling (see `Throttled`)
+
+Each throttled response doubles the delay (up to `MAX_BACKOFF`), each successful request halves
+it again, until it's gone.
+*/
+#[derive(Debug, Default)]
+pub struct Backoff {
+    delay: Mutex<Duration>,
+}
+
+impl Backoff {
+    /// Returns the current extra delay
+    pub fn delay(&self) -> Duration {
+        *self.delay.lock().unwrap()
+    }
+
+    /// Raises the delay after a throttled response and returns how long to wait before the retry
+    pub fn throttled(&self, throttled: &Throttled) -> Duration {
+        let mut delay = self.delay.lock().unwrap();
+        *delay = (*delay * 2).clamp(MIN_BACKOFF, MAX_BACKOFF);
+
+        throttled.retry_after.unwrap_or(*delay).min(MAX_BACKOFF)
+    }
+
+    /// Lowers the delay after a successful request
+    pub fn ease_off(&self) {
+        let mut delay = self.delay.lock().unwrap();
+        *delay = match *delay / 2 {
+            half if half < MIN_BACKOFF => Duration::ZERO,
+            half => half,
+        };
+    }
+}
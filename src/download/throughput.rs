@@ -0,0 +1,161 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// The span of time the throughput is averaged over
+const WINDOW: Duration = Duration::from_secs(10);
+
+/// The shortest time between two samples (bytes arriving in between add to the last one)
+const RESOLUTION: Duration = Duration::from_millis(100);
+
+/**
+Measures the recent throughput of a download, to estimate the time it needs to finish
+
+The rate is a moving average over the last few seconds, so the estimate follows a server which
+speeds up or slows down (and runs up while nothing arrives at all).
+*/
+#[derive(Debug)]
+pub struct Throughput {
+    /// The total of bytes at points in time (the oldest one being right before the window)
+    samples: VecDeque<(Instant, u64)>,
+    total: u64,
+}
+
+impl Throughput {
+    pub fn new() -> Self {
+        Self::starting_at(Instant::now())
+    }
+
+    fn starting_at(start: Instant) -> Self {
+        Self {
+            samples: VecDeque::from([(start, 0)]),
+            total: 0,
+        }
+    }
+
+    /// Counts bytes which just arrived
+    pub fn add(&mut self, bytes: u64) {
+        self.add_at(bytes, Instant::now());
+    }
+
+    fn add_at(&mut self, bytes: u64, now: Instant) {
+        self.total += bytes;
+
+        // The first sample is the start, which mustn't take any bytes
+        let recent = self.samples.len() > 1
+            && self
+                .samples
+                .back()
+                .is_some_and(|(time, _)| now.duration_since(*time) < RESOLUTION);
+        match self.samples.back_mut() {
+            Some((_, total)) if recent => *total = self.total,
+            _ => self.samples.push_back((now, self.total)),
+        }
+
+        // Keep a single sample from before the window as its start
+        while self.samples.len() > 2 && now.duration_since(self.samples[1].0) >= WINDOW {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Returns the average bytes per second of the last few seconds
+    pub fn rate(&self) -> f64 {
+        self.rate_at(Instant::now())
+    }
+
+    fn rate_at(&self, now: Instant) -> f64 {
+        let (start, start_total) = match self.samples.front() {
+            Some(sample) => *sample,
+            None => return 0.0,
+        };
+
+        let bytes = self.total - start_total;
+        let secs = now.duration_since(start).as_secs_f64();
+
+        if secs > 0.0 {
+            bytes as f64 / secs
+        } else {
+            0.0
+        }
+    }
+
+    /// Estimates the time needed for the remaining bytes (None while nothing arrives)
+    pub fn eta(&self, remaining: u64) -> Option<Duration> {
+        self.eta_at(remaining, Instant::now())
+    }
+
+    fn eta_at(&self, remaining: u64, now: Instant) -> Option<Duration> {
+        let rate = self.rate_at(now);
+
+        (rate > 0.0).then(|| Duration::from_secs_f64(remaining as f64 / rate))
+    }
+}
+
+impl Default for Throughput {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds bytes at a steady rate (in steps of 100ms), returning the point in time afterwards
+    fn feed(throughput: &mut Throughput, from: Instant, secs: u64, bytes_per_sec: u64) -> Instant {
+        let step = Duration::from_millis(100);
+        let mut now = from;
+        for _ in 0..secs * 10 {
+            now += step;
+            throughput.add_at(bytes_per_sec / 10, now);
+        }
+
+        now
+    }
+
+    /// Checks if a duration is within a tolerance (in percent) of the expected seconds
+    fn is_about(duration: Duration, secs: f64, tolerance: f64) -> bool {
+        (duration.as_secs_f64() - secs).abs() <= secs * tolerance / 100.0
+    }
+
+    #[test]
+    fn estimates_the_time_left_at_a_steady_rate() {
+        let start = Instant::now();
+        let mut throughput = Throughput::starting_at(start);
+        let now = feed(&mut throughput, start, 5, 1000);
+
+        assert!((throughput.rate_at(now) - 1000.0).abs() < 10.0);
+        let eta = throughput.eta_at(10_000, now).unwrap();
+        assert!(is_about(eta, 10.0, 1.0), "{:?}", eta);
+    }
+
+    #[test]
+    fn follows_a_server_which_slows_down() {
+        let start = Instant::now();
+        let mut throughput = Throughput::starting_at(start);
+        let now = feed(&mut throughput, start, 20, 1000);
+        let now = feed(&mut throughput, now, 10, 100);
+
+        // Only the last seconds count (with a single sample from before them)
+        let eta = throughput.eta_at(1000, now).unwrap();
+        assert!(is_about(eta, 10.0, 15.0), "{:?}", eta);
+
+        // While nothing arrives, the estimate runs up
+        let later = throughput
+            .eta_at(1000, now + Duration::from_secs(5))
+            .unwrap();
+        assert!(later > eta, "{:?} after {:?}", later, eta);
+    }
+
+    #[test]
+    fn has_no_estimate_before_anything_arrives() {
+        let start = Instant::now();
+        let throughput = Throughput::starting_at(start);
+
+        assert_eq!(
+            throughput.eta_at(1000, start + Duration::from_secs(1)),
+            None
+        );
+    }
+}
@@ -0,0 +1,110 @@
+use anyhow::Result;
+use std::{fmt, future::Future, io, time::Duration};
+use suppaftp::FtpError;
+
+/// The default time allowed for establishing a connection
+pub const DEFAULT_CONNECT_TIMEOUT: &str = "30s";
+
+/// The default time allowed without receiving any data
+pub const DEFAULT_READ_TIMEOUT: &str = "1m";
+
+/// How often a download which timed out is tried again (before only its file fails)
+pub const MAX_TIMEOUT_RETRIES: u32 = 3;
+
+/// The default time an unused connection is kept open for the next request
+pub const DEFAULT_POOL_IDLE_TIMEOUT: &str = "90s";
+
+/// The default interval of TCP keep-alive probes (which keep idle connections from being dropped)
+pub const DEFAULT_TCP_KEEPALIVE: &str = "60s";
+
+/**
+The error of an operation which didn't make progress in time
+
+Unlike other errors, a timeout is worth retrying (see `is_timeout`).
+*/
+#[derive(Debug, Clone, Copy)]
+pub struct TimedOut(pub Duration);
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Timed out after {:?} without a response", self.0)
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+/**
+Awaits a future, but fails with `TimedOut` if it doesn't complete within the given duration
+
+Used for each read of a response, so the timeout resets as long as data keeps flowing.
+*/
+pub async fn with_timeout<T, E>(
+    duration: Duration,
+    future: impl Future<Output = Result<T, E>>,
+) -> Result<T>
+where
+    E: Into<anyhow::Error>,
+{
+    match tokio::time::timeout(duration, future).await {
+        Ok(res) => res.map_err(Into::into),
+        Err(_) => Err(TimedOut(duration).into()),
+    }
+}
+
+/**
+Checks if an error was caused by a timeout (of a connection or a read)
+*/
+pub fn is_timeout(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        if cause.is::<TimedOut>() {
+            return true;
+        }
+
+        if let Some(error) = cause.downcast_ref::<reqwest::Error>() {
+            return error.is_timeout();
+        }
+
+        // FTP errors don't expose their cause as a source
+        let io_error = match cause.downcast_ref::<FtpError>() {
+            Some(FtpError::ConnectionError(error)) => Some(error),
+            _ => cause.downcast_ref::<io::Error>(),
+        };
+
+        io_error.is_some_and(|error| {
+            matches!(
+                error.kind(),
+                io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+            )
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn times_out_without_progress() {
+        let started = std::time::Instant::now();
+        let res = with_timeout(Duration::from_millis(100), async {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            Ok::<_, io::Error>(())
+        })
+        .await;
+
+        let error = res.unwrap_err();
+        assert!(error.is::<TimedOut>());
+        assert!(is_timeout(&error));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn passes_on_results_in_time() {
+        let res = with_timeout(Duration::from_secs(10), async { Ok::<_, io::Error>(1) }).await;
+        assert_eq!(res.unwrap(), 1);
+
+        let error = io::Error::new(io::ErrorKind::NotFound, "gone");
+        let res = with_timeout(Duration::from_secs(10), async { Err::<(), _>(error) }).await;
+        assert!(!is_timeout(&res.unwrap_err()));
+    }
+}
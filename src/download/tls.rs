@@ -0,0 +1,93 @@
+use anyhow::{bail, Context, Result};
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier},
+    Certificate, ClientConfig, Error, RootCertStore, ServerName,
+};
+use std::{convert::TryFrom, sync::Arc, time::SystemTime};
+
+/**
+Verifies certificates for a fixed host name instead of the one connected to (see `--tls-server-name`)
+
+The chain is still validated against the trusted roots, only the name checked is a different one.
+This helps with mirrors behind an alias (e.g. of a CDN) whose certificate doesn't match the host.
+*/
+struct FixedNameVerifier {
+    inner: WebPkiVerifier,
+    server_name: ServerName,
+}
+
+impl ServerCertVerifier for FixedNameVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        _server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, Error> {
+        self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            &self.server_name,
+            scts,
+            ocsp_response,
+            now,
+        )
+    }
+}
+
+/**
+Makes a TLS configuration which expects certificates for `server_name` on every host
+
+The roots of the system are trusted, and the certificates of `ca_cert_pem` (see `--ca-cert`) as
+well. HTTP/2 is only offered if the clients use it anyway (see `--http2`).
+*/
+pub fn fixed_name_config(
+    server_name: &str,
+    ca_cert_pem: Option<&[u8]>,
+    http2: bool,
+) -> Result<ClientConfig> {
+    let server_name = ServerName::try_from(server_name)
+        .with_context(|| format!("Invalid TLS server name {}", server_name))?;
+
+    let mut roots = RootCertStore::empty();
+    for certificate in
+        rustls_native_certs::load_native_certs().context("Cannot load the root certificates")?
+    {
+        // Skip the odd broken certificate of the system (like reqwest does)
+        let _ = roots.add(&Certificate(certificate.0));
+    }
+
+    if let Some(mut pem) = ca_cert_pem {
+        let certificates =
+            rustls_pemfile::certs(&mut pem).context("Cannot parse the CA certificate")?;
+        if certificates.is_empty() {
+            bail!("The CA certificate contains no certificates");
+        }
+
+        for certificate in certificates {
+            roots
+                .add(&Certificate(certificate))
+                .context("Invalid CA certificate")?;
+        }
+    }
+
+    let verifier = FixedNameVerifier {
+        inner: WebPkiVerifier::new(roots, None),
+        server_name,
+    };
+
+    let mut config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+
+    config.alpn_protocols = if http2 {
+        vec![b"h2".to_vec()]
+    } else {
+        vec![b"http/1.1".to_vec()]
+    };
+
+    Ok(config)
+}
@@ -1,6 +1,25 @@
-use anyhow::{bail, Result};
+use super::{
+    paths,
+    units::{format_size, parse_size},
+};
+use anyhow::{anyhow, bail, Context, Result};
 use chrono::Utc;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use log::info;
+use percent_encoding::percent_decode_str;
+use reqwest::Url;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashSet},
+    env, fmt, fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+/// The first bytes of gzip data (which tell compressed state stores apart, see `--compress-state`)
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
 
 /**
 This enum defines an entry in an open-directory listing.
@@ -21,13 +40,264 @@ pub enum Node {
     CrawledDir(DirLinkMetaData, Vec<Node>),
 }
 
+impl Node {
+    /// Iterates over this node and all nodes below it (depth-first, in the order of the listings)
+    pub fn iter(&self) -> NodeIter<'_> {
+        NodeIter { stack: vec![self] }
+    }
+
+    /// Iterates over the files of the tree
+    pub fn iter_files(&self) -> impl Iterator<Item = &FileLinkMetaData> {
+        self.iter().filter_map(|node| match node {
+            Node::File(file) => Some(file),
+            _ => None,
+        })
+    }
+
+    /// Iterates over the directories of the tree (including this one and the ones not crawled yet)
+    pub fn iter_dirs(&self) -> impl Iterator<Item = &DirLinkMetaData> {
+        self.iter().filter_map(|node| match node {
+            Node::File(_) => None,
+            Node::PendingDir(dir) | Node::CrawledDir(dir, _) => Some(dir),
+        })
+    }
+
+    /// Counts the files & directories in the tree and sums up the (known) file sizes
+    pub fn summarize(&self) -> TreeSummary {
+        let mut summary = TreeSummary::default();
+
+        for node in self.iter() {
+            match node {
+                Node::File(file) => {
+                    summary.files += 1;
+                    match file.size_bytes {
+                        Some(size) => summary.total_bytes += size,
+                        None => summary.unknown_sizes += 1,
+                    }
+                }
+                _ => summary.directories += 1,
+            }
+        }
+
+        summary
+    }
+
+    /// Sorts the children of all directories in the tree by their URL
+    pub fn sort(&mut self) {
+        if let Node::CrawledDir(_, children) = self {
+            sort_nodes(children);
+        }
+    }
+
+    /// Sets the depth of the node, and the ones of its children (one level deeper)
+    pub fn set_depth(&mut self, depth: u64) {
+        match self {
+            Node::File(file) => file.depth = depth,
+            Node::PendingDir(dir) => dir.depth = depth,
+            Node::CrawledDir(dir, children) => {
+                dir.depth = depth;
+                for child in children {
+                    child.set_depth(depth + 1);
+                }
+            }
+        }
+    }
+
+    /// Returns the URL of the node
+    pub fn url(&self) -> &str {
+        match self {
+            Node::File(file) => &file.url,
+            Node::PendingDir(dir) | Node::CrawledDir(dir, _) => &dir.url,
+        }
+    }
+
+    /// Returns the directories of the tree which haven't been crawled (e.g. as they couldn't be parsed)
+    pub fn pending_dirs(&self) -> Vec<&DirLinkMetaData> {
+        self.iter()
+            .filter_map(|node| match node {
+                Node::PendingDir(dir) => Some(dir),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Returns the URLs of all directories of the tree (crawled or not)
+    pub fn dir_urls(&self) -> Vec<String> {
+        self.iter_dirs().map(|dir| dir.url.clone()).collect()
+    }
+
+    /// Finds a directory of the tree which hasn't been crawled by its URL
+    pub fn find_pending_dir(&self, url: &str) -> Option<&DirLinkMetaData> {
+        self.iter().find_map(|node| match node {
+            Node::PendingDir(dir) if dir.url == url => Some(dir),
+            _ => None,
+        })
+    }
+
+    /// Replaces a directory of the tree which hasn't been crawled (or removes it if there is no replacement)
+    pub fn replace_pending_dir(&mut self, url: &str, replacement: Option<Node>) -> bool {
+        let children = match self {
+            Node::CrawledDir(_, children) => children,
+            _ => return false,
+        };
+
+        match children
+            .iter()
+            .position(|child| matches!(child, Node::PendingDir(dir) if dir.url == url))
+        {
+            Some(index) => {
+                match replacement {
+                    Some(node) => children[index] = node,
+                    None => {
+                        children.remove(index);
+                    }
+                }
+                true
+            }
+            None => children
+                .iter_mut()
+                .find(|child| child.find_pending_dir(url).is_some())
+                .is_some_and(|child| child.replace_pending_dir(url, replacement)),
+        }
+    }
+
+    /// Removes a file from the tree (by its URL)
+    pub fn remove_file(&mut self, url: &str) -> bool {
+        let children = match self {
+            Node::CrawledDir(_, children) => children,
+            _ => return false,
+        };
+
+        match children
+            .iter()
+            .position(|child| matches!(child, Node::File(file) if file.url == url))
+        {
+            Some(index) => {
+                children.remove(index);
+                true
+            }
+            None => children.iter_mut().any(|child| child.remove_file(url)),
+        }
+    }
+
+    /// Calls a function on every file in the tree
+    pub fn for_each_file_mut(&mut self, function: &mut impl FnMut(&mut FileLinkMetaData)) {
+        match self {
+            Node::File(file) => function(file),
+            Node::PendingDir(_) => {}
+            Node::CrawledDir(_, children) => {
+                for child in children {
+                    child.for_each_file_mut(function);
+                }
+            }
+        }
+    }
+
+    /**
+    Finds the file or directory at a path relative to this (directory) node (e.g. `docs/deep` or
+    `docs/a.txt`)
+
+    The path is matched against the (decoded) URLs of the nodes. Directories which haven't been
+    crawled can be found, but nothing below them (as it isn't known yet).
+    */
+    pub fn find(&self, path: &str) -> Option<&Node> {
+        let url = match self {
+            Node::PendingDir(meta) | Node::CrawledDir(meta, _) => Url::parse(&meta.url).ok()?,
+            Node::File(_) => return None,
+        };
+
+        let path = path.trim_matches('/');
+        if path.is_empty() {
+            return Some(self);
+        }
+
+        let target = decoded_path(&url.join(path).ok()?);
+        let target = target.trim_end_matches('/');
+
+        self.iter().find(|node| {
+            Url::parse(node.url())
+                .is_ok_and(|url| decoded_path(&url).trim_end_matches('/') == target)
+        })
+    }
+
+    /// Finds the crawled directory at a path relative to this (directory) node (see `find`)
+    pub fn find_dir(&self, path: &str) -> Option<&Node> {
+        self.find(path)
+            .filter(|node| matches!(node, Node::CrawledDir(..)))
+    }
+}
+
+/**
+Iterates over the nodes of a tree (see `Node::iter`)
+
+The children of crawled directories come right after them, in the order of the listing.
+Directories which haven't been crawled have no children (yet).
+*/
+#[derive(Debug, Clone)]
+pub struct NodeIter<'a> {
+    stack: Vec<&'a Node>,
+}
+
+impl<'a> Iterator for NodeIter<'a> {
+    type Item = &'a Node;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = self.stack.pop()?;
+        if let Node::CrawledDir(_, children) = node {
+            self.stack.extend(children.iter().rev());
+        }
+
+        Some(node)
+    }
+}
+
+/// The totals of a (crawled) tree
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct TreeSummary {
+    pub files: u64,
+    pub directories: u64,
+    pub total_bytes: u64,
+
+    /// The number of files whose size couldn't be parsed (not included in `total_bytes`)
+    pub unknown_sizes: u64,
+}
+
+impl fmt::Display for TreeSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} files in {} directories, {}",
+            self.files,
+            self.directories,
+            format_size(self.total_bytes)
+        )?;
+
+        if self.unknown_sizes > 0 {
+            write!(f, " (+ {} files of unknown size)", self.unknown_sizes)?;
+        }
+
+        Ok(())
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct FileLinkMetaData {
     pub url: String,
     pub name: String,
     pub last_modified: String,
     pub size: String,
+
+    /// The description column of the listing (omitted from JSON if empty)
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub description: String,
+
+    /// The size in bytes (as far as it could be parsed from the listing)
+    #[serde(default)]
+    pub size_bytes: Option<u64>,
+
+    /// The depth below the root directory (whose files are at depth 1)
+    #[serde(default)]
+    pub depth: u64,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -35,60 +305,1022 @@ pub struct DirLinkMetaData {
     pub url: String,
     pub name: String,
     pub last_modified: String,
+
+    /// The description column of the listing (omitted from JSON if empty)
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub description: String,
+
+    /// The depth below the root directory (which is at depth 0)
+    #[serde(default)]
+    pub depth: u64,
+}
+
+#[cfg(test)]
+impl FileLinkMetaData {
+    /// Makes a file of a listing, named by the last segment of its URL (for the tests)
+    pub fn for_test(url: &str, size: u64) -> Self {
+        Self {
+            url: url.to_owned(),
+            name: url.rsplit('/').next().unwrap().to_owned(),
+            last_modified: "2021-01-01 10:00".to_owned(),
+            size: size.to_string(),
+            description: String::new(),
+            size_bytes: Some(size),
+            depth: 1,
+        }
+    }
+}
+
+#[cfg(test)]
+impl DirLinkMetaData {
+    /// Makes a directory of a listing, named by its URL (for the tests)
+    pub fn for_test(url: &str) -> Self {
+        Self {
+            url: url.to_owned(),
+            name: url.to_owned(),
+            last_modified: "2021-01-01 10:00".to_owned(),
+            description: String::new(),
+            depth: 0,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum CrawlingState {
     Complete(Node),
-    Partial(Node),
+    Partial(PartialCrawl),
     None,
 }
 
+impl CrawlingState {
+    /// Returns the (complete or partial) tree, if there is one
+    pub fn root(&self) -> Option<&Node> {
+        match self {
+            CrawlingState::Complete(root) => Some(root),
+            CrawlingState::Partial(partial) => Some(&partial.root),
+            CrawlingState::None => None,
+        }
+    }
+
+    pub fn root_mut(&mut self) -> Option<&mut Node> {
+        match self {
+            CrawlingState::Complete(root) => Some(root),
+            CrawlingState::Partial(partial) => Some(&mut partial.root),
+            CrawlingState::None => None,
+        }
+    }
+}
+
 /**
+A crawl which hasn't been finished (e.g. as it was interrupted or cut off by the crawl limits)
+
+The directories which still have to be crawled are PendingDir nodes of the tree, and their URLs
+make up the frontier (in the order they get crawled). Resuming the crawl continues from the
+frontier, so the directories crawled before aren't listed again.
+*/
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PartialCrawl {
+    pub root: Node,
+    pub frontier: Vec<String>,
+
+    /// Whether files were left out of the listings (by `--preview`), so the crawl can't be resumed
+    #[serde(default)]
+    pub truncated: bool,
+}
+
+impl PartialCrawl {
+    /// Starts a crawl from the listing of the root (whose directories make up the frontier)
+    pub fn new(root: Node) -> Self {
+        let frontier = root
+            .pending_dirs()
+            .into_iter()
+            .map(|dir| dir.url.clone())
+            .collect();
+
+        Self {
+            root,
+            frontier,
+            truncated: false,
+        }
+    }
+
+    /// Checks if the crawl can be continued from the frontier (instead of starting over)
+    pub fn is_resumable(&self) -> bool {
+        !self.truncated
+    }
+
+    /// Returns the next directory of the frontier (dropping URLs which aren't in the tree anymore)
+    pub fn next_dir(&mut self) -> Option<DirLinkMetaData> {
+        while let Some(url) = self.frontier.first() {
+            match self.root.find_pending_dir(url) {
+                Some(dir) => return Some(dir.clone()),
+                None => {
+                    self.frontier.remove(0);
+                }
+            }
+        }
+
+        None
+    }
+
+    /**
+    Replaces the next directory of the frontier with the result of crawling it
+
+    The directory is removed from the tree if `crawled` is None (e.g. as it was crawled from
+    elsewhere already). The directories which were left out while crawling it (`left_out`) are
+    added to the end of the frontier.
+    */
+    pub fn advance(&mut self, crawled: Option<Node>, left_out: Vec<String>) {
+        if self.frontier.is_empty() {
+            return;
+        }
+
+        let url = self.frontier.remove(0);
+        self.root.replace_pending_dir(&url, crawled);
+        self.frontier.extend(left_out);
+    }
 
+    /// Returns the directories which couldn't be parsed (the PendingDir nodes beyond the frontier)
+    pub fn unparsed_dirs(&self) -> Vec<&DirLinkMetaData> {
+        self.root
+            .pending_dirs()
+            .into_iter()
+            .filter(|dir| !self.frontier.contains(&dir.url))
+            .collect()
+    }
+
+    /// Turns into the crawling state (which is complete once the frontier is empty)
+    pub fn into_state(self) -> CrawlingState {
+        if self.frontier.is_empty() && !self.truncated {
+            CrawlingState::Complete(self.root)
+        } else {
+            CrawlingState::Partial(self)
+        }
+    }
+}
+
+/**
+Persists the progress of a crawl & download across runs
 */
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct StateStore {
+    /// The schema version (stores from before versioning are version 1)
+    #[serde(default = "StateStore::unversioned")]
+    pub version: u64,
+
     pub created_at: String,
     pub last_modified: String,
     pub crawling_state: CrawlingState,
 
     // TODO use borrowed string slices instead
     pub downloaded_urls: Vec<String>,
+
+    /// What identifies the downloaded files regardless of their URL (see `--match-by`)
+    #[serde(default)]
+    pub downloaded_files: Vec<FileIdentity>,
+
+    /// Downloads which were replaced by a link to an identical file (see `--dedup`)
+    #[serde(default)]
+    pub dedup_links: Vec<DedupLink>,
+
+    /// The cache validators of downloaded files (by URL, see `--revalidate`)
+    #[serde(default)]
+    pub validators: BTreeMap<String, Validators>,
+
+    /// The statistics of all runs using this state store (see `--stats`)
+    #[serde(default)]
+    pub stats: LifetimeStats,
+
+    /// The directories an interrupted download has yet to work through (by URL, in order)
+    #[serde(default)]
+    pub download_queue: Vec<String>,
+
+    /// The files whose download failed, to be retried without crawling (see `--replay-failures`)
+    #[serde(default)]
+    pub failed_files: Vec<FileLinkMetaData>,
+
+    /// How many attempts the downloads took and how they ended (by URL)
+    #[serde(default)]
+    pub attempts: BTreeMap<String, DownloadAttempts>,
+
+    /// Save the state store as gzip-compressed JSON (see `--compress-state`)
+    #[serde(skip)]
+    pub compressed: bool,
+}
+
+/// Statistics accumulated over several runs
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct LifetimeStats {
+    pub runs: u64,
+    pub bytes_downloaded: u64,
+
+    /// The requests made through the backend (listings, downloads & HEAD requests)
+    pub requests: u64,
+
+    pub elapsed_secs: f64,
+}
+
+impl LifetimeStats {
+    /// Adds the statistics of another run
+    pub fn add(&mut self, other: &LifetimeStats) {
+        self.runs += other.runs;
+        self.bytes_downloaded += other.bytes_downloaded;
+        self.requests += other.requests;
+        self.elapsed_secs += other.elapsed_secs;
+    }
+}
+
+impl fmt::Display for LifetimeStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} runs downloaded {} in {} requests and {:.1}s",
+            self.runs,
+            format_size(self.bytes_downloaded),
+            self.requests,
+            self.elapsed_secs
+        )
+    }
+}
+
+/// How the last download of a file ended
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AttemptStatus {
+    Downloaded,
+    Failed,
+}
+
+impl fmt::Display for AttemptStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Downloaded => write!(f, "downloaded"),
+            Self::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+/// The attempts to download a file (over all runs)
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct DownloadAttempts {
+    /// The requests the last download took (the first one & its retries)
+    pub attempts: u32,
+
+    /// The retries of all downloads of the file (flaky files keep adding up)
+    pub retries: u64,
+
+    pub status: AttemptStatus,
+}
+
+/// The cache validators of a downloaded file (from the headers of the response)
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct Validators {
+    /// The `ETag` header (sent back as `If-None-Match`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+
+    /// The `Last-Modified` header (sent back as `If-Modified-Since`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    /// Checks if the server sent neither validator
+    pub fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+}
+
+/// How files are recognized as downloaded by an earlier run (see `--match-by`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchBy {
+    /// The exact URL
+    Url,
+
+    /// The path below the root URL (so the server or the root may move)
+    Path,
+
+    /// The path below the root URL, the size & the date (so changed files get downloaded again)
+    Content,
+}
+
+/// What identifies a downloaded file regardless of its URL (see `--match-by`)
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct FileIdentity {
+    /// The (decoded) path below the root URL, separated by `/`
+    pub path: String,
+
+    #[serde(default)]
+    pub size_bytes: Option<u64>,
+
+    #[serde(default)]
+    pub last_modified: String,
+}
+
+impl FileIdentity {
+    /// Identifies a file below the root URL
+    pub fn of(file: &FileLinkMetaData, root_url: &Url) -> Self {
+        let path = Url::parse(&file.url)
+            .map(|url| paths::path_below(root_url, &url))
+            .unwrap_or_else(|_| file.url.clone());
+
+        Self {
+            path,
+            size_bytes: file.size_bytes,
+            last_modified: file.last_modified.clone(),
+        }
+    }
+
+    /// Checks if two identities stand for the same file
+    fn matches(&self, other: &Self, match_by: MatchBy) -> bool {
+        match match_by {
+            MatchBy::Url | MatchBy::Path => self.path == other.path,
+            MatchBy::Content => self == other,
+        }
+    }
+}
+
+/// A downloaded file which was replaced by a link to an identical, earlier download
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DedupLink {
+    /// The URL of the duplicate
+    pub url: String,
+
+    /// The URL of the file which was actually stored
+    pub canonical_url: String,
+}
+
+impl Default for StateStore {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl StateStore {
-    pub const VERSION: u64 = 1;
+    /// The current schema version
+    ///
+    /// - 1: The original schema (without a `version` field)
+    /// - 2: Adds `version` and the parsed `size_bytes` of files
+    /// - 3: Adds the `dedup_links`
+    /// - 4: Adds the `validators`
+    /// - 5: Adds the lifetime `stats`
+    /// - 6: Adds the `depth` of every node
+    /// - 7: Adds the `downloaded_files` (identities for `--match-by`)
+    /// - 8: Adds the `frontier` of partial crawls (so they can be resumed)
+    /// - 9: Adds the `download_queue` & the `failed_files`
+    /// - 10: Adds the `attempts` of the downloads
+    pub const VERSION: u64 = 10;
 
     pub fn new() -> Self {
         let now = Utc::now().to_rfc3339();
 
         Self {
+            version: Self::VERSION,
             created_at: now.clone(),
             last_modified: now,
             crawling_state: CrawlingState::None,
             downloaded_urls: vec![],
+            downloaded_files: vec![],
+            dedup_links: vec![],
+            validators: BTreeMap::new(),
+            stats: LifetimeStats::default(),
+            download_queue: vec![],
+            failed_files: vec![],
+            attempts: BTreeMap::new(),
+            compressed: false,
+        }
+    }
+
+    /**
+    Returns the default location of the state store for a download (used by `--resume`)
+
+    The name is a hash of the URL and the destination, so every mirror gets its own state store
+    in the cache directory of the user (e.g. `~/.cache/od-get/`).
+    */
+    pub fn default_path(url: &Url, destination: &Path) -> Result<PathBuf> {
+        let cache_dir = cache_dir().ok_or_else(|| {
+            anyhow!("Cannot find a cache directory for --resume (use --state-store instead)")
+        })?;
+
+        let hash = Sha256::digest(format!("{}\n{}", url, destination.display()).as_bytes());
+        let name = format!("{:x}", hash)[..16].to_owned();
+
+        Ok(cache_dir.join("od-get").join(format!("{}.json", name)))
+    }
+
+    /// The version of state stores which predate the version field
+    fn unversioned() -> u64 {
+        1
+    }
+
+    /// Loads the state store from disk (or makes a new one if there is none)
+    ///
+    /// Only a missing file leads to a new state store, any other I/O or parsing error is
+    /// returned, so existing progress never gets discarded silently.
+    pub fn load(path: &str) -> Result<Self> {
+        match fs::read(path) {
+            Ok(bytes) => {
+                let compressed = bytes.starts_with(&GZIP_MAGIC);
+                let mut state_store = Self::decode(bytes)
+                    .and_then(|json| Self::from_json(&json))
+                    .with_context(|| format!("Cannot load the state store {}", path))?;
+
+                // Keep a compressed state store compressed
+                state_store.compressed = compressed;
+
+                Ok(state_store)
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                info!("No state store at {}, starting fresh", path);
+                Ok(Self::new())
+            }
+            Err(err) => Err(err).with_context(|| format!("Cannot read the state store {}", path)),
         }
     }
 
+    /// Reads the JSON of a state store (decompressing it if it's gzip-compressed)
+    pub fn read_json(path: &str) -> Result<String> {
+        let bytes =
+            fs::read(path).with_context(|| format!("Cannot read the state store {}", path))?;
+
+        Self::decode(bytes).with_context(|| format!("Cannot read the state store {}", path))
+    }
+
+    /// Decodes the bytes of a state store file, which are either plain or gzip-compressed JSON
+    fn decode(bytes: Vec<u8>) -> Result<String> {
+        if !bytes.starts_with(&GZIP_MAGIC) {
+            return String::from_utf8(bytes).context("The state store is not valid UTF-8");
+        }
+
+        let mut json = String::new();
+        GzDecoder::new(bytes.as_slice())
+            .read_to_string(&mut json)
+            .context("The state store is not valid gzip-compressed JSON")?;
+
+        Ok(json)
+    }
+
+    /// Parses a serialized state store, migrating it from older schema versions
+    pub fn from_json(json: &str) -> Result<Self> {
+        let value: serde_json::Value =
+            serde_json::from_str(json).context("The state store is not valid JSON")?;
+
+        // Check which schema version to expect
+        let version = Self::version_of(&value);
+
+        if version > Self::VERSION {
+            bail!(
+                "The state store has version {}, but this version of od-get only supports up to version {}",
+                version,
+                Self::VERSION
+            );
+        }
+
+        // Partial crawls used to be just the tree (which left out the directories cut off)
+        let mut value = value;
+        if version < 8 {
+            if let Some(partial) = value.pointer_mut("/crawling_state/Partial") {
+                *partial = serde_json::json!({
+                    "root": partial.take(),
+                    "frontier": [],
+                    "truncated": true,
+                });
+            }
+        }
+
+        let mut state_store: Self = serde_json::from_value(value).with_context(|| {
+            format!(
+                "The state store doesn't match the schema of version {}",
+                version
+            )
+        })?;
+
+        // Upgrade older state stores in place
+        if version < Self::VERSION {
+            state_store.migrate(version);
+            info!(
+                "Migrated the state store from version {} to version {}",
+                version,
+                Self::VERSION
+            );
+        }
+
+        Ok(state_store)
+    }
+
+    /// Returns the schema version of a serialized state store (before any migration)
+    pub fn stored_version(json: &str) -> Result<u64> {
+        let value: serde_json::Value =
+            serde_json::from_str(json).context("The state store is not valid JSON")?;
+
+        Ok(Self::version_of(&value))
+    }
+
+    fn version_of(value: &serde_json::Value) -> u64 {
+        value
+            .get("version")
+            .and_then(serde_json::Value::as_u64)
+            .unwrap_or_else(Self::unversioned)
+    }
+
+    /// Upgrades the state store from an older schema version
+    fn migrate(&mut self, from: u64) {
+        // Version 2 added the parsed file sizes
+        if from < 2 {
+            if let Some(root) = self.crawling_state.root_mut() {
+                root.for_each_file_mut(&mut |file| file.size_bytes = parse_size(&file.size));
+            }
+        }
+
+        // Version 3 added the dedup_links (which default to none)
+        // Version 4 added the validators (which default to none)
+        // Version 5 added the stats (which start at zero, as earlier runs weren't counted)
+
+        // Version 6 added the depths (which follow from the tree)
+        if from < 6 {
+            if let Some(root) = self.crawling_state.root_mut() {
+                root.set_depth(0);
+            }
+        }
+
+        // Version 7 added the identities of the downloaded files (which follow from the tree)
+        if from < 7 {
+            if let Some(root) = self.crawling_state.root_mut() {
+                if let Ok(root_url) = Url::parse(root.url()) {
+                    let downloaded: HashSet<&str> =
+                        self.downloaded_urls.iter().map(String::as_str).collect();
+                    let downloaded_files = &mut self.downloaded_files;
+
+                    root.for_each_file_mut(&mut |file| {
+                        if downloaded.contains(file.url.as_str()) {
+                            downloaded_files.push(FileIdentity::of(file, &root_url));
+                        }
+                    });
+                }
+            }
+        }
+
+        // Version 8 added the frontier (older partial crawls can't be resumed, so they get redone)
+        // Version 9 added the download queue (which defaults to none, so downloads start at the root)
+
+        // Version 10 added the attempts (earlier downloads took at least one, their retries are lost)
+        if from < 10 {
+            let downloaded = self
+                .downloaded_urls
+                .iter()
+                .map(|url| (url, AttemptStatus::Downloaded));
+            let failed = self
+                .failed_files
+                .iter()
+                .map(|file| (&file.url, AttemptStatus::Failed));
+
+            for (url, status) in downloaded.chain(failed) {
+                self.attempts.insert(
+                    url.clone(),
+                    DownloadAttempts {
+                        attempts: 1,
+                        retries: 0,
+                        status,
+                    },
+                );
+            }
+        }
+
+        self.version = Self::VERSION;
+    }
+
+    /// Checks if a file was downloaded before (below the root URL)
+    pub fn is_downloaded(
+        &self,
+        file: &FileLinkMetaData,
+        root_url: &Url,
+        match_by: MatchBy,
+    ) -> bool {
+        if match_by == MatchBy::Url {
+            return self.downloaded_urls.contains(&file.url);
+        }
+
+        let identity = FileIdentity::of(file, root_url);
+        self.downloaded_files
+            .iter()
+            .any(|downloaded| downloaded.matches(&identity, match_by))
+    }
+
+    /// Marks a file as downloaded (replacing an earlier download of the same path)
+    pub fn mark_downloaded(&mut self, file: &FileLinkMetaData, root_url: &Url) {
+        if !self.downloaded_urls.contains(&file.url) {
+            self.downloaded_urls.push(file.url.clone());
+        }
+        self.failed_files.retain(|failed| failed.url != file.url);
+
+        let identity = FileIdentity::of(file, root_url);
+        self.downloaded_files
+            .retain(|downloaded| downloaded.path != identity.path);
+        self.downloaded_files.push(identity);
+    }
+
+    /// Forgets the download of a file (so it gets downloaded again)
+    pub fn forget_download(&mut self, file: &FileLinkMetaData, root_url: &Url) {
+        self.downloaded_urls.retain(|url| *url != file.url);
+
+        let identity = FileIdentity::of(file, root_url);
+        self.downloaded_files
+            .retain(|downloaded| downloaded.path != identity.path);
+    }
+
+    /// Drops a file which is gone from the server from the stored tree
+    pub fn forget_file(&mut self, url: &str) {
+        if let Some(root) = self.crawling_state.root_mut() {
+            root.remove_file(url);
+        }
+        self.failed_files.retain(|failed| failed.url != url);
+    }
+
+    /// Corrects the size of a file of the stored tree (as the server reported it)
+    pub fn resize_file(&mut self, url: &str, size: u64) {
+        if let Some(root) = self.crawling_state.root_mut() {
+            root.for_each_file_mut(&mut |file| {
+                if file.url == url {
+                    file.size_bytes = Some(size);
+                    file.size = size.to_string();
+                }
+            });
+        }
+    }
+
+    /// Marks a file as failed (replacing an earlier failure of the same URL)
+    pub fn mark_failed(&mut self, file: &FileLinkMetaData) {
+        self.failed_files.retain(|failed| failed.url != file.url);
+        self.failed_files.push(file.clone());
+    }
+
+    /// Records how many attempts the download of a file took and how it ended
+    pub fn record_attempts(
+        &mut self,
+        file: &FileLinkMetaData,
+        attempts: u32,
+        status: AttemptStatus,
+    ) {
+        let record = self
+            .attempts
+            .entry(file.url.clone())
+            .or_insert(DownloadAttempts {
+                attempts: 0,
+                retries: 0,
+                status,
+            });
+
+        record.attempts = attempts;
+        record.retries += u64::from(attempts.saturating_sub(1));
+        record.status = status;
+    }
+
+    /// The files which needed the most retries (flakiest first, at most `count` of them)
+    pub fn flakiest_files(&self, count: usize) -> Vec<(&str, &DownloadAttempts)> {
+        let mut flaky: Vec<_> = self
+            .attempts
+            .iter()
+            .filter(|(_, record)| record.retries > 0)
+            .map(|(url, record)| (url.as_str(), record))
+            .collect();
+        flaky.sort_by_key(|(_, record)| Reverse(record.retries));
+        flaky.truncate(count);
+
+        flaky
+    }
+
     pub fn update_modified_time(&mut self) {
         self.last_modified = Utc::now().to_rfc3339();
     }
 
-    pub fn get_root_ref(&self) -> Result<&Node> {
-        if let CrawlingState::Complete(root) = &self.crawling_state {
-            Ok(root)
+    /**
+    Persists the state store to disk (atomically, by writing to a temporary file first)
+
+    It's written as compact gzip-compressed JSON if it's `compressed` or the path ends with `.gz`,
+    and as pretty-printed JSON otherwise.
+    */
+    pub fn save(&mut self, path: &str) -> Result<()> {
+        // Update the modified time
+        self.update_modified_time();
+
+        // Compress it if desired (or if the path asks for it)
+        let bytes = if self.compressed || path.ends_with(".gz") {
+            let mut encoder = GzEncoder::new(vec![], Compression::default());
+            serde_json::to_writer(&mut encoder, &self)?;
+            encoder.finish()?
         } else {
-            bail!("Crawl is not complete")
-        }
+            serde_json::to_string_pretty(&self)?.into_bytes()
+        };
+
+        // Serialize the state store next to its destination, then replace the old one
+        let temp_path = format!("{}.tmp", path);
+        fs::write(&temp_path, bytes)
+            .with_context(|| format!("Cannot write to state store {}", temp_path))?;
+        fs::rename(&temp_path, path)
+            .with_context(|| format!("Cannot write to state store {}", path))?;
+
+        Ok(())
+    }
+
+    /// Returns the crawled tree (which is only a part of the server's after a `--preview` or an interrupted crawl)
+    pub fn get_root_ref(&self) -> Result<&Node> {
+        self.crawling_state
+            .root()
+            .ok_or_else(|| anyhow!("Crawl is not complete"))
     }
 
     pub fn get_root_ref_mut(&mut self) -> Result<&mut Node> {
-        if let CrawlingState::Complete(root) = &mut self.crawling_state {
-            Ok(root)
-        } else {
-            bail!("Crawl is not complete")
+        self.crawling_state
+            .root_mut()
+            .ok_or_else(|| anyhow!("Crawl is not complete"))
+    }
+
+    /// Returns the URLs of the directories a partial crawl still has to crawl
+    pub fn frontier(&self) -> &[String] {
+        match &self.crawling_state {
+            CrawlingState::Partial(partial) => &partial.frontier,
+            _ => &[],
+        }
+    }
+}
+
+/// Returns the cache directory of the user (following the conventions of the platform)
+fn cache_dir() -> Option<PathBuf> {
+    if let Some(dir) = env::var_os("XDG_CACHE_HOME").filter(|dir| !dir.is_empty()) {
+        return Some(PathBuf::from(dir));
+    }
+
+    if cfg!(windows) {
+        env::var_os("LOCALAPPDATA").map(PathBuf::from)
+    } else if cfg!(target_os = "macos") {
+        env::var_os("HOME").map(|home| PathBuf::from(home).join("Library").join("Caches"))
+    } else {
+        env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache"))
+    }
+}
+
+/// Returns the percent-decoded path of a URL
+fn decoded_path(url: &Url) -> String {
+    percent_decode_str(url.path())
+        .decode_utf8_lossy()
+        .into_owned()
+}
+
+/// Sorts nodes (and the children of crawled directories) by their URL
+pub fn sort_nodes(nodes: &mut [Node]) {
+    nodes.sort_by(|a, b| a.url().cmp(b.url()));
+
+    for node in nodes {
+        node.sort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Makes a directory with the given children
+    fn dir(url: &str, children: Vec<Node>) -> Node {
+        Node::CrawledDir(DirLinkMetaData::for_test(url), children)
+    }
+
+    /// Makes a file of the given size
+    fn file(url: &str, size: u64) -> Node {
+        Node::File(FileLinkMetaData::for_test(url, size))
+    }
+
+    /// A tree of three levels (with a directory which wasn't crawled)
+    fn tree() -> Node {
+        dir(
+            "http://a/",
+            vec![
+                file("http://a/1.txt", 1000),
+                dir(
+                    "http://a/b/",
+                    vec![
+                        file("http://a/b/2.txt", 2000),
+                        dir("http://a/b/c/", vec![file("http://a/b/c/3.txt", 48)]),
+                    ],
+                ),
+                Node::PendingDir(DirLinkMetaData::for_test("http://a/d/")),
+            ],
+        )
+    }
+
+    #[test]
+    fn summarizes_a_tree() {
+        let mut root = tree();
+        if let Node::CrawledDir(_, children) = &mut root {
+            let mut unknown = FileLinkMetaData::for_test("http://a/4.txt", 0);
+            unknown.size_bytes = None;
+            children.push(Node::File(unknown));
         }
+
+        let summary = root.summarize();
+
+        assert_eq!(
+            summary,
+            TreeSummary {
+                files: 4,
+                directories: 4,
+                total_bytes: 3048,
+                unknown_sizes: 1,
+            }
+        );
+        assert_eq!(
+            summary.to_string(),
+            "4 files in 4 directories, 3.0 KiB (+ 1 files of unknown size)"
+        );
+    }
+
+    #[test]
+    fn iterates_over_the_files_and_directories() {
+        let root = tree();
+
+        let files: Vec<&str> = root.iter_files().map(|file| file.url.as_str()).collect();
+        assert_eq!(
+            files,
+            ["http://a/1.txt", "http://a/b/2.txt", "http://a/b/c/3.txt"]
+        );
+
+        // Including the root & the directory which wasn't crawled
+        let mut dirs: Vec<&str> = root.iter_dirs().map(|dir| dir.url.as_str()).collect();
+        dirs.sort_unstable();
+        assert_eq!(
+            dirs,
+            ["http://a/", "http://a/b/", "http://a/b/c/", "http://a/d/"]
+        );
+    }
+
+    #[test]
+    fn finds_nodes_by_their_path() {
+        let root = tree();
+        let url_of = |path: &str| root.find(path).map(Node::url);
+
+        assert_eq!(url_of(""), Some("http://a/"));
+        assert_eq!(url_of("1.txt"), Some("http://a/1.txt"));
+        assert_eq!(url_of("b"), Some("http://a/b/"));
+        assert_eq!(url_of("/b/c/"), Some("http://a/b/c/"));
+        assert_eq!(url_of("b/c/3.txt"), Some("http://a/b/c/3.txt"));
+        assert_eq!(url_of("d"), Some("http://a/d/"));
+        assert_eq!(url_of("missing.txt"), None);
+
+        // Nothing below a directory which wasn't crawled (nor below a file)
+        assert_eq!(url_of("d/4.txt"), None);
+        assert!(root.find("1.txt").unwrap().find("x").is_none());
+    }
+
+    #[test]
+    fn saves_by_replacing_the_old_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        let path = path.to_str().unwrap();
+        fs::write(path, "old").unwrap();
+
+        let mut state = StateStore::new();
+        state.downloaded_urls.push("http://a/1.txt".to_owned());
+        state.save(path).unwrap();
+
+        // No temporary file is left behind
+        let names: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, ["state.json"]);
+        assert_eq!(
+            StateStore::load(path).unwrap().downloaded_urls,
+            ["http://a/1.txt"]
+        );
+    }
+
+    #[test]
+    fn round_trips_a_compressed_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_owned();
+        let mut state = StateStore::new();
+        state.crawling_state = CrawlingState::Complete(tree());
+        state.downloaded_urls.push("http://a/1.txt".to_owned());
+
+        // Compressed by the extension, or by the option (and recognized by the content)
+        state.save(&path("plain.json")).unwrap();
+        state.save(&path("state.json.gz")).unwrap();
+        state.compressed = true;
+        state.save(&path("compressed.json")).unwrap();
+
+        let plain = StateStore::load(&path("plain.json")).unwrap();
+        assert!(!plain.compressed);
+        let as_json = |state: &StateStore| {
+            let mut value = serde_json::to_value(state).unwrap();
+            value["last_modified"] = serde_json::Value::Null;
+            value
+        };
+        for name in ["state.json.gz", "compressed.json"] {
+            assert!(fs::read(path(name)).unwrap().starts_with(&GZIP_MAGIC));
+
+            let loaded = StateStore::load(&path(name)).unwrap();
+            assert!(loaded.compressed);
+            assert_eq!(as_json(&loaded), as_json(&plain));
+        }
+    }
+
+    #[test]
+    fn loads_a_current_store() {
+        let mut state = StateStore::new();
+        state.crawling_state = CrawlingState::Complete(tree());
+        state.downloaded_urls.push("http://a/1.txt".to_owned());
+        let json = serde_json::to_string(&state).unwrap();
+
+        let loaded = StateStore::from_json(&json).unwrap();
+
+        assert_eq!(loaded.version, StateStore::VERSION);
+        assert_eq!(loaded.downloaded_urls, ["http://a/1.txt"]);
+        assert_eq!(
+            loaded.get_root_ref().unwrap().summarize(),
+            tree().summarize()
+        );
+    }
+
+    #[test]
+    fn migrates_an_unversioned_store() {
+        let json = r#"{
+            "created_at": "2021-01-01T00:00:00Z",
+            "last_modified": "2021-01-01T00:00:00Z",
+            "crawling_state": {"Complete": {"CrawledDir": [
+                {"url": "http://a/", "name": "/", "last_modified": ""},
+                [{"File": {"url": "http://a/1.txt", "name": "1.txt", "last_modified": "", "size": "2K"}}]
+            ]}},
+            "downloaded_urls": ["http://a/1.txt"]
+        }"#;
+        assert_eq!(StateStore::stored_version(json).unwrap(), 1);
+
+        let state = StateStore::from_json(json).unwrap();
+
+        assert_eq!(state.version, StateStore::VERSION);
+        let file = state.get_root_ref().unwrap().iter_files().next().unwrap();
+        assert_eq!(file.size_bytes, Some(2048));
+        assert_eq!(file.depth, 1);
+        assert_eq!(state.downloaded_files.len(), 1);
+        assert_eq!(state.attempts["http://a/1.txt"].attempts, 1);
+        assert_eq!(state.stats, LifetimeStats::default());
+    }
+
+    #[test]
+    fn refuses_corrupt_and_newer_stores() {
+        let error = StateStore::from_json("{\"created_at\": ").unwrap_err();
+        assert!(error.to_string().contains("not valid JSON"), "{:#}", error);
+
+        let error = StateStore::from_json(r#"{"version": 2, "created_at": 3}"#).unwrap_err();
+        assert!(
+            error.to_string().contains("schema of version 2"),
+            "{:#}",
+            error
+        );
+
+        let json = format!(r#"{{"version": {}}}"#, StateStore::VERSION + 1);
+        let error = StateStore::from_json(&json).unwrap_err();
+        assert!(
+            error.to_string().contains("only supports up to"),
+            "{:#}",
+            error
+        );
+    }
+
+    #[test]
+    fn starts_fresh_without_a_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("missing.json");
+
+        let state = StateStore::load(path.to_str().unwrap()).unwrap();
+
+        assert!(matches!(state.crawling_state, CrawlingState::None));
+        assert!(state.downloaded_urls.is_empty());
+    }
+
+    #[test]
+    fn fails_on_an_unreadable_store() {
+        // A directory can't be read as a file
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+
+        let error = StateStore::load(path).unwrap_err();
+
+        assert!(
+            error.to_string().contains("Cannot read the state store"),
+            "{:#}",
+            error
+        );
+    }
+
+    #[test]
+    fn loads_a_valid_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        let mut state = StateStore::new();
+        state.downloaded_urls.push("http://a/1.txt".to_owned());
+        fs::write(&path, serde_json::to_string(&state).unwrap()).unwrap();
+
+        let loaded = StateStore::load(path.to_str().unwrap()).unwrap();
+
+        assert_eq!(loaded.downloaded_urls, ["http://a/1.txt"]);
     }
 }
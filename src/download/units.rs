@@ -0,0 +1,285 @@
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
+use rand::Rng;
+use std::time::Duration;
+
+/// The multipliers of the size suffixes used in listings (powers of 1024, like Apache)
+const SIZE_SUFFIXES: [(char, u64); 5] = [
+    ('K', 1 << 10),
+    ('M', 1 << 20),
+    ('G', 1 << 30),
+    ('T', 1 << 40),
+    ('P', 1 << 50),
+];
+
+/**
+Parses a human-readable size (e.g. `12`, `1.2K`, `3M`, `4.5 GiB`) into a byte count
+
+Returns None for sizes which can't be parsed (e.g. the `-` of directories).
+*/
+pub fn parse_size(text: &str) -> Option<u64> {
+    let text = text.trim();
+
+    // Split the number from its unit
+    let split = text
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number: f64 = number.parse().ok()?;
+
+    // Only the first letter of the unit is relevant (`K`, `KB`, `KiB`, ...)
+    let multiplier = match unit.trim().chars().next() {
+        None => 1,
+        Some(c) if c.eq_ignore_ascii_case(&'B') => 1,
+        Some(c) => {
+            SIZE_SUFFIXES
+                .iter()
+                .find(|(suffix, _)| c.eq_ignore_ascii_case(suffix))?
+                .1
+        }
+    };
+
+    Some((number * multiplier as f64).round() as u64)
+}
+
+/**
+Returns the precision of a human-readable size in bytes (what its last digit stands for)
+
+E.g. `1234` is exact to 1 byte, while `1.2K` is only exact to 0.1 KiB (102 bytes).
+*/
+pub fn size_precision(text: &str) -> Option<u64> {
+    let text = text.trim();
+    parse_size(text)?;
+
+    let number_end = text
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(text.len());
+    let decimals = text[..number_end]
+        .split_once('.')
+        .map_or(0, |(_, decimals)| decimals.len());
+
+    // The multiplier of the unit is what a size of 1 (in that unit) parses to
+    let unit = &text[number_end..];
+    let multiplier = parse_size(&format!("1{}", unit))?;
+
+    Some(((multiplier as f64 / 10f64.powi(decimals as i32)) as u64).max(1))
+}
+
+/**
+Formats a byte count human-readably (e.g. `1.5 GiB`)
+*/
+pub fn format_size(bytes: u64) -> String {
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+
+    // Find the largest fitting suffix
+    let (suffix, multiplier) = SIZE_SUFFIXES
+        .iter()
+        .rev()
+        .find(|(_, multiplier)| bytes >= *multiplier)
+        .unwrap_or(&SIZE_SUFFIXES[0]);
+
+    format!("{:.1} {}iB", bytes as f64 / *multiplier as f64, suffix)
+}
+
+/// An inclusive range of bytes of a file (e.g. `0-1023`), open-ended if there is no end
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: Option<u64>,
+}
+
+impl ByteRange {
+    /// Returns the value of the Range header requesting these bytes (e.g. `bytes=0-1023`)
+    pub fn header_value(&self) -> String {
+        match self.end {
+            Some(end) => format!("bytes={}-{}", self.start, end),
+            None => format!("bytes={}-", self.start),
+        }
+    }
+}
+
+/**
+Parses a byte range (e.g. `0-1023`, or `1024-` for everything from the 1024th byte on)
+
+Returns None for ranges which can't be parsed or end before they start.
+*/
+pub fn parse_range(text: &str) -> Option<ByteRange> {
+    let (start, end) = text.trim().split_once('-')?;
+    let start = start.trim().parse().ok()?;
+    let end = match end.trim() {
+        "" => None,
+        end => Some(end.parse().ok()?),
+    };
+
+    match end {
+        Some(end) if end < start => None,
+        _ => Some(ByteRange { start, end }),
+    }
+}
+
+/// A range of durations to wait between requests (see `--delay-jitter`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DelayRange {
+    pub min: Duration,
+    pub max: Duration,
+}
+
+impl DelayRange {
+    /// Returns the middle of the range (which deterministic runs always wait for)
+    pub fn midpoint(&self) -> Duration {
+        self.min + (self.max - self.min) / 2
+    }
+
+    /// Returns a random duration within the range
+    pub fn sample(&self) -> Duration {
+        if self.min == self.max {
+            return self.min;
+        }
+
+        rand::thread_rng().gen_range(self.min..=self.max)
+    }
+}
+
+/// The hours of the day during which no delay applies between requests (see `--schedule`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    /// The start & end of each window (ending on the next day if it ends before it starts)
+    windows: Vec<(NaiveTime, NaiveTime)>,
+}
+
+impl Schedule {
+    /// Checks if a time of day is within one of the windows (including the start, not the end)
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        self.windows.iter().any(|&(start, end)| {
+            if start <= end {
+                start <= time && time < end
+            } else {
+                start <= time || time < end
+            }
+        })
+    }
+}
+
+/**
+Parses the windows of a schedule (e.g. `00:00-06:00` or `22:00-02:00,12:00-13:30`)
+
+Returns None for schedules which can't be parsed or have an empty window.
+*/
+pub fn parse_schedule(text: &str) -> Option<Schedule> {
+    let windows = text
+        .split(',')
+        .map(|window| {
+            let (start, end) = window.trim().split_once('-')?;
+            let start = NaiveTime::parse_from_str(start.trim(), "%H:%M").ok()?;
+            let end = NaiveTime::parse_from_str(end.trim(), "%H:%M").ok()?;
+
+            (start != end).then_some((start, end))
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    Some(Schedule { windows })
+}
+
+/**
+Parses a range of durations (e.g. `1s-3s` or `500ms-2s`)
+
+Returns None for ranges which can't be parsed or end before they start.
+*/
+pub fn parse_delay_range(text: &str) -> Option<DelayRange> {
+    let (min, max) = text.trim().split_once('-')?;
+    let (min, max) = (parse_duration(min)?, parse_duration(max)?);
+
+    if max < min {
+        return None;
+    }
+
+    Some(DelayRange { min, max })
+}
+
+/// The formats of the dates in listings (e.g. `2021-03-04 12:34` or Apache's `04-Mar-2021 12:34`)
+const DATE_FORMATS: [&str; 7] = [
+    "%Y-%m-%d %H:%M",
+    "%Y-%m-%d %H:%M:%S",
+    "%d-%b-%Y %H:%M",
+    "%d-%b-%Y %H:%M:%S",
+    // IIS (in the short & long US formats, and in the 24-hour one of e.g. German servers)
+    "%m/%d/%Y %I:%M %p",
+    "%A, %B %d, %Y %I:%M %p",
+    "%d.%m.%Y %H:%M",
+];
+
+/**
+Parses the date of a listing entry (e.g. `2021-03-04 12:34`)
+
+Listings don't state a time zone, so the date is taken to be in UTC.
+*/
+pub fn parse_date(text: &str) -> Option<DateTime<Utc>> {
+    let text = text.trim();
+
+    DATE_FORMATS
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(text, format).ok())
+        .map(|date| Utc.from_utc_datetime(&date))
+}
+
+/**
+Parses a point in time given either as a date (e.g. `2021-03-04`, `2021-03-04T12:34:56Z` or a
+date like in listings), or as a duration before `now` (e.g. `7d` or `12h`)
+
+Dates without a time zone are taken to be in UTC (like the dates of listings), dates without a
+time start at midnight.
+*/
+pub fn parse_since(text: &str, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let text = text.trim();
+
+    if let Some(duration) = parse_duration(text) {
+        return Some(now - chrono::Duration::from_std(duration).ok()?);
+    }
+
+    if let Ok(date) = DateTime::parse_from_rfc3339(text) {
+        return Some(date.with_timezone(&Utc));
+    }
+
+    if let Ok(date) = NaiveDate::parse_from_str(text, "%Y-%m-%d") {
+        return Some(Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0)?));
+    }
+
+    parse_date(text)
+}
+
+/**
+Parses a human-readable duration (e.g. `90s`, `30m`, `1h30m`, `2d`) into a Duration
+*/
+pub fn parse_duration(text: &str) -> Option<Duration> {
+    let mut total = Duration::ZERO;
+    let mut rest = text.trim();
+
+    if rest.is_empty() {
+        return None;
+    }
+
+    // Add up every `<number><unit>` pair
+    while !rest.is_empty() {
+        let split = rest.find(|c: char| !c.is_ascii_digit())?;
+        let (number, tail) = rest.split_at(split);
+        let number: u64 = number.parse().ok()?;
+
+        let unit_len = tail
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(tail.len());
+        let (unit, tail) = tail.split_at(unit_len);
+
+        total += match unit {
+            "ms" => Duration::from_millis(number),
+            "s" => Duration::from_secs(number),
+            "m" => Duration::from_secs(number * 60),
+            "h" => Duration::from_secs(number * 60 * 60),
+            "d" => Duration::from_secs(number * 60 * 60 * 24),
+            _ => return None,
+        };
+        rest = tail;
+    }
+
+    Some(total)
+}
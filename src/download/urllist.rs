@@ -0,0 +1,211 @@
+use super::{
+    crawl::root_meta,
+    filenames::decode_filename,
+    types::{FileLinkMetaData, Node},
+    units::parse_size,
+};
+use anyhow::{bail, Context, Result};
+use log::warn;
+use reqwest::Url;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/**
+Reads a list of file URLs (one per line, e.g. for `--urls-file`)
+
+Lines may also be in the format of `--manifest` (`url<TAB>size<TAB>last_modified`), so the size
+and date get taken over. Empty lines and lines starting with `#` are ignored.
+*/
+pub fn read_url_list(path: &Path) -> Result<Vec<FileLinkMetaData>> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("Cannot read the URL list {}", path.display()))?;
+
+    let mut files = vec![];
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut fields = line.split('\t');
+        let url = fields.next().unwrap_or_default();
+        let size = fields.next().unwrap_or_default().trim().to_owned();
+        let last_modified = fields.next().unwrap_or_default().trim().to_owned();
+
+        let url = Url::parse(url.trim())
+            .with_context(|| format!("Invalid URL in line {} of {}", index + 1, path.display()))?;
+        let name = url
+            .path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .map(decode_filename)
+            .unwrap_or_default();
+
+        files.push(FileLinkMetaData {
+            url: url.to_string(),
+            name,
+            last_modified,
+            size_bytes: parse_size(&size),
+            size,
+            description: String::new(),
+            depth: 0,
+        });
+    }
+
+    Ok(files)
+}
+
+/**
+Reads a list of mirrors (a root URL per line, e.g. for `--mirror-list`)
+
+The URL may be followed by the destination of the mirror (separated by whitespace), which is
+relative to `--destination` (unless it's absolute). Empty lines and lines starting with `#` are
+ignored.
+*/
+pub fn read_mirror_list(path: &Path) -> Result<Vec<(Url, Option<PathBuf>)>> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("Cannot read the mirror list {}", path.display()))?;
+
+    let mut mirrors = vec![];
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (url, destination) = match line.split_once(char::is_whitespace) {
+            Some((url, destination)) => (url, Some(PathBuf::from(destination.trim()))),
+            None => (line, None),
+        };
+        let url = Url::parse(url)
+            .with_context(|| format!("Invalid URL in line {} of {}", index + 1, path.display()))?;
+
+        mirrors.push((url, destination));
+    }
+
+    if mirrors.is_empty() {
+        bail!("The mirror list {} is empty", path.display());
+    }
+
+    Ok(mirrors)
+}
+
+/**
+Builds a tree (as if it was crawled) holding the given files below the root URL
+
+The directories get derived from the paths of the URLs. Files outside of the root are dropped
+with a warning.
+*/
+pub fn build_tree(root: &Url, files: Vec<FileLinkMetaData>) -> Node {
+    tree_of(root, files.into_iter().map(Node::File).collect())
+}
+
+/**
+Reads a frontier (the URLs of the directories still to be crawled, e.g. for `--import-frontier`)
+
+The format is the one of `write_frontier`, a URL per line. Empty lines and lines starting with `#`
+are ignored.
+*/
+pub fn read_frontier(path: &Path) -> Result<Vec<Url>> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("Cannot read the frontier {}", path.display()))?;
+
+    text.lines()
+        .enumerate()
+        .map(|(index, line)| (index, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(index, line)| {
+            Url::parse(line)
+                .with_context(|| format!("Invalid URL in line {} of {}", index + 1, path.display()))
+        })
+        .collect()
+}
+
+/// Writes the URLs of the directories still to be crawled (see `--export-frontier`)
+pub fn write_frontier(path: &Path, frontier: &[String]) -> Result<()> {
+    let text: String = frontier.iter().map(|url| format!("{}\n", url)).collect();
+
+    fs::write(path, text)
+        .with_context(|| format!("Cannot write the frontier to {}", path.display()))
+}
+
+/**
+Builds a tree (as if it was partly crawled) holding the given directories below the root URL
+
+The directories are PendingDir nodes, so a crawl of the tree only lists them (and their
+sub-directories). The directories above them get derived from their paths.
+*/
+pub fn build_frontier_tree(root: &Url, dirs: Vec<Url>) -> Node {
+    tree_of(
+        root,
+        dirs.iter()
+            .map(|url| Node::PendingDir(root_meta(url)))
+            .collect(),
+    )
+}
+
+/// Builds a tree holding the given nodes below the root URL (dropping the ones outside of it)
+fn tree_of(root: &Url, nodes: Vec<Node>) -> Node {
+    // Treat the root as a directory, even if its URL lacks the trailing slash
+    let mut root = root.clone();
+    if !root.path().ends_with('/') {
+        root.set_path(&format!("{}/", root.path()));
+    }
+
+    let mut children = vec![];
+    for node in nodes {
+        let relative = Url::parse(node.url()).ok().and_then(|url| {
+            let path = url.path().strip_prefix(root.path())?.trim_end_matches('/');
+            (url.origin() == root.origin() && !path.is_empty()).then(|| path.to_owned())
+        });
+
+        match relative {
+            Some(relative) => {
+                let segments: Vec<&str> = relative.split('/').collect();
+                insert_node(&mut children, &root, &segments[..segments.len() - 1], node);
+            }
+            None => warn!("(Urls) Skip {} (not below {})", node.url(), root),
+        }
+    }
+
+    let mut root = Node::CrawledDir(root_meta(&root), children);
+    root.set_depth(0);
+
+    root
+}
+
+/// Inserts a node into the directory at the given (relative) path, creating it if needed
+fn insert_node(nodes: &mut Vec<Node>, dir_url: &Url, dirs: &[&str], node: Node) {
+    let (dir, rest) = match dirs.split_first() {
+        Some((dir, rest)) => (dir, rest),
+        None => {
+            nodes.push(node);
+            return;
+        }
+    };
+
+    // Empty segments (e.g. from `//`) don't make a directory
+    if dir.is_empty() {
+        return insert_node(nodes, dir_url, rest, node);
+    }
+
+    // Relative to the directory (so segments like `a:b` aren't taken for a scheme)
+    let url = match dir_url.join(&format!("./{}/", dir)) {
+        Ok(url) => url,
+        Err(_) => return nodes.push(node),
+    };
+
+    let position = nodes.iter().position(|node| match node {
+        Node::CrawledDir(meta, _) => meta.url == url.as_str(),
+        _ => false,
+    });
+    let position = position.unwrap_or_else(|| {
+        nodes.push(Node::CrawledDir(root_meta(&url), vec![]));
+        nodes.len() - 1
+    });
+
+    if let Node::CrawledDir(_, children) = &mut nodes[position] {
+        insert_node(children, &url, rest, node);
+    }
+}
@@ -0,0 +1,58 @@
+use anyhow::{bail, Context, Result};
+use reqwest::header::HeaderValue;
+use std::{
+    fs,
+    path::Path,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/**
+A pool of User-Agent headers which take turns, one per request (see `--user-agent-file`)
+
+The agents are used round-robin, so the order of the headers is the same on every run.
+*/
+#[derive(Debug)]
+pub struct UserAgentPool {
+    agents: Vec<HeaderValue>,
+    next: AtomicUsize,
+}
+
+impl UserAgentPool {
+    /**
+    Reads the User-Agents from a file (one per line)
+
+    Empty lines and lines starting with `#` are ignored.
+    */
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Cannot read the User-Agent file {}", path.display()))?;
+
+        let agents = text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .enumerate()
+            .map(|(index, line)| {
+                HeaderValue::from_str(line).with_context(|| {
+                    format!("Invalid User-Agent {} in {}", index + 1, path.display())
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        if agents.is_empty() {
+            bail!("The User-Agent file {} is empty", path.display());
+        }
+
+        Ok(Self {
+            agents,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Returns the User-Agent for the next request
+    pub fn next(&self) -> &HeaderValue {
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.agents.len();
+
+        &self.agents[index]
+    }
+}
@@ -0,0 +1,215 @@
+use super::{
+    checksum::{self, ChecksumKind},
+    fetch::{get_last_segment, local_name},
+    paths,
+    types::{FileLinkMetaData, Node, StateStore},
+    units::{format_size, size_precision},
+};
+use anyhow::Result;
+use log::{debug, warn};
+use reqwest::Url;
+use std::{
+    collections::HashSet,
+    fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// A local file which doesn't match the state store
+#[derive(Debug, Clone)]
+pub enum Discrepancy {
+    /// The file doesn't exist (anymore)
+    Missing(PathBuf),
+
+    /// The size of the file differs from the one in the listing
+    WrongSize {
+        path: PathBuf,
+        expected: u64,
+        actual: u64,
+    },
+
+    /// The digest of the file differs from the one of its checksum sidecar
+    WrongChecksum { path: PathBuf, kind: ChecksumKind },
+}
+
+/// The result of checking a mirror against its state store
+#[derive(Debug, Clone, Default)]
+pub struct VerifyReport {
+    /// The number of files which were checked (all downloaded files)
+    pub checked: u64,
+
+    /// The number of files which exist, but whose size isn't known from the listing
+    pub unknown_sizes: u64,
+
+    pub discrepancies: Vec<Discrepancy>,
+
+    /// The files of the discrepancies (to download them again)
+    pub damaged: Vec<FileLinkMetaData>,
+}
+
+impl fmt::Display for Discrepancy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Discrepancy::Missing(path) => write!(f, "Missing {}", path.display()),
+            Discrepancy::WrongSize {
+                path,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Wrong size of {} ({} instead of {})",
+                path.display(),
+                format_size(*actual),
+                format_size(*expected)
+            ),
+            Discrepancy::WrongChecksum { path, kind } => write!(
+                f,
+                "Wrong {} checksum of {}",
+                kind.extension(),
+                path.display()
+            ),
+        }
+    }
+}
+
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Checked {} files, {} missing or corrupted",
+            self.checked,
+            self.discrepancies.len()
+        )?;
+
+        if self.unknown_sizes > 0 {
+            write!(
+                f,
+                " ({} of unknown size only checked to exist)",
+                self.unknown_sizes
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/**
+Checks the local copies of the downloaded files of a completed state store
+
+Every file in the done_list has to exist below the destination, and (as far as the listing tells)
+have the size stated by the server. Listings often round sizes (e.g. `1.2K`), so sizes only have
+to match up to the precision of the listing. Files with a checksum sidecar (see
+`--emit-checksums`) also have to match its digest.
+*/
+pub fn verify(
+    state: &StateStore,
+    destination: &Path,
+    max_length: Option<usize>,
+) -> Result<VerifyReport> {
+    let root = state.get_root_ref()?;
+    let base_url = match root {
+        Node::CrawledDir(meta, _) => paths::parent_url(&Url::from_str(&meta.url)?),
+        _ => return Ok(VerifyReport::default()),
+    };
+
+    let downloaded: HashSet<&str> = state.downloaded_urls.iter().map(String::as_str).collect();
+
+    let mut report = VerifyReport::default();
+    verify_node(
+        root,
+        &base_url,
+        destination,
+        max_length,
+        &downloaded,
+        &mut report,
+    )?;
+
+    Ok(report)
+}
+
+/// Checks the downloaded files of a node and its children
+fn verify_node(
+    node: &Node,
+    base_url: &Url,
+    destination: &Path,
+    max_length: Option<usize>,
+    downloaded: &HashSet<&str>,
+    report: &mut VerifyReport,
+) -> Result<()> {
+    let (meta, children) = match node {
+        Node::CrawledDir(meta, children) => (meta, children),
+        _ => return Ok(()),
+    };
+
+    let dir_path = paths::url_to_local_path(base_url, &Url::from_str(&meta.url)?, destination);
+
+    for child in children {
+        match child {
+            Node::File(file) if downloaded.contains(file.url.as_str()) => {
+                let url = Url::from_str(&file.url)?;
+                let file_path = dir_path.join(local_name(get_last_segment(&url), max_length));
+
+                report.checked += 1;
+                if let Some(discrepancy) = verify_file(file, file_path, report) {
+                    warn!("{}", discrepancy);
+                    report.discrepancies.push(discrepancy);
+                    report.damaged.push(file.clone());
+                }
+            }
+            Node::File(_) => (),
+            _ => verify_node(child, base_url, destination, max_length, downloaded, report)?,
+        }
+    }
+
+    Ok(())
+}
+
+/**
+Returns the size of a file according to the listing and how exact it is (if known)
+
+A size of `1.2K` is e.g. only exact to 0.1 KiB, so sizes are considered equal if they differ by
+less than the precision.
+*/
+pub fn expected_size(file: &FileLinkMetaData) -> Option<(u64, u64)> {
+    Some((file.size_bytes?, size_precision(&file.size)?))
+}
+
+/// Checks a single downloaded file
+fn verify_file(
+    file: &FileLinkMetaData,
+    file_path: PathBuf,
+    report: &mut VerifyReport,
+) -> Option<Discrepancy> {
+    let actual = match fs::metadata(&file_path) {
+        Ok(metadata) => metadata.len(),
+        Err(_) => return Some(Discrepancy::Missing(file_path)),
+    };
+
+    match expected_size(file) {
+        Some((expected, precision)) if actual.abs_diff(expected) >= precision => {
+            return Some(Discrepancy::WrongSize {
+                path: file_path,
+                expected,
+                actual,
+            });
+        }
+        Some(_) => (),
+        None => report.unknown_sizes += 1,
+    }
+
+    // Catch corruptions which keep the size (as far as a sidecar tells)
+    for kind in ChecksumKind::ALL {
+        if let Some(expected) = checksum::read_sidecar(&file_path, kind) {
+            if checksum::hash_file(&file_path, kind).ok() != Some(expected) {
+                return Some(Discrepancy::WrongChecksum {
+                    path: file_path,
+                    kind,
+                });
+            }
+        }
+    }
+
+    debug!("(Verify) Ok {}", file_path.display());
+
+    None
+}
@@ -0,0 +1,50 @@
+use log::warn;
+use std::{
+    io,
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// The attribute holding the URL a file was downloaded from
+pub const SOURCE_ATTR: &str = "user.od-get.source";
+
+/// The attribute holding the SHA-256 digest of a file (as lowercase hex)
+pub const SHA256_ATTR: &str = "user.od-get.sha256";
+
+/// Whether a failure to set the attributes was reported already
+static WARNED: AtomicBool = AtomicBool::new(false);
+
+/**
+Tags a downloaded file with its source URL and digest (see `--xattrs`)
+
+The attributes move along with the file (within the filesystem), unlike sidecar files. Where they
+aren't supported (e.g. on FAT or tmpfs without `user_xattr`), a warning is logged once and the
+download goes on.
+*/
+pub fn tag(file_path: &Path, source_url: &str, sha256: &str) {
+    let result =
+        set(file_path, SOURCE_ATTR, source_url).and_then(|()| set(file_path, SHA256_ATTR, sha256));
+
+    if let Err(error) = result {
+        if !WARNED.swap(true, Ordering::Relaxed) {
+            warn!(
+                "(Xattrs) Cannot set extended attributes on {} (files stay untagged): {}",
+                file_path.display(),
+                error
+            );
+        }
+    }
+}
+
+#[cfg(unix)]
+fn set(file_path: &Path, name: &str, value: &str) -> io::Result<()> {
+    xattr::set(file_path, name, value.as_bytes())
+}
+
+#[cfg(not(unix))]
+fn set(_file_path: &Path, _name: &str, _value: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "extended attributes are only supported on Unix",
+    ))
+}
@@ -0,0 +1,210 @@
+use crate::download::{
+    crawl::{
+        ErrorStatus, FormatMismatch, ListingTooLarge, NotAListing, RedirectLoop, UnparsedRows,
+    },
+    fetch::{DiskFull, SizeMismatch},
+    lockfile::Drift,
+    throttle::Throttled,
+    timeout::TimedOut,
+};
+use reqwest::StatusCode;
+use serde::Serialize;
+use std::{fmt, io, process::ExitCode};
+use suppaftp::FtpError;
+
+/// The categories of errors a run can fail with (each with its own exit code)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// Anything else (e.g. an interruption with Ctrl-C)
+    Other,
+
+    /// Invalid arguments or config file
+    Usage,
+
+    /// No (usable) response from the server
+    Network,
+
+    /// A listing (or state store) which couldn't be parsed
+    Parse,
+
+    /// Reading or writing local files (e.g. a full disk)
+    Io,
+
+    /// The server denied access
+    Auth,
+}
+
+impl ErrorKind {
+    /// Returns the exit code of a run failing with this kind of error
+    pub fn exit_code(self) -> u8 {
+        match self {
+            Self::Other => 1,
+            Self::Usage => 2,
+            Self::Network => 3,
+            Self::Parse => 4,
+            Self::Io => 5,
+            Self::Auth => 6,
+        }
+    }
+}
+
+/**
+An error in the arguments or the config file
+
+Wraps the error (keeping its message & causes), so it's recognized as a usage error.
+*/
+#[derive(Debug)]
+pub struct UsageError(anyhow::Error);
+
+impl UsageError {
+    pub fn wrap(error: anyhow::Error) -> anyhow::Error {
+        Self(error).into()
+    }
+}
+
+impl fmt::Display for UsageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for UsageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.0.source()
+    }
+}
+
+/// How errors are printed (see `--error-format`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorFormat {
+    Text,
+    Json,
+}
+
+/// An error the run failed with, as printed with `--error-format json`
+#[derive(Debug, Serialize)]
+pub struct ErrorReport {
+    pub kind: ErrorKind,
+
+    /// The message of the error and its causes
+    pub message: String,
+
+    /// The URL which caused the error (if it's known)
+    pub url: Option<String>,
+
+    /// The state store of the run (if any), which holds its progress
+    pub state_store: Option<String>,
+    pub exit_code: u8,
+}
+
+impl ErrorReport {
+    pub fn new(error: &anyhow::Error, state_store: Option<String>) -> Self {
+        let kind = classify(error);
+
+        Self {
+            kind,
+            message: format!("{:#}", error),
+            url: offending_url(error),
+            state_store,
+            exit_code: kind.exit_code(),
+        }
+    }
+}
+
+/**
+Prints the error a run failed with (to stderr) and returns the exit code of its kind
+
+The text format is the one of errors returned from `main` (with the causes & backtrace).
+*/
+pub fn report_error(
+    error: &anyhow::Error,
+    format: ErrorFormat,
+    state_store: Option<String>,
+) -> ExitCode {
+    let report = ErrorReport::new(error, state_store);
+
+    match format {
+        ErrorFormat::Text => eprintln!("Error: {:?}", error),
+        ErrorFormat::Json => match serde_json::to_string(&report) {
+            Ok(json) => eprintln!("{}", json),
+            Err(_) => eprintln!("Error: {:?}", error),
+        },
+    }
+
+    ExitCode::from(report.exit_code)
+}
+
+/// Finds the kind of an error (by its first recognized cause)
+pub fn classify(error: &anyhow::Error) -> ErrorKind {
+    if error.is::<UsageError>() {
+        return ErrorKind::Usage;
+    }
+
+    for cause in error.chain() {
+        if let Some(status) = cause.downcast_ref::<ErrorStatus>() {
+            return match status.status {
+                StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => ErrorKind::Auth,
+                _ => ErrorKind::Network,
+            };
+        }
+        if let Some(error) = cause.downcast_ref::<reqwest::Error>() {
+            return match error.status() {
+                Some(StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN) => ErrorKind::Auth,
+                _ => ErrorKind::Network,
+            };
+        }
+        if cause.is::<Throttled>()
+            || cause.is::<TimedOut>()
+            || cause.is::<RedirectLoop>()
+            || cause.is::<SizeMismatch>()
+            || cause.is::<FtpError>()
+        {
+            return ErrorKind::Network;
+        }
+        if cause.is::<NotAListing>()
+            || cause.is::<UnparsedRows>()
+            || cause.is::<FormatMismatch>()
+            || cause.is::<ListingTooLarge>()
+            || cause.is::<serde_json::Error>()
+        {
+            return ErrorKind::Parse;
+        }
+        if cause.is::<DiskFull>() || cause.is::<io::Error>() {
+            return ErrorKind::Io;
+        }
+    }
+
+    ErrorKind::Other
+}
+
+/// Finds the URL which caused an error (if any cause knows it)
+fn offending_url(error: &anyhow::Error) -> Option<String> {
+    error.chain().find_map(|cause| {
+        if let Some(status) = cause.downcast_ref::<ErrorStatus>() {
+            Some(status.url.clone())
+        } else if let Some(error) = cause.downcast_ref::<reqwest::Error>() {
+            error.url().map(|url| url.to_string())
+        } else if let Some(throttled) = cause.downcast_ref::<Throttled>() {
+            Some(throttled.url.clone())
+        } else if let Some(mismatch) = cause.downcast_ref::<SizeMismatch>() {
+            Some(mismatch.url.clone())
+        } else if let Some(full) = cause.downcast_ref::<DiskFull>() {
+            Some(full.url.clone())
+        } else if let Some(drift) = cause.downcast_ref::<Drift>() {
+            Some(drift.url.clone())
+        } else if let Some(listing) = cause.downcast_ref::<NotAListing>() {
+            Some(listing.url.clone())
+        } else if let Some(rows) = cause.downcast_ref::<UnparsedRows>() {
+            Some(rows.url.clone())
+        } else if let Some(mismatch) = cause.downcast_ref::<FormatMismatch>() {
+            Some(mismatch.url.clone())
+        } else if let Some(listing) = cause.downcast_ref::<ListingTooLarge>() {
+            Some(listing.url.clone())
+        } else {
+            cause
+                .downcast_ref::<RedirectLoop>()
+                .and_then(|redirects| redirects.urls.first().cloned())
+        }
+    })
+}
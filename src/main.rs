@@ -1,20 +1,58 @@
 // For internal use within the CLI application only
 pub(crate) mod cli;
+pub(crate) mod client;
+pub(crate) mod config;
 pub(crate) mod constants;
+pub(crate) mod errors;
 
 // Export as a library
 pub mod download;
 
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use clap::ArgMatches;
 use download::{
-    crawl,
+    catalog, crawl, diff,
+    events::NdjsonEvents,
+    excludes::DirExcludes,
     fetch::{self, DownloadRecursiveStatus},
-    types::{CrawlingState, Node, StateStore},
+    hook,
+    listing::{self, BoundedListing, DirectoryListing, MarkedListing, SortedListing},
+    lock::RunLock,
+    lockfile::Lockfile,
+    manifest, merge,
+    observer::{DownloadObserver, Observers},
+    paths,
+    progress::{Progress, ProgressLines},
+    prune,
+    report::{DownloadReport, RunMarker},
+    robots::{PoliteBackend, Robots},
+    s3::S3Target,
+    status::StatusSocket,
+    tarball::TarOutput,
+    types::{CrawlingState, LifetimeStats, Node, PartialCrawl, StateStore, TreeSummary},
+    units::{format_size, DelayRange},
+    urllist,
+    useragents::UserAgentPool,
+    verify::{self, Discrepancy},
 };
-use std::fs;
+use errors::{ErrorFormat, UsageError};
+use log::{info, warn, LevelFilter};
+use reqwest::Url;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs,
+    io::{BufRead, IsTerminal, Write},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::Instant,
+};
+use tokio::io::AsyncWriteExt;
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> ExitCode {
+    // The time budget (--max-time) counts from here
+    let started_at = Instant::now();
+
     // The working directory
     let pwd = std::env::current_dir()
         .unwrap()
@@ -25,169 +63,1379 @@ async fn main() -> Result<()> {
     // Parse the command line parameters into arg-matches
     let matches = cli::configure_parser(&pwd).get_matches();
 
-    // Print the name and version of the application along its license notice
-    println!("{} {}", constants::NAME, constants::VERSION);
-    println!("{}\n", constants::LICENSE);
+    // Print a failure for people or for scripts (see --error-format)
+    let error_format = match matches.value_of("error_format") {
+        Some("json") => ErrorFormat::Json,
+        _ => ErrorFormat::Text,
+    };
+
+    // The state store of the current root URL (which holds the progress of a failed run)
+    let mut state_store_path = None;
+
+    match run(matches, &pwd, started_at, &mut state_store_path).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => errors::report_error(&error, error_format, state_store_path),
+    }
+}
+
+/// Does what the arguments ask for (running a subcommand or mirroring the root URLs)
+async fn run(
+    matches: ArgMatches<'_>,
+    pwd: &str,
+    started_at: Instant,
+    state_store_path: &mut Option<String>,
+) -> Result<()> {
+    // Print a completion script (generated from the parser) instead of doing anything else
+    if let Some(matches) = matches.subcommand_matches("completions") {
+        let shell = matches
+            .value_of("shell")
+            .unwrap()
+            .parse()
+            .map_err(|e| anyhow!("{}", e))?;
+        cli::configure_parser(pwd).gen_completions_to(
+            constants::NAME,
+            shell,
+            &mut std::io::stdout(),
+        );
+
+        return Ok(());
+    }
+
+    // Print the name and version of the application along its license notice (on stderr when
+    // stdout is piped or carries data, so it stays clean)
+    let data_on_stdout = [
+        "ndjson",
+        "stdout",
+        "manifest",
+        "range",
+        "test_url",
+        "from_html",
+        "count_only",
+    ]
+    .iter()
+    .any(|arg| matches.is_present(arg))
+        || matches.value_of("dump_tree") == Some(constants::DUMP_TO_STDOUT);
+    if !matches.is_present("no_banner") {
+        if data_on_stdout || !std::io::stdout().is_terminal() {
+            eprintln!("{} {}", constants::NAME, constants::VERSION);
+            eprintln!("{}\n", constants::LICENSE);
+        } else {
+            println!("{} {}", constants::NAME, constants::VERSION);
+            println!("{}\n", constants::LICENSE);
+        }
+    }
+
+    // Check an existing mirror instead of downloading (if desired)
+    if let Some(matches) = matches.subcommand_matches("verify") {
+        env_logger::Builder::new()
+            .filter_level(LevelFilter::Info)
+            .parse_env("RUST_LOG")
+            .init();
+
+        return verify_mirror(
+            matches.value_of("state_store").unwrap(),
+            &std::env::current_dir()?.join(matches.value_of("destination").unwrap()),
+            matches
+                .value_of("max_filename_length")
+                .map(cli::parse_name_length_arg)
+                .transpose()?,
+        );
+    }
+
+    // Summarize a state store instead of downloading (if desired)
+    if let Some(matches) = matches.subcommand_matches("state-info") {
+        return state_info(matches.value_of("state_store").unwrap());
+    }
+
+    // Merge state stores instead of downloading (if desired)
+    if let Some(matches) = matches.subcommand_matches("merge-state") {
+        env_logger::Builder::new()
+            .filter_level(LevelFilter::Info)
+            .parse_env("RUST_LOG")
+            .init();
+
+        return merge_state(
+            matches.value_of("first").unwrap(),
+            matches.value_of("second").unwrap(),
+            matches.value_of("output").unwrap(),
+        );
+    }
+
+    // Compare the trees of two state stores instead of downloading (if desired)
+    if let Some(matches) = matches.subcommand_matches("diff-state") {
+        return diff_state(
+            matches.value_of("old").unwrap(),
+            matches.value_of("new").unwrap(),
+            matches.is_present("json"),
+        );
+    }
 
     // Try to extract the desired configuration from the arg-matches
-    let cli_options = cli::get_options(matches)?;
+    let cli_options = cli::get_options(matches).map_err(UsageError::wrap)?;
+    state_store_path.clone_from(&cli_options.state_store_path);
+
+    // Log to stderr (at a level depending on -v/-q, unless overridden by RUST_LOG)
+    let log_level = cli_options.log_level();
+    env_logger::Builder::new()
+        .filter_level(log_level.min(LevelFilter::Warn))
+        .filter_module(module_path!(), log_level)
+        .parse_env("RUST_LOG")
+        .init();
+
+    for key in &cli_options.unknown_config_keys {
+        warn!("Ignoring the unknown key `{}` of the config file", key);
+    }
+
+    // Work on every root URL in turn (several mirrors if the URL contained a brace pattern, or
+    // the ones of a mirror list, each with its own destination)
+    if cli_options.roots.len() < 2 && cli_options.mirror_list.is_none() {
+        return download_root(&cli_options, started_at).await;
+    }
+
+    let mut cli_options = cli_options;
+    let roots = cli_options.roots.clone();
+    let destinations = cli_options.root_destinations.clone();
+    let mut failed = vec![];
+    for (index, (root, destination)) in roots.into_iter().zip(destinations).enumerate() {
+        info!(
+            "Mirror {} of {}: {}",
+            index + 1,
+            cli_options.roots.len(),
+            root
+        );
+        cli_options.set_root(root, destination)?;
+        state_store_path.clone_from(&cli_options.state_store_path);
 
-    // Make a new client for issuing HTTP(S) requests
-    let client = reqwest::Client::new();
+        match download_root(&cli_options, started_at).await {
+            // The other mirrors are only needed if this one fails
+            Ok(()) if cli_options.failover => break,
+            Ok(()) => (),
+            Err(error) if cli_options.failover && index + 1 < cli_options.roots.len() => {
+                warn!("(Failover) {} failed: {:#}", cli_options.url, error);
+            }
+            // Go on with the other mirrors (failing the run once they're done)
+            Err(error) if cli_options.skip_errors && !cli_options.failover => {
+                warn!("(Error) Mirror {} failed: {:#}", cli_options.url, error);
+                failed.push(cli_options.url.to_string());
+            }
+            Err(error) => return Err(error),
+        }
+    }
+
+    if !failed.is_empty() {
+        bail!(
+            "{} of {} mirrors failed: {}",
+            failed.len(),
+            cli_options.roots.len(),
+            failed.join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+/// Crawls & downloads the current root URL
+async fn download_root(cli_options: &cli::CliOptions, started_at: Instant) -> Result<()> {
+    // Create the output directory (if it doesn't exist and the files aren't uploaded or archived)
+    if !cli_options.no_download && cli_options.writes_locally() {
+        fs::create_dir_all(&cli_options.destination).with_context(|| {
+            format!(
+                "Cannot create the output directory {}",
+                cli_options.destination.display()
+            )
+        })?;
+
+        if let Some(temp_dir) = &cli_options.temp_dir {
+            fs::create_dir_all(temp_dir).with_context(|| {
+                format!(
+                    "Cannot create the temporary directory {}",
+                    temp_dir.display()
+                )
+            })?;
+        }
+    }
+
+    // Keep other runs from writing the same files (or state store) at the same time, until this
+    // run ends (the locks are released when dropped, also on errors & Ctrl-C)
+    let _locks = lock_mirror(cli_options)?;
+
+    // Configure the clients once (the same way for listings, files, robots.txt & S3)
+    let client::Clients {
+        client,
+        file_client,
+    } = client::build_clients(cli_options)?;
+
+    // Only download a part of a single file (if desired)
+    if let Some(range) = cli_options.range {
+        let mut stdout = tokio::io::stdout();
+        let bytes = crawl::fetch_range(
+            &file_client,
+            &cli_options.url,
+            range,
+            cli_options.read_timeout,
+            &mut stdout,
+        )
+        .await?;
+        stdout.flush().await?;
+
+        info!("Downloaded {} bytes of {}", bytes, cli_options.url);
+        return Ok(());
+    }
+
+    // Only download a single file to stdout (if desired)
+    if cli_options.stdout {
+        let mut stdout = tokio::io::stdout();
+        let bytes = crawl::fetch_file_to(
+            &file_client,
+            &cli_options.url,
+            cli_options.read_timeout,
+            &mut stdout,
+        )
+        .await?;
+        stdout.flush().await?;
+
+        info!("Downloaded {} bytes of {}", bytes, cli_options.url);
+        return Ok(());
+    }
+
+    // Only parse a single listing and show what was found in it (if desired)
+    if cli_options.test_url {
+        let backend = crawl::ApacheHttpBackend::new(client, cli_options.read_timeout)
+            .with_format(cli_options.server_format());
+        let (name, nodes) = backend.test_page(&cli_options.url).await?;
+        print_listing(&cli_options.url, &name, &nodes);
+
+        return Ok(());
+    }
+
+    // Only parse a saved listing (if desired), which needs no requests at all
+    if let Some(path) = &cli_options.from_html {
+        let body = fs::read(path)
+            .with_context(|| format!("Cannot read the listing {}", path.display()))?;
+        let backend = crawl::ApacheHttpBackend::new(client, cli_options.read_timeout)
+            .with_format(cli_options.server_format());
+        let (name, nodes) = backend.parse_saved_page(&body, &cli_options.url)?;
+        print_listing(&cli_options.url, &name, &nodes);
+
+        return Ok(());
+    }
+
+    // Upload to S3 instead of writing to the disk (if desired)
+    let s3 = match &cli_options.output {
+        Some(output) => Some(S3Target::from_url(output, client.clone())?),
+        None => None,
+    };
+
+    // Follow the robots.txt of HTTP(S) servers (unless told otherwise)
+    let robots = if cli_options.ignore_robots || cli_options.url.scheme() == "ftp" {
+        Robots::default()
+    } else {
+        Robots::fetch(
+            &client,
+            &cli_options.url,
+            &cli_options.user_agent,
+            cli_options.read_timeout,
+        )
+        .await
+    };
+
+    // Take turns with several User-Agents (if desired)
+    let user_agents = match &cli_options.user_agent_file {
+        Some(path) => Some(UserAgentPool::load(path)?),
+        None => None,
+    };
+
+    // Pick the backend for the kind of server
+    let mut inner = listing::backend_for(
+        &cli_options.url,
+        client,
+        file_client,
+        cli_options.connect_timeout,
+        cli_options.read_timeout,
+        user_agents,
+        cli_options.server_format(),
+    );
+
+    // Stay below the root URL (unless told otherwise)
+    if !cli_options.allow_external {
+        inner = Box::new(BoundedListing::new(inner, &cli_options.url));
+    }
+
+    // Respect the markers of the mirror operators
+    if let Some(marker) = &cli_options.skip_marker {
+        inner = Box::new(MarkedListing::new(inner, marker));
+    }
+
+    // Work through the entries in a fixed order (if desired)
+    if cli_options.deterministic {
+        inner = Box::new(SortedListing::new(inner));
+    }
+
+    let mut backend = PoliteBackend::new(inner, robots, cli_options.crawl_delay);
+
+    // Vary the delay between requests (but keep deterministic runs reproducible)
+    if let Some(jitter) = cli_options.delay_jitter {
+        backend = backend.with_jitter(if cli_options.deterministic {
+            let midpoint = jitter.midpoint();
+            DelayRange {
+                min: midpoint,
+                max: midpoint,
+            }
+        } else {
+            jitter
+        });
+    }
+
+    // Go at full speed during some hours of the day (if desired)
+    if let Some(schedule) = &cli_options.schedule {
+        backend = backend.with_schedule(schedule.clone());
+    }
+
+    // Stop after a number of requests (if desired)
+    if let Some(max_requests) = cli_options.max_requests {
+        backend = backend.with_max_requests(max_requests);
+    }
+
+    // Write the events as JSON lines (if desired)
+    let events = if cli_options.ndjson {
+        NdjsonEvents::new()
+    } else {
+        NdjsonEvents::disabled()
+    };
+
+    // Only count the tree (if desired), which is neither kept nor downloaded
+    if cli_options.count_only {
+        let excludes = DirExcludes::new(cli_options.exclude_dirs.clone(), &cli_options.url);
+        let count = crawl::count_tree(
+            &cli_options.url,
+            &backend,
+            &excludes,
+            cli_options.continue_on_parse_error,
+            cli_options.follow_symlinks,
+        );
+        let summary = tokio::select! {
+            summary = count => summary?,
+            _ = backend.out_of_requests() => {
+                bail!("Reached the request limit before counting the whole tree")
+            }
+        };
+
+        println!("{}", summary);
+        println!(
+            "COUNT files={} directories={} bytes={} unknown_sizes={} elapsed={:.1}s",
+            summary.files,
+            summary.directories,
+            summary.total_bytes,
+            summary.unknown_sizes,
+            started_at.elapsed().as_secs_f64()
+        );
+
+        return Ok(());
+    }
+
+    // The files of an outdated stored tree which are gone from the server (see --revalidate)
+    let mut gone_files = vec![];
 
     // Crawl the root directory
     // TODO extract to `setup` or `crawl` function
-    let (mut state_store, state_path, mut done_list) = if let Some(state_path) =
+    let (mut state_store, state_path) = if let Some(state_path) =
         cli_options.state_store_path.clone()
     {
         // A state store is desired
 
-        // Try to load the state store from the file system
-        let mut state_store: StateStore =
-            serde_json::from_str(&fs::read_to_string(&state_path).unwrap_or("make-new".to_owned()))
-                .unwrap_or(StateStore::new());
+        // The cache directory may not exist yet
+        if cli_options.resume {
+            if let Some(parent) = Path::new(&state_path).parent() {
+                fs::create_dir_all(parent).with_context(|| {
+                    format!("Cannot create the cache directory {}", parent.display())
+                })?;
+            }
+            info!("Resuming with the state store {}", state_path);
+        }
 
-        // Clone the done_list
-        let done_list: Vec<String> = state_store.downloaded_urls.clone();
+        // Try to load the state store from the file system
+        let mut state_store = StateStore::load(&state_path)?;
+        state_store.compressed |= cli_options.compress_state;
 
         // Return the pre-made crawl list or start crawling
         match state_store.crawling_state {
-            CrawlingState::Complete(_) => (state_store, Some(state_path), done_list),
-            CrawlingState::Partial(_) | CrawlingState::None => {
-                // Perform the crawl
-                // TODO utilize partial crawls in the future
-
-                let mut root = crawl::get_root_dir(&cli_options.url, &client).await?;
-
-                // Expand the tree
-                if let Node::CrawledDir(_, ref mut children) = root {
-                    crawl::expand_node(children, &client).await?;
-                } else {
-                    panic!("Cannot expand root node")
-                }
+            // The tree doesn't get kept when streaming (and isn't needed to retry failures)
+            _ if cli_options.stream || cli_options.replay_failures => {
+                (state_store, Some(state_path))
+            }
 
-                // Update the modified time
-                state_store.update_modified_time();
+            // Crawl again when revalidating (so new files get found as well) or when the
+            // server moved (so the files get downloaded from the new one)
+            CrawlingState::Complete(ref root)
+                if !cli_options.revalidate && root.url() == cli_options.url.as_str() =>
+            {
+                (state_store, Some(state_path))
+            }
+            _ => {
+                // The files of the stored tree, to tell which ones are gone from the server
+                let stored: Vec<String> = match &state_store.crawling_state {
+                    CrawlingState::Complete(root)
+                        if cli_options.revalidate && root.url() == cli_options.url.as_str() =>
+                    {
+                        root.iter_files().map(|file| file.url.clone()).collect()
+                    }
+                    _ => vec![],
+                };
 
-                // Save the completed crawl
-                state_store.crawling_state = CrawlingState::Complete(root.clone());
+                // Perform the crawl (continuing a partial one), and save it even when it gets
+                // interrupted with Ctrl-C or runs out of requests, so the next run continues
+                // from there
+                let res = tokio::select! {
+                    res = get_tree(cli_options, &backend, &events, &mut state_store.crawling_state) => res,
+                    _ = backend.out_of_requests() => Ok(()),
+                    _ = tokio::signal::ctrl_c() => Err(anyhow!("Interrupted")),
+                };
 
                 // Serialize & persist the new state store
-                fs::write(&state_path, serde_json::to_string_pretty(&state_store)?)
-                    .expect("Cannot write to state store");
+                state_store.save(&state_path)?;
+                res?;
+
+                if let Some(root) = state_store.crawling_state.root() {
+                    let found: HashSet<&str> =
+                        root.iter_files().map(|file| file.url.as_str()).collect();
+                    gone_files = stored
+                        .into_iter()
+                        .filter(|url| !found.contains(url.as_str()))
+                        .collect();
+                    for url in &gone_files {
+                        info!("(Gone) Skip file {}", url);
+                    }
+                }
 
                 // Return the crawl results
-                (state_store, Some(state_path), done_list)
+                (state_store, Some(state_path))
             }
         }
     } else {
         // No state store is desired
 
-        // Check if --no-download was specified
-        if cli_options.no_download {
+        // Check if --no-download was specified (a dry run, a manifest or a catalog doesn't need
+        // to persist anything)
+        if cli_options.no_download
+            && !cli_options.dry_run
+            && !cli_options.manifest
+            && cli_options.catalog.is_none()
+        {
             bail!("Cannot use --no-download without --state-store")
         }
 
         // Make a phantom state store (not persisted)
         let mut state_store = StateStore::new();
 
-        let mut root = crawl::get_root_dir(&cli_options.url, &client).await?;
+        // The crawl happens along the download when streaming
+        if !cli_options.stream {
+            tokio::select! {
+                res = get_tree(cli_options, &backend, &events, &mut state_store.crawling_state) => res?,
+                _ = backend.out_of_requests() => (),
+            }
+        }
 
-        // Expand the tree
-        if let Node::CrawledDir(_, ref mut children) = root {
-            crawl::expand_node(children, &client).await?;
-        } else {
-            panic!("Cannot expand root node")
+        (state_store, None)
+    };
+
+    // The crawled tree (which doesn't exist when streaming, and isn't used to retry failures)
+    if !cli_options.stream && !cli_options.replay_failures {
+        // Don't trust the sizes from the listings (if desired)
+        if cli_options.head_sizes {
+            let downloaded_urls = state_store.downloaded_urls.clone();
+            let root = state_store.get_root_ref_mut()?;
+            tokio::select! {
+                corrected = fetch::discover_sizes(root, &backend, &downloaded_urls) => {
+                    info!("Corrected the sizes of {} files", corrected?);
+                }
+                _ = backend.out_of_requests() => (),
+            }
         }
 
-        // Save the completed crawl
-        state_store.crawling_state = CrawlingState::Complete(root.clone());
+        // A stored tree may stem from a run without --deterministic
+        if cli_options.deterministic {
+            state_store.get_root_ref_mut()?.sort();
+        }
+
+        // Summarize the crawled tree
+        info!("Found {}", state_store.get_root_ref()?.summarize());
+
+        if let Some(tree_path) = &cli_options.dump_tree {
+            write_tree(state_store.get_root_ref()?, tree_path)?;
+        }
+
+        // Add the files to a catalog instead of downloading them (if desired)
+        if let Some(db_path) = &cli_options.catalog {
+            let summary = catalog::write_catalog(state_store.get_root_ref()?, db_path)?;
+            info!("Updated the catalog {} ({})", db_path.display(), summary);
+        }
+
+        // Print the files instead of downloading them (if desired)
+        if cli_options.manifest {
+            let root = select_root(state_store.get_root_ref()?, cli_options)?;
+            let count = manifest::write_manifest(root, cli_options, &mut std::io::stdout().lock())?;
+            info!("Listed {} files", count);
+        }
+
+        // Remove local files which are gone from the server (if desired)
+        if cli_options.prune || cli_options.prune_dry_run {
+            prune_mirror(
+                state_store.get_root_ref()?,
+                cli_options,
+                state_path.as_deref(),
+            )?;
+        }
 
-        (state_store, None, vec![])
+        // Ask before downloading a lot (if desired)
+        if let Some(threshold) = cli_options.confirm_over {
+            let summary = select_root(state_store.get_root_ref()?, cli_options)?.summarize();
+            if !cli_options.no_download && !cli_options.yes && summary.total_bytes > threshold {
+                let stdin = std::io::stdin();
+                confirm_download(&summary, stdin.is_terminal(), &mut stdin.lock())?;
+            }
+        }
+    }
+
+    // Whether the download was stopped by --max-time
+    let mut timed_out = false;
+
+    // Whether the run was stopped by --max-requests (during the crawl or the download)
+    let mut out_of_requests = backend.is_out_of_requests();
+
+    // The statistics of this run (added to the ones of the state store)
+    let mut downloaded_bytes = 0;
+    let run_stats = |bytes_downloaded| LifetimeStats {
+        runs: 1,
+        bytes_downloaded,
+        requests: backend.requests(),
+        elapsed_secs: started_at.elapsed().as_secs_f64(),
     };
 
     // Only download files if --no-download was not specified
-    // TODO extract to `download_files` function
     if !cli_options.no_download {
-        // TODO implement the counters
-        let mut counters = download::fetch::LimitCounts::new();
-        let mut counters_1 = counters.clone();
+        // Walk a copy of the tree (or of the desired branch), so the state store can be saved
+        // while downloading (there is none when streaming or retrying failures)
+        let root = if cli_options.stream || cli_options.replay_failures {
+            None
+        } else {
+            Some(select_root(state_store.get_root_ref()?, cli_options)?.clone())
+        };
 
-        let res = {
-            fetch::download_recursive(
-                state_store.get_root_ref()?,
-                &cli_options,
-                &client,
-                &mut counters_1,
-                &mut done_list,
-            )
-            .await?
+        // Show the progress of the download (if desired, growing along the crawl when streaming)
+        let progress = match &root {
+            _ if !cli_options.progress => Progress::disabled(),
+            Some(root) => Progress::new(root),
+            None => Progress::growing(),
         };
 
-        if let DownloadRecursiveStatus::Do(ref to_do) = res {
-            for task in to_do {
-                let (node, options, client) = task;
-                // TODO implement more than one level of recursion
-                // res = fetch::download_recursive(node, options, client, &mut counters).await?;
-                match fetch::download_recursive(
-                    node,
-                    options,
-                    client,
-                    &mut counters,
-                    &mut done_list,
-                )
-                .await
+        // Answer with the progress on a socket (if desired)
+        let status = match (&cli_options.status_socket, &root) {
+            (None, _) => None,
+            (Some(path), Some(root)) => Some(StatusSocket::bind(path, root)?),
+            (Some(path), None) => Some(StatusSocket::bind_growing(path)?),
+        };
+
+        // Print lines of progress instead when there are no bars (e.g. in a log)
+        let lines = match (cli_options.progress_interval, &root) {
+            (None, _) => None,
+            _ if cli_options.progress => None,
+            (Some(interval), Some(root)) => Some(ProgressLines::new(root, interval)),
+            (Some(interval), None) => Some(ProgressLines::growing(interval)),
+        };
+
+        let mut observers = Observers(vec![&progress, &events]);
+        if let Some(status) = &status {
+            observers.0.push(status);
+        }
+        if let Some(lines) = &lines {
+            observers.0.push(lines);
+        }
+
+        // The point in time at which the download has to stop (if any)
+        let deadline = cli_options.max_time.map(|max_time| started_at + max_time);
+
+        // Kept outside of the download, so the report survives an interruption
+        let mut counters = fetch::LimitCounts::new().with_gone_files(&gone_files);
+        if let Some(s3) = s3 {
+            info!("Uploading to {}", s3.url_of(""));
+            counters = counters.with_s3(s3);
+        }
+        if let Some(path) = &cli_options.output_tar {
+            info!("Writing into {}", path.display());
+            counters = counters.with_tar(TarOutput::open(path)?);
+        }
+        if cli_options.write_lockfile || cli_options.frozen {
+            let path = cli_options.destination.join(constants::LOCKFILE);
+            let lockfile = if cli_options.frozen {
+                Lockfile::open_frozen(&path)?
+            } else {
+                Lockfile::open(&path)?
+            };
+
+            // Show a drift of the server before anything gets downloaded
+            if let (true, Some(root)) = (lockfile.is_frozen(), &root) {
+                lockfile.check_tree(root)?;
+            }
+            counters = counters.with_lockfile(lockfile);
+        }
+        let download_started_at = Instant::now();
+
+        // Stop downloading (and save the progress) when interrupted with Ctrl-C, or (cleanly)
+        // when the time budget is used up
+        let res = tokio::select! {
+            res = download_files(root.as_ref(), cli_options, &backend, &mut counters, &mut state_store, &observers) => res,
+            _ = wait_for_deadline(deadline) => {
+                timed_out = true;
+                Ok(())
+            }
+            _ = backend.out_of_requests() => {
+                out_of_requests = true;
+                Ok(())
+            }
+            _ = tokio::signal::ctrl_c() => Err(anyhow!("Interrupted")),
+        };
+
+        // Check the whole mirror once it's complete (if desired)
+        let res = match res {
+            Ok(()) if cli_options.verify_after && !timed_out && !out_of_requests => {
+                tokio::select! {
+                    res = verify_download(cli_options, &backend, &mut counters, &mut state_store, &observers) => res,
+                    _ = tokio::signal::ctrl_c() => Err(anyhow!("Interrupted")),
+                }
+            }
+            res => res,
+        };
+        progress.finish();
+        drop(observers);
+        drop(lines);
+
+        // The archive is only usable once it's finished (also after an error or Ctrl-C)
+        counters.finish_tar()?;
+        counters.save_lockfile()?;
+
+        // Summarize the download
+        let mut report = counters.into_report();
+        if let Some(root) = &root {
+            let frontier = state_store.frontier();
+            report.unparsed_dirs = root
+                .pending_dirs()
+                .into_iter()
+                .filter(|dir| !frontier.contains(&dir.url))
+                .map(|dir| dir.url.clone())
+                .collect();
+        }
+        if !cli_options.deterministic {
+            report.elapsed_secs = download_started_at.elapsed().as_secs_f64();
+        }
+        info!("{}", report);
+
+        if let Some(report_path) = &cli_options.report_path {
+            write_report(&report, report_path)?;
+        }
+
+        if cli_options.write_manifest {
+            write_marker(
+                &RunMarker::new(
+                    cli_options.url.as_str(),
+                    constants::VERSION,
+                    &report,
+                    res.is_err(),
+                ),
+                &cli_options.destination.join(constants::MARKER_FILE),
+            )?;
+        }
+
+        // Always end with a summary for scripts (even when --quiet, but off stdout when it
+        // carries data)
+        let tree_on_stdout = cli_options
+            .dump_tree
+            .as_ref()
+            .is_some_and(|path| path.as_os_str() == constants::DUMP_TO_STDOUT);
+        if cli_options.ndjson || tree_on_stdout {
+            eprintln!("{}", report.summary_line(res.is_err()));
+        } else {
+            println!("{}", report.summary_line(res.is_err()));
+        }
+
+        if let Some(command) = &cli_options.notify_command {
+            notify(command, &report, res.is_err());
+        }
+
+        downloaded_bytes = report.total_bytes;
+
+        if let Err(error) = res {
+            // Persist the progress made so far (without hiding the error, e.g. of a full disk,
+            // behind the one of saving)
+            if let Some(state_path) = state_path {
+                if let Err(save_error) =
+                    write_state(&mut state_store, &state_path, &run_stats(downloaded_bytes))
                 {
-                    Ok(_) => {}
-                    Err(error) => {
-                        if let Some(state_path) = state_path {
-                            // Update the modified time
-                            state_store.update_modified_time();
-
-                            // Update the done_list
-                            state_store.downloaded_urls = done_list;
-
-                            // Serialize & persist the new state store
-                            fs::write(state_path, serde_json::to_string_pretty(&state_store)?)
-                                .expect("Cannot write to state store");
-                        }
-
-                        // Return the error and halt execution
-                        bail!(error)
-                    }
+                    warn!(
+                        "Cannot save the progress to {}: {:#}",
+                        state_path, save_error
+                    );
                 }
             }
+
+            // Return the error and halt execution
+            bail!(error)
         }
     }
 
+    if timed_out {
+        info!(
+            "Stopped after reaching the time limit ({} files done)",
+            state_store.downloaded_urls.len()
+        );
+    }
+    if out_of_requests {
+        info!(
+            "Stopped after reaching the limit of {} requests ({} files done)",
+            backend.requests(),
+            state_store.downloaded_urls.len()
+        );
+    }
+
     // Persist the new state to disk if necessary
     if let Some(state_path) = state_path {
-        write_state(&mut state_store, &state_path, done_list)?;
-        println!("Download done.");
+        write_state(&mut state_store, &state_path, &run_stats(downloaded_bytes))?;
+
+        if cli_options.stats {
+            let mut lines = vec![state_store.stats.to_string()];
+
+            // The files which needed the most retries (likely to fail again)
+            for (url, record) in state_store.flakiest_files(constants::FLAKIEST_FILES) {
+                lines.push(format!(
+                    "  {} retries of {} ({} last time after {} attempts)",
+                    record.retries, url, record.status, record.attempts
+                ));
+            }
+
+            // Keep stdout to the JSON lines
+            for line in lines {
+                if cli_options.ndjson {
+                    eprintln!("{}", line);
+                } else {
+                    println!("{}", line);
+                }
+            }
+        }
+
+        if !timed_out && !out_of_requests {
+            info!("Download done.");
+        }
+    } else if !timed_out && !out_of_requests {
+        info!("All done.");
+    }
+
+    Ok(())
+}
+
+/// Locks the output directory and the state store (whichever this run writes to)
+fn lock_mirror(options: &cli::CliOptions) -> Result<Vec<RunLock>> {
+    if options.stdout || options.range.is_some() {
+        return Ok(vec![]);
+    }
+
+    let mut paths = vec![];
+    if !options.no_download {
+        if options.writes_locally() {
+            paths.push(options.destination.join(constants::LOCK_FILE));
+        }
+        if let Some(tar_path) = &options.output_tar {
+            paths.push(PathBuf::from(format!("{}.lock", tar_path.display())));
+        }
+    }
+    if let Some(state_path) = &options.state_store_path {
+        paths.push(PathBuf::from(format!("{}.lock", state_path)));
+    }
+
+    paths
+        .iter()
+        .map(|path| RunLock::acquire(path, options.force_lock))
+        .collect()
+}
+
+/// Downloads the files of the crawled tree
+async fn download_files(
+    root: Option<&Node>,
+    options: &cli::CliOptions,
+    backend: &dyn DirectoryListing,
+    counters: &mut fetch::LimitCounts,
+    state_store: &mut StateStore,
+    observer: &dyn DownloadObserver,
+) -> Result<()> {
+    // Only retry the files which failed before (if desired)
+    if options.replay_failures {
+        return fetch::download_failures(options, backend, counters, state_store, observer).await;
+    }
+
+    // Crawl along the download when streaming
+    let root = match root {
+        Some(root) => root,
+        None => {
+            return fetch::download_stream(
+                &options.url,
+                options,
+                backend,
+                counters,
+                state_store,
+                observer,
+            )
+            .await
+        }
+    };
+
+    // Work through the whole tree at once (if desired)
+    if options.newest_first {
+        return fetch::download_newest_first(
+            root,
+            options,
+            backend,
+            counters,
+            state_store,
+            observer,
+        )
+        .await;
+    }
+
+    // Work through the tree one directory at a time (breadth-first), continuing with the
+    // directories left by an interrupted download (if any)
+    let mut to_do = queued_dirs(root, options, &state_store.download_queue)
+        .unwrap_or_else(|| VecDeque::from([root]));
+    state_store.download_queue = to_do.iter().map(|node| node.url().to_owned()).collect();
+
+    // The queue in the state store follows along, so it's saved with the progress. A directory
+    // leaves it once all of its files are done.
+    while let Some(&node) = to_do.front() {
+        let res =
+            fetch::download_recursive(node, options, backend, counters, state_store, observer)
+                .await?;
+        to_do.pop_front();
+        state_store.download_queue.remove(0);
+
+        // Queue the sub-directories
+        if let DownloadRecursiveStatus::Do(tasks) = res {
+            for (node, _, _) in tasks {
+                state_store.download_queue.push(node.url().to_owned());
+                to_do.push_back(node);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/**
+Finds the directories of the tree an interrupted download had yet to work through
+
+Returns None if there are none, if they aren't all part of the tree (e.g. after a new crawl), or
+when revalidating (which has to check every file again).
+*/
+fn queued_dirs<'a>(
+    root: &'a Node,
+    options: &cli::CliOptions,
+    queue: &[String],
+) -> Option<VecDeque<&'a Node>> {
+    if queue.is_empty() || options.revalidate {
+        return None;
+    }
+
+    let dirs: HashMap<&str, &Node> = root
+        .iter()
+        .filter(|node| matches!(node, Node::CrawledDir(..)))
+        .map(|node| (node.url(), node))
+        .collect();
+    let queued: Option<VecDeque<&Node>> = queue
+        .iter()
+        .map(|url| dirs.get(url.as_str()).copied())
+        .collect();
+
+    match &queued {
+        Some(queued) => info!(
+            "Resuming the download with {} queued directories",
+            queued.len()
+        ),
+        None => info!("The queued directories aren't part of the tree, downloading all of it"),
+    }
+
+    queued
+}
+
+/// Deletes (or lists, on a dry run) the local files which no longer exist on the server
+fn prune_mirror(root: &Node, options: &cli::CliOptions, state_path: Option<&str>) -> Result<()> {
+    // An empty listing is more likely a glitch of the server than an emptied directory
+    if let Node::CrawledDir(_, children) = root {
+        if children.is_empty() {
+            warn!("Not pruning, as the remote directory is empty");
+            return Ok(());
+        }
+    }
+
+    // Never prune the files written by od-get itself
+    let pwd = std::env::current_dir()?;
+    let protected: Vec<PathBuf> = state_path
+        .map(Path::new)
+        .into_iter()
+        .chain(options.report_path.as_deref())
+        .map(|path| pwd.join(path))
+        .chain(Some(options.destination.join(constants::MARKER_FILE)))
+        .collect();
+
+    let dry_run = options.prune_dry_run || options.dry_run;
+    let pruned = prune::prune(
+        root,
+        &paths::parent_url(&options.url),
+        &options.destination,
+        &protected,
+        options.prune_exclude.as_ref(),
+        options.max_filename_length,
+        dry_run,
+    )?;
+
+    if dry_run {
+        info!("Would prune {} stale files or directories", pruned.len());
     } else {
-        println!("All done.");
+        info!("Pruned {} stale files or directories", pruned.len());
     }
 
     Ok(())
 }
 
-/// Persists the state to disk
-fn write_state(
+/**
+Checks the downloaded files once the download is done (see `--verify-after`)
+
+With `--repair`, the missing or corrupted files are downloaded again (and checked once more).
+*/
+async fn verify_download(
+    options: &cli::CliOptions,
+    backend: &dyn DirectoryListing,
+    counters: &mut fetch::LimitCounts,
     state_store: &mut StateStore,
-    state_path: &str,
-    done_list: Vec<String>,
+    observer: &dyn DownloadObserver,
 ) -> Result<()> {
-    // Update the modified time
-    state_store.update_modified_time();
+    let max_length = options.max_filename_length;
+    let mut report = verify::verify(state_store, &options.destination, max_length)?;
+    info!("{}", report);
 
-    // Update the done_list
-    state_store.downloaded_urls = done_list;
+    if !report.discrepancies.is_empty() && options.repair {
+        info!("Repairing {} files", report.damaged.len());
+        for file in &report.damaged {
+            state_store.forget_download(file, &options.url);
+        }
+        for discrepancy in &report.discrepancies {
+            if let Discrepancy::WrongSize { path, .. } | Discrepancy::WrongChecksum { path, .. } =
+                discrepancy
+            {
+                fs::remove_file(path)
+                    .with_context(|| format!("Cannot remove {}", path.display()))?;
+            }
+        }
+        fetch::download_listed_files(
+            &report.damaged,
+            options,
+            backend,
+            counters,
+            state_store,
+            observer,
+        )
+        .await?;
 
-    // Serialize & persist the new state store
-    fs::write(state_path, serde_json::to_string_pretty(&state_store)?)
-        .expect("Cannot write to state store");
+        report = verify::verify(state_store, &options.destination, max_length)?;
+        info!("{}", report);
+    }
 
-    println!("Wrote state store to {}", state_path);
+    if !report.discrepancies.is_empty() {
+        bail!(
+            "The mirror in {} has {} missing or corrupted files",
+            options.destination.display(),
+            report.discrepancies.len()
+        );
+    }
 
     Ok(())
 }
+
+/// Checks the local files of a mirror against its (completed) state store
+fn verify_mirror(state_path: &str, destination: &Path, max_length: Option<usize>) -> Result<()> {
+    let state_store = StateStore::from_json(&StateStore::read_json(state_path)?)?;
+
+    let report = verify::verify(&state_store, destination, max_length)?;
+    info!("{}", report);
+
+    if !report.discrepancies.is_empty() {
+        bail!(
+            "The mirror in {} doesn't match the state store",
+            destination.display()
+        );
+    }
+
+    Ok(())
+}
+
+/**
+Asks whether to go on with a download larger than --confirm-over (answered from the input)
+
+Without a terminal to ask on, the download is refused (unless --yes is given).
+*/
+fn confirm_download(
+    summary: &TreeSummary,
+    interactive: bool,
+    input: &mut dyn BufRead,
+) -> Result<()> {
+    let question = format!(
+        "This will download {} files ({})",
+        summary.files,
+        format_size(summary.total_bytes)
+    );
+
+    if !interactive {
+        bail!(
+            "{}, which is more than --confirm-over (pass --yes to proceed)",
+            question
+        );
+    }
+
+    eprint!("{}. Continue? [y/N] ", question);
+    std::io::stderr().flush()?;
+
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+
+    match answer.trim().to_lowercase().as_str() {
+        "y" | "yes" => Ok(()),
+        _ => bail!("Aborted"),
+    }
+}
+
+/// Merges two state stores into a new one (see the `merge-state` subcommand)
+fn merge_state(first_path: &str, second_path: &str, output_path: &str) -> Result<()> {
+    let load =
+        |path: &str| -> Result<StateStore> { StateStore::from_json(&StateStore::read_json(path)?) };
+
+    let merged = merge::merge(load(first_path)?, load(second_path)?);
+    for conflict in &merged.conflicts {
+        warn!("Conflict: {}", conflict);
+    }
+
+    let mut state_store = merged.state_store;
+    state_store.save(output_path)?;
+    info!(
+        "Merged {} & {} into {} ({} downloaded URLs, {} conflicts)",
+        first_path,
+        second_path,
+        output_path,
+        state_store.downloaded_urls.len(),
+        merged.conflicts.len()
+    );
+
+    Ok(())
+}
+
+/// Prints the changes between the trees of two state stores (see the `diff-state` subcommand)
+fn diff_state(old_path: &str, new_path: &str, json: bool) -> Result<()> {
+    let load = |path: &str| -> Result<Node> {
+        let state_store = StateStore::from_json(&StateStore::read_json(path)?)?;
+
+        match state_store.crawling_state.root() {
+            Some(root) => Ok(root.clone()),
+            None => bail!("The state store {} contains no tree", path),
+        }
+    };
+
+    let diff = diff::diff(&load(old_path)?, &load(new_path)?);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&diff)?);
+        return Ok(());
+    }
+
+    for url in diff.added_dirs.iter().chain(&diff.added_files) {
+        println!("+ {}", url);
+    }
+    for url in diff.removed_dirs.iter().chain(&diff.removed_files) {
+        println!("- {}", url);
+    }
+    for modified in diff.modified_dirs.iter().chain(&diff.modified_files) {
+        println!("~ {}", modified.url);
+    }
+    eprintln!("{}", diff);
+
+    Ok(())
+}
+
+/// Prints a summary of a state store (see the `state-info` subcommand)
+fn state_info(state_path: &str) -> Result<()> {
+    let json = StateStore::read_json(state_path)?;
+    let version = StateStore::stored_version(&json)?;
+    let state_store = StateStore::from_json(&json)?;
+
+    let (crawling_state, root) = match &state_store.crawling_state {
+        CrawlingState::Complete(root) => ("Complete", Some(root)),
+        CrawlingState::Partial(partial) => ("Partial", Some(&partial.root)),
+        CrawlingState::None => ("None", None),
+    };
+
+    println!(
+        "Schema version:  {} (current {})",
+        version,
+        StateStore::VERSION
+    );
+    println!("Crawling state:  {}", crawling_state);
+    if let Some(root) = root {
+        println!("Tree:            {}", root.summarize());
+    }
+    if !state_store.frontier().is_empty() {
+        println!(
+            "Frontier:        {} directories to crawl",
+            state_store.frontier().len()
+        );
+    }
+    if !state_store.download_queue.is_empty() {
+        println!(
+            "Download queue:  {} directories to download",
+            state_store.download_queue.len()
+        );
+    }
+    println!("Downloaded URLs: {}", state_store.downloaded_urls.len());
+    if !state_store.failed_files.is_empty() {
+        println!(
+            "Failed files:    {} to retry (--replay-failures)",
+            state_store.failed_files.len()
+        );
+    }
+    println!("Created at:      {}", state_store.created_at);
+    println!("Last modified:   {}", state_store.last_modified);
+    if state_store.stats.runs > 0 {
+        println!("Statistics:      {}", state_store.stats);
+    }
+
+    Ok(())
+}
+
+/**
+Runs the --notify-command (via the shell) with the totals of the download as environment
+variables (e.g. `OD_GET_FILES`)
+
+A failing command only gets logged, as the download itself is done at this point.
+*/
+fn notify(command: &str, report: &DownloadReport, aborted: bool) {
+    let mut shell = hook::shell(command);
+    shell.env("OD_GET_STATUS", if aborted { "failed" } else { "done" });
+    for (name, value) in report.totals() {
+        shell.env(format!("OD_GET_{}", name.to_uppercase()), value);
+    }
+
+    match shell.status() {
+        Ok(status) if status.success() => (),
+        Ok(status) => warn!("The notify command failed ({})", status),
+        Err(error) => warn!("Cannot run the notify command: {}", error),
+    }
+}
+
+/// Completes at the deadline, or never if there is none
+async fn wait_for_deadline(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+/**
+Crawls the tree below the URL into the crawling state (or builds it from the --urls-file without
+crawling)
+
+A partial crawl of the same URL is continued from its frontier (unless revalidating). The crawl
+stays partial while it's cut short by its limits (e.g. --preview), and the state is updated as the
+crawl goes, so it can be saved if the crawl gets interrupted.
+*/
+async fn get_tree(
+    options: &cli::CliOptions,
+    backend: &dyn DirectoryListing,
+    observer: &dyn DownloadObserver,
+    state: &mut CrawlingState,
+) -> Result<()> {
+    if let Some(urls_file) = &options.urls_file {
+        let files = urllist::read_url_list(urls_file)?;
+        info!("Read {} URLs from {}", files.len(), urls_file.display());
+
+        *state = CrawlingState::Complete(urllist::build_tree(&options.url, files));
+        return Ok(());
+    }
+
+    let mut budget = crawl::CrawlBudget::new(options.max_dirs, options.max_nodes)
+        .with_max_files(options.preview);
+    let excludes = DirExcludes::new(options.exclude_dirs.clone(), &options.url);
+
+    // Only crawl the directories of another run's frontier (if desired)
+    if let Some(path) = &options.import_frontier {
+        let dirs = urllist::read_frontier(path)?;
+        info!("Read {} directories from {}", dirs.len(), path.display());
+
+        let root = urllist::build_frontier_tree(&options.url, dirs);
+        root.iter()
+            .filter(|node| matches!(node, Node::PendingDir(_)))
+            .for_each(|node| observer.on_discovered(node));
+        *state = CrawlingState::Partial(PartialCrawl::new(root));
+    }
+
+    match state {
+        CrawlingState::Partial(partial)
+            if !options.revalidate
+                && partial.is_resumable()
+                && partial.root.url() == options.url.as_str() =>
+        {
+            info!(
+                "Resuming the crawl ({} directories to go)",
+                partial.frontier.len()
+            );
+        }
+        _ => {
+            let partial =
+                crawl::start_crawl(&options.url, backend, &excludes, &mut budget, observer).await?;
+            *state = CrawlingState::Partial(partial);
+        }
+    }
+
+    if let CrawlingState::Partial(partial) = state {
+        crawl::crawl_frontier(
+            partial,
+            backend,
+            &excludes,
+            options.continue_on_parse_error,
+            options.follow_symlinks,
+            &mut budget,
+            observer,
+        )
+        .await?;
+    }
+
+    // The crawl is complete once the frontier is empty
+    if let CrawlingState::Partial(partial) = std::mem::replace(state, CrawlingState::None) {
+        *state = partial.into_state();
+    }
+
+    // Leave the rest of the crawl to another run (if desired)
+    if let Some(path) = &options.export_frontier {
+        let frontier = match state {
+            CrawlingState::Partial(partial) => partial.frontier.as_slice(),
+            _ => &[],
+        };
+        urllist::write_frontier(path, frontier)?;
+        info!(
+            "Wrote {} directories still to be crawled to {}",
+            frontier.len(),
+            path.display()
+        );
+    }
+
+    Ok(())
+}
+
+/// Returns the directory of the crawled tree to work on (the root, or the one given by --subpath)
+fn select_root<'a>(root: &'a Node, options: &cli::CliOptions) -> Result<&'a Node> {
+    match &options.subpath {
+        Some(subpath) => root
+            .find_dir(subpath)
+            .ok_or_else(|| anyhow!("The subpath {} doesn't exist in the crawled tree", subpath)),
+        None => Ok(root),
+    }
+}
+
+/// Prints the name and the entries of a single listing, with all their fields (see `--test-url`)
+fn print_listing(url: &Url, name: &str, nodes: &[Node]) {
+    println!("Directory: {}", name);
+    println!("Found {} entries at {}", nodes.len(), url);
+
+    for node in nodes {
+        match node {
+            Node::File(file) => {
+                println!("\nfile {}", file.url);
+                println!("  name:          {}", file.name);
+                println!("  last modified: {}", file.last_modified);
+                match file.size_bytes {
+                    Some(bytes) => println!("  size:          {} ({} bytes)", file.size, bytes),
+                    None => println!("  size:          {} (unknown)", file.size),
+                }
+                println!("  description:   {}", file.description);
+            }
+            Node::PendingDir(dir) | Node::CrawledDir(dir, _) => {
+                println!("\ndir  {}", dir.url);
+                println!("  name:          {}", dir.name);
+                println!("  last modified: {}", dir.last_modified);
+                println!("  description:   {}", dir.description);
+            }
+        }
+    }
+}
+
+/// Writes the crawled tree as JSON
+fn write_tree(root: &Node, tree_path: &Path) -> Result<()> {
+    let json = serde_json::to_string_pretty(root)?;
+
+    if tree_path == Path::new(constants::DUMP_TO_STDOUT) {
+        println!("{}", json);
+        return Ok(());
+    }
+
+    fs::write(tree_path, json)
+        .with_context(|| format!("Cannot write the tree to {}", tree_path.display()))?;
+
+    info!("Wrote tree to {}", tree_path.display());
+
+    Ok(())
+}
+
+/// Writes the report of a download as JSON
+fn write_report(report: &DownloadReport, report_path: &Path) -> Result<()> {
+    fs::write(report_path, serde_json::to_string_pretty(report)?)
+        .with_context(|| format!("Cannot write the report to {}", report_path.display()))?;
+
+    info!("Wrote report to {}", report_path.display());
+
+    Ok(())
+}
+
+/// Writes the metadata of the run as JSON (see --write-manifest)
+fn write_marker(marker: &RunMarker, marker_path: &Path) -> Result<()> {
+    fs::write(marker_path, serde_json::to_string_pretty(marker)?)
+        .with_context(|| format!("Cannot write the run metadata to {}", marker_path.display()))?;
+
+    info!("Wrote the run metadata to {}", marker_path.display());
+
+    Ok(())
+}
+
+/// Persists the state to disk (including the statistics of this run)
+fn write_state(state_store: &mut StateStore, state_path: &str, run: &LifetimeStats) -> Result<()> {
+    state_store.stats.add(run);
+    state_store.save(state_path)?;
+
+    info!("Wrote state store to {}", state_path);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn confirms_large_downloads_by_the_answer() {
+        let summary = TreeSummary {
+            files: 3,
+            total_bytes: 5 << 30,
+            ..TreeSummary::default()
+        };
+
+        assert!(confirm_download(&summary, true, &mut "y\n".as_bytes()).is_ok());
+        assert!(confirm_download(&summary, true, &mut "YES\n".as_bytes()).is_ok());
+
+        let error = confirm_download(&summary, true, &mut "n\n".as_bytes()).unwrap_err();
+        assert_eq!(error.to_string(), "Aborted");
+        assert!(confirm_download(&summary, true, &mut "".as_bytes()).is_err());
+
+        // Nobody to ask
+        let error = confirm_download(&summary, false, &mut "y\n".as_bytes()).unwrap_err();
+        assert!(error.to_string().contains("--yes"), "{}", error);
+    }
+}
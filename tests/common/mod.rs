@@ -0,0 +1,485 @@
+//! A server of directory listings (like the ones of Apache) for running od-get against
+#![allow(dead_code)]
+
+use chrono::{DateTime, Utc};
+use std::{
+    collections::HashMap,
+    fs,
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener},
+    path::{Path, PathBuf},
+    process::{Command, Output},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+use tempfile::TempDir;
+
+/// A request to the server
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// Returns a header (by its lowercase name)
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_str)
+    }
+}
+
+/// A response of the server
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+
+    /// How long the connection is kept open after the body
+    pub stall: Option<Duration>,
+}
+
+impl Response {
+    pub fn new(status: u16, body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            status,
+            headers: vec![],
+            body: body.into(),
+            stall: None,
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Keeps the connection open after the body (which stalls a client expecting more of it)
+    pub fn stalling(mut self, duration: Duration) -> Self {
+        self.stall = Some(duration);
+        self
+    }
+}
+
+/// Answers requests instead of the files (by returning a response)
+type Handler = dyn Fn(&Request) -> Option<Response> + Send + Sync;
+
+/**
+Serves the files of a temporary directory, and listings of its directories
+
+The listings are the ones of Apache (with exact sizes), files are served with their size, date
+& ranges. A handler may answer requests first (e.g. with errors).
+*/
+pub struct Server {
+    pub root: TempDir,
+    address: SocketAddr,
+    scheme: &'static str,
+    requests: Arc<Mutex<Vec<Request>>>,
+    connections: Arc<AtomicUsize>,
+}
+
+impl Server {
+    /// Serves the files only
+    pub fn new() -> Self {
+        Self::start("127.0.0.1:0", |_| None)
+    }
+
+    /// Serves the responses of the handler, or the files
+    pub fn with_handler(
+        handler: impl Fn(&Request) -> Option<Response> + Send + Sync + 'static,
+    ) -> Self {
+        Self::start("127.0.0.1:0", handler)
+    }
+
+    /// Serves at an address (e.g. `[::1]:0`)
+    pub fn start(
+        address: &str,
+        handler: impl Fn(&Request) -> Option<Response> + Send + Sync + 'static,
+    ) -> Self {
+        Self::listen(address, None, false, handler)
+    }
+
+    /// Serves the files via HTTPS (with the certificate of `tests/fixtures/tls`)
+    pub fn with_tls() -> Self {
+        Self::with_tls_at("127.0.0.1:0")
+    }
+
+    /// Serves the files via HTTPS at an address (which the certificate may not be issued for)
+    pub fn with_tls_at(address: &str) -> Self {
+        Self::listen(address, Some(tls_config()), false, |_| None)
+    }
+
+    /// Serves the files, answering any number of requests per connection
+    pub fn with_keep_alive() -> Self {
+        Self::listen("127.0.0.1:0", None, true, |_| None)
+    }
+
+    fn listen(
+        address: &str,
+        tls: Option<Arc<rustls::ServerConfig>>,
+        keep_alive: bool,
+        handler: impl Fn(&Request) -> Option<Response> + Send + Sync + 'static,
+    ) -> Self {
+        let root = tempfile::tempdir().unwrap();
+        let listener = TcpListener::bind(address).unwrap();
+        let address = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(vec![]));
+        let connections = Arc::new(AtomicUsize::new(0));
+
+        let handler: Arc<Handler> = Arc::new(handler);
+        let files = root.path().to_owned();
+        let log = requests.clone();
+        let scheme = if tls.is_some() { "https" } else { "http" };
+        let counter = connections.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let (handler, files, log) = (handler.clone(), files.clone(), log.clone());
+                let tls = tls.clone();
+                thread::spawn(move || match tls {
+                    Some(tls) => {
+                        let connection = rustls::ServerConnection::new(tls).unwrap();
+                        let stream = rustls::StreamOwned::new(connection, stream);
+                        serve(stream, keep_alive, &*handler, &files, &log)
+                    }
+                    None => serve(stream, keep_alive, &*handler, &files, &log),
+                });
+            }
+        });
+
+        Self {
+            root,
+            address,
+            scheme,
+            requests,
+            connections,
+        }
+    }
+
+    /// Returns the URL of a path (e.g. `/pub/`)
+    pub fn url(&self, path: &str) -> String {
+        format!("{}://{}{}", self.scheme, self.address, path)
+    }
+
+    /// Adds a file (and the directories above it)
+    pub fn file(&self, path: &str, content: impl AsRef<[u8]>) -> &Self {
+        let path = self.root.path().join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+        self
+    }
+
+    /// Adds a directory (and the directories above it)
+    pub fn dir(&self, path: &str) -> &Self {
+        fs::create_dir_all(self.root.path().join(path)).unwrap();
+        self
+    }
+
+    /// Removes a file
+    pub fn remove(&self, path: &str) -> &Self {
+        fs::remove_file(self.root.path().join(path)).unwrap();
+        self
+    }
+
+    /// Returns the requests received so far (as `METHOD /path`)
+    pub fn requests(&self) -> Vec<String> {
+        let requests = self.requests.lock().unwrap();
+        requests
+            .iter()
+            .map(|request| format!("{} {}", request.method, request.path))
+            .collect()
+    }
+
+    /// Returns the requests received so far
+    pub fn full_requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Returns the number of connections accepted so far
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+}
+
+/// Loads the certificate (issued for `localhost` & `127.0.0.1` by `ca.pem`) and its key
+fn tls_config() -> Arc<rustls::ServerConfig> {
+    let fixture = |name| {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/tls")
+            .join(name);
+        BufReader::new(fs::File::open(path).unwrap())
+    };
+    let certs = rustls_pemfile::certs(&mut fixture("server.pem")).unwrap();
+    let key = rustls_pemfile::pkcs8_private_keys(&mut fixture("server.key"))
+        .unwrap()
+        .remove(0);
+
+    let config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(
+            certs.into_iter().map(rustls::Certificate).collect(),
+            rustls::PrivateKey(key),
+        )
+        .unwrap();
+
+    Arc::new(config)
+}
+
+/// Answers the requests of a connection (closing it after the first one, unless `keep_alive`)
+fn serve(
+    stream: impl Read + Write,
+    keep_alive: bool,
+    handler: &Handler,
+    files: &Path,
+    log: &Mutex<Vec<Request>>,
+) {
+    let mut reader = BufReader::new(stream);
+
+    while let Some(request) = read_request(&mut reader) {
+        log.lock().unwrap().push(request.clone());
+
+        let response = handler(&request).unwrap_or_else(|| respond(&request, files));
+        if write_response(reader.get_mut(), &request, response, keep_alive).is_err() || !keep_alive
+        {
+            return;
+        }
+    }
+}
+
+/// Reads the next request of a connection (if there is one)
+fn read_request(reader: &mut impl BufRead) -> Option<Request> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).unwrap_or(0) == 0 {
+        return None;
+    }
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_owned();
+    let path = parts.next().unwrap_or_default().to_owned();
+
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).unwrap_or(0) == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.insert(name.trim().to_lowercase(), value.trim().to_owned());
+        }
+    }
+    let mut body = vec![
+        0;
+        headers
+            .get("content-length")
+            .and_then(|length| length.parse().ok())
+            .unwrap_or(0)
+    ];
+    let _ = reader.read_exact(&mut body);
+
+    Some(Request {
+        method,
+        path,
+        headers,
+        body,
+    })
+}
+
+fn write_response(
+    mut stream: impl Write,
+    request: &Request,
+    response: Response,
+    keep_alive: bool,
+) -> std::io::Result<()> {
+    let mut head = format!("HTTP/1.1 {} Status\r\n", response.status);
+    if !response
+        .headers
+        .iter()
+        .any(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+    {
+        head.push_str(&format!("Content-Length: {}\r\n", response.body.len()));
+    }
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    let connection = if keep_alive { "keep-alive" } else { "close" };
+    head.push_str(&format!("Connection: {}\r\n\r\n", connection));
+
+    stream.write_all(head.as_bytes())?;
+    if request.method != "HEAD" {
+        stream.write_all(&response.body)?;
+    }
+    stream.flush()?;
+    if let Some(stall) = response.stall {
+        thread::sleep(stall);
+    }
+
+    Ok(())
+}
+
+/// Serves a file or the listing of a directory
+fn respond(request: &Request, files: &Path) -> Response {
+    let path = percent_decode(request.path.split('?').next().unwrap());
+    let local = files.join(path.trim_start_matches('/'));
+
+    if local.is_dir() {
+        if !path.ends_with('/') {
+            return Response::new(301, "").header("Location", &format!("{}/", path));
+        }
+        return Response::new(200, listing(&path, &local)).header("Content-Type", "text/html");
+    }
+
+    let content = match fs::read(&local) {
+        Ok(content) => content,
+        Err(_) => return Response::new(404, "Not Found"),
+    };
+    let modified: DateTime<Utc> = fs::metadata(&local).unwrap().modified().unwrap().into();
+    let size = content.len();
+
+    let range = request
+        .header("range")
+        .and_then(|range| range.strip_prefix("bytes="))
+        .and_then(|range| range.split_once('-'))
+        .and_then(|(start, end)| {
+            let start: usize = start.parse().ok()?;
+            let end: usize = end.parse().unwrap_or(size.saturating_sub(1));
+            Some((start, end.min(size.saturating_sub(1))))
+        });
+
+    let response = match range {
+        Some((start, _)) if start >= size => {
+            return Response::new(416, "").header("Content-Range", &format!("bytes */{}", size))
+        }
+        Some((start, end)) => Response::new(206, &content[start..=end]).header(
+            "Content-Range",
+            &format!("bytes {}-{}/{}", start, end, size),
+        ),
+        None => Response::new(200, content),
+    };
+
+    response
+        .header("Accept-Ranges", "bytes")
+        .header("Content-Type", "application/octet-stream")
+        .header(
+            "Last-Modified",
+            &modified.format("%a, %d %b %Y %H:%M:%S GMT").to_string(),
+        )
+}
+
+/// Renders the listing of a directory like Apache does
+fn listing(path: &str, dir: &Path) -> String {
+    let mut entries: Vec<_> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    entries.sort();
+
+    let mut rows = vec![
+        r#"<tr><th><a href="?C=N;O=D">Name</a></th><th><a href="?C=M;O=A">Last modified</a></th><th><a href="?C=S;O=A">Size</a></th><th><a href="?C=D;O=A">Description</a></th></tr>"#.to_owned(),
+        r#"<tr><td><a href="../">Parent Directory</a></td><td>&nbsp;</td><td align="right">  - </td><td>&nbsp;</td></tr>"#.to_owned(),
+    ];
+    for entry in entries {
+        let name = entry.file_name().unwrap().to_string_lossy().into_owned();
+        let metadata = fs::metadata(&entry).unwrap();
+        let modified: DateTime<Utc> = metadata.modified().unwrap().into();
+        let (href, size) = match metadata.is_dir() {
+            true => (format!("{}/", percent_encode(&name)), "-".to_owned()),
+            false => (percent_encode(&name), metadata.len().to_string()),
+        };
+        rows.push(format!(
+            r#"<tr><td><a href="{}">{}</a></td><td align="right">{}  </td><td align="right">{} </td><td>&nbsp;</td></tr>"#,
+            href,
+            escape(&name),
+            modified.format("%Y-%m-%d %H:%M"),
+            size
+        ));
+    }
+
+    format!(
+        "<!DOCTYPE HTML PUBLIC \"-//W3C//DTD HTML 3.2 Final//EN\">\n<html>\n<head>\n<title>Index of {0}</title>\n</head>\n<body>\n<h1>Index of {0}</h1>\n<table>\n{1}\n</table>\n</body></html>\n",
+        escape(path),
+        rows.join("\n")
+    )
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn percent_encode(text: &str) -> String {
+    percent_encoding::utf8_percent_encode(text, percent_encoding::NON_ALPHANUMERIC)
+        .to_string()
+        .replace("%2E", ".")
+        .replace("%2D", "-")
+        .replace("%5F", "_")
+}
+
+fn percent_decode(text: &str) -> String {
+    percent_encoding::percent_decode_str(text)
+        .decode_utf8_lossy()
+        .into_owned()
+}
+
+/// Runs od-get (without a backtrace on errors)
+pub fn od_get(args: &[&str]) -> Output {
+    command(args).output().unwrap()
+}
+
+/// Runs od-get in a working directory
+pub fn od_get_in(dir: &Path, args: &[&str]) -> Output {
+    command(args).current_dir(dir).output().unwrap()
+}
+
+/// Makes the command running od-get (without a backtrace on errors)
+pub fn command(args: &[&str]) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_od-get"));
+    command.args(args).env("RUST_BACKTRACE", "0");
+
+    command
+}
+
+/// Runs od-get, which has to succeed
+pub fn run(args: &[&str]) -> Output {
+    let output = od_get(args);
+    assert!(
+        output.status.success(),
+        "od-get {:?} failed: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    output
+}
+
+/// Lists the files below a directory (as relative paths, sorted) with their contents
+pub fn files_of(dir: &Path) -> Vec<(String, String)> {
+    let mut files = vec![];
+    let mut pending = vec![PathBuf::from(dir)];
+    while let Some(path) = pending.pop() {
+        for entry in fs::read_dir(&path).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                pending.push(path);
+            } else {
+                let name = path
+                    .strip_prefix(dir)
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned();
+                let content = String::from_utf8_lossy(&fs::read(&path).unwrap()).into_owned();
+                files.push((name, content));
+            }
+        }
+    }
+    files.sort();
+
+    files
+}
@@ -0,0 +1,192 @@
+mod common;
+
+use common::{files_of, od_get, run, Server};
+use std::{fs, path::Path};
+
+#[test]
+fn keeps_both_files_of_a_sanitized_name() {
+    let server = Server::new();
+    server
+        .file("coll/a:b.txt", "colon")
+        .file("coll/a_b.txt", "under");
+    let destination = tempfile::tempdir().unwrap();
+
+    let args = [
+        "-q",
+        "--on-conflict",
+        "rename",
+        "-d",
+        destination.path().to_str().unwrap(),
+        &server.url("/coll/"),
+    ];
+    run(&args);
+
+    let files = files_of(destination.path());
+    let contents: Vec<&str> = files.iter().map(|(_, content)| content.as_str()).collect();
+    assert_eq!(files.len(), 2, "{:?}", files);
+    assert!(contents.contains(&"colon") && contents.contains(&"under"));
+
+    // Another run leaves both where they are
+    run(&args);
+    assert_eq!(files_of(destination.path()), files);
+}
+
+#[test]
+fn filtered_files_do_not_claim_a_path() {
+    let server = Server::new();
+    server
+        .file("coll/a:b.bin", "colon")
+        .file("coll/a_b.bin", "under");
+    let destination = tempfile::tempdir().unwrap();
+
+    // Only the second file is wanted, so it's saved under its own name
+    run(&[
+        "-q",
+        "--on-conflict",
+        "rename",
+        "-F",
+        "^a_b",
+        "-d",
+        destination.path().to_str().unwrap(),
+        &server.url("/coll/"),
+    ]);
+
+    let files = files_of(destination.path());
+    assert_eq!(files, [("coll/a_b.bin".to_owned(), "under".to_owned())]);
+}
+
+/// Serves a file, a directory & another file, which the tests put something in the way of
+fn clashing_tree() -> Server {
+    let server = Server::new();
+    server
+        .file("pub/a.txt", "a")
+        .file("pub/docs/c.txt", "c")
+        .file("pub/size.txt", "size");
+    server
+}
+
+/// Mirrors the clashing tree into a destination prepared with conflicts, under a policy
+///
+/// Returns if the run succeeded, and the files of the destination afterwards.
+fn mirror_with_conflicts(
+    server: &Server,
+    policy: &str,
+    prepare: fn(&Path),
+) -> (bool, Vec<(String, String)>) {
+    let destination = tempfile::tempdir().unwrap();
+    fs::create_dir_all(destination.path().join("pub")).unwrap();
+    prepare(&destination.path().join("pub"));
+
+    let output = od_get(&[
+        "-q",
+        "--skip-errors",
+        "--on-conflict",
+        policy,
+        "-d",
+        destination.path().to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+
+    (output.status.success(), files_of(destination.path()))
+}
+
+/// Turns pairs of string slices into owned ones
+fn owned(files: &[(&str, &str)]) -> Vec<(String, String)> {
+    files
+        .iter()
+        .map(|(path, content)| (path.to_string(), content.to_string()))
+        .collect()
+}
+
+#[test]
+fn handles_files_and_directories_in_each_others_way() {
+    let server = clashing_tree();
+    // A directory where a file goes, and a file where a directory goes
+    let prepare: fn(&Path) = |pub_dir| {
+        fs::create_dir(pub_dir.join("a.txt")).unwrap();
+        fs::write(pub_dir.join("a.txt/inner"), "inner").unwrap();
+        fs::write(pub_dir.join("docs"), "docs").unwrap();
+    };
+
+    // Directories are never removed, and a file in the way of one aborts with "error"
+    let expected = [
+        (
+            "skip",
+            true,
+            owned(&[
+                ("pub/a.txt/inner", "inner"),
+                ("pub/docs", "docs"),
+                ("pub/size.txt", "size"),
+            ]),
+        ),
+        (
+            "overwrite",
+            true,
+            owned(&[
+                ("pub/a.txt/inner", "inner"),
+                ("pub/docs/c.txt", "c"),
+                ("pub/size.txt", "size"),
+            ]),
+        ),
+        (
+            "rename",
+            true,
+            owned(&[
+                ("pub/a.txt.1", "a"),
+                ("pub/a.txt/inner", "inner"),
+                ("pub/docs.1", "docs"),
+                ("pub/docs/c.txt", "c"),
+                ("pub/size.txt", "size"),
+            ]),
+        ),
+        (
+            "error",
+            false,
+            owned(&[
+                ("pub/a.txt/inner", "inner"),
+                ("pub/docs", "docs"),
+                ("pub/size.txt", "size"),
+            ]),
+        ),
+    ];
+    for (policy, success, files) in expected {
+        assert_eq!(
+            mirror_with_conflicts(&server, policy, prepare),
+            (success, files),
+            "{}",
+            policy
+        );
+    }
+}
+
+#[test]
+fn handles_files_of_another_size() {
+    let server = clashing_tree();
+    let prepare: fn(&Path) = |pub_dir| fs::write(pub_dir.join("size.txt"), "old content").unwrap();
+
+    // The failed file doesn't fail the run (with --skip-errors)
+    let expected = [
+        ("skip", vec![("pub/size.txt", "old content")]),
+        ("overwrite", vec![("pub/size.txt", "size")]),
+        (
+            "rename",
+            vec![("pub/size.txt", "old content"), ("pub/size.txt.1", "size")],
+        ),
+        ("error", vec![("pub/size.txt", "old content")]),
+    ];
+    for (policy, size_files) in expected {
+        let files = owned(
+            &[
+                &[("pub/a.txt", "a"), ("pub/docs/c.txt", "c")],
+                &size_files[..],
+            ]
+            .concat(),
+        );
+        assert_eq!(
+            mirror_with_conflicts(&server, policy, prepare),
+            (true, files),
+            "{}",
+            policy
+        );
+    }
+}
@@ -0,0 +1,67 @@
+mod common;
+
+use common::{files_of, od_get, run, Response, Server};
+use std::fs;
+
+/// Serves a tree only to clients with the session cookie
+fn members_only() -> Server {
+    let server = Server::with_handler(|request| {
+        let cookies = request.header("cookie").unwrap_or_default();
+        let member = cookies.split("; ").any(|cookie| cookie == "session=secret");
+        (!member && request.path != "/robots.txt").then(|| Response::new(403, "Forbidden"))
+    });
+    server.file("pub/a.txt", "a").file("pub/sub/b.txt", "b");
+    server
+}
+
+fn expected_files() -> Vec<(String, String)> {
+    vec![
+        ("pub/a.txt".to_owned(), "a".to_owned()),
+        ("pub/sub/b.txt".to_owned(), "b".to_owned()),
+    ]
+}
+
+#[test]
+fn sends_cookies() {
+    let server = members_only();
+    let destination = tempfile::tempdir().unwrap();
+    let destination = destination.path().to_str().unwrap();
+
+    let output = od_get(&["-q", "-d", destination, &server.url("/pub/")]);
+    assert!(!output.status.success());
+
+    run(&[
+        "-q",
+        "--cookie",
+        "session=secret",
+        "-d",
+        destination,
+        &server.url("/pub/"),
+    ]);
+    assert_eq!(files_of(destination.as_ref()), expected_files());
+}
+
+#[test]
+fn loads_a_cookies_file() {
+    let server = members_only();
+    let dir = tempfile::tempdir().unwrap();
+    let cookies = dir.path().join("cookies.txt");
+    fs::write(
+        &cookies,
+        "# Netscape HTTP Cookie File\n\
+         not a cookie\n\
+         127.0.0.1\tFALSE\t/\tFALSE\t0\tsession\tsecret\n",
+    )
+    .unwrap();
+    let destination = dir.path().join("files");
+
+    run(&[
+        "-q",
+        "--cookies-file",
+        cookies.to_str().unwrap(),
+        "-d",
+        destination.to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+    assert_eq!(files_of(&destination), expected_files());
+}
@@ -0,0 +1,944 @@
+mod common;
+
+use common::{files_of, od_get, od_get_in, run, Response, Server};
+use flate2::{write::GzEncoder, Compression};
+use serde_json::Value;
+use std::{
+    fs,
+    io::Write,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Renders a listing of links (in the table of Apache)
+fn listing(title: &str, hrefs: &[&str]) -> String {
+    let rows: Vec<String> = hrefs
+        .iter()
+        .map(|href| {
+            let size = if href.ends_with('/') { "-" } else { "1" };
+            format!(
+                r#"<tr><td><a href="{0}">{0}</a></td><td>2021-01-01 10:00</td><td>{1}</td></tr>"#,
+                href, size
+            )
+        })
+        .collect();
+
+    format!(
+        "<html><body><h1>Index of {}</h1><table>\n{}\n</table></body></html>",
+        title,
+        rows.join("\n")
+    )
+}
+
+#[test]
+fn terminates_on_parent_and_self_links() {
+    let server = Server::with_handler(|request| {
+        let hrefs: &[&str] = match request.path.as_str() {
+            "/pub/" => &["../", "./", "/pub/", "/", "a.txt", "sub/"],
+            "/pub/sub/" => &["../", "./", "/pub/", "/pub/sub/", "b.txt"],
+            _ => return None,
+        };
+        Some(Response::new(200, listing(&request.path, hrefs)).header("Content-Type", "text/html"))
+    });
+    server.file("pub/a.txt", "a").file("pub/sub/b.txt", "b");
+    let destination = tempfile::tempdir().unwrap();
+
+    run(&[
+        "-q",
+        "-d",
+        destination.path().to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+
+    assert_eq!(
+        files_of(destination.path()),
+        [
+            ("pub/a.txt".to_owned(), "a".to_owned()),
+            ("pub/sub/b.txt".to_owned(), "b".to_owned()),
+        ]
+    );
+
+    // Each listing got fetched once
+    let requests = server.requests();
+    let listings: Vec<&str> = requests
+        .iter()
+        .map(String::as_str)
+        .filter(|request| request.ends_with('/'))
+        .collect();
+    assert_eq!(listings, ["GET /pub/", "GET /pub/sub/"]);
+}
+
+#[test]
+fn follows_robots_txt() {
+    let server = Server::new();
+    server
+        .file("robots.txt", "User-agent: *\nDisallow: /pub/private/\n")
+        .file("pub/public/a.txt", "a")
+        .file("pub/private/b.txt", "b");
+    let destination = tempfile::tempdir().unwrap();
+    let destination = destination.path();
+    let url = server.url("/pub/");
+
+    run(&["-q", "-d", destination.to_str().unwrap(), &url]);
+
+    assert_eq!(
+        files_of(destination),
+        [("pub/public/a.txt".to_owned(), "a".to_owned())]
+    );
+    assert!(server
+        .requests()
+        .iter()
+        .all(|request| !request.contains("/pub/private/")));
+
+    // Unless told otherwise
+    run(&[
+        "-q",
+        "--ignore-robots",
+        "-d",
+        destination.to_str().unwrap(),
+        &url,
+    ]);
+
+    assert_eq!(files_of(destination).len(), 2);
+}
+
+#[test]
+fn follows_the_pages_of_a_listing() {
+    let server = Server::with_handler(|request| {
+        let page = match request.path.as_str() {
+            "/pub/" => {
+                listing("/pub", &["a.txt", "b.txt"]) + r#"<a rel="next" href="?page=2">2</a>"#
+            }
+            "/pub/?page=2" => listing("/pub", &["c.txt"]),
+            _ => return None,
+        };
+        Some(Response::new(200, page).header("Content-Type", "text/html"))
+    });
+    server
+        .file("pub/a.txt", "a")
+        .file("pub/b.txt", "b")
+        .file("pub/c.txt", "c");
+    let dir = tempfile::tempdir().unwrap();
+    let tree = dir.path().join("tree.json");
+    let destination = dir.path().join("files");
+
+    run(&[
+        "-q",
+        "--dump-tree",
+        tree.to_str().unwrap(),
+        "-d",
+        destination.to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+
+    // All entries are children of the one directory
+    let tree: Value = serde_json::from_slice(&fs::read(&tree).unwrap()).unwrap();
+    let children: Vec<&str> = tree["CrawledDir"][1]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|node| node["File"]["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(children, ["a.txt", "b.txt", "c.txt"]);
+    assert_eq!(files_of(&destination).len(), 3);
+}
+
+/// Serves a tree whose directory /pub/old/ moved to /pub/new/ (at the given host)
+fn moved_tree(host: &'static str) -> Server {
+    let server = Server::with_handler(move |request| match request.path.as_str() {
+        "/pub/" => Some(Response::new(200, listing("/pub", &["a.txt", "old/"]))),
+        "/pub/old/" => {
+            let port = request.header("host")?.rsplit(':').next()?;
+            let location = format!("http://{}:{}/pub/new/", host, port);
+            Some(Response::new(301, "").header("Location", &location))
+        }
+        _ => None,
+    });
+    server
+        .file("pub/a.txt", "a")
+        .file("pub/new/b.txt", "b")
+        .file("pub/new/sub/c.txt", "c");
+    server
+}
+
+#[test]
+fn resolves_links_against_the_redirect_target() {
+    let server = moved_tree("127.0.0.1");
+    let destination = tempfile::tempdir().unwrap();
+
+    run(&[
+        "-q",
+        "-d",
+        destination.path().to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+
+    // The files of the moved directory are fetched from where it moved to
+    let requests = server.requests();
+    for request in [
+        "GET /pub/new/b.txt",
+        "GET /pub/new/sub/",
+        "GET /pub/new/sub/c.txt",
+    ] {
+        assert!(requests.iter().any(|r| r == request), "{:?}", requests);
+    }
+    assert!(!requests
+        .iter()
+        .any(|request| request.starts_with("GET /pub/old/b")));
+    assert_eq!(files_of(destination.path()).len(), 3);
+}
+
+#[test]
+fn blocks_redirects_to_other_hosts() {
+    let server = moved_tree("localhost");
+    let destination = tempfile::tempdir().unwrap();
+
+    let output = od_get(&[
+        "-q",
+        "--no-cross-host",
+        "-d",
+        destination.path().to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+
+    assert!(!output.status.success());
+    let log = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        log.contains("Not following the redirect to another host"),
+        "{}",
+        log
+    );
+    assert!(!server
+        .requests()
+        .iter()
+        .any(|r| r.starts_with("GET /pub/new/")));
+}
+
+#[test]
+fn stays_below_the_root() {
+    let server = Server::with_handler(|request| match request.path.as_str() {
+        "/pub/" => Some(Response::new(
+            200,
+            listing(
+                "/pub",
+                &[
+                    "a.txt",
+                    "../other/",
+                    "/other/b.txt",
+                    "http://example.invalid/c.txt",
+                ],
+            ),
+        )),
+        _ => None,
+    });
+    server.file("pub/a.txt", "a").file("other/b.txt", "b");
+    let destination = tempfile::tempdir().unwrap();
+
+    run(&[
+        "-q",
+        "-d",
+        destination.path().to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+
+    assert_eq!(
+        files_of(destination.path()),
+        [("pub/a.txt".to_owned(), "a".to_owned())]
+    );
+    assert!(!server
+        .requests()
+        .iter()
+        .any(|request| request.starts_with("GET /other/")));
+}
+
+#[test]
+fn stays_below_a_redirected_root() {
+    let server = moved_tree("127.0.0.1");
+    let destination = tempfile::tempdir().unwrap();
+
+    run(&[
+        "-q",
+        "-d",
+        destination.path().to_str().unwrap(),
+        &server.url("/pub/old/"),
+    ]);
+
+    // The links of the moved root are below where it moved to, so they're kept
+    let mut files: Vec<String> = files_of(destination.path())
+        .into_iter()
+        .map(|(_, content)| content)
+        .collect();
+    files.sort();
+    assert_eq!(files, ["b", "c"]);
+}
+
+#[test]
+fn streams_the_same_files_as_a_crawl() {
+    let server = Server::new();
+    for dir in 0..5 {
+        for file in 0..=dir {
+            server.file(
+                &format!("pub/d{}/f{}.txt", dir, file),
+                format!("{}/{}", dir, file),
+            );
+        }
+    }
+    let crawled = tempfile::tempdir().unwrap();
+    let streamed = tempfile::tempdir().unwrap();
+
+    run(&[
+        "-q",
+        "-d",
+        crawled.path().to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+    run(&[
+        "-q",
+        "--stream",
+        "-d",
+        streamed.path().to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+
+    let files = files_of(streamed.path());
+    assert_eq!(files.len(), 15);
+    assert_eq!(files, files_of(crawled.path()));
+}
+
+#[test]
+fn crawls_a_directory_linked_in_several_forms_once() {
+    let server = Server::with_handler(|request| match request.path.as_str() {
+        "/pub/" => {
+            let rows: Vec<String> = ["sub/", "sub", "sub//"]
+                .iter()
+                .map(|href| {
+                    format!(
+                        r#"<tr><td><a href="{0}">{0}</a></td><td>2021-01-01 10:00</td><td>-</td></tr>"#,
+                        href
+                    )
+                })
+                .collect();
+            let body = format!(
+                "<html><body><h1>Index of /pub</h1><table>\n{}\n</table></body></html>",
+                rows.join("\n")
+            );
+            Some(Response::new(200, body))
+        }
+        _ => None,
+    });
+    server.file("pub/sub/a.txt", "a");
+    let destination = tempfile::tempdir().unwrap();
+
+    run(&[
+        "-q",
+        "-d",
+        destination.path().to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+
+    let requests = server.requests();
+    let listings = requests
+        .iter()
+        .filter(|request| request.starts_with("GET /pub/sub"))
+        .filter(|request| !request.ends_with(".txt"))
+        .count();
+    assert_eq!(listings, 1, "{:?}", requests);
+    assert_eq!(
+        files_of(destination.path()),
+        [("pub/sub/a.txt".to_owned(), "a".to_owned())]
+    );
+}
+
+/// Compresses a body with gzip
+fn gzip(body: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(vec![], Compression::default());
+    encoder.write_all(body).unwrap();
+    encoder.finish().unwrap()
+}
+
+/// Serves a gzip-encoded listing with a gzip-encoded file
+fn compressed_tree() -> Server {
+    Server::with_handler(|request| {
+        let accepts_gzip = request
+            .header("accept-encoding")
+            .is_some_and(|encodings| encodings.contains("gzip"));
+        let body = match request.path.as_str() {
+            // Without a size (which wouldn't match one of the bodies)
+            "/pub/" if accepts_gzip => listing("/pub", &["notes.txt"]).replace(">1<", ">?<"),
+            "/pub/notes.txt" => "plain text".to_owned(),
+            _ => return None,
+        };
+        Some(Response::new(200, gzip(body.as_bytes())).header("Content-Encoding", "gzip"))
+    })
+}
+
+#[test]
+fn parses_compressed_listings() {
+    let server = compressed_tree();
+    let raw = tempfile::tempdir().unwrap();
+    let decompressed = tempfile::tempdir().unwrap();
+
+    run(&[
+        "-q",
+        "-d",
+        raw.path().to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+    run(&[
+        "-q",
+        "--decompress",
+        "-d",
+        decompressed.path().to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+
+    // The file is kept as sent, unless it should be decompressed
+    let raw = fs::read(raw.path().join("pub/notes.txt")).unwrap();
+    assert_eq!(raw, gzip(b"plain text"));
+    assert_eq!(
+        files_of(decompressed.path()),
+        [("pub/notes.txt".to_owned(), "plain text".to_owned())]
+    );
+}
+
+#[test]
+fn keeps_empty_directories_in_the_tree() {
+    let server = Server::new();
+    server.file("pub/a.txt", "a").dir("pub/empty");
+    let dir = tempfile::tempdir().unwrap();
+    let tree = dir.path().join("tree.json");
+
+    run(&[
+        "-q",
+        "--dump-tree",
+        tree.to_str().unwrap(),
+        "-d",
+        dir.path().join("files").to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+
+    let tree: Value = serde_json::from_slice(&fs::read(&tree).unwrap()).unwrap();
+    let empty = tree["CrawledDir"][1]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find_map(|node| node.get("CrawledDir"))
+        .unwrap();
+    assert_eq!(empty[0]["url"], server.url("/pub/empty/"));
+    assert_eq!(empty[1], Value::Array(vec![]));
+}
+
+#[test]
+fn continues_past_a_directory_which_is_not_a_listing() {
+    let server = Server::with_handler(|request| {
+        (request.path == "/pub/bad/").then(|| {
+            Response::new(200, "<html><body>Under construction</body></html>")
+                .header("Content-Type", "text/html")
+        })
+    });
+    server
+        .file("pub/good/a.txt", "a")
+        .file("pub/bad/b.txt", "b")
+        .file("pub/more/c.txt", "cc");
+    let dir = tempfile::tempdir().unwrap();
+    let report = dir.path().join("report.json");
+    let destination = dir.path().join("files");
+
+    let output = od_get(&[
+        "-q",
+        "-d",
+        destination.to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+    assert!(!output.status.success());
+
+    run(&[
+        "-q",
+        "--continue-on-parse-error",
+        "--report",
+        report.to_str().unwrap(),
+        "-d",
+        destination.to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+
+    assert_eq!(
+        files_of(&destination),
+        [
+            ("pub/good/a.txt".to_owned(), "a".to_owned()),
+            ("pub/more/c.txt".to_owned(), "cc".to_owned()),
+        ]
+    );
+    let report: Value = serde_json::from_slice(&fs::read(&report).unwrap()).unwrap();
+    assert_eq!(report["unparsed_dirs"][0], server.url("/pub/bad/"));
+}
+
+#[test]
+fn crawls_an_ipv6_host_with_a_port() {
+    let server = Server::start("[::1]:0", |_| None);
+    server
+        .file("pub/a.txt", "a")
+        .file("pub/sub dir/b.txt", "bb");
+    let dir = tempfile::tempdir().unwrap();
+    let tree = dir.path().join("tree.json");
+    let destination = dir.path().join("files");
+    let url = server.url("/pub/");
+    assert!(url.starts_with("http://[::1]:"), "{}", url);
+
+    run(&[
+        "-q",
+        "--dump-tree",
+        tree.to_str().unwrap(),
+        "-d",
+        destination.to_str().unwrap(),
+        &url,
+    ]);
+
+    // The links keep the brackets & the port
+    let tree = fs::read_to_string(&tree).unwrap();
+    assert!(tree.contains(&format!("\"{}a.txt\"", url)), "{}", tree);
+    assert!(tree.contains(&format!("\"{}sub%20dir/\"", url)), "{}", tree);
+    assert!(
+        tree.contains(&format!("\"{}sub%20dir/b.txt\"", url)),
+        "{}",
+        tree
+    );
+    assert_eq!(
+        files_of(&destination),
+        [
+            ("pub/a.txt".to_owned(), "a".to_owned()),
+            ("pub/sub dir/b.txt".to_owned(), "bb".to_owned()),
+        ]
+    );
+}
+
+#[test]
+fn takes_turns_with_the_user_agents() {
+    let server = Server::new();
+    server
+        .file("pub/a.txt", "a")
+        .file("pub/b.txt", "bb")
+        .file("pub/sub/c.txt", "ccc");
+    let dir = tempfile::tempdir().unwrap();
+    let agents = dir.path().join("agents.txt");
+    fs::write(&agents, "# Browsers\nagent-1\nagent-2\n\nagent-3\n").unwrap();
+
+    run(&[
+        "-q",
+        "--deterministic",
+        "--no-stream",
+        "--user-agent-file",
+        agents.to_str().unwrap(),
+        "-d",
+        dir.path().join("files").to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+
+    let used: Vec<String> = server
+        .full_requests()
+        .iter()
+        .filter(|request| request.path != "/robots.txt")
+        .map(|request| request.header("user-agent").unwrap().to_owned())
+        .collect();
+    assert!(used.len() >= 5, "{:?}", used);
+    for (index, agent) in used.iter().enumerate() {
+        assert_eq!(*agent, format!("agent-{}", index % 3 + 1), "{:?}", used);
+    }
+}
+
+#[test]
+fn stops_at_the_max_dirs() {
+    let server = Server::new();
+    let mut path = "pub".to_owned();
+    for depth in 0..10 {
+        server.file(
+            &format!("{}/file{}.txt", path, depth),
+            "x".repeat(depth + 1),
+        );
+        path = format!("{}/d{}", path, depth + 1);
+    }
+    let destination = tempfile::tempdir().unwrap();
+
+    let output = od_get(&[
+        "--max-dirs",
+        "3",
+        "-d",
+        destination.path().to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+    assert!(output.status.success());
+
+    let listed: Vec<String> = server
+        .requests()
+        .into_iter()
+        .filter(|request| request.ends_with('/'))
+        .collect();
+    assert_eq!(listed, ["GET /pub/", "GET /pub/d1/", "GET /pub/d1/d2/"]);
+    assert_eq!(
+        files_of(destination.path()),
+        [
+            ("pub/d1/d2/file2.txt".to_owned(), "xxx".to_owned()),
+            ("pub/d1/file1.txt".to_owned(), "xx".to_owned()),
+            ("pub/file0.txt".to_owned(), "x".to_owned()),
+        ]
+    );
+
+    let log = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        log.contains("Reached the crawl limit after 3 directories"),
+        "{}",
+        log
+    );
+}
+
+#[test]
+fn waits_within_the_delay_jitter() {
+    let times = Arc::new(Mutex::new(vec![]));
+    let server = Server::with_handler({
+        let times = times.clone();
+        move |_| {
+            times.lock().unwrap().push(Instant::now());
+            None
+        }
+    });
+    for (name, content) in [("a", "1"), ("b", "22"), ("c", "333"), ("d", "4444")] {
+        server.file(&format!("pub/{}.txt", name), content);
+    }
+    let destination = tempfile::tempdir().unwrap();
+
+    run(&[
+        "-q",
+        "--delay-jitter",
+        "200ms-400ms",
+        "-d",
+        destination.path().to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+
+    // The listing & the four files wait their turn (robots.txt is fetched beforehand)
+    let times = times.lock().unwrap();
+    assert_eq!(times.len(), 1 + 5);
+    for pair in times[1..].windows(2) {
+        let delay = pair[1] - pair[0];
+        assert!(delay >= Duration::from_millis(190), "{:?}", delay);
+        assert!(delay <= Duration::from_millis(400 + 150), "{:?}", delay);
+    }
+}
+
+#[test]
+fn reuses_connections_unless_told_otherwise() {
+    let connections = |args: &[&str]| {
+        let server = Server::with_keep_alive();
+        for index in 0..20 {
+            server.file(&format!("pub/{}.txt", index), "x".repeat(index + 1));
+        }
+        let destination = tempfile::tempdir().unwrap();
+
+        run(&[
+            &["-q", "-d", destination.path().to_str().unwrap()],
+            args,
+            &[server.url("/pub/").as_str()],
+        ]
+        .concat());
+        assert_eq!(files_of(destination.path()).len(), 20);
+
+        (server.connections(), server.requests().len())
+    };
+
+    // robots.txt, the listing & the files over few connections (e.g. of the listing & file clients)
+    let (pooled, requests) = connections(&[]);
+    assert_eq!(requests, 22);
+    assert!(pooled <= 3, "{} connections", pooled);
+
+    // A connection for every request
+    let (unpooled, requests) = connections(&["--pool-size", "0"]);
+    assert_eq!(unpooled, requests);
+}
+
+#[test]
+fn never_requests_excluded_directories() {
+    let server = Server::new();
+    server
+        .file("pub/a.txt", "a")
+        .file("pub/old/b.txt", "bb")
+        .file("pub/old/deeper/c.txt", "ccc")
+        .file("pub/sub/tmp/d.txt", "dddd")
+        .file("pub/sub/e.txt", "eeeee");
+    let dir = tempfile::tempdir().unwrap();
+    let state = dir.path().join("state.json");
+    let destination = dir.path().join("files");
+
+    run(&[
+        "-q",
+        "--exclude-dir",
+        "old",
+        "--exclude-dir",
+        "sub/**/tmp",
+        "-S",
+        state.to_str().unwrap(),
+        "-d",
+        destination.to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+
+    let requests = server.requests();
+    assert!(
+        requests
+            .iter()
+            .all(|request| !request.contains("/old/") && !request.contains("/tmp/")),
+        "{:?}",
+        requests
+    );
+    assert_eq!(
+        files_of(&destination),
+        [
+            ("pub/a.txt".to_owned(), "a".to_owned()),
+            ("pub/sub/e.txt".to_owned(), "eeeee".to_owned()),
+        ]
+    );
+
+    // Nor do they stay in the tree
+    let stored = fs::read_to_string(&state).unwrap();
+    assert!(!stored.contains("/old/"), "{}", stored);
+    assert!(!stored.contains("/tmp/"), "{}", stored);
+}
+
+#[cfg(unix)]
+#[test]
+fn crawls_a_symlinked_directory_once() {
+    let server = Server::new();
+    server
+        .file("pub/real/a.txt", "a")
+        .file("pub/real/b.txt", "bb")
+        .file("pub/c.txt", "ccc");
+    let root = server.root.path().join("pub");
+    std::os::unix::fs::symlink(root.join("real"), root.join("link")).unwrap();
+
+    let downloads = |args: &[&str]| {
+        let destination = tempfile::tempdir().unwrap();
+        let requests = server.requests().len();
+        run(&[
+            &["-q", "-d", destination.path().to_str().unwrap()],
+            args,
+            &[server.url("/pub/").as_str()],
+        ]
+        .concat());
+
+        let mut downloads: Vec<String> = server.requests()[requests..]
+            .iter()
+            .filter(|request| request.starts_with("GET /pub/") && !request.ends_with('/'))
+            .cloned()
+            .collect();
+        downloads.sort();
+        downloads
+    };
+
+    // Both paths are listed, but the files of the alias aren't downloaded again
+    assert_eq!(
+        downloads(&[]),
+        [
+            "GET /pub/c.txt",
+            "GET /pub/link/a.txt",
+            "GET /pub/link/b.txt",
+        ]
+    );
+    assert_eq!(
+        downloads(&["--follow-symlinks"]),
+        [
+            "GET /pub/c.txt",
+            "GET /pub/link/a.txt",
+            "GET /pub/link/b.txt",
+            "GET /pub/real/a.txt",
+            "GET /pub/real/b.txt",
+        ]
+    );
+}
+
+#[test]
+fn waits_as_long_as_a_throttling_server_asks() {
+    let times = Arc::new(Mutex::new(vec![]));
+    let server = Server::with_handler({
+        let times = times.clone();
+        move |request| {
+            let mut times = times.lock().unwrap();
+            times.push((request.path.clone(), Instant::now()));
+            let tries = times
+                .iter()
+                .filter(|(path, _)| *path == request.path)
+                .count();
+
+            match request.path.as_str() {
+                "/pub/" if tries <= 2 => {
+                    Some(Response::new(429, "Too Many Requests").header("Retry-After", "1"))
+                }
+                "/pub/a.txt" if tries <= 1 => {
+                    Some(Response::new(503, "Service Unavailable").header("Retry-After", "1"))
+                }
+                _ => None,
+            }
+        }
+    });
+    server.file("pub/a.txt", "a");
+    let destination = tempfile::tempdir().unwrap();
+
+    run(&[
+        "-q",
+        "-d",
+        destination.path().to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+
+    assert_eq!(
+        files_of(destination.path()),
+        [("pub/a.txt".to_owned(), "a".to_owned())]
+    );
+
+    // Each retry came after the Retry-After
+    let times = times.lock().unwrap();
+    for path in ["/pub/", "/pub/a.txt"] {
+        let tries: Vec<Instant> = times
+            .iter()
+            .filter(|(tried, _)| tried == path)
+            .map(|(_, time)| *time)
+            .collect();
+        assert_eq!(tries.len(), if path == "/pub/" { 3 } else { 2 });
+        for pair in tries.windows(2) {
+            assert!(
+                pair[1] - pair[0] >= Duration::from_millis(950),
+                "{:?}",
+                pair
+            );
+        }
+    }
+}
+
+#[test]
+fn skips_a_directory_with_a_redirect_loop() {
+    let server = Server::with_handler(|request| {
+        let location = match request.path.as_str() {
+            "/pub/bounce/" => "/pub/bounce",
+            "/pub/bounce" => "/pub/bounce/",
+            _ => return None,
+        };
+        Some(Response::new(301, "").header("Location", location))
+    });
+    server
+        .file("pub/a.txt", "a")
+        .file("pub/sub/b.txt", "bb")
+        .dir("pub/bounce");
+    let destination = tempfile::tempdir().unwrap();
+    let args = ["-d", destination.path().to_str().unwrap()];
+
+    let output = od_get(&[&args[..], &[server.url("/pub/").as_str()]].concat());
+    assert!(!output.status.success());
+    let log = String::from_utf8(output.stderr).unwrap();
+    assert!(log.contains("Redirect loop"), "{}", log);
+
+    let output = run(&[
+        &args[..],
+        &["--continue-on-parse-error", server.url("/pub/").as_str()],
+    ]
+    .concat());
+    let log = String::from_utf8(output.stderr).unwrap();
+    assert!(log.contains("Redirect loop"), "{}", log);
+    assert!(log.contains(&server.url("/pub/bounce/")), "{}", log);
+    assert_eq!(
+        files_of(destination.path()),
+        [
+            ("pub/a.txt".to_owned(), "a".to_owned()),
+            ("pub/sub/b.txt".to_owned(), "bb".to_owned()),
+        ]
+    );
+}
+
+#[test]
+fn downloads_while_crawling() {
+    // The last listing takes a while, so downloads can get ahead of it
+    let events = Arc::new(Mutex::new(vec![]));
+    let server = Server::with_handler({
+        let events = events.clone();
+        move |request| {
+            events.lock().unwrap().push(request.path.clone());
+            if request.path == "/pub/z/" {
+                std::thread::sleep(Duration::from_millis(500));
+                events.lock().unwrap().push("listed /pub/z/".to_owned());
+            }
+            None
+        }
+    });
+    server
+        .file("pub/a.txt", "a")
+        .file("pub/b/b.txt", "bb")
+        .file("pub/z/z.txt", "zzz");
+
+    // Returns whether the first file was requested before the crawl was done
+    let overlaps = |args: &[&str]| {
+        let destination = tempfile::tempdir().unwrap();
+        events.lock().unwrap().clear();
+        run(&[
+            &["-q", "-d", destination.path().to_str().unwrap()],
+            args,
+            &[server.url("/pub/").as_str()],
+        ]
+        .concat());
+
+        assert_eq!(
+            files_of(destination.path()),
+            [
+                ("pub/a.txt".to_owned(), "a".to_owned()),
+                ("pub/b/b.txt".to_owned(), "bb".to_owned()),
+                ("pub/z/z.txt".to_owned(), "zzz".to_owned()),
+            ]
+        );
+        let events = events.lock().unwrap();
+        let position = |event: &str| events.iter().position(|made| made == event).unwrap();
+        position("/pub/a.txt") < position("listed /pub/z/")
+    };
+
+    assert!(overlaps(&[]));
+    assert!(!overlaps(&["--no-stream"]));
+}
+
+#[test]
+fn counts_a_tree_listing_several_directories_at_once() {
+    // Every listing below the root takes a while
+    let server = Server::with_handler(|request| {
+        if request.path.starts_with("/pub/d") {
+            thread::sleep(Duration::from_millis(500));
+        }
+        None
+    });
+    for name in ["d1", "d2", "d3", "d4"] {
+        server.file(&format!("pub/{}/{}.txt", name, name), "abc");
+    }
+    server.file("pub/top.bin", "0123456789");
+    let dir = tempfile::tempdir().unwrap();
+
+    let started = Instant::now();
+    let output = od_get_in(dir.path(), &["--count-only", &server.url("/pub/")]);
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        stdout.contains("COUNT files=5 directories=5 bytes=22 unknown_sizes=0"),
+        "{}",
+        stdout
+    );
+    assert!(
+        started.elapsed() < Duration::from_millis(1500),
+        "The listings were fetched one by one ({:?})",
+        started.elapsed()
+    );
+
+    // Nothing was downloaded or written
+    let requests = server.requests();
+    assert!(
+        !requests
+            .iter()
+            .any(|request| request.starts_with("GET /pub/") && !request.ends_with('/')),
+        "{:?}",
+        requests
+    );
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+}
@@ -0,0 +1,49 @@
+mod common;
+
+use common::{files_of, run, Server};
+use serde_json::Value;
+use std::fs;
+
+#[test]
+#[cfg(unix)]
+fn links_identical_files() {
+    use std::os::unix::fs::MetadataExt;
+
+    let server = Server::new();
+    server
+        .file("pub/a/same.bin", "identical")
+        .file("pub/b/copy.bin", "identical")
+        .file("pub/c.bin", "different");
+    let dir = tempfile::tempdir().unwrap();
+    let state = dir.path().join("state.json");
+    let destination = dir.path().join("files");
+
+    run(&[
+        "-q",
+        "--dedup",
+        "-S",
+        state.to_str().unwrap(),
+        "-d",
+        destination.to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+
+    // The copies share one file, the file of the same size but other content doesn't
+    let inode = |path: &str| fs::metadata(destination.join(path)).unwrap().ino();
+    assert_eq!(inode("pub/a/same.bin"), inode("pub/b/copy.bin"));
+    assert_ne!(inode("pub/a/same.bin"), inode("pub/c.bin"));
+    assert_eq!(files_of(&destination).len(), 3);
+
+    let state: Value = serde_json::from_slice(&fs::read(&state).unwrap()).unwrap();
+    let links = state["dedup_links"].as_array().unwrap();
+    assert_eq!(links.len(), 1);
+    let mut urls = [
+        links[0]["url"].as_str().unwrap(),
+        links[0]["canonical_url"].as_str().unwrap(),
+    ];
+    urls.sort();
+    assert_eq!(
+        urls,
+        [server.url("/pub/a/same.bin"), server.url("/pub/b/copy.bin")]
+    );
+}
@@ -0,0 +1,84 @@
+mod common;
+
+use common::{files_of, run, Response, Server};
+use serde_json::Value;
+use std::fs;
+
+/// Serves a listing with descriptions (for all but one file)
+fn described_tree() -> Server {
+    let server = Server::with_handler(|request| {
+        let rows: String = [
+            ("a.iso", "Release 1.0"),
+            ("b.iso", "Release 2.0 &amp; notes"),
+            ("c.txt", "&nbsp;"),
+        ]
+        .iter()
+        .map(|(name, description)| {
+            format!(
+                r#"<tr><td><a href="{0}">{0}</a></td><td>2021-01-01 10:00</td><td>1</td><td>{1}</td></tr>"#,
+                name, description
+            )
+        })
+        .collect();
+
+        (request.path == "/pub/").then(|| {
+            Response::new(
+                200,
+                format!(
+                    "<html><body><h1>Index of /pub</h1><table>{}</table></body></html>",
+                    rows
+                ),
+            )
+        })
+    });
+    for name in ["a.iso", "b.iso", "c.txt"] {
+        server.file(&format!("pub/{}", name), "x");
+    }
+    server
+}
+
+#[test]
+fn keeps_the_descriptions() {
+    let server = described_tree();
+    let dir = tempfile::tempdir().unwrap();
+    let tree = dir.path().join("tree.json");
+    let destination = dir.path().join("files");
+
+    run(&[
+        "-q",
+        "--save-descriptions",
+        "--dump-tree",
+        tree.to_str().unwrap(),
+        "-d",
+        destination.to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+
+    // The descriptions are in the tree (unless empty)
+    let tree: Value = serde_json::from_slice(&fs::read(&tree).unwrap()).unwrap();
+    let descriptions: Vec<String> = tree["CrawledDir"][1]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|node| node["File"]["description"].to_string())
+        .collect();
+    assert_eq!(
+        descriptions,
+        [r#""Release 1.0""#, r#""Release 2.0 & notes""#, "null"]
+    );
+
+    // ... and next to the files
+    assert_eq!(
+        files_of(&destination),
+        [
+            ("pub/a.iso".to_owned(), "x".to_owned()),
+            ("pub/a.iso.description".to_owned(), "Release 1.0".to_owned()),
+            ("pub/b.iso".to_owned(), "x".to_owned()),
+            (
+                "pub/b.iso.description".to_owned(),
+                "Release 2.0 & notes".to_owned()
+            ),
+            ("pub/c.txt".to_owned(), "x".to_owned()),
+        ]
+    );
+}
@@ -0,0 +1,760 @@
+mod common;
+
+use common::{files_of, od_get_in, run, Response, Server};
+use std::{
+    fs,
+    io::Read,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+/// Serves a small tree
+fn small_tree() -> Server {
+    let server = Server::new();
+    server.file("pub/a.txt", "a").file("pub/sub/b.txt", "bb");
+    server
+}
+
+/// Returns the files of the tree, as laid out below a destination
+fn expected_files() -> Vec<(String, String)> {
+    vec![
+        ("pub/a.txt".to_owned(), "a".to_owned()),
+        ("pub/sub/b.txt".to_owned(), "bb".to_owned()),
+    ]
+}
+
+#[test]
+fn writes_below_an_absolute_output_dir() {
+    let server = small_tree();
+    let pwd = tempfile::tempdir().unwrap();
+    let output_dir = tempfile::tempdir().unwrap();
+    let destination = output_dir.path().join("mirror");
+
+    let output = od_get_in(
+        pwd.path(),
+        &[
+            "-q",
+            "--output-dir",
+            destination.to_str().unwrap(),
+            &server.url("/pub/"),
+        ],
+    );
+    assert!(output.status.success());
+
+    assert_eq!(files_of(&destination), expected_files());
+    assert!(files_of(pwd.path()).is_empty());
+}
+
+#[test]
+fn resolves_a_relative_output_dir_against_the_pwd() {
+    let server = small_tree();
+    let pwd = tempfile::tempdir().unwrap();
+
+    let output = od_get_in(pwd.path(), &["-q", "-d", "out/here", &server.url("/pub/")]);
+    assert!(output.status.success());
+
+    assert_eq!(files_of(&pwd.path().join("out/here")), expected_files());
+}
+
+#[test]
+fn downloads_only_a_subpath() {
+    let server = Server::new();
+    server
+        .file("pub/a.txt", "a")
+        .file("pub/docs/d.txt", "d")
+        .file("pub/docs/deep/e.txt", "e")
+        .file("pub/src/s.txt", "s");
+    let destination = tempfile::tempdir().unwrap();
+    let destination = destination.path();
+
+    let output = od_get_in(
+        destination,
+        &["-q", "--subpath", "docs", &server.url("/pub/")],
+    );
+    assert!(output.status.success());
+    assert_eq!(
+        files_of(destination),
+        [
+            ("pub/docs/d.txt".to_owned(), "d".to_owned()),
+            ("pub/docs/deep/e.txt".to_owned(), "e".to_owned()),
+        ]
+    );
+
+    // A subpath which isn't in the tree is an error
+    let output = od_get_in(
+        destination,
+        &["-q", "--subpath", "nope", &server.url("/pub/")],
+    );
+    assert!(!output.status.success());
+    let log = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        log.contains("The subpath nope doesn't exist in the crawled tree"),
+        "{}",
+        log
+    );
+}
+
+#[test]
+fn names_files_by_their_path() {
+    // (With different sizes, so the directories aren't taken for aliases)
+    let server = Server::new();
+    server
+        .file("pub/a/readme.txt", "a")
+        .file("pub/b/readme.txt", "bb")
+        .file("pub/b/c/readme.txt", "ccc");
+    let destination = tempfile::tempdir().unwrap();
+    let destination = destination.path();
+
+    let output = od_get_in(
+        destination,
+        &["-q", "--path-as-name", "__", &server.url("/pub/")],
+    );
+    assert!(output.status.success());
+    assert_eq!(
+        files_of(destination),
+        [
+            ("a__readme.txt".to_owned(), "a".to_owned()),
+            ("b__c__readme.txt".to_owned(), "ccc".to_owned()),
+            ("b__readme.txt".to_owned(), "bb".to_owned()),
+        ]
+    );
+}
+
+#[test]
+fn downloads_a_list_of_urls() {
+    let server = Server::new();
+    server
+        .file("pub/a.txt", "a")
+        .file("pub/sub/b.txt", "b")
+        .file("pub/sub/unlisted.txt", "c");
+    let dir = tempfile::tempdir().unwrap();
+    let urls = dir.path().join("urls.txt");
+    let destination = dir.path().join("files");
+    fs::write(
+        &urls,
+        format!(
+            "# Some files\n{}\n\n{}\t1\t2021-01-01 10:00\n",
+            server.url("/pub/a.txt"),
+            server.url("/pub/sub/b.txt")
+        ),
+    )
+    .unwrap();
+
+    run(&[
+        "-q",
+        "--urls-file",
+        urls.to_str().unwrap(),
+        "-d",
+        destination.to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+
+    assert_eq!(
+        files_of(&destination),
+        [
+            ("pub/a.txt".to_owned(), "a".to_owned()),
+            ("pub/sub/b.txt".to_owned(), "b".to_owned()),
+        ]
+    );
+    // Nothing got crawled
+    assert!(!server
+        .requests()
+        .iter()
+        .any(|request| request.ends_with('/')));
+}
+
+#[test]
+fn downloads_every_mirror_of_a_brace_pattern() {
+    let server = Server::new();
+    server.file("a/one.txt", "1").file("b/two.txt", "22");
+    let destination = tempfile::tempdir().unwrap();
+
+    run(&[
+        "-q",
+        "-d",
+        destination.path().to_str().unwrap(),
+        &server.url("/{a,b}/"),
+    ]);
+
+    assert_eq!(
+        files_of(destination.path()),
+        [
+            ("a/one.txt".to_owned(), "1".to_owned()),
+            ("b/two.txt".to_owned(), "22".to_owned()),
+        ]
+    );
+}
+
+#[test]
+fn only_fails_over_to_the_next_mirror() {
+    let server = Server::new();
+    server.file("b/two.txt", "22").file("c/three.txt", "333");
+    let destination = tempfile::tempdir().unwrap();
+
+    run(&[
+        "-q",
+        "--failover",
+        "-d",
+        destination.path().to_str().unwrap(),
+        &server.url("/{missing,b,c}/"),
+    ]);
+
+    assert_eq!(
+        files_of(destination.path()),
+        [("b/two.txt".to_owned(), "22".to_owned())]
+    );
+}
+
+#[test]
+fn only_creates_empty_directories_if_desired() {
+    let server = Server::new();
+    server.file("pub/a.txt", "a").dir("pub/void/inner");
+
+    // Both when streaming and when crawling first
+    for mode in ["--stream", "--no-stream"] {
+        let without = tempfile::tempdir().unwrap();
+        let with = tempfile::tempdir().unwrap();
+
+        run(&[
+            "-q",
+            mode,
+            "-d",
+            without.path().to_str().unwrap(),
+            &server.url("/pub/"),
+        ]);
+        run(&[
+            "-q",
+            mode,
+            "--include-empty-dirs",
+            "-d",
+            with.path().to_str().unwrap(),
+            &server.url("/pub/"),
+        ]);
+
+        assert!(without.path().join("pub/a.txt").is_file(), "{}", mode);
+        assert!(!without.path().join("pub/void").exists(), "{}", mode);
+        assert!(with.path().join("pub/void/inner").is_dir(), "{}", mode);
+        assert_eq!(files_of(with.path()), files_of(without.path()));
+    }
+}
+
+#[test]
+fn names_files_by_their_decoded_names() {
+    let server = Server::new();
+    server
+        .file("pub/привет.txt", "cyrillic")
+        .file("pub/plain name.txt", "ascii");
+    let destination = tempfile::tempdir().unwrap();
+
+    run(&[
+        "-q",
+        "-d",
+        destination.path().to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+
+    assert_eq!(
+        files_of(destination.path()),
+        [
+            ("pub/plain name.txt".to_owned(), "ascii".to_owned()),
+            ("pub/привет.txt".to_owned(), "cyrillic".to_owned()),
+        ]
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn tags_files_with_their_source() {
+    use sha2::{Digest, Sha256};
+
+    let server = small_tree();
+    let destination = tempfile::tempdir().unwrap();
+
+    run(&[
+        "-q",
+        "--xattrs",
+        "-d",
+        destination.path().to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+
+    let file = destination.path().join("pub/sub/b.txt");
+    let attribute = |name| String::from_utf8(xattr::get(&file, name).unwrap().unwrap()).unwrap();
+    assert_eq!(
+        attribute("user.od-get.source"),
+        server.url("/pub/sub/b.txt")
+    );
+    let digest: String = Sha256::digest(b"bb")
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    assert_eq!(attribute("user.od-get.sha256"), digest);
+}
+
+#[test]
+fn moves_downloads_from_the_temp_dir() {
+    let server = small_tree();
+    server.file("pub/other/a.txt", "other");
+    let destination = tempfile::tempdir().unwrap();
+
+    // On Linux, /dev/shm is (usually) another filesystem, so the files can't simply be renamed
+    let shm = Path::new("/dev/shm");
+    let temp_dir = if shm.is_dir() {
+        tempfile::tempdir_in(shm).unwrap()
+    } else {
+        tempfile::tempdir().unwrap()
+    };
+
+    run(&[
+        "-q",
+        "--temp-dir",
+        temp_dir.path().to_str().unwrap(),
+        "-d",
+        destination.path().to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+
+    // Nothing is left behind
+    assert_eq!(
+        files_of(destination.path()),
+        [
+            ("pub/a.txt".to_owned(), "a".to_owned()),
+            ("pub/other/a.txt".to_owned(), "other".to_owned()),
+            ("pub/sub/b.txt".to_owned(), "bb".to_owned()),
+        ]
+    );
+    assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+}
+
+#[cfg(unix)]
+#[test]
+fn hardlinks_unchanged_files_of_the_previous_snapshot() {
+    use std::os::unix::fs::MetadataExt;
+
+    let server = small_tree();
+    server.file("pub/sub/c.txt", "ccc");
+    let destination = tempfile::tempdir().unwrap();
+    let snapshots = || {
+        let mut snapshots: Vec<_> = fs::read_dir(destination.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        snapshots.sort();
+        snapshots
+    };
+    let args = [
+        "-q",
+        "--snapshot",
+        "-d",
+        destination.path().to_str().unwrap(),
+    ];
+
+    run(&[&args[..], &[server.url("/pub/").as_str()]].concat());
+    let first = snapshots().remove(0);
+
+    // The snapshots are named by the second the run started
+    std::thread::sleep(std::time::Duration::from_millis(1100));
+    server.file("pub/sub/b.txt", "changed");
+    run(&[
+        &args[..],
+        &["--link-dest", first.to_str().unwrap(), &server.url("/pub/")],
+    ]
+    .concat());
+
+    let second = snapshots().remove(1);
+    let inode = |snapshot: &Path, path: &str| fs::metadata(snapshot.join(path)).unwrap().ino();
+    assert_eq!(inode(&first, "pub/a.txt"), inode(&second, "pub/a.txt"));
+    assert_eq!(
+        inode(&first, "pub/sub/c.txt"),
+        inode(&second, "pub/sub/c.txt")
+    );
+    assert_ne!(
+        inode(&first, "pub/sub/b.txt"),
+        inode(&second, "pub/sub/b.txt")
+    );
+    assert_eq!(
+        files_of(&second),
+        [
+            ("pub/a.txt".to_owned(), "a".to_owned()),
+            ("pub/sub/b.txt".to_owned(), "changed".to_owned()),
+            ("pub/sub/c.txt".to_owned(), "ccc".to_owned()),
+        ]
+    );
+    assert_eq!(
+        fs::read_to_string(first.join("pub/sub/b.txt")).unwrap(),
+        "bb"
+    );
+
+    // Only the changed file was downloaded again
+    let downloads = |path: &str| {
+        let request = format!("GET {}", path);
+        server
+            .requests()
+            .iter()
+            .filter(|&made| *made == request)
+            .count()
+    };
+    assert_eq!(downloads("/pub/a.txt"), 1);
+    assert_eq!(downloads("/pub/sub/b.txt"), 2);
+}
+
+#[test]
+fn shortens_overly_long_names() {
+    let server = Server::new();
+    let long = format!("{}.txt", "n".repeat(240));
+    server
+        .file(&format!("pub/{}", long), "long")
+        .file("pub/short.txt", "short");
+    let dir = tempfile::tempdir().unwrap();
+    let destination = dir.path().join("files");
+    let report = dir.path().join("report.json");
+
+    let names = || {
+        run(&[
+            "-q",
+            "--max-filename-length",
+            "64",
+            "--report",
+            report.to_str().unwrap(),
+            "-d",
+            destination.to_str().unwrap(),
+            &server.url("/pub/"),
+        ]);
+        files_of(&destination)
+    };
+
+    let files = names();
+    assert_eq!(files.len(), 2);
+    let (path, content) = &files[0];
+    let name = path.strip_prefix("pub/").unwrap();
+    assert!(name.len() <= 64 && name.starts_with("nnnn") && name.ends_with(".txt"));
+    assert_eq!(content, "long");
+    assert_eq!(files[1], ("pub/short.txt".to_owned(), "short".to_owned()));
+
+    // The same name on every run, which the report traces back to the URL
+    fs::remove_dir_all(&destination).unwrap();
+    assert_eq!(names(), files);
+    let report = fs::read_to_string(&report).unwrap();
+    assert!(report.contains(name), "{}", report);
+    assert!(report.contains(&long), "{}", report);
+}
+
+#[test]
+fn leaves_incomplete_downloads_under_the_part_suffix() {
+    let server = Server::with_handler(|request| {
+        (request.path == "/pub/cut.bin")
+            .then(|| Response::new(200, "01234").header("Content-Length", "10"))
+    });
+    server
+        .file("pub/cut.bin", "0123456789")
+        .file("pub/ok.txt", "ok");
+    let destination = tempfile::tempdir().unwrap();
+
+    run(&[
+        "-q",
+        "--skip-errors",
+        "--part-suffix",
+        ".partial",
+        "-d",
+        destination.path().to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+
+    assert_eq!(
+        files_of(destination.path()),
+        [
+            ("pub/cut.bin.partial".to_owned(), "01234".to_owned()),
+            ("pub/ok.txt".to_owned(), "ok".to_owned()),
+        ]
+    );
+}
+
+/// Packs files into a gzipped tarball (taking the paths as they are, even `..` ones)
+fn tarball(files: &[(&str, &str)]) -> Vec<u8> {
+    let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+        vec![],
+        flate2::Compression::default(),
+    ));
+    for (path, content) in files {
+        let mut header = tar::Header::new_gnu();
+        header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append(&header, content.as_bytes()).unwrap();
+    }
+
+    builder.into_inner().unwrap().finish().unwrap()
+}
+
+#[test]
+fn extracts_tarballs_within_their_directory() {
+    let server = Server::new();
+    server.file(
+        "pub/data.tar.gz",
+        tarball(&[
+            ("readme.txt", "hi"),
+            ("sub/b.txt", "bb"),
+            ("../evil.txt", "evil"),
+        ]),
+    );
+    let destination = tempfile::tempdir().unwrap();
+
+    run(&[
+        "-q",
+        "--extract",
+        "--extract-delete",
+        "-d",
+        destination.path().to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+
+    assert_eq!(
+        files_of(destination.path()),
+        [
+            ("pub/data/readme.txt".to_owned(), "hi".to_owned()),
+            ("pub/data/sub/b.txt".to_owned(), "bb".to_owned()),
+        ]
+    );
+}
+
+/// Reads the entries of a (maybe gzipped) tarball, as sorted (path, content) pairs
+fn entries_of(archive: &Path) -> Vec<(String, String)> {
+    let file = fs::File::open(archive).unwrap();
+    let reader: Box<dyn Read> = if archive.extension().unwrap() == "tgz" {
+        Box::new(flate2::read::GzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+
+    let mut entries: Vec<(String, String)> = tar::Archive::new(reader)
+        .entries()
+        .unwrap()
+        .map(|entry| {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().to_string_lossy().into_owned();
+            let mut content = String::new();
+            entry.read_to_string(&mut content).unwrap();
+            (path, content)
+        })
+        .collect();
+    entries.sort();
+
+    entries
+}
+
+#[test]
+fn writes_the_files_into_a_tarball() {
+    let server = small_tree();
+
+    for name in ["mirror.tar", "mirror.tgz"] {
+        let dir = tempfile::tempdir().unwrap();
+        let destination = dir.path().join("files");
+        let archive = dir.path().join(name);
+
+        run(&[
+            "-q",
+            "--output-tar",
+            archive.to_str().unwrap(),
+            "-d",
+            destination.to_str().unwrap(),
+            &server.url("/pub/"),
+        ]);
+
+        assert_eq!(
+            entries_of(&archive),
+            [
+                ("pub/a.txt".to_owned(), "a".to_owned()),
+                ("pub/sub/b.txt".to_owned(), "bb".to_owned()),
+            ]
+        );
+        // Nothing but the archive was written
+        assert!(!destination.exists());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
+
+#[test]
+fn names_directories_by_their_decoded_href() {
+    // The link text of the directory is cut short (like Apache does with long names)
+    let server = Server::with_handler(|request| {
+        (request.path == "/pub/").then(|| {
+            Response::new(
+                200,
+                r#"<html><body><h1>Index of /pub</h1><table>
+<tr><td><a href="My%20Files/">My Fil..&gt;</a></td><td>2021-03-04 12:00</td><td>-</td></tr>
+<tr><td><a href="50%25%20off/">50% off/</a></td><td>2021-03-04 12:00</td><td>-</td></tr>
+</table></body></html>"#,
+            )
+            .header("Content-Type", "text/html")
+        })
+    });
+    server
+        .file("pub/My Files/a.txt", "a")
+        .file("pub/My Files/Sub Dir/b.txt", "bb")
+        .file("pub/50% off/c.txt", "ccc");
+
+    for stream in [true, false] {
+        let destination = tempfile::tempdir().unwrap();
+        let tree = destination.path().join("tree.json");
+        let mut args = vec![
+            "-q",
+            "--dump-tree",
+            tree.to_str().unwrap(),
+            "-d",
+            destination.path().to_str().unwrap(),
+        ];
+        if !stream {
+            args.push("--no-stream");
+        }
+        let url = server.url("/pub/");
+        args.push(&url);
+
+        run(&args);
+
+        assert_eq!(
+            files_of(&destination.path().join("pub")),
+            [
+                ("50% off/c.txt".to_owned(), "ccc".to_owned()),
+                ("My Files/Sub Dir/b.txt".to_owned(), "bb".to_owned()),
+                ("My Files/a.txt".to_owned(), "a".to_owned()),
+            ]
+        );
+        // The tree names it by the href, not by the link text
+        let tree = fs::read_to_string(&tree).unwrap();
+        assert!(tree.contains(r#""name": "/pub/My Files""#), "{}", tree);
+        assert!(!tree.contains("My Fil.."), "{}", tree);
+    }
+    let requests = server.requests();
+    assert!(
+        requests.contains(&"GET /pub/My%20Files/Sub%20Dir/".to_owned()),
+        "{:?}",
+        requests
+    );
+}
+
+#[test]
+fn mirrors_each_root_of_a_mirror_list() {
+    let first = Server::new();
+    first.file("pub/a.txt", "a");
+    let second = Server::new();
+    second.file("data/b.txt", "bb");
+    let dir = tempfile::tempdir().unwrap();
+    let list = dir.path().join("mirrors.txt");
+    let destination = dir.path().join("files");
+    fs::write(
+        &list,
+        format!(
+            "# Two mirrors & a broken one\n{} first\n{} broken\n\n{} second\n",
+            first.url("/pub/"),
+            "http://127.0.0.1:1/pub/",
+            second.url("/data/")
+        ),
+    )
+    .unwrap();
+
+    // The broken mirror doesn't stop the others
+    run(&[
+        "-q",
+        "--skip-errors",
+        "--mirror-list",
+        list.to_str().unwrap(),
+        "-d",
+        destination.to_str().unwrap(),
+    ]);
+
+    assert_eq!(
+        files_of(&destination),
+        [
+            ("first/pub/a.txt".to_owned(), "a".to_owned()),
+            ("second/data/b.txt".to_owned(), "bb".to_owned()),
+        ]
+    );
+}
+
+/// Returns bytes which don't repeat in any short cycle (so misplaced chunks show)
+fn noise(len: usize) -> Vec<u8> {
+    let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+    (0..len)
+        .map(|_| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed as u8
+        })
+        .collect()
+}
+
+#[test]
+fn writes_large_files_through_a_memory_map() {
+    let large = noise(5 << 20);
+    let server = Server::new();
+    server
+        .file("pub/large.bin", large.clone())
+        .file("pub/empty.txt", "");
+    let dir = tempfile::tempdir().unwrap();
+    let destination = dir.path().join("files");
+
+    run(&[
+        "-q",
+        "--mmap",
+        "-d",
+        destination.to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+
+    assert!(fs::read(destination.join("pub/large.bin")).unwrap() == large);
+    // Nothing is left behind, and an empty file is written as well
+    let names: Vec<String> = files_of(&destination)
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    assert_eq!(names, ["pub/empty.txt", "pub/large.bin"]);
+}
+
+#[test]
+fn downloads_large_files_in_segments() {
+    let ranges = Arc::new(Mutex::new(vec![]));
+    let seen = ranges.clone();
+    let server = Server::with_handler(move |request| {
+        match (request.path.as_str(), request.header("range")) {
+            // This one ignores ranges, so it's downloaded in one piece
+            ("/pub/whole.bin", Some(_)) if request.method == "GET" => {
+                Some(Response::new(200, noise(16 << 20)))
+            }
+            (path, Some(range)) => {
+                seen.lock().unwrap().push(format!("{} {}", path, range));
+                None
+            }
+            _ => None,
+        }
+    });
+    let large = noise(24 << 20);
+    server
+        .file("pub/large.bin", large.clone())
+        .file("pub/whole.bin", noise(16 << 20));
+    let dir = tempfile::tempdir().unwrap();
+    let destination = dir.path().join("files");
+
+    run(&[
+        "-q",
+        "--segments",
+        "3",
+        "-d",
+        destination.to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+
+    assert!(fs::read(destination.join("pub/large.bin")).unwrap() == large);
+    assert!(fs::read(destination.join("pub/whole.bin")).unwrap() == noise(16 << 20));
+    let mut ranges = ranges.lock().unwrap().clone();
+    ranges.sort();
+    assert_eq!(
+        ranges,
+        [
+            "/pub/large.bin bytes=0-8388607",
+            "/pub/large.bin bytes=16777216-25165823",
+            "/pub/large.bin bytes=8388608-16777215",
+        ]
+    );
+}
@@ -0,0 +1,338 @@
+mod common;
+
+use common::{command, Response, Server};
+use serde_json::{json, Value};
+use std::{
+    fs,
+    process::Command,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Runs od-get with `--error-format json`, returning its exit code & the error it printed
+fn run_failing(args: &[&str]) -> (i32, Value) {
+    let destination = tempfile::tempdir().unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_od-get"))
+        .args(["--error-format", "json", "-d"])
+        .arg(destination.path())
+        .args(args)
+        .env("RUST_BACKTRACE", "0")
+        .output()
+        .unwrap();
+
+    // The error is the last line (after the banner)
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let line = stderr.lines().last().unwrap();
+    let error = serde_json::from_str(line).unwrap_or_else(|_| panic!("Not JSON: {}", line));
+
+    (output.status.code().unwrap(), error)
+}
+
+#[test]
+fn reports_usage_errors() {
+    let (code, error) = run_failing(&["--max-dirs", "abc", "http://127.0.0.1:1/"]);
+
+    assert_eq!(code, 2);
+    assert_eq!(error["kind"], "usage");
+    assert_eq!(error["exit_code"], 2);
+    assert!(error["message"].as_str().unwrap().contains("abc"));
+    assert_eq!(error["url"], Value::Null);
+}
+
+#[test]
+fn reports_network_errors() {
+    let (code, error) = run_failing(&["http://127.0.0.1:1/files/"]);
+
+    assert_eq!(code, 3);
+    assert_eq!(error["kind"], "network");
+    assert_eq!(error["exit_code"], 3);
+    assert_eq!(error["url"], "http://127.0.0.1:1/files/");
+    assert_eq!(error["state_store"], Value::Null);
+}
+
+#[test]
+fn reports_the_state_store() {
+    let dir = tempfile::tempdir().unwrap();
+    let state_store = dir.path().join("state.json");
+    let state_store = state_store.to_str().unwrap();
+    let (code, error) = run_failing(&["-S", state_store, "http://127.0.0.1:1/files/"]);
+
+    assert_eq!(code, 3);
+    assert_eq!(error["state_store"], state_store);
+}
+
+#[test]
+fn times_out_on_a_stalled_server() {
+    let server = Server::with_handler(|_| {
+        thread::sleep(Duration::from_secs(30));
+        None
+    });
+
+    let started = Instant::now();
+    let (code, error) = run_failing(&["--timeout", "1s", &server.url("/pub/")]);
+
+    assert!(started.elapsed() < Duration::from_secs(10));
+    assert_eq!(code, 3);
+    assert_eq!(error["kind"], "network");
+    assert!(error["message"]
+        .as_str()
+        .unwrap()
+        .contains("Timed out after 1s"));
+}
+
+#[test]
+fn fails_only_a_file_which_stalls_midway() {
+    let server = Server::with_handler(|request| match request.path.as_str() {
+        "/pub/stalled.bin" if request.method == "GET" => Some(
+            Response::new(200, "sta")
+                .header("Content-Length", "10")
+                .stalling(Duration::from_secs(10)),
+        ),
+        _ => None,
+    });
+    server
+        .file("pub/a.txt", "a")
+        .file("pub/stalled.bin", "stalled...");
+    let dir = tempfile::tempdir().unwrap();
+    let report = dir.path().join("report.json");
+    let destination = dir.path().join("files");
+
+    let started = Instant::now();
+    let output = command(&[
+        "-q",
+        "--timeout",
+        "300ms",
+        "--report",
+        report.to_str().unwrap(),
+        "-d",
+        destination.to_str().unwrap(),
+        &server.url("/pub/"),
+    ])
+    .output()
+    .unwrap();
+
+    // The file is tried again a few times, then left out (instead of stopping the run)
+    assert!(started.elapsed() < Duration::from_secs(10));
+    assert!(output.status.success());
+    let report: Value = serde_json::from_slice(&fs::read(&report).unwrap()).unwrap();
+    assert_eq!(report["failed"][0]["url"], server.url("/pub/stalled.bin"));
+    assert!(report["failed"][0]["error"]
+        .as_str()
+        .unwrap()
+        .contains("Timed out after 300ms"));
+    let attempts = server
+        .requests()
+        .iter()
+        .filter(|request| *request == "GET /pub/stalled.bin")
+        .count();
+    assert_eq!(attempts, 4);
+    assert_eq!(fs::read(destination.join("pub/a.txt")).unwrap(), b"a");
+    assert!(!destination.join("pub/stalled.bin").exists());
+}
+
+#[test]
+fn reports_a_missing_root() {
+    let server = Server::new();
+    let (code, error) = run_failing(&[&server.url("/missing/")]);
+
+    assert_eq!(code, 3);
+    assert_eq!(error["kind"], "network");
+    let message = error["message"].as_str().unwrap();
+    assert!(
+        message.contains("/missing/ does not exist (404 Not Found)"),
+        "{}",
+        message
+    );
+}
+
+#[test]
+fn reports_an_empty_root() {
+    let server =
+        Server::with_handler(|_| Some(Response::new(200, "").header("Content-Type", "text/html")));
+    let (code, error) = run_failing(&[&server.url("/pub/")]);
+
+    assert_eq!(code, 4);
+    assert_eq!(error["kind"], "parse");
+    assert_eq!(error["url"], server.url("/pub/"));
+    let message = error["message"].as_str().unwrap();
+    assert!(
+        message.contains("returned an empty response"),
+        "{}",
+        message
+    );
+}
+
+#[test]
+fn reports_a_page_which_is_not_a_listing() {
+    let server = Server::with_handler(|_| {
+        Some(
+            Response::new(
+                200,
+                "<html><head><title>Welcome</title></head><body>Hi!</body></html>",
+            )
+            .header("Content-Type", "text/html"),
+        )
+    });
+    let (code, error) = run_failing(&[&server.url("/")]);
+
+    assert_eq!(code, 4);
+    assert_eq!(error["kind"], "parse");
+    let message = error["message"].as_str().unwrap();
+    assert!(
+        message.contains("does not appear to be an open directory listing"),
+        "{}",
+        message
+    );
+    assert!(message.contains("Welcome"), "{}", message);
+}
+
+#[test]
+fn reports_a_listing_which_is_too_large() {
+    let server = Server::new();
+    for index in 0..50 {
+        server.file(&format!("pub/file-{}.txt", index), "a");
+    }
+    let url = server.url("/pub/");
+    let (code, error) = run_failing(&["--max-listing-size", "2K", &url]);
+
+    assert_eq!(code, 4);
+    assert_eq!(error["kind"], "parse");
+    assert_eq!(error["url"], url);
+    let message = error["message"].as_str().unwrap();
+    assert!(
+        message.contains(&format!("The listing {} is larger than 2.0 KiB", url)),
+        "{}",
+        message
+    );
+
+    // The default limit leaves it alone
+    let destination = tempfile::tempdir().unwrap();
+    let output = command(&["-q", "-d", destination.path().to_str().unwrap(), &url])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+}
+
+#[test]
+fn reports_a_file_which_is_not_a_listing() {
+    let server = Server::new();
+    server.file("image.iso", [0u8, 1, 2, 3]);
+    let (code, error) = run_failing(&[&server.url("/image.iso")]);
+
+    assert_eq!(code, 4);
+    assert_eq!(error["kind"], "parse");
+    let message = error["message"].as_str().unwrap();
+    assert!(
+        message.contains(
+            "does not appear to be an open directory listing (found application/octet-stream)"
+        ),
+        "{}",
+        message
+    );
+}
+
+#[test]
+fn reports_a_login_page() {
+    let server = Server::with_handler(|request| {
+        (request.path == "/login").then(|| {
+            Response::new(
+                200,
+                r#"<html><head><title>Sign in</title></head><body><form method="post"><input name="user"><input type="password" name="pass"></form></body></html>"#,
+            )
+            .header("Content-Type", "text/html")
+        })
+    });
+    let (_, error) = run_failing(&[&server.url("/login")]);
+
+    let message = error["message"].as_str().unwrap();
+    assert!(message.contains("looks like a login page"), "{}", message);
+    assert!(message.contains("--cookie"), "{}", message);
+}
+
+#[test]
+fn reports_a_listing_which_broke_off() {
+    // The server closes the connection long before the announced end of the listing
+    let server = Server::with_handler(|request| {
+        (request.path == "/pub/").then(|| {
+            Response::new(200, "<html><body><h1>Index of /pub</h1>")
+                .header("Content-Type", "text/html")
+                .header("Content-Length", "1000")
+        })
+    });
+    let (_, error) = run_failing(&[&server.url("/pub/")]);
+
+    let message = error["message"].as_str().unwrap();
+    assert!(
+        message.contains(&format!(
+            "Cannot read {} (the response broke off)",
+            server.url("/pub/")
+        )),
+        "{}",
+        message
+    );
+}
+
+#[test]
+fn names_the_status_and_the_url_of_a_forbidden_directory() {
+    let server = Server::with_handler(|request| {
+        (request.path == "/pub/secret/").then(|| Response::new(403, "Forbidden"))
+    });
+    server.file("pub/a.txt", "a").file("pub/secret/b.txt", "bb");
+
+    let (code, error) = run_failing(&[&server.url("/pub/")]);
+
+    assert_eq!(code, 6);
+    assert_eq!(error["kind"], "auth");
+    let message = error["message"].as_str().unwrap();
+    assert!(message.contains("403"), "{}", message);
+    assert!(message.contains(&server.url("/pub/secret/")), "{}", message);
+    assert_eq!(error["url"], server.url("/pub/secret/"));
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn stops_and_saves_the_progress_when_the_disk_is_full() {
+    let server = Server::new();
+    server
+        .file("pub/a.txt", "a")
+        .file("pub/b.txt", "bb")
+        .file("pub/c.txt", "ccc");
+    let dir = tempfile::tempdir().unwrap();
+    let state = dir.path().join("state.json");
+    let destination = dir.path().join("files");
+
+    // Writing the second file fails like on a full disk
+    fs::create_dir_all(destination.join("pub")).unwrap();
+    std::os::unix::fs::symlink("/dev/full", destination.join("pub/b.txt.od-part")).unwrap();
+
+    let output = command(&[
+        "--skip-errors",
+        "--error-format",
+        "json",
+        "-S",
+        state.to_str().unwrap(),
+        "-d",
+        destination.to_str().unwrap(),
+        &server.url("/pub/"),
+    ])
+    .output()
+    .unwrap();
+
+    // The run stops (despite --skip-errors) with an I/O error about the file
+    assert_eq!(output.status.code(), Some(5));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let error: Value = serde_json::from_str(stderr.lines().last().unwrap()).unwrap();
+    assert_eq!(error["kind"], "io");
+    assert_eq!(error["url"], server.url("/pub/b.txt"));
+    assert!(
+        error["message"].as_str().unwrap().contains("disk is full"),
+        "{}",
+        error
+    );
+    assert!(!server.requests().contains(&"GET /pub/c.txt".to_owned()));
+
+    // The temporary file is gone, and the progress before it got saved
+    assert!(fs::symlink_metadata(destination.join("pub/b.txt.od-part")).is_err());
+    let state: Value = serde_json::from_slice(&fs::read(&state).unwrap()).unwrap();
+    assert_eq!(state["downloaded_urls"], json!([server.url("/pub/a.txt")]));
+}
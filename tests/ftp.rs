@@ -0,0 +1,139 @@
+mod common;
+
+use common::{files_of, run};
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    sync::Arc,
+    thread,
+};
+
+/// The directories of an FTP server (by path, e.g. `/pub`), with their entries (by name)
+///
+/// Files have a content, directories have none.
+type Tree = BTreeMap<&'static str, Vec<(&'static str, Option<&'static str>)>>;
+
+/// Serves a tree over FTP (in passive mode), returning the URL of the server
+fn serve_ftp(tree: Tree) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let tree = Arc::new(tree);
+
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let tree = tree.clone();
+            thread::spawn(move || session(stream, &tree));
+        }
+    });
+
+    format!("ftp://{}", address)
+}
+
+/// Renders the listing of a directory (like `ls -l` does)
+fn list(tree: &Tree, path: &str) -> Option<String> {
+    let entries = tree.get(path.trim_end_matches('/'))?;
+
+    Some(
+        entries
+            .iter()
+            .map(|(name, content)| match content {
+                Some(content) => format!(
+                    "-rw-r--r-- 1 ftp ftp {} Jan 01 10:00 {}\r\n",
+                    content.len(),
+                    name
+                ),
+                None => format!("drwxr-xr-x 1 ftp ftp 0 Jan 01 10:00 {}\r\n", name),
+            })
+            .collect(),
+    )
+}
+
+/// Returns the content of a file
+fn retrieve(tree: &Tree, path: &str) -> Option<String> {
+    let (dir, name) = path.rsplit_once('/')?;
+    let entries = tree.get(dir)?;
+
+    entries
+        .iter()
+        .find(|(entry, _)| *entry == name)
+        .and_then(|(_, content)| content.map(str::to_owned))
+}
+
+/// Answers the commands of one control connection
+fn session(mut control: TcpStream, tree: &Tree) {
+    let mut reader = BufReader::new(control.try_clone().unwrap());
+    let mut passive: Option<TcpListener> = None;
+    let _ = control.write_all(b"220 Mock FTP\r\n");
+
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).unwrap_or(0) == 0 {
+            return;
+        }
+        let line = line.trim_end();
+        let (command, argument) = line.split_once(' ').unwrap_or((line, ""));
+
+        let reply = match command.to_ascii_uppercase().as_str() {
+            "USER" => "331 Password please".to_owned(),
+            "PASS" => "230 Logged in".to_owned(),
+            "TYPE" => "200 Type set".to_owned(),
+            "PASV" => {
+                let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+                let port = listener.local_addr().unwrap().port();
+                passive = Some(listener);
+                format!(
+                    "227 Entering Passive Mode (127,0,0,1,{},{})",
+                    port / 256,
+                    port % 256
+                )
+            }
+            command @ ("LIST" | "RETR") => {
+                let body = match command {
+                    "LIST" => list(tree, argument),
+                    _ => retrieve(tree, argument),
+                };
+                match (body, passive.take()) {
+                    (Some(body), Some(listener)) => {
+                        let _ = control.write_all(b"150 Sending\r\n");
+                        let (mut data, _) = listener.accept().unwrap();
+                        let _ = data.write_all(body.as_bytes());
+                        drop(data);
+                        "226 Done".to_owned()
+                    }
+                    _ => "550 Not found".to_owned(),
+                }
+            }
+            "QUIT" => {
+                let _ = control.write_all(b"221 Bye\r\n");
+                return;
+            }
+            _ => "502 Not implemented".to_owned(),
+        };
+        let _ = control.write_all(format!("{}\r\n", reply).as_bytes());
+    }
+}
+
+#[test]
+fn mirrors_an_ftp_tree() {
+    let url = serve_ftp(BTreeMap::from([
+        ("/pub", vec![("a.txt", Some("hello")), ("sub", None)]),
+        ("/pub/sub", vec![("b.txt", Some("ftp!"))]),
+    ]));
+    let destination = tempfile::tempdir().unwrap();
+
+    run(&[
+        "-q",
+        "-d",
+        destination.path().to_str().unwrap(),
+        &format!("{}/pub/", url),
+    ]);
+
+    assert_eq!(
+        files_of(destination.path()),
+        [
+            ("pub/a.txt".to_owned(), "hello".to_owned()),
+            ("pub/sub/b.txt".to_owned(), "ftp!".to_owned()),
+        ]
+    );
+}
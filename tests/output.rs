@@ -0,0 +1,634 @@
+mod common;
+
+use common::{command, od_get, od_get_in, run, Server};
+use filetime::{set_file_mtime, FileTime};
+use serde_json::Value;
+use std::{
+    fs, thread,
+    time::{Duration, Instant},
+};
+
+/// Serves a small tree
+fn small_tree() -> Server {
+    let server = Server::new();
+    server.file("pub/a.txt", "a").file("pub/sub/b.txt", "bb");
+    server
+}
+
+#[test]
+fn default_runs_are_quiet() {
+    let server = small_tree();
+    let destination = tempfile::tempdir().unwrap();
+    let output = run(&[
+        "-d",
+        destination.path().to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+
+    // No details of the crawl, nor of the single files
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        !stderr.contains("DEBUG") && !stderr.contains("TRACE"),
+        "{}",
+        stderr
+    );
+    assert!(!stderr.contains("a.txt"), "{}", stderr);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(!stdout.contains("a.txt"), "{}", stdout);
+}
+
+#[test]
+fn verbose_runs_show_the_crawl() {
+    let server = small_tree();
+    let destination = tempfile::tempdir().unwrap();
+    let output = run(&[
+        "-v",
+        "-d",
+        destination.path().to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("DEBUG"), "{}", stderr);
+    assert!(stderr.contains("a.txt"), "{}", stderr);
+}
+
+#[test]
+fn prints_a_manifest_of_the_files() {
+    let server = small_tree();
+    server.file("pub/sub/c.log", "ccc");
+    for path in ["pub/a.txt", "pub/sub/b.txt", "pub/sub/c.log"] {
+        let path = server.root.path().join(path);
+        set_file_mtime(path, FileTime::from_unix_time(1_609_495_200, 0)).unwrap();
+    }
+
+    let output = run(&["-q", "--manifest", "-f", r"\.log$", &server.url("/pub/")]);
+
+    // One line per wanted file (in a stable order), and nothing else
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let expected: Vec<String> = [("/pub/a.txt", 1), ("/pub/sub/b.txt", 2)]
+        .iter()
+        .map(|(path, size)| format!("{}\t{}\t2021-01-01 10:00", server.url(path), size))
+        .collect();
+    assert_eq!(stdout.lines().collect::<Vec<_>>(), expected);
+}
+
+#[test]
+fn ends_with_a_summary_line() {
+    let server = small_tree();
+    let destination = tempfile::tempdir().unwrap();
+    let output = run(&[
+        "-q",
+        "-d",
+        destination.path().to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+
+    // Even a quiet run prints the summary (as the last line)
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let summary = stdout.lines().last().unwrap();
+    let (totals, elapsed) = summary.rsplit_once(" elapsed=").unwrap();
+    assert_eq!(totals, "DONE files=2 bytes=3 skipped=0 failed=0");
+    assert!(elapsed.ends_with('s') && elapsed[..elapsed.len() - 1].parse::<f64>().is_ok());
+}
+
+#[cfg(unix)]
+#[test]
+fn notifies_with_the_totals() {
+    let server = small_tree();
+    let dir = tempfile::tempdir().unwrap();
+    let env = dir.path().join("env.txt");
+    let destination = dir.path().join("files");
+
+    run(&[
+        "-q",
+        "--notify-command",
+        &format!(
+            "echo $OD_GET_STATUS $OD_GET_FILES $OD_GET_BYTES $OD_GET_SKIPPED $OD_GET_FAILED > '{}'",
+            env.display()
+        ),
+        "-d",
+        destination.to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+
+    assert_eq!(std::fs::read_to_string(&env).unwrap(), "done 2 3 0 0\n");
+}
+
+#[cfg(unix)]
+#[test]
+fn runs_the_hook_of_each_file() {
+    let server = Server::new();
+    server.file("pub/a.txt", "a").file("pub/sub/it's.txt", "bb");
+    let dir = tempfile::tempdir().unwrap();
+    let log = dir.path().join("hooks.txt");
+    let destination = dir.path().join("files");
+    let mirror = |hook: &[&str]| {
+        command(
+            &[
+                &["-q", "-d", destination.to_str().unwrap(), "--exec"],
+                hook,
+                &[&server.url("/pub/")],
+            ]
+            .concat(),
+        )
+        .output()
+        .unwrap()
+    };
+
+    let output = mirror(&[&format!(
+        "printf '%s %s %s\\n' {{path}} {{url}} {{size}} >> '{}'",
+        log.display()
+    )]);
+
+    assert!(output.status.success());
+    let mut lines: Vec<String> = fs::read_to_string(&log)
+        .unwrap()
+        .lines()
+        .map(str::to_owned)
+        .collect();
+    lines.sort();
+    assert_eq!(
+        lines,
+        [
+            format!(
+                "{} {} 1",
+                destination.join("pub/a.txt").display(),
+                server.url("/pub/a.txt")
+            ),
+            format!(
+                "{} {} 2",
+                destination.join("pub/sub/it's.txt").display(),
+                server.url("/pub/sub/it%27s.txt")
+            ),
+        ]
+    );
+
+    // A failing hook only warns, unless the run is told to fail
+    fs::remove_dir_all(&destination).unwrap();
+    assert!(mirror(&["exit 3"]).status.success());
+    fs::remove_dir_all(&destination).unwrap();
+    let output = mirror(&["exit 3", "--exec-fail"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("The hook of"), "{}", stderr);
+}
+
+#[cfg(unix)]
+#[test]
+fn keeps_the_names_of_files_out_of_the_hook() {
+    let server = Server::new();
+    server.file("pub/a{url}b;touch pwned", "a");
+    let dir = tempfile::tempdir().unwrap();
+    let log = dir.path().join("hooks.txt");
+    let destination = dir.path().join("files");
+
+    let output = command(&[
+        "-q",
+        "-d",
+        destination.to_str().unwrap(),
+        "--exec",
+        &format!("echo {{path}} {{url}} >> '{}'", log.display()),
+        &server.url("/pub/"),
+    ])
+    .current_dir(dir.path())
+    .output()
+    .unwrap();
+
+    assert!(output.status.success());
+    assert!(!dir.path().join("pwned").exists());
+    assert_eq!(
+        fs::read_to_string(&log).unwrap(),
+        format!(
+            "{} {}\n",
+            destination.join("pub/a{url}b;touch pwned").display(),
+            server.url("/pub/a%7Burl%7Db%3Btouch%20pwned")
+        )
+    );
+}
+
+#[test]
+fn prints_lines_of_progress_at_the_interval() {
+    // The files finish one after the other
+    let server = Server::with_handler(|request| {
+        if let Some(name) = request.path.strip_prefix("/pub/f") {
+            if request.method == "GET" {
+                let index: u64 = name.trim_end_matches(".bin").parse().unwrap();
+                thread::sleep(Duration::from_millis(200 * index));
+            }
+        }
+        None
+    });
+    for index in 1..=4 {
+        server.file(&format!("pub/f{}.bin", index), vec![0; 1000]);
+    }
+    let dir = tempfile::tempdir().unwrap();
+    let destination = dir.path().join("files");
+    let mirror = |quiet: &[&str]| {
+        let destination = destination.to_str().unwrap();
+        let args = [&["--progress-interval", "100ms", "-d", destination], quiet].concat();
+        let output = run(&[&args[..], &[server.url("/pub/").as_str()]].concat());
+        fs::remove_dir_all(destination).unwrap();
+
+        String::from_utf8(output.stderr).unwrap()
+    };
+
+    let stderr = mirror(&[]);
+    let done: Vec<u64> = stderr
+        .lines()
+        .filter(|line| line.starts_with('[') && line.contains("/4 files, "))
+        .map(|line| line[1..line.find('/').unwrap()].parse().unwrap())
+        .collect();
+
+    assert!(done.windows(2).all(|pair| pair[0] <= pair[1]), "{:?}", done);
+    assert!(done.first() < done.last(), "{}", stderr);
+
+    // Quiet runs print none
+    assert!(!mirror(&["-q"]).contains(" files, "));
+}
+
+#[test]
+fn prints_completions_for_each_shell() {
+    for shell in ["bash", "zsh", "fish", "powershell", "elvish"] {
+        let output = run(&["completions", shell]);
+
+        let script = String::from_utf8(output.stdout).unwrap();
+        assert!(script.contains("od-get"), "{}: {}", shell, script);
+        assert!(script.contains("continue-on-parse-error"), "{}", shell);
+    }
+}
+
+#[test]
+fn pipes_a_file_to_stdout() {
+    let server = Server::new();
+    let content: Vec<u8> = (0..500_000u32).map(|i| (i * 13 % 256) as u8).collect();
+    server.file("pub/data.bin", &content);
+    let dir = tempfile::tempdir().unwrap();
+
+    let output = od_get_in(dir.path(), &["--stdout", &server.url("/pub/data.bin")]);
+
+    assert!(output.status.success());
+    assert!(output.stdout == content, "{} bytes", output.stdout.len());
+
+    // The banner goes to stderr, and nothing to the disk
+    assert!(String::from_utf8(output.stderr).unwrap().contains("od-get"));
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    assert!(!server.requests().contains(&"GET /pub/".to_owned()));
+}
+
+#[test]
+fn prints_a_range_of_a_file() {
+    let server = Server::new();
+    let content: Vec<u8> = (0..3000u32).map(|i| (i % 251) as u8).collect();
+    server.file("pub/data.bin", &content);
+
+    let output = run(&["-q", "--range", "0-1023", &server.url("/pub/data.bin")]);
+
+    assert_eq!(output.stdout, &content[..1024]);
+    let request = server
+        .full_requests()
+        .into_iter()
+        .find(|request| request.path == "/pub/data.bin")
+        .unwrap();
+    assert_eq!(request.header("range"), Some("bytes=0-1023"));
+}
+
+#[test]
+fn rejects_an_invalid_range() {
+    let output = od_get(&["--range", "10-5", "http://127.0.0.1:9/pub/a.bin"]);
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("Invalid range"), "{}", stderr);
+}
+
+#[test]
+fn writes_the_metadata_of_the_run() {
+    let server = small_tree();
+    let destination = tempfile::tempdir().unwrap();
+    let url = server.url("/pub/");
+
+    run(&[
+        "-q",
+        "--write-manifest",
+        "-d",
+        destination.path().to_str().unwrap(),
+        &url,
+    ]);
+
+    let marker = std::fs::read(destination.path().join(".od-get.json")).unwrap();
+    let marker: serde_json::Value = serde_json::from_slice(&marker).unwrap();
+    assert_eq!(marker["source"], url);
+    assert_eq!(marker["version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(marker["aborted"], false);
+    assert_eq!(marker["totals"]["files"], "2");
+    assert!(marker["arguments"]
+        .as_array()
+        .unwrap()
+        .contains(&"--write-manifest".into()));
+}
+
+#[test]
+fn keeps_secrets_out_of_the_metadata_of_the_run() {
+    let server = small_tree();
+    let destination = tempfile::tempdir().unwrap();
+    let url = server.url("/pub/").replace("://", "://me:hunter2@");
+
+    run(&[
+        "-q",
+        "--write-manifest",
+        "--cookie",
+        "session=s3cr3t",
+        "--cookie=token=t0k3n",
+        "-d",
+        destination.path().to_str().unwrap(),
+        &url,
+    ]);
+
+    let marker = fs::read_to_string(destination.path().join(".od-get.json")).unwrap();
+    for secret in ["s3cr3t", "t0k3n", "hunter2", "me:"] {
+        assert!(!marker.contains(secret), "{}", marker);
+    }
+    let marker: Value = serde_json::from_str(&marker).unwrap();
+    assert_eq!(marker["source"], server.url("/pub/"));
+    let arguments = marker["arguments"].as_array().unwrap();
+    assert!(arguments.contains(&"--cookie".into()));
+    assert!(arguments.contains(&"<redacted>".into()));
+    assert!(arguments.contains(&"--cookie=<redacted>".into()));
+    assert!(arguments.contains(&server.url("/pub/").into()));
+}
+
+#[test]
+fn writes_json_lines_of_the_files() {
+    let server = small_tree();
+    let destination = tempfile::tempdir().unwrap();
+    let output = run(&[
+        "--ndjson",
+        "-d",
+        destination.path().to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+
+    // Only JSON on stdout (the banner goes to stderr)
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let events: Vec<Value> = stdout
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap_or_else(|_| panic!("Not JSON: {}", line)))
+        .collect();
+    let has_event = |kind: &str, path: &str, status: &str| {
+        events.iter().any(|event| {
+            event["type"] == kind && event["url"] == server.url(path) && event["status"] == status
+        })
+    };
+
+    assert!(has_event("dir", "/pub/sub/", "found"), "{}", stdout);
+    for (path, size) in [("/pub/a.txt", 1), ("/pub/sub/b.txt", 2)] {
+        for status in ["found", "downloading", "done"] {
+            assert!(
+                has_event("file", path, status),
+                "{} {}: {}",
+                path,
+                status,
+                stdout
+            );
+        }
+        assert!(events
+            .iter()
+            .filter(|event| event["url"] == server.url(path))
+            .all(|event| event["size"] == size));
+    }
+    assert!(String::from_utf8(output.stderr).unwrap().contains("od-get"));
+}
+
+#[test]
+fn leaves_out_the_banner() {
+    let server = small_tree();
+    let dir = tempfile::tempdir().unwrap();
+    let tree = dir.path().join("tree.json");
+
+    // The tree on stdout, without anything else
+    let output = run(&[
+        "--no-banner",
+        "--dump-tree",
+        "-",
+        "-d",
+        dir.path().to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        !stdout.contains("od-get") && !stdout.contains("License"),
+        "{}",
+        stdout
+    );
+    let _: Value = serde_json::from_str(&stdout).unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(!stderr.contains("Licensed under"), "{}", stderr);
+
+    // Without --no-banner, the banner goes to stderr (as stdout is piped)
+    let output = run(&[
+        "-q",
+        "--dump-tree",
+        tree.to_str().unwrap(),
+        "-d",
+        dir.path().to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(!stdout.contains("Licensed under"), "{}", stdout);
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("Licensed under"));
+}
+
+#[test]
+fn shows_the_entries_of_a_single_listing() {
+    let server = small_tree();
+
+    let output = od_get(&["--test-url", &server.url("/pub/")]);
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("Directory: /pub/"), "{}", stdout);
+    assert!(stdout.contains("Found 2 entries"), "{}", stdout);
+    assert!(
+        stdout.contains(&format!(
+            "file {}\n  name:          a.txt",
+            server.url("/pub/a.txt")
+        )),
+        "{}",
+        stdout
+    );
+    assert!(
+        stdout.contains(&format!(
+            "dir  {}\n  name:          /pub/sub",
+            server.url("/pub/sub/")
+        )),
+        "{}",
+        stdout
+    );
+
+    // Neither the subdirectory nor the file were requested
+    let requests = server.requests();
+    assert!(
+        !requests.contains(&"GET /pub/sub/".to_owned()),
+        "{:?}",
+        requests
+    );
+    assert!(
+        !requests.contains(&"GET /pub/a.txt".to_owned()),
+        "{:?}",
+        requests
+    );
+}
+
+#[test]
+fn parses_a_saved_listing_against_the_url() {
+    let dir = tempfile::tempdir().unwrap();
+    let listing = dir.path().join("listing.html");
+    fs::write(
+        &listing,
+        r#"<html><body><h1>Index of /data</h1><table>
+<tr><td><a href="../">Parent Directory</a></td><td></td><td>-</td></tr>
+<tr><td><a href="a.txt">a.txt</a></td><td>2021-03-04 12:00</td><td>1.5K</td></tr>
+<tr><td><a href="sub/">sub/</a></td><td>2021-03-04 12:00</td><td>-</td></tr>
+</table></body></html>"#,
+    )
+    .unwrap();
+
+    let output = od_get(&[
+        "--from-html",
+        listing.to_str().unwrap(),
+        "https://mirror.example/data/",
+    ]);
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("Directory: /data"), "{}", stdout);
+    assert!(stdout.contains("Found 2 entries"), "{}", stdout);
+    assert!(
+        stdout.contains(
+            "file https://mirror.example/data/a.txt\n  name:          a.txt\n  last modified: 2021-03-04 12:00\n  size:          1.5K (1536 bytes)"
+        ),
+        "{}",
+        stdout
+    );
+    assert!(
+        stdout.contains("dir  https://mirror.example/data/sub/"),
+        "{}",
+        stdout
+    );
+}
+
+/// Reads the line of JSON a status socket answers with (once it's there)
+#[cfg(unix)]
+fn query_status(path: &std::path::Path) -> Option<Value> {
+    use std::io::Read;
+
+    let mut stream = std::os::unix::net::UnixStream::connect(path).ok()?;
+    let mut line = String::new();
+    stream.read_to_string(&mut line).ok()?;
+
+    Some(serde_json::from_str(&line).unwrap())
+}
+
+#[test]
+#[cfg(unix)]
+fn serves_the_progress_on_a_status_socket() {
+    // The last file never finishes
+    let server = Server::with_handler(|request| {
+        if request.path == "/pub/z.bin" {
+            thread::sleep(Duration::from_secs(30));
+        }
+        None
+    });
+    server
+        .file("pub/a.txt", "a")
+        .file("pub/b.txt", "bb")
+        .file("pub/z.bin", "z");
+    let dir = tempfile::tempdir().unwrap();
+    let socket = dir.path().join("status.sock");
+
+    let mut child = command(&[
+        "-q",
+        "--status-socket",
+        socket.to_str().unwrap(),
+        "-d",
+        dir.path().join("files").to_str().unwrap(),
+        &server.url("/pub/"),
+    ])
+    .spawn()
+    .unwrap();
+
+    // Wait until the run hangs on the last file
+    let started = Instant::now();
+    let status = loop {
+        assert!(child.try_wait().unwrap().is_none(), "od-get exited early");
+        assert!(started.elapsed() < Duration::from_secs(20), "No progress");
+        match query_status(&socket) {
+            Some(status) if status["files_done"] == 2 => break status,
+            _ => thread::sleep(Duration::from_millis(50)),
+        }
+    };
+    child.kill().unwrap();
+    child.wait().unwrap();
+
+    assert_eq!(status["files_failed"], 0);
+    assert_eq!(status["files_total"], 3);
+    assert_eq!(status["bytes_done"], 3);
+    assert_eq!(status["bytes_total"], 4);
+    // The last file has no response yet, so it doesn't count as started
+    assert_eq!(status["current"], Value::Null);
+    assert!(status["elapsed_secs"].as_f64().unwrap() > 0.0, "{}", status);
+}
+
+/// Reads the URLs, sizes & depths of the files of a catalog (by URL)
+fn catalog_rows(path: &std::path::Path) -> Vec<(String, Option<i64>, i64)> {
+    let connection = rusqlite::Connection::open(path).unwrap();
+    let mut statement = connection
+        .prepare("SELECT url, size_bytes, depth FROM files ORDER BY url")
+        .unwrap();
+    let rows = statement
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+        .unwrap();
+
+    rows.map(Result::unwrap).collect()
+}
+
+#[test]
+fn collects_the_files_in_a_catalog() {
+    let server = small_tree();
+    let dir = tempfile::tempdir().unwrap();
+    let catalog = dir.path().join("catalog.db");
+    let destination = dir.path().join("files");
+    let args = [
+        "-q",
+        "--catalog",
+        catalog.to_str().unwrap(),
+        "-d",
+        destination.to_str().unwrap(),
+        &server.url("/pub/"),
+    ];
+
+    run(&args);
+
+    assert_eq!(
+        catalog_rows(&catalog),
+        [
+            (server.url("/pub/a.txt"), Some(1), 1),
+            (server.url("/pub/sub/b.txt"), Some(2), 2),
+        ]
+    );
+    assert!(!destination.exists());
+
+    // Another run updates the rows
+    server.file("pub/sub/b.txt", "bbbb");
+    run(&args);
+    assert_eq!(
+        catalog_rows(&catalog),
+        [
+            (server.url("/pub/a.txt"), Some(1), 1),
+            (server.url("/pub/sub/b.txt"), Some(4), 2),
+        ]
+    );
+}
@@ -0,0 +1,82 @@
+mod common;
+
+use common::{files_of, od_get, run, Server};
+use std::{fs, path::Path};
+
+/// Mirrors a tree into a destination holding stale files (one of which is excluded from pruning)
+fn mirror_with_stale_files(destination: &Path, args: &[&str]) -> String {
+    let server = Server::new();
+    server.file("pub/a.txt", "a").file("pub/sub/b.txt", "b");
+    fs::create_dir_all(destination.join("pub/old")).unwrap();
+    fs::write(destination.join("pub/stale.txt"), "stale").unwrap();
+    fs::write(destination.join("pub/old/c.txt"), "c").unwrap();
+    fs::write(destination.join("pub/notes.log"), "mine").unwrap();
+
+    let url = server.url("/pub/");
+    let mut all_args = vec![
+        "--prune-exclude",
+        r"\.log$",
+        "-d",
+        destination.to_str().unwrap(),
+        &url,
+    ];
+    all_args.extend(args);
+    let output = run(&all_args);
+
+    String::from_utf8(output.stderr).unwrap()
+}
+
+#[test]
+fn prunes_stale_files() {
+    let destination = tempfile::tempdir().unwrap();
+    mirror_with_stale_files(destination.path(), &["--prune"]);
+
+    assert_eq!(
+        files_of(destination.path()),
+        [
+            ("pub/a.txt".to_owned(), "a".to_owned()),
+            ("pub/notes.log".to_owned(), "mine".to_owned()),
+            ("pub/sub/b.txt".to_owned(), "b".to_owned()),
+        ]
+    );
+    assert!(!destination.path().join("pub/old").exists());
+}
+
+#[test]
+fn lists_stale_files_on_a_dry_run() {
+    let destination = tempfile::tempdir().unwrap();
+    let log = mirror_with_stale_files(destination.path(), &["--prune-dry-run"]);
+
+    assert!(destination.path().join("pub/stale.txt").exists());
+    assert!(destination.path().join("pub/old/c.txt").exists());
+    for path in ["pub/stale.txt", "pub/old"] {
+        let line = format!("Would prune {}", destination.path().join(path).display());
+        assert!(log.contains(&line), "{}", log);
+    }
+    assert!(!log.contains("notes.log"), "{}", log);
+}
+
+#[test]
+fn refuses_to_prune_a_custom_layout() {
+    let server = Server::new();
+    server.file("pub/sub/a.txt", "a");
+    let url = server.url("/pub/");
+
+    for (layout, file) in [
+        (["--path-template", "{name}"], "a.txt"),
+        (["--path-as-name", "__"], "sub__a.txt"),
+    ] {
+        let destination = tempfile::tempdir().unwrap();
+        let destination = destination.path();
+        let args = [&layout[..], &["-q", "-d", destination.to_str().unwrap()]].concat();
+        run(&[&args[..], &[url.as_str()]].concat());
+
+        // Pruning would take every file laid out this way for a stale one
+        let output = od_get(&[&args[..], &["--prune", url.as_str()]].concat());
+
+        assert_eq!(output.status.code(), Some(2));
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.contains("Cannot use --prune with"), "{}", stderr);
+        assert_eq!(files_of(destination), [(file.to_owned(), "a".to_owned())]);
+    }
+}
@@ -0,0 +1,319 @@
+mod common;
+
+use common::{od_get, run, Response, Server};
+use filetime::{set_file_mtime, FileTime};
+use serde_json::{json, Value};
+use std::fs;
+
+#[test]
+fn reports_downloaded_skipped_and_failed_files() {
+    let server = Server::with_handler(|request| {
+        (request.path == "/pub/broken.txt" && request.method == "GET")
+            .then(|| Response::new(500, "Internal Server Error"))
+    });
+    server
+        .file("pub/good.txt", "good")
+        .file("pub/broken.txt", "broken")
+        .file("pub/unwanted.log", "unwanted");
+    let dir = tempfile::tempdir().unwrap();
+    let report = dir.path().join("report.json");
+
+    run(&[
+        "-q",
+        "--skip-errors",
+        "-f",
+        r"\.log$",
+        "--report",
+        report.to_str().unwrap(),
+        "-d",
+        dir.path().join("files").to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+
+    let report: Value = serde_json::from_slice(&fs::read(&report).unwrap()).unwrap();
+    let downloaded = report["downloaded"].as_array().unwrap();
+    assert_eq!(downloaded.len(), 1);
+    assert_eq!(downloaded[0]["url"], server.url("/pub/good.txt"));
+    assert_eq!(downloaded[0]["bytes"], 4);
+    assert_eq!(report["total_bytes"], 4);
+
+    let skipped = report["skipped"].as_array().unwrap();
+    assert_eq!(skipped.len(), 1);
+    assert_eq!(skipped[0]["url"], server.url("/pub/unwanted.log"));
+    assert_eq!(skipped[0]["reason"], "filtered");
+
+    let failed = report["failed"].as_array().unwrap();
+    assert_eq!(failed.len(), 1);
+    assert_eq!(failed[0]["url"], server.url("/pub/broken.txt"));
+    assert!(failed[0]["error"].as_str().unwrap().contains("500"));
+}
+
+#[test]
+fn deterministic_runs_write_identical_reports() {
+    let server = Server::new();
+    for name in ["c", "a", "b"] {
+        server
+            .file(&format!("pub/{}.txt", name), name)
+            .file(&format!("pub/{}/{}.txt", name, name), name);
+    }
+    let dir = tempfile::tempdir().unwrap();
+
+    let reports: Vec<Vec<u8>> = (0..2)
+        .map(|run_number| {
+            let report = dir.path().join(format!("report{}.json", run_number));
+            run(&[
+                "-q",
+                "--deterministic",
+                "--report",
+                report.to_str().unwrap(),
+                "-d",
+                dir.path()
+                    .join(format!("files{}", run_number))
+                    .to_str()
+                    .unwrap(),
+                &server.url("/pub/"),
+            ]);
+            fs::read(&report).unwrap()
+        })
+        .collect();
+
+    // The paths of the files differ between the runs, so only the URLs are compared
+    assert_eq!(
+        String::from_utf8_lossy(&reports[0]).replace("files0", "files1"),
+        String::from_utf8_lossy(&reports[1])
+    );
+
+    // The files of each directory come sorted, before the ones of its subdirectories
+    let report: Value = serde_json::from_slice(&reports[0]).unwrap();
+    let urls: Vec<&str> = report["downloaded"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|file| file["url"].as_str().unwrap())
+        .collect();
+    let expected: Vec<String> = ["a.txt", "b.txt", "c.txt", "a/a.txt", "b/b.txt", "c/c.txt"]
+        .iter()
+        .map(|path| server.url(&format!("/pub/{}", path)))
+        .collect();
+    assert_eq!(urls, expected);
+}
+
+/// Serves a file which breaks off, and one which is smaller than the listing says
+fn short_tree() -> Server {
+    let server = Server::with_handler(|request| match request.path.as_str() {
+        "/pub/cut.bin" => Some(Response::new(200, "01234").header("Content-Length", "10")),
+        "/pub/small.bin" => Some(Response::new(200, "01234")),
+        _ => None,
+    });
+    server
+        .file("pub/cut.bin", "0123456789")
+        .file("pub/small.bin", "0123456789")
+        .file("pub/ok.txt", "ok");
+    server
+}
+
+#[test]
+fn does_not_mark_short_downloads_as_done() {
+    let server = short_tree();
+    let dir = tempfile::tempdir().unwrap();
+    let state = dir.path().join("state.json");
+    let report = dir.path().join("report.json");
+    let destination = dir.path().join("files");
+
+    run(&[
+        "-q",
+        "--skip-errors",
+        "-S",
+        state.to_str().unwrap(),
+        "--report",
+        report.to_str().unwrap(),
+        "-d",
+        destination.to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+
+    let state: Value = serde_json::from_slice(&fs::read(&state).unwrap()).unwrap();
+    assert_eq!(state["downloaded_urls"], json!([server.url("/pub/ok.txt")]));
+    let report: Value = serde_json::from_slice(&fs::read(&report).unwrap()).unwrap();
+    let mut failed: Vec<&str> = report["failed"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|file| file["url"].as_str().unwrap())
+        .collect();
+    failed.sort();
+    assert_eq!(
+        failed,
+        [server.url("/pub/cut.bin"), server.url("/pub/small.bin")]
+    );
+    assert!(!destination.join("pub/cut.bin").exists());
+    assert!(!destination.join("pub/small.bin").exists());
+}
+
+#[test]
+fn aborts_on_a_size_mismatch_when_strict() {
+    let server = short_tree();
+    let destination = tempfile::tempdir().unwrap();
+
+    let output = od_get(&[
+        "-q",
+        "--strict-size",
+        "-f",
+        "cut",
+        "-d",
+        destination.path().to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+
+    assert!(!output.status.success());
+    let log = String::from_utf8(output.stderr).unwrap();
+    assert!(log.contains("instead of the"), "{}", log);
+}
+
+#[test]
+fn downloads_the_newest_files_first() {
+    let server = Server::new();
+    // (path, year)
+    let files = [
+        ("pub/old.txt", 2020),
+        ("pub/mid.txt", 2021),
+        ("pub/sub/newest.txt", 2023),
+        ("pub/sub/deep/newer.txt", 2022),
+        ("pub/z/older.txt", 2020),
+    ];
+    for (index, (path, year)) in files.iter().enumerate() {
+        server.file(path, "x".repeat(index + 1));
+        // Early in the year (an hour later for each file, to tell apart the ones of 2020)
+        let time = (year - 1970) * 365 * 86_400 + 12 * 86_400 + index as i64 * 3_600;
+        set_file_mtime(
+            server.root.path().join(path),
+            FileTime::from_unix_time(time, 0),
+        )
+        .unwrap();
+    }
+    let destination = tempfile::tempdir().unwrap();
+
+    run(&[
+        "-q",
+        "--newest-first",
+        "-d",
+        destination.path().to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+
+    let downloads: Vec<String> = server
+        .requests()
+        .into_iter()
+        .filter(|request| request.starts_with("GET /pub/") && !request.ends_with('/'))
+        .collect();
+    assert_eq!(
+        downloads,
+        [
+            "GET /pub/sub/newest.txt",
+            "GET /pub/sub/deep/newer.txt",
+            "GET /pub/mid.txt",
+            "GET /pub/z/older.txt",
+            "GET /pub/old.txt",
+        ]
+    );
+}
+
+#[test]
+fn aborts_after_the_max_failures() {
+    let server = Server::with_handler(|request| {
+        (request.path.ends_with(".txt") && request.method == "GET")
+            .then(|| Response::new(500, "Internal Server Error"))
+    });
+    for (name, content) in [
+        ("a", "1"),
+        ("b", "22"),
+        ("c", "333"),
+        ("d", "4444"),
+        ("e", "5"),
+    ] {
+        server.file(&format!("pub/{}.txt", name), content);
+    }
+    let dir = tempfile::tempdir().unwrap();
+    let state = dir.path().join("state.json");
+
+    let output = od_get(&[
+        "-q",
+        "--skip-errors",
+        "--max-failures",
+        "3",
+        "-S",
+        state.to_str().unwrap(),
+        "-d",
+        dir.path().join("files").to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+
+    assert!(!output.status.success());
+    let log = String::from_utf8(output.stderr).unwrap();
+    assert!(log.contains("Aborted after 3 failed files"), "{}", log);
+
+    // No further file was tried, and the failures were saved
+    let tried: Vec<String> = server
+        .requests()
+        .into_iter()
+        .filter(|request| request.starts_with("GET /pub/") && request.ends_with(".txt"))
+        .collect();
+    assert_eq!(
+        tried,
+        ["GET /pub/a.txt", "GET /pub/b.txt", "GET /pub/c.txt"]
+    );
+    let state: Value = serde_json::from_slice(&fs::read(&state).unwrap()).unwrap();
+    assert_eq!(state["failed_files"].as_array().unwrap().len(), 3);
+}
+
+#[test]
+fn trips_the_breaker_after_failures_in_a_row() {
+    let server = Server::with_handler(|request| {
+        (request.path.contains("bad") && request.method == "GET")
+            .then(|| Response::new(500, "Internal Server Error"))
+    });
+    for name in [
+        "1-ok", "2-bad", "3-bad", "4-ok", "5-bad", "6-bad", "7-bad", "8-ok",
+    ] {
+        server.file(&format!("pub/{}.txt", name), name);
+    }
+    let dir = tempfile::tempdir().unwrap();
+    let state = dir.path().join("state.json");
+
+    let output = od_get(&[
+        "-q",
+        "--skip-errors",
+        "--consecutive-failures",
+        "3",
+        "-S",
+        state.to_str().unwrap(),
+        "-d",
+        dir.path().join("files").to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+
+    assert!(!output.status.success());
+    let log = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        log.contains("Aborted after 3 files failed in a row (--consecutive-failures 3)"),
+        "{}",
+        log
+    );
+
+    // The first two failures were reset by the download after them, the third in a row tripped it
+    let tried: Vec<String> = server
+        .requests()
+        .into_iter()
+        .filter(|request| request.starts_with("GET /pub/") && request.ends_with(".txt"))
+        .collect();
+    assert_eq!(tried.last().unwrap(), "GET /pub/7-bad.txt");
+    assert_eq!(tried.len(), 7);
+
+    // The progress was saved
+    let state: Value = serde_json::from_slice(&fs::read(&state).unwrap()).unwrap();
+    assert_eq!(
+        state["downloaded_urls"],
+        json!([server.url("/pub/1-ok.txt"), server.url("/pub/4-ok.txt")])
+    );
+    assert_eq!(state["failed_files"].as_array().unwrap().len(), 5);
+}
@@ -0,0 +1,130 @@
+mod common;
+
+use common::{files_of, run, Response, Server};
+use serde_json::Value;
+use std::fs;
+
+#[test]
+fn skips_the_files_which_are_gone() {
+    let server = Server::new();
+    server
+        .file("stale/a.txt", "a")
+        .file("stale/b.txt", "b")
+        .file("stale/c.txt", "c");
+    let dir = tempfile::tempdir().unwrap();
+    let state = dir.path().join("state.json");
+    let report = dir.path().join("report.json");
+    let destination = dir.path().join("files");
+    let url = server.url("/stale/");
+
+    // Store the tree, then change the server
+    run(&["-q", "-n", "-S", state.to_str().unwrap(), &url]);
+    server.remove("stale/b.txt").file("stale/c.txt", "grown");
+
+    run(&[
+        "-q",
+        "--revalidate",
+        "-S",
+        state.to_str().unwrap(),
+        "--report",
+        report.to_str().unwrap(),
+        "-d",
+        destination.to_str().unwrap(),
+        &url,
+    ]);
+
+    let files = files_of(&destination);
+    let expected = [("stale/a.txt", "a"), ("stale/c.txt", "grown")];
+    let expected: Vec<_> = expected
+        .iter()
+        .map(|(name, content)| (name.to_string(), content.to_string()))
+        .collect();
+    assert_eq!(files, expected);
+
+    let report: Value = serde_json::from_slice(&fs::read(&report).unwrap()).unwrap();
+    assert_eq!(report["skipped"][0]["url"], server.url("/stale/b.txt"));
+    assert_eq!(report["skipped"][0]["reason"], "gone");
+    assert_eq!(report["failed"].as_array().unwrap().len(), 0);
+
+    // The file is dropped from the stored tree as well
+    let state = fs::read_to_string(&state).unwrap();
+    assert!(state.contains("c.txt") && !state.contains("b.txt"));
+}
+
+#[test]
+fn skips_unchanged_files_by_their_etag() {
+    let server = Server::with_handler(|request| match request.path.as_str() {
+        "/etag/a.txt" if request.header("if-none-match") == Some("\"v1\"") => {
+            Some(Response::new(304, ""))
+        }
+        "/etag/a.txt" => Some(Response::new(200, "a").header("ETag", "\"v1\"")),
+        _ => None,
+    });
+    server.file("etag/a.txt", "a");
+    let dir = tempfile::tempdir().unwrap();
+    let state = dir.path().join("state.json");
+    let report = dir.path().join("report.json");
+    let destination = dir.path().join("files");
+    let args = [
+        "-q",
+        "--revalidate",
+        "-S",
+        state.to_str().unwrap(),
+        "--report",
+        report.to_str().unwrap(),
+        "-d",
+        destination.to_str().unwrap(),
+    ];
+    let url = server.url("/etag/");
+
+    run(&[&args[..], &[url.as_str()]].concat());
+    let downloads = server.full_requests().len();
+    run(&[&args[..], &[url.as_str()]].concat());
+
+    // The second download of the file was a conditional one, which transferred nothing
+    let requests = server.full_requests();
+    let again = requests[downloads..]
+        .iter()
+        .find(|request| request.method == "GET" && request.path == "/etag/a.txt")
+        .unwrap();
+    assert_eq!(again.header("if-none-match"), Some("\"v1\""));
+    assert_eq!(
+        files_of(&destination),
+        [("etag/a.txt".to_owned(), "a".to_owned())]
+    );
+
+    let report: Value = serde_json::from_slice(&fs::read(&report).unwrap()).unwrap();
+    assert_eq!(report["skipped"][0]["url"], server.url("/etag/a.txt"));
+    assert_eq!(report["skipped"][0]["reason"], "up_to_date");
+}
+
+#[test]
+fn downloads_the_files_added_since() {
+    let server = Server::new();
+    server.file("new/a.txt", "a");
+    let dir = tempfile::tempdir().unwrap();
+    let state = dir.path().join("state.json");
+    let destination = dir.path().join("files");
+    let args = [
+        "-q",
+        "--revalidate",
+        "-S",
+        state.to_str().unwrap(),
+        "-d",
+        destination.to_str().unwrap(),
+    ];
+    let url = server.url("/new/");
+
+    run(&[&args[..], &[url.as_str()]].concat());
+    server.file("new/b.txt", "b").file("new/sub/c.txt", "c");
+    run(&[&args[..], &[url.as_str()]].concat());
+
+    assert_eq!(
+        files_of(&destination),
+        [
+            ("new/a.txt".to_owned(), "a".to_owned()),
+            ("new/b.txt".to_owned(), "b".to_owned()),
+            ("new/sub/c.txt".to_owned(), "c".to_owned()),
+        ]
+    );
+}
@@ -0,0 +1,74 @@
+mod common;
+
+use common::{command, Response, Server};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+/// The objects of an S3 bucket (by path, like `/bucket/key`)
+type Objects = Arc<Mutex<BTreeMap<String, String>>>;
+
+/// Serves the requests of multipart uploads, keeping the uploaded objects
+fn serve_s3(objects: Objects) -> Server {
+    Server::with_handler(move |request| {
+        let (path, query) = request.path.split_once('?').unwrap_or((&request.path, ""));
+
+        Some(match (request.method.as_str(), query) {
+            ("POST", "uploads=") => Response::new(
+                200,
+                "<InitiateMultipartUploadResult><UploadId>1</UploadId></InitiateMultipartUploadResult>",
+            ),
+            ("PUT", _) => {
+                let mut objects = objects.lock().unwrap();
+                let object = objects.entry(path.to_owned()).or_default();
+                object.push_str(&String::from_utf8_lossy(&request.body));
+                Response::new(200, "").header("ETag", "\"part\"")
+            }
+            ("POST", _) => Response::new(200, "<CompleteMultipartUploadResult/>"),
+            _ => Response::new(400, "Unexpected request"),
+        })
+    })
+}
+
+#[test]
+fn uploads_to_a_bucket() {
+    let server = Server::new();
+    server.file("pub/a.txt", "a").file("pub/sub/b.txt", "b");
+    let objects = Objects::default();
+    let s3 = serve_s3(objects.clone());
+    let home = tempfile::tempdir().unwrap();
+
+    let output = command(&["-q", "--output", "s3://bucket/mirror", &server.url("/pub/")])
+        .env("HOME", home.path())
+        .env("AWS_ENDPOINT_URL", s3.url(""))
+        .env("AWS_ACCESS_KEY_ID", "key")
+        .env("AWS_SECRET_ACCESS_KEY", "secret")
+        .env("AWS_REGION", "eu-central-1")
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let objects = objects.lock().unwrap();
+    let expected = [
+        ("/bucket/mirror/pub/a.txt", "a"),
+        ("/bucket/mirror/pub/sub/b.txt", "b"),
+    ];
+    let expected: BTreeMap<String, String> = expected
+        .iter()
+        .map(|(key, content)| (key.to_string(), content.to_string()))
+        .collect();
+    assert_eq!(*objects, expected);
+
+    // Every request is signed
+    assert!(s3
+        .full_requests()
+        .iter()
+        .all(|request| request.header("authorization").is_some_and(
+            |authorization| authorization.starts_with("AWS4-HMAC-SHA256 Credential=key/")
+        )));
+}
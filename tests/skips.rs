@@ -0,0 +1,392 @@
+mod common;
+
+use common::{command, files_of, run, Response, Server};
+use filetime::{set_file_mtime, FileTime};
+use std::{fs, process::Stdio};
+
+/// 2021-01-01 10:00 UTC
+const REMOTE_DATE: i64 = 1_609_495_200;
+
+#[test]
+fn only_downloads_newer_files() {
+    let server = Server::new();
+    server
+        .file("pub/old.txt", "remote")
+        .file("pub/new.txt", "remote");
+    for name in ["old.txt", "new.txt"] {
+        let path = server.root.path().join("pub").join(name);
+        set_file_mtime(path, FileTime::from_unix_time(REMOTE_DATE, 0)).unwrap();
+    }
+
+    // One local copy is from before the remote date, one from after it
+    let destination = tempfile::tempdir().unwrap();
+    let local = destination.path().join("pub");
+    fs::create_dir(&local).unwrap();
+    fs::write(local.join("old.txt"), "local").unwrap();
+    fs::write(local.join("new.txt"), "local").unwrap();
+    let day = 24 * 60 * 60;
+    set_file_mtime(
+        local.join("old.txt"),
+        FileTime::from_unix_time(REMOTE_DATE - day, 0),
+    )
+    .unwrap();
+    set_file_mtime(
+        local.join("new.txt"),
+        FileTime::from_unix_time(REMOTE_DATE + day, 0),
+    )
+    .unwrap();
+
+    run(&[
+        "-q",
+        "--only-newer",
+        "-d",
+        destination.path().to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+
+    assert_eq!(
+        files_of(destination.path()),
+        [
+            ("pub/new.txt".to_owned(), "local".to_owned()),
+            ("pub/old.txt".to_owned(), "remote".to_owned()),
+        ]
+    );
+}
+
+#[test]
+fn only_downloads_files_within_the_size_range() {
+    // The listing shows the sizes (or an odd one, which cannot be parsed)
+    let server = Server::with_handler(|request| {
+        let rows: String = [
+            ("tiny.bin", "10"),
+            ("small.bin", "100"),
+            ("medium.bin", "1.0K"),
+            ("large.bin", "5.0K"),
+            ("unknown.bin", "?"),
+        ]
+        .iter()
+        .map(|(name, size)| {
+            format!(
+                r#"<tr><td><a href="{0}">{0}</a></td><td>2021-01-01 10:00</td><td>{1}</td></tr>"#,
+                name, size
+            )
+        })
+        .collect();
+        (request.path == "/pub/").then(|| {
+            Response::new(
+                200,
+                format!(
+                    "<html><body><h1>Index of /pub</h1><table>{}</table></body></html>",
+                    rows
+                ),
+            )
+        })
+    });
+    for (name, size) in [
+        ("tiny.bin", 10),
+        ("small.bin", 100),
+        ("medium.bin", 1024),
+        ("large.bin", 5120),
+        ("unknown.bin", 1),
+    ] {
+        server.file(&format!("pub/{}", name), "x".repeat(size));
+    }
+    let destination = tempfile::tempdir().unwrap();
+
+    run(&[
+        "-q",
+        "--min-size",
+        "50",
+        "--max-size",
+        "1K",
+        "-d",
+        destination.path().to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+
+    let names: Vec<String> = files_of(destination.path())
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    assert_eq!(
+        names,
+        ["pub/medium.bin", "pub/small.bin", "pub/unknown.bin"]
+    );
+}
+
+#[test]
+fn trusts_the_sizes_of_head_requests() {
+    // The listing claims the file is large, but it's tiny
+    let server = Server::with_handler(|request| {
+        (request.path == "/pub/").then(|| {
+            Response::new(
+                200,
+                r#"<html><body><h1>Index of /pub</h1><table><tr><td><a href="big.bin">big.bin</a></td><td>2021-01-01 10:00</td><td>5.0K</td></tr></table></body></html>"#,
+            )
+        })
+    });
+    server.file("pub/big.bin", "x".repeat(10));
+    let listed = tempfile::tempdir().unwrap();
+    let headed = tempfile::tempdir().unwrap();
+    let url = server.url("/pub/");
+
+    run(&[
+        "-q",
+        "--max-size",
+        "1K",
+        "-d",
+        listed.path().to_str().unwrap(),
+        &url,
+    ]);
+    run(&[
+        "-q",
+        "--head-sizes",
+        "--max-size",
+        "1K",
+        "-d",
+        headed.path().to_str().unwrap(),
+        &url,
+    ]);
+
+    assert!(files_of(listed.path()).is_empty());
+    assert_eq!(
+        files_of(headed.path()),
+        [("pub/big.bin".to_owned(), "x".repeat(10))]
+    );
+    assert!(server.requests().contains(&"HEAD /pub/big.bin".to_owned()));
+}
+
+#[test]
+fn only_downloads_more_than_confirmed_with_yes() {
+    let server = Server::new();
+    server.file("pub/big.bin", "x".repeat(2048));
+    let refused = tempfile::tempdir().unwrap();
+    let confirmed = tempfile::tempdir().unwrap();
+
+    // Without a terminal to ask on
+    let output = command(&[
+        "-q",
+        "--confirm-over",
+        "1K",
+        "-d",
+        refused.path().to_str().unwrap(),
+        &server.url("/pub/"),
+    ])
+    .stdin(Stdio::null())
+    .output()
+    .unwrap();
+    assert!(!output.status.success());
+    assert!(files_of(refused.path()).is_empty());
+
+    run(&[
+        "-q",
+        "--confirm-over",
+        "1K",
+        "--yes",
+        "-d",
+        confirmed.path().to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+    assert_eq!(files_of(confirmed.path()).len(), 1);
+}
+
+#[test]
+fn only_downloads_files_changed_since_a_date() {
+    let server = Server::new();
+    server
+        .file("pub/old.txt", "old")
+        .file("pub/new.txt", "new")
+        .file("pub/sub/older.txt", "older")
+        .file("pub/sub/newer.txt", "newer")
+        .file("pub/sub/today.txt", "today");
+    for (path, days) in [
+        ("pub/old.txt", -1),
+        ("pub/new.txt", 0),
+        ("pub/sub/older.txt", -30),
+        ("pub/sub/newer.txt", 1),
+    ] {
+        let time = FileTime::from_unix_time(REMOTE_DATE + days * 86_400, 0);
+        set_file_mtime(server.root.path().join(path), time).unwrap();
+    }
+    let downloads = |since: &str| {
+        let destination = tempfile::tempdir().unwrap();
+        run(&[
+            "-q",
+            "--since",
+            since,
+            "-d",
+            destination.path().to_str().unwrap(),
+            &server.url("/pub/"),
+        ]);
+
+        files_of(destination.path())
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect::<Vec<_>>()
+    };
+
+    // On the day of the remote date, or after it
+    assert_eq!(
+        downloads("2021-01-01"),
+        ["pub/new.txt", "pub/sub/newer.txt", "pub/sub/today.txt"]
+    );
+    assert_eq!(downloads("7d"), ["pub/sub/today.txt"]);
+}
+
+#[test]
+fn only_downloads_the_accepted_types() {
+    let files = [
+        ("pub/photo.jpg", "jpeg", "image/jpeg"),
+        ("pub/doc.pdf", "pdf!!", "application/pdf"),
+        ("pub/page.jpg", "<html>", "text/html; charset=utf-8"),
+        ("pub/sub/pic.png", "png", "image/png"),
+    ];
+    let server = Server::with_handler(move |request| {
+        let (_, content, content_type) = files
+            .iter()
+            .find(|(path, _, _)| request.path == format!("/{}", path))?;
+        Some(Response::new(200, *content).header("Content-Type", content_type))
+    });
+    for (path, content, _) in files {
+        server.file(path, content);
+    }
+    let destination = tempfile::tempdir().unwrap();
+
+    run(&[
+        "-q",
+        "--accept-type",
+        "image/*,application/pdf",
+        "-d",
+        destination.path().to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+
+    // Even though its extension says otherwise
+    assert_eq!(
+        files_of(destination.path()),
+        [
+            ("pub/doc.pdf".to_owned(), "pdf!!".to_owned()),
+            ("pub/photo.jpg".to_owned(), "jpeg".to_owned()),
+            ("pub/sub/pic.png".to_owned(), "png".to_owned()),
+        ]
+    );
+    assert!(!server.requests().contains(&"GET /pub/page.jpg".to_owned()));
+}
+
+#[test]
+fn skips_the_files_of_ignore_files() {
+    let server = Server::new();
+    server
+        .file("pub/a.txt", "a")
+        .file("pub/b.tmp", "b")
+        .file("pub/sub/c.tmp", "c")
+        .file("pub/sub/keep.tmp", "keep");
+    let destination = tempfile::tempdir().unwrap();
+    fs::create_dir_all(destination.path().join("pub/sub")).unwrap();
+    fs::write(destination.path().join("pub/.od-get-ignore"), "*.tmp\n").unwrap();
+    fs::write(
+        destination.path().join("pub/sub/.od-get-ignore"),
+        "!keep.tmp\n",
+    )
+    .unwrap();
+
+    run(&[
+        "-q",
+        "-d",
+        destination.path().to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+
+    assert_eq!(
+        files_of(destination.path()),
+        [
+            ("pub/.od-get-ignore".to_owned(), "*.tmp\n".to_owned()),
+            ("pub/a.txt".to_owned(), "a".to_owned()),
+            (
+                "pub/sub/.od-get-ignore".to_owned(),
+                "!keep.tmp\n".to_owned()
+            ),
+            ("pub/sub/keep.tmp".to_owned(), "keep".to_owned()),
+        ]
+    );
+    assert!(!server.requests().contains(&"GET /pub/b.tmp".to_owned()));
+}
+
+#[test]
+fn skips_the_files_marked_by_the_operators() {
+    let server = Server::new();
+    server
+        .file("pub/a.txt", "a")
+        .file("pub/big.iso", "big")
+        .file("pub/big.iso.nomirror", "")
+        .file("pub/private/.nomirror", "")
+        .file("pub/private/secret.txt", "secret")
+        .file("pub/private/open/c.txt", "c");
+
+    for stream in [true, false] {
+        let destination = tempfile::tempdir().unwrap();
+        let mut args = vec![
+            "-q",
+            "--skip-marker",
+            ".nomirror",
+            "-d",
+            destination.path().to_str().unwrap(),
+        ];
+        if !stream {
+            args.push("--no-stream");
+        }
+        let url = server.url("/pub/");
+        args.push(&url);
+
+        run(&args);
+
+        // Sub-directories of a marked one are still mirrored
+        assert_eq!(
+            files_of(destination.path()),
+            [
+                ("pub/a.txt".to_owned(), "a".to_owned()),
+                ("pub/private/open/c.txt".to_owned(), "c".to_owned()),
+            ]
+        );
+    }
+    assert!(!server.requests().contains(&"GET /pub/big.iso".to_owned()));
+}
+
+#[test]
+fn downloads_a_sample_of_each_directory() {
+    let server = Server::new();
+    for (dir, count) in [("pub", 3), ("pub/five", 5), ("pub/one", 1)] {
+        for size in 1..=count {
+            server.file(&format!("{}/f{}.txt", dir, size), "x".repeat(size));
+        }
+    }
+    let destination = tempfile::tempdir().unwrap();
+
+    // The two largest files of each directory (or all of a smaller one)
+    run(&[
+        "-q",
+        "--sample-per-dir",
+        "2",
+        "--sort",
+        "size",
+        "--sort-desc",
+        "-d",
+        destination.path().to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+
+    let names: Vec<String> = files_of(destination.path())
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    assert_eq!(
+        names,
+        [
+            "pub/f2.txt",
+            "pub/f3.txt",
+            "pub/five/f4.txt",
+            "pub/five/f5.txt",
+            "pub/one/f1.txt",
+        ]
+    );
+}
@@ -0,0 +1,116 @@
+mod common;
+
+use common::{files_of, od_get, run, Server};
+use std::{
+    io::{self, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+/// Runs a SOCKS5 proxy which connects every host to localhost, returning its address & the hosts
+/// (as `host:port`) asked for
+fn serve_socks5() -> (String, Arc<Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let hosts = Arc::new(Mutex::new(vec![]));
+
+    thread::spawn({
+        let hosts = hosts.clone();
+        move || {
+            for stream in listener.incoming().flatten() {
+                let hosts = hosts.clone();
+                thread::spawn(move || {
+                    let _ = connect(stream, &hosts);
+                });
+            }
+        }
+    });
+
+    (address, hosts)
+}
+
+/// Answers the handshake of a client, then passes the bytes on to & from localhost
+fn connect(mut client: TcpStream, hosts: &Mutex<Vec<String>>) -> io::Result<()> {
+    // The greeting (only accepting no authentication)
+    let mut header = [0; 2];
+    client.read_exact(&mut header)?;
+    let mut methods = vec![0; header[1] as usize];
+    client.read_exact(&mut methods)?;
+    client.write_all(&[5, 0])?;
+
+    // The CONNECT request (with a domain name, as the proxy has to resolve the hosts)
+    let mut request = [0; 4];
+    client.read_exact(&mut request)?;
+    assert_eq!(request[3], 3, "Not a domain name");
+    let mut length = [0; 1];
+    client.read_exact(&mut length)?;
+    let mut host = vec![0; length[0] as usize];
+    client.read_exact(&mut host)?;
+    let mut port = [0; 2];
+    client.read_exact(&mut port)?;
+    let port = u16::from_be_bytes(port);
+    hosts
+        .lock()
+        .unwrap()
+        .push(format!("{}:{}", String::from_utf8_lossy(&host), port));
+
+    let server = TcpStream::connect(("127.0.0.1", port))?;
+    client.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0])?;
+
+    let (mut client_reader, mut server_writer) = (client.try_clone()?, server.try_clone()?);
+    thread::spawn(move || {
+        let _ = io::copy(&mut client_reader, &mut server_writer);
+        let _ = server_writer.shutdown(std::net::Shutdown::Write);
+    });
+    let (mut server_reader, mut client_writer) = (server, client);
+    io::copy(&mut server_reader, &mut client_writer)?;
+    client_writer.shutdown(std::net::Shutdown::Write)
+}
+
+#[test]
+fn mirrors_an_onion_host_through_the_proxy() {
+    let server = Server::new();
+    server.file("pub/a.txt", "a").file("pub/sub/b.txt", "bb");
+    let (proxy, hosts) = serve_socks5();
+    let onion = server.url("/pub/").replace("127.0.0.1", "mirror.onion");
+    let destination = tempfile::tempdir().unwrap();
+
+    run(&[
+        "-q",
+        "--socks5",
+        &proxy,
+        "-d",
+        destination.path().to_str().unwrap(),
+        &onion,
+    ]);
+
+    assert_eq!(
+        files_of(destination.path()),
+        [
+            ("pub/a.txt".to_owned(), "a".to_owned()),
+            ("pub/sub/b.txt".to_owned(), "bb".to_owned()),
+        ]
+    );
+
+    // Every connection went through the proxy, which got the name (instead of an address)
+    let port = server.url("").rsplit(':').next().unwrap().to_owned();
+    let hosts = hosts.lock().unwrap();
+    assert!(!hosts.is_empty());
+    assert!(
+        hosts
+            .iter()
+            .all(|host| *host == format!("mirror.onion:{}", port)),
+        "{:?}",
+        hosts
+    );
+}
+
+#[test]
+fn rejects_an_onion_host_without_a_proxy() {
+    let output = od_get(&["http://mirror.onion/pub/"]);
+
+    assert_eq!(output.status.code(), Some(2));
+    let log = String::from_utf8(output.stderr).unwrap();
+    assert!(log.contains("--socks5"), "{}", log);
+}
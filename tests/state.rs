@@ -0,0 +1,797 @@
+mod common;
+
+use common::{command, files_of, run, Response, Server};
+use serde_json::{json, Value};
+use std::{
+    fs,
+    path::Path,
+    process::Child,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Serves five files and one which never finishes (so the run can be interrupted during it)
+fn stalling_tree() -> Server {
+    let server = Server::with_handler(|request| {
+        if request.path == "/pub/z.bin" && request.method == "GET" {
+            thread::sleep(Duration::from_secs(30));
+        }
+        None
+    });
+    for name in ["a", "b", "c", "d", "e"] {
+        server.file(&format!("pub/{}.txt", name), name);
+    }
+    server.file("pub/z.bin", "z");
+    server
+}
+
+/// Reads the downloaded URLs of a state store (once it has been written)
+fn downloaded_urls(path: &Path) -> Option<Vec<String>> {
+    let state: Value = serde_json::from_slice(&fs::read(path).ok()?).ok()?;
+    let urls = state["downloaded_urls"].as_array()?;
+
+    Some(
+        urls.iter()
+            .map(|url| url.as_str().unwrap().to_owned())
+            .collect(),
+    )
+}
+
+/// Waits until the state store has recorded a number of downloads
+fn wait_for_downloads(child: &mut Child, path: &Path, count: usize) {
+    let started = Instant::now();
+    while downloaded_urls(path).is_none_or(|urls| urls.len() < count) {
+        assert!(child.try_wait().unwrap().is_none(), "od-get exited early");
+        assert!(
+            started.elapsed() < Duration::from_secs(20),
+            "No progress saved"
+        );
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn autosaves_the_progress() {
+    let server = stalling_tree();
+    let dir = tempfile::tempdir().unwrap();
+    let state = dir.path().join("state.json");
+
+    let mut child = command(&[
+        "-q",
+        "--save-interval",
+        "1",
+        "-S",
+        state.to_str().unwrap(),
+        "-d",
+        dir.path().join("files").to_str().unwrap(),
+        &server.url("/pub/"),
+    ])
+    .spawn()
+    .unwrap();
+
+    // Kill the run while it hangs on the last file
+    wait_for_downloads(&mut child, &state, 5);
+    child.kill().unwrap();
+    child.wait().unwrap();
+
+    let mut urls = downloaded_urls(&state).unwrap();
+    urls.sort();
+    let expected: Vec<String> = ["a", "b", "c", "d", "e"]
+        .iter()
+        .map(|name| server.url(&format!("/pub/{}.txt", name)))
+        .collect();
+    assert_eq!(urls, expected);
+}
+
+/// Waits until the files of a run have been written (except for the one which never finishes)
+fn wait_for_files(child: &mut Child, destination: &Path, count: usize) {
+    let started = Instant::now();
+    while count_files(destination) < count {
+        assert!(child.try_wait().unwrap().is_none(), "od-get exited early");
+        assert!(
+            started.elapsed() < Duration::from_secs(20),
+            "No files written"
+        );
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/**
+Counts the files of a mirror below a directory, without reading them (as the run may be renaming
+them)
+
+The files of the run itself (like the lock) are left out, so they can't stand in for a download.
+*/
+fn count_files(dir: &Path) -> usize {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| match entry.file_type() {
+            Ok(kind) if kind.is_dir() => count_files(&entry.path()),
+            _ if entry.file_name().to_string_lossy().starts_with(".od-get") => 0,
+            Ok(_) => 1,
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+/// Interrupts a run like Ctrl-C does
+#[cfg(unix)]
+fn interrupt(child: &mut Child) {
+    let status = std::process::Command::new("kill")
+        .args(["-INT", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let status = child.wait().unwrap();
+    assert!(!status.success());
+}
+
+#[test]
+#[cfg(unix)]
+fn saves_the_progress_when_interrupted() {
+    let server = stalling_tree();
+    let dir = tempfile::tempdir().unwrap();
+    let state = dir.path().join("state.json");
+    let destination = dir.path().join("files");
+
+    // Never save on the way (so only the interruption does)
+    let mut child = command(&[
+        "-q",
+        "--save-interval",
+        "1000",
+        "-S",
+        state.to_str().unwrap(),
+        "-d",
+        destination.to_str().unwrap(),
+        &server.url("/pub/"),
+    ])
+    .spawn()
+    .unwrap();
+
+    // The first files are there when the part file of the last one is
+    wait_for_files(&mut child, &destination, 6);
+    interrupt(&mut child);
+
+    assert_eq!(downloaded_urls(&state).unwrap().len(), 5);
+}
+
+#[test]
+#[cfg(unix)]
+fn interrupting_a_run_without_a_state_store_saves_nothing() {
+    let server = stalling_tree();
+    let dir = tempfile::tempdir().unwrap();
+    let destination = dir.path().join("files");
+
+    let mut child = command(&[
+        "-q",
+        "-d",
+        destination.to_str().unwrap(),
+        &server.url("/pub/"),
+    ])
+    .current_dir(dir.path())
+    .spawn()
+    .unwrap();
+    wait_for_files(&mut child, &destination, 6);
+    interrupt(&mut child);
+
+    let names: Vec<String> = files_of(dir.path())
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    assert!(
+        names.iter().all(|name| name.starts_with("files/")),
+        "{:?}",
+        names
+    );
+}
+
+#[test]
+fn stops_after_the_time_limit() {
+    let server = stalling_tree();
+    let dir = tempfile::tempdir().unwrap();
+    let state = dir.path().join("state.json");
+
+    // The last file would take 30 seconds
+    let started = Instant::now();
+    let output = command(&[
+        "-q",
+        "--max-time",
+        "3s",
+        "-S",
+        state.to_str().unwrap(),
+        "-d",
+        dir.path().join("files").to_str().unwrap(),
+        &server.url("/pub/"),
+    ])
+    .output()
+    .unwrap();
+    assert!(output.status.success());
+    assert!(started.elapsed() < Duration::from_secs(20));
+
+    let mut urls = downloaded_urls(&state).unwrap();
+    urls.sort();
+    let expected: Vec<String> = ["a", "b", "c", "d", "e"]
+        .iter()
+        .map(|name| server.url(&format!("/pub/{}.txt", name)))
+        .collect();
+    assert_eq!(urls, expected);
+}
+
+#[test]
+fn resumes_with_a_discovered_state_store() {
+    let server = Server::new();
+    server.file("pub/a.txt", "a").file("pub/b.txt", "b");
+    let dir = tempfile::tempdir().unwrap();
+    let cache = dir.path().join("cache");
+    let destination = dir.path().join("files");
+    let resume = || {
+        let output = command(&[
+            "-q",
+            "--resume",
+            "-d",
+            destination.to_str().unwrap(),
+            &server.url("/pub/"),
+        ])
+        .env("XDG_CACHE_HOME", &cache)
+        .output()
+        .unwrap();
+        assert!(output.status.success());
+    };
+
+    resume();
+    let stores: Vec<_> = fs::read_dir(cache.join("od-get")).unwrap().collect();
+    assert_eq!(stores.len(), 1);
+    let state = stores[0].as_ref().unwrap().path();
+    let mut urls = downloaded_urls(&state).unwrap();
+    urls.sort();
+    assert_eq!(urls, [server.url("/pub/a.txt"), server.url("/pub/b.txt")]);
+
+    // The second run finds the store, so it neither crawls nor downloads again
+    let requests = server.requests().len();
+    resume();
+    assert_eq!(server.requests()[requests..], ["GET /robots.txt"]);
+    assert_eq!(fs::read_dir(cache.join("od-get")).unwrap().count(), 1);
+}
+
+#[test]
+fn adds_up_the_stats_of_the_runs() {
+    let server = Server::new();
+    server.file("pub/a.txt", "aaa");
+    let dir = tempfile::tempdir().unwrap();
+    let state = dir.path().join("state.json");
+    let destination = dir.path().join("files");
+    let args = [
+        "-q",
+        "--stats",
+        "-S",
+        state.to_str().unwrap(),
+        "-d",
+        destination.to_str().unwrap(),
+    ];
+    let url = server.url("/pub/");
+    let stats = || {
+        let state: Value = serde_json::from_slice(&fs::read(&state).unwrap()).unwrap();
+        state["stats"].clone()
+    };
+
+    run(&[&args[..], &[url.as_str()]].concat());
+    let first = stats();
+    // The second run downloads the file again, as it changed
+    server.file("pub/a.txt", "aaaaa");
+    let output = run(&[&args[..], &["--revalidate", url.as_str()]].concat());
+    let second = stats();
+
+    assert_eq!(first["runs"], 1);
+    assert_eq!(first["bytes_downloaded"], 3);
+    assert_eq!(second["runs"], 2);
+    assert_eq!(second["bytes_downloaded"], 3 + 5);
+    assert!(second["requests"].as_u64() > first["requests"].as_u64());
+    assert!(second["elapsed_secs"].as_f64() >= first["elapsed_secs"].as_f64());
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("2 runs downloaded 8"), "{}", stdout);
+}
+
+#[test]
+fn summarizes_a_state_store() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = dir.path().join("state.json");
+    fs::write(
+        &state,
+        r#"{
+            "created_at": "2021-01-01T00:00:00Z",
+            "last_modified": "2021-01-02T00:00:00Z",
+            "crawling_state": {"Complete": {"CrawledDir": [
+                {"url": "http://a/", "name": "/", "last_modified": ""},
+                [
+                    {"File": {"url": "http://a/1.txt", "name": "1.txt", "last_modified": "", "size": "2K"}},
+                    {"CrawledDir": [
+                        {"url": "http://a/b/", "name": "/b", "last_modified": ""},
+                        [{"File": {"url": "http://a/b/2.txt", "name": "2.txt", "last_modified": "", "size": "1K"}}]
+                    ]}
+                ]
+            ]}},
+            "downloaded_urls": ["http://a/1.txt"]
+        }"#,
+    )
+    .unwrap();
+
+    let output = run(&["state-info", state.to_str().unwrap()]);
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    for line in [
+        "Schema version:  1",
+        "Crawling state:  Complete",
+        "Tree:            2 files in 2 directories, 3.0 KiB",
+        "Downloaded URLs: 1",
+        "Last modified:   2021-01-02T00:00:00Z",
+    ] {
+        assert!(stdout.contains(line), "{}", stdout);
+    }
+}
+
+#[test]
+fn previews_only_the_first_files() {
+    let server = Server::new();
+    server
+        .file("pub/a/1.txt", "1")
+        .file("pub/a/2.txt", "22")
+        .file("pub/a/3.txt", "333")
+        .file("pub/b/4.txt", "4444")
+        .file("pub/c/5.txt", "55555");
+    let dir = tempfile::tempdir().unwrap();
+    let state = dir.path().join("state.json");
+    let destination = dir.path().join("files");
+
+    run(&[
+        "-q",
+        "--preview",
+        "2",
+        "-S",
+        state.to_str().unwrap(),
+        "-d",
+        destination.to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+
+    // Nothing is listed once the preview has enough files
+    assert_eq!(
+        server.requests(),
+        [
+            "GET /robots.txt",
+            "GET /pub/",
+            "GET /pub/a/",
+            "GET /pub/a/1.txt",
+            "GET /pub/a/2.txt",
+        ]
+    );
+    assert_eq!(
+        files_of(&destination),
+        [
+            ("pub/a/1.txt".to_owned(), "1".to_owned()),
+            ("pub/a/2.txt".to_owned(), "22".to_owned()),
+        ]
+    );
+
+    let stored: Value = serde_json::from_slice(&fs::read(&state).unwrap()).unwrap();
+    let partial = &stored["crawling_state"]["Partial"];
+    assert_eq!(partial["truncated"], true);
+    assert_eq!(
+        partial["frontier"],
+        serde_json::json!([server.url("/pub/b/"), server.url("/pub/c/")])
+    );
+    assert_eq!(
+        downloaded_urls(&state).unwrap(),
+        [server.url("/pub/a/1.txt"), server.url("/pub/a/2.txt")]
+    );
+}
+
+/// Returns the files downloaded from the server since a number of requests
+fn file_downloads(server: &Server, since: usize) -> Vec<String> {
+    server.requests()[since..]
+        .iter()
+        .filter(|request| request.starts_with("GET /pub/") && !request.ends_with('/'))
+        .cloned()
+        .collect()
+}
+
+#[test]
+fn recognizes_moved_files_by_their_path() {
+    let server = Server::new();
+    server.file("pub/a.txt", "a").file("pub/sub/b.txt", "bb");
+    let dir = tempfile::tempdir().unwrap();
+    let state = dir.path().join("state.json");
+    let destination = dir.path().join("files");
+    let args = [
+        "-q",
+        "-S",
+        state.to_str().unwrap(),
+        "-d",
+        destination.to_str().unwrap(),
+    ];
+    run(&[&args[..], &[server.url("/pub/").as_str()]].concat());
+
+    // The same tree from another host
+    let moved = server.url("/pub/").replace("127.0.0.1", "localhost");
+    let requests = server.requests().len();
+    run(&[&args[..], &["--match-by", "path", moved.as_str()]].concat());
+
+    let downloads = file_downloads(&server, requests);
+    assert!(downloads.is_empty(), "{:?}", downloads);
+
+    // Matching by the URL downloads them again
+    let requests = server.requests().len();
+    run(&[&args[..], &["--match-by", "url", moved.as_str()]].concat());
+
+    let downloads = file_downloads(&server, requests);
+    assert_eq!(downloads, ["GET /pub/a.txt", "GET /pub/sub/b.txt"]);
+}
+
+#[test]
+fn resumes_a_partial_crawl_from_its_frontier() {
+    let server = Server::new();
+    server
+        .file("pub/a.txt", "a")
+        .file("pub/sub/b.txt", "bb")
+        .file("pub/sub/deeper/c.txt", "ccc")
+        .file("pub/other/d.txt", "dddd");
+    let dir = tempfile::tempdir().unwrap();
+    let state = dir.path().join("state.json");
+    let destination = dir.path().join("files");
+    let args = [
+        "-q",
+        "--no-stream",
+        "-S",
+        state.to_str().unwrap(),
+        "-d",
+        destination.to_str().unwrap(),
+    ];
+
+    // Only the root is listed, the rest is left as the frontier
+    let output = command(&[&args[..], &["--max-dirs", "1", &server.url("/pub/")]].concat())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stored: Value = serde_json::from_slice(&fs::read(&state).unwrap()).unwrap();
+    assert_eq!(
+        stored["crawling_state"]["Partial"]["frontier"],
+        serde_json::json!([server.url("/pub/other/"), server.url("/pub/sub/")])
+    );
+
+    let requests = server.requests().len();
+    run(&[&args[..], &[server.url("/pub/").as_str()]].concat());
+
+    let listed: Vec<String> = server.requests()[requests..]
+        .iter()
+        .filter(|request| request.ends_with('/'))
+        .cloned()
+        .collect();
+    assert_eq!(
+        listed,
+        ["GET /pub/other/", "GET /pub/sub/", "GET /pub/sub/deeper/"]
+    );
+    assert_eq!(
+        files_of(&destination),
+        [
+            ("pub/a.txt".to_owned(), "a".to_owned()),
+            ("pub/other/d.txt".to_owned(), "dddd".to_owned()),
+            ("pub/sub/b.txt".to_owned(), "bb".to_owned()),
+            ("pub/sub/deeper/c.txt".to_owned(), "ccc".to_owned()),
+        ]
+    );
+}
+
+#[test]
+#[cfg(unix)]
+fn resumes_the_download_from_the_queued_directories() {
+    // The last file of b/ stalls the first run only
+    let stalling = Arc::new(AtomicBool::new(true));
+    let server = Server::with_handler({
+        let stalling = stalling.clone();
+        move |request| {
+            if request.path == "/pub/b/z.bin" && stalling.swap(false, Ordering::SeqCst) {
+                thread::sleep(Duration::from_secs(30));
+            }
+            None
+        }
+    });
+    server
+        .file("pub/a/a1.txt", "a")
+        .file("pub/b/b1.txt", "bb")
+        .file("pub/b/z.bin", "zzz")
+        .file("pub/c/c1.txt", "cccc");
+    let dir = tempfile::tempdir().unwrap();
+    let state = dir.path().join("state.json");
+    let destination = dir.path().join("files");
+    let args = [
+        "--no-stream",
+        "-S",
+        state.to_str().unwrap(),
+        "-d",
+        destination.to_str().unwrap(),
+    ];
+
+    let mut child = command(&[&args[..], &["-q", server.url("/pub/").as_str()]].concat())
+        .spawn()
+        .unwrap();
+    wait_for_files(&mut child, &destination, 3);
+    interrupt(&mut child);
+
+    // a/ is done, b/ is not
+    let stored: Value = serde_json::from_slice(&fs::read(&state).unwrap()).unwrap();
+    assert_eq!(
+        stored["download_queue"],
+        serde_json::json!([server.url("/pub/b/"), server.url("/pub/c/")])
+    );
+
+    let output = run(&[&args[..], &["-v", server.url("/pub/").as_str()]].concat());
+    let log = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        log.contains("Resuming the download with 2 queued directories"),
+        "{}",
+        log
+    );
+    assert!(log.contains("Already have file b1.txt"), "{}", log);
+    assert!(!log.contains("a1.txt"), "{}", log);
+    assert_eq!(files_of(&destination).len(), 4);
+}
+
+#[test]
+#[cfg(unix)]
+fn refuses_to_run_while_another_run_holds_the_lock() {
+    let server = stalling_tree();
+    let dir = tempfile::tempdir().unwrap();
+    let destination = dir.path().join("files");
+    let args = [
+        "-q",
+        "-d",
+        destination.to_str().unwrap(),
+        &server.url("/pub/"),
+    ];
+
+    // The files & the part file of the last one
+    let mut first = command(&args).spawn().unwrap();
+    wait_for_files(&mut first, &destination, 6);
+    assert!(destination.join(".od-get.lock").exists());
+
+    let second = command(&args).output().unwrap();
+    assert!(!second.status.success());
+    let log = String::from_utf8(second.stderr).unwrap();
+    assert!(log.contains("Another run holds the lock"), "{}", log);
+
+    // The lock is released once the first run is interrupted
+    interrupt(&mut first);
+    assert!(!destination.join(".od-get.lock").exists());
+}
+
+/// Reads the URLs of the failed files of a state store
+fn failed_urls(path: &Path) -> Vec<String> {
+    let state: Value = serde_json::from_slice(&fs::read(path).unwrap()).unwrap();
+    let mut urls: Vec<String> = state["failed_files"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|file| file["url"].as_str().unwrap().to_owned())
+        .collect();
+    urls.sort();
+
+    urls
+}
+
+#[test]
+fn replays_only_the_failed_files() {
+    // The bad files fail until the server is healthy again
+    let healthy = Arc::new(AtomicBool::new(false));
+    let server = Server::with_handler({
+        let healthy = healthy.clone();
+        move |request| {
+            (request.path.contains("/bad") && !healthy.load(Ordering::SeqCst))
+                .then(|| Response::new(500, "Internal Server Error"))
+        }
+    });
+    server
+        .file("pub/good.txt", "good")
+        .file("pub/bad1.txt", "bad1")
+        .file("pub/sub/bad2.txt", "bad2")
+        .file("pub/sub/fine.txt", "fine");
+    let dir = tempfile::tempdir().unwrap();
+    let state = dir.path().join("state.json");
+    let destination = dir.path().join("files");
+
+    let args = [
+        "-q",
+        "-S",
+        state.to_str().unwrap(),
+        "-d",
+        destination.to_str().unwrap(),
+        &server.url("/pub/"),
+    ];
+    run(&[&args[..], &["--skip-errors"]].concat());
+    assert_eq!(
+        failed_urls(&state),
+        [server.url("/pub/bad1.txt"), server.url("/pub/sub/bad2.txt")]
+    );
+
+    healthy.store(true, Ordering::SeqCst);
+    let since = server.requests().len();
+    run(&[&args[..], &["--replay-failures"]].concat());
+
+    // Nothing was listed again, and only the failed files were fetched
+    let requests = &server.requests()[since..];
+    assert!(
+        !requests.iter().any(|request| request.ends_with('/')),
+        "{:?}",
+        requests
+    );
+    let mut downloads = file_downloads(&server, since);
+    downloads.sort();
+    assert_eq!(downloads, ["GET /pub/bad1.txt", "GET /pub/sub/bad2.txt"]);
+    assert!(failed_urls(&state).is_empty());
+    assert_eq!(
+        files_of(&destination),
+        [
+            ("pub/bad1.txt".to_owned(), "bad1".to_owned()),
+            ("pub/good.txt".to_owned(), "good".to_owned()),
+            ("pub/sub/bad2.txt".to_owned(), "bad2".to_owned()),
+            ("pub/sub/fine.txt".to_owned(), "fine".to_owned()),
+        ]
+    );
+}
+
+/// Returns the requests since a number of requests, except for the robots.txt
+fn requests_since(server: &Server, since: usize) -> Vec<String> {
+    server.requests()[since..]
+        .iter()
+        .filter(|request| !request.ends_with("/robots.txt"))
+        .cloned()
+        .collect()
+}
+
+#[test]
+fn stops_after_the_max_requests() {
+    let server = Server::new();
+    server
+        .file("pub/a.txt", "a")
+        .file("pub/b.txt", "b")
+        .file("pub/sub/c.txt", "c");
+    let dir = tempfile::tempdir().unwrap();
+    let state = dir.path().join("state.json");
+    let destination = dir.path().join("files");
+    let args = [
+        "-q",
+        "-S",
+        state.to_str().unwrap(),
+        "-d",
+        destination.to_str().unwrap(),
+        &server.url("/pub/"),
+    ];
+
+    // Both listings and one of the files
+    run(&[&args[..], &["--max-requests", "3"]].concat());
+
+    let first = requests_since(&server, 0);
+    assert_eq!(first.len(), 3, "{:?}", first);
+    assert_eq!(downloaded_urls(&state).unwrap().len(), 1);
+
+    // The next run only downloads the rest
+    let since = server.requests().len();
+    run(&args);
+
+    let second = requests_since(&server, since);
+    assert_eq!(second.len(), 2, "{:?}", second);
+    assert!(
+        second.iter().all(|request| !first.contains(request)),
+        "{:?} after {:?}",
+        second,
+        first
+    );
+    assert_eq!(files_of(&destination).len(), 3);
+}
+
+#[test]
+fn crawls_only_an_imported_frontier() {
+    let server = Server::new();
+    server
+        .file("pub/a.txt", "a")
+        .file("pub/x/1.txt", "1")
+        .file("pub/y/2.txt", "22")
+        .file("pub/z/3.txt", "333");
+    let dir = tempfile::tempdir().unwrap();
+    let frontier = dir.path().join("frontier.txt");
+
+    // The root and one of its directories are crawled, the others are left to another run
+    run(&[
+        "-q",
+        "--max-dirs",
+        "2",
+        "--export-frontier",
+        frontier.to_str().unwrap(),
+        "-d",
+        dir.path().join("first").to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+    let mut exported: Vec<String> = fs::read_to_string(&frontier)
+        .unwrap()
+        .lines()
+        .map(str::to_owned)
+        .collect();
+    exported.sort();
+    assert_eq!(exported.len(), 2, "{:?}", exported);
+
+    let since = server.requests().len();
+    let destination = dir.path().join("second");
+    run(&[
+        "-q",
+        "--import-frontier",
+        frontier.to_str().unwrap(),
+        "-d",
+        destination.to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+
+    let mut listed: Vec<String> = requests_since(&server, since)
+        .into_iter()
+        .filter(|request| request.ends_with('/'))
+        .map(|request| server.url(request.trim_start_matches("GET ")))
+        .collect();
+    listed.sort();
+    assert_eq!(listed, exported);
+    assert_eq!(files_of(&destination).len(), 2);
+    for (path, _) in files_of(&destination) {
+        let dir_url = server.url(&format!("/{}", &path[..path.rfind('/').unwrap() + 1]));
+        assert!(
+            exported.contains(&dir_url),
+            "{} not below {:?}",
+            path,
+            exported
+        );
+    }
+}
+
+#[test]
+fn records_the_retries_of_a_download() {
+    // The flaky file is only served on the third request
+    let failures = Arc::new(AtomicUsize::new(0));
+    let server = Server::with_handler({
+        let failures = failures.clone();
+        move |request| {
+            (request.path == "/pub/flaky.txt" && failures.fetch_add(1, Ordering::SeqCst) < 2)
+                .then(|| Response::new(503, "Service Unavailable").header("Retry-After", "1"))
+        }
+    });
+    server
+        .file("pub/flaky.txt", "flaky")
+        .file("pub/steady.txt", "steady");
+    let dir = tempfile::tempdir().unwrap();
+    let state = dir.path().join("state.json");
+
+    run(&[
+        "-q",
+        "-S",
+        state.to_str().unwrap(),
+        "-d",
+        dir.path().join("files").to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+
+    let state: Value = serde_json::from_slice(&fs::read(&state).unwrap()).unwrap();
+    let attempts = &state["attempts"];
+    assert_eq!(
+        attempts[server.url("/pub/flaky.txt")],
+        json!({"attempts": 3, "retries": 2, "status": "downloaded"})
+    );
+    assert_eq!(
+        attempts[server.url("/pub/steady.txt")],
+        json!({"attempts": 1, "retries": 0, "status": "downloaded"})
+    );
+}
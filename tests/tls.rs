@@ -0,0 +1,88 @@
+mod common;
+
+use common::{files_of, od_get, run, Server};
+use std::path::Path;
+
+/// Serves a small tree via HTTPS (with a certificate of a CA unknown to the system)
+fn tls_tree() -> Server {
+    let server = Server::with_tls();
+    server.file("pub/a.txt", "secure");
+    server
+}
+
+#[test]
+fn trusts_a_custom_ca() {
+    let server = tls_tree();
+    let destination = tempfile::tempdir().unwrap();
+    let ca = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/tls/ca.pem");
+
+    run(&[
+        "-q",
+        "--ca-cert",
+        ca.to_str().unwrap(),
+        "-d",
+        destination.path().to_str().unwrap(),
+        &server.url("/pub/"),
+    ]);
+
+    assert_eq!(
+        files_of(destination.path()),
+        [("pub/a.txt".to_owned(), "secure".to_owned())]
+    );
+}
+
+#[test]
+fn only_accepts_unknown_certificates_when_insecure() {
+    let server = tls_tree();
+    let destination = tempfile::tempdir().unwrap();
+    let destination = destination.path().to_str().unwrap();
+
+    let output = od_get(&["-q", "-d", destination, &server.url("/pub/")]);
+    assert!(!output.status.success());
+    let log = String::from_utf8(output.stderr).unwrap();
+    assert!(log.contains("certificate"), "{}", log);
+    assert!(files_of(Path::new(destination)).is_empty());
+
+    let output = run(&["--insecure", "-d", destination, &server.url("/pub/")]);
+    assert_eq!(
+        files_of(Path::new(destination)),
+        [("pub/a.txt".to_owned(), "secure".to_owned())]
+    );
+    let log = String::from_utf8(output.stderr).unwrap();
+    assert!(log.contains("NOT verifying TLS certificates"), "{}", log);
+}
+
+#[test]
+fn verifies_the_certificate_for_another_name() {
+    // The certificate is for localhost & 127.0.0.1 only
+    let server = Server::with_tls_at("127.0.0.2:0");
+    server.file("pub/a.txt", "secure");
+    let ca = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/tls/ca.pem");
+    let destination = tempfile::tempdir().unwrap();
+    let args = [
+        "-q",
+        "--ca-cert",
+        ca.to_str().unwrap(),
+        "-d",
+        destination.path().to_str().unwrap(),
+    ];
+
+    let output = od_get(&[&args[..], &[server.url("/pub/").as_str()]].concat());
+    assert!(!output.status.success());
+    let log = String::from_utf8(output.stderr).unwrap();
+    assert!(log.contains("certificate"), "{}", log);
+    assert!(files_of(destination.path()).is_empty());
+
+    // Still checked against the CA
+    let name = ["--tls-server-name", "localhost"];
+    run(&[&args[..], &name, &[server.url("/pub/").as_str()]].concat());
+    assert_eq!(
+        files_of(destination.path()),
+        [("pub/a.txt".to_owned(), "secure".to_owned())]
+    );
+
+    let elsewhere = tempfile::tempdir().unwrap();
+    let elsewhere = ["-q", "-d", elsewhere.path().to_str().unwrap()];
+    let output = od_get(&[&name[..], &elsewhere, &[server.url("/pub/").as_str()]].concat());
+    assert!(!output.status.success());
+}
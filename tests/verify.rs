@@ -0,0 +1,174 @@
+mod common;
+
+use common::{od_get, run, Server};
+use md5::Md5;
+use sha2::{Digest, Sha256};
+use std::fs;
+
+#[test]
+fn reports_missing_and_corrupted_files() {
+    let server = Server::new();
+    server
+        .file("pub/a.txt", "a")
+        .file("pub/b.txt", "bb")
+        .file("pub/sub/c.txt", "ccc");
+    let dir = tempfile::tempdir().unwrap();
+    let state = dir.path().join("state.json");
+    let destination = dir.path().join("files");
+    let (state, destination) = (state.to_str().unwrap(), destination.to_str().unwrap());
+    run(&["-q", "-S", state, "-d", destination, &server.url("/pub/")]);
+
+    // An intact mirror passes
+    run(&["verify", "-S", state, "-d", destination]);
+
+    fs::write(dir.path().join("files/pub/b.txt"), "corrupted").unwrap();
+    fs::remove_file(dir.path().join("files/pub/sub/c.txt")).unwrap();
+    let output = od_get(&["verify", "-S", state, "-d", destination]);
+    let log = String::from_utf8(output.stderr).unwrap();
+
+    assert!(!output.status.success());
+    let b = dir.path().join("files/pub/b.txt");
+    let c = dir.path().join("files/pub/sub/c.txt");
+    for line in [
+        format!("Wrong size of {} (9 B instead of 2 B)", b.display()),
+        format!("Missing {}", c.display()),
+        "Checked 3 files, 2 missing or corrupted".to_owned(),
+    ] {
+        assert!(log.contains(&line), "{}", log);
+    }
+}
+
+/// Returns the digest of some bytes as lowercase hex
+fn hex_digest<D: Digest>(bytes: &[u8]) -> String {
+    D::digest(bytes)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[test]
+fn emits_checksums_matching_the_files() {
+    let content: Vec<u8> = (0..300_000u32).map(|i| (i * 7 % 251) as u8).collect();
+    let server = Server::new();
+    server
+        .file("pub/big.bin", &content)
+        .file("pub/sub/small.txt", "small");
+
+    for (kind, expected) in [
+        ("sha256", hex_digest::<Sha256>(&content)),
+        ("md5", hex_digest::<Md5>(&content)),
+    ] {
+        let destination = tempfile::tempdir().unwrap();
+        run(&[
+            "-q",
+            "--emit-checksums",
+            kind,
+            "-d",
+            destination.path().to_str().unwrap(),
+            &server.url("/pub/"),
+        ]);
+
+        let files = destination.path().join("pub");
+        assert_eq!(fs::read(files.join("big.bin")).unwrap(), content);
+        assert_eq!(
+            fs::read_to_string(files.join(format!("big.bin.{}", kind))).unwrap(),
+            format!("{}  big.bin\n", expected)
+        );
+        assert!(files.join(format!("sub/small.txt.{}", kind)).exists());
+    }
+}
+
+#[test]
+fn fails_on_drift_from_the_lockfile() {
+    let server = Server::new();
+    server.file("pub/a.txt", "a").file("pub/sub/b.txt", "bb");
+    let destination = tempfile::tempdir().unwrap();
+    let args = [
+        "-q",
+        "-d",
+        destination.path().to_str().unwrap(),
+        &server.url("/pub/"),
+    ];
+
+    run(&[&args[..], &["--write-lockfile"]].concat());
+    let lockfile = fs::read_to_string(destination.path().join("od-get.lock")).unwrap();
+    assert!(
+        lockfile.contains(&hex_digest::<Sha256>(b"bb")),
+        "{}",
+        lockfile
+    );
+
+    // An unchanged mirror has nothing to download
+    let since = server.requests().len();
+    run(&[&args[..], &["--frozen"]].concat());
+    assert!(!server.requests()[since..].contains(&"GET /pub/a.txt".to_owned()));
+
+    // A new file is refused
+    server.file("pub/new.txt", "new");
+    let output = od_get(&[&args[..], &["--frozen"]].concat());
+    assert!(!output.status.success());
+    let log = String::from_utf8(output.stderr).unwrap();
+    assert!(log.contains("pub/new.txt"), "{}", log);
+    assert!(!destination.path().join("pub/new.txt").exists());
+
+    // So is a changed one
+    server
+        .remove("pub/new.txt")
+        .file("pub/sub/b.txt", "changed");
+    let output = od_get(&[&args[..], &["--frozen"]].concat());
+    assert!(!output.status.success());
+    let log = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        log.contains(&format!(
+            "pub/sub/b.txt ({}) differs from the lockfile",
+            server.url("/pub/sub/b.txt")
+        )),
+        "{}",
+        log
+    );
+    assert_eq!(
+        fs::read_to_string(destination.path().join("pub/sub/b.txt")).unwrap(),
+        "bb"
+    );
+}
+
+#[test]
+fn detects_and_repairs_corrupted_files_after_the_download() {
+    let server = Server::new();
+    server.file("pub/a.txt", "aaa").file("pub/sub/b.txt", "bbb");
+    let dir = tempfile::tempdir().unwrap();
+    let state = dir.path().join("state.json");
+    let destination = dir.path().join("files");
+    let args = [
+        "-q",
+        "--emit-checksums",
+        "sha256",
+        "-S",
+        state.to_str().unwrap(),
+        "-d",
+        destination.to_str().unwrap(),
+        &server.url("/pub/"),
+    ];
+    run(&args);
+
+    // A corruption which keeps the size
+    let corrupted = destination.join("pub/sub/b.txt");
+    fs::write(&corrupted, "bxb").unwrap();
+
+    let output = od_get(&[&args[..], &["--verify-after"]].concat());
+    assert!(!output.status.success());
+    let log = String::from_utf8(output.stderr).unwrap();
+    assert!(log.contains("has 1 missing or corrupted files"), "{}", log);
+    assert_eq!(fs::read_to_string(&corrupted).unwrap(), "bxb");
+
+    // Only the corrupted file is downloaded again
+    let since = server.requests().len();
+    run(&[&args[..], &["--verify-after", "--repair"]].concat());
+    assert_eq!(fs::read_to_string(&corrupted).unwrap(), "bbb");
+    let downloads: Vec<String> = server.requests()[since..]
+        .iter()
+        .filter(|request| request.starts_with("GET /pub/") && request.ends_with(".txt"))
+        .cloned()
+        .collect();
+    assert_eq!(downloads, ["GET /pub/sub/b.txt"]);
+}